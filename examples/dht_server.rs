@@ -7,17 +7,25 @@ use std::io::{Error, ErrorKind};
 
 use futures::*;
 use futures::sync::mpsc;
-use hex::FromHex;
-use tokio::net::UdpSocket;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::runtime::Runtime;
 use failure::Fail;
 
+use std::env;
+use std::fs::File;
 use std::net::SocketAddr;
 
 use tox::toxcore::dht::server::*;
+use tox::toxcore::dht::server::rate_limiter::RateLimiter;
+use tox::toxcore::dht::kbucket::SubnetLimit;
 use tox::toxcore::dht::server_ext::ServerExt;
 use tox::toxcore::dht::packed_node::*;
 use tox::toxcore::dht::lan_discovery::*;
 use tox::toxcore::crypto_core::*;
+use tox::toxcore::toxid::parse_public_key;
+use tox::toxcore::dissect::pcap::{PcapWriter, write_tap};
+use tox::toxcore::metrics::MetricsServer;
+use tox::toxcore::port_mapping::{PortMapper, PortMapperConfig, Protocol};
 use tox::toxcore::stats::Stats;
 
 const BOOTSTRAP_NODES: [(&str, &str); 9] = [
@@ -75,19 +83,57 @@ fn main() {
     server.set_bootstrap_info(3_000_000_000, Box::new(|_| b"This is tox-rs".to_vec()));
     server.enable_lan_discovery(true);
     server.enable_ipv6_mode(local_addr.is_ipv6());
+    server.set_rate_limiter(RateLimiter::default());
+    server.set_subnet_limits(SubnetLimits {
+        kbucket: Some(SubnetLimit::new(2)),
+        ktree: Some(SubnetLimit::new(8)),
+        friend: Some(SubnetLimit::new(2)),
+    });
 
     // Bootstrap from nodes
     for &(pk, saddr) in &BOOTSTRAP_NODES {
-        // get PK bytes of the bootstrap node
-        let bootstrap_pk_bytes: [u8; 32] = FromHex::from_hex(pk).unwrap();
-        // create PK from bytes
-        let bootstrap_pk = PublicKey::from_slice(&bootstrap_pk_bytes).unwrap();
+        let bootstrap_pk = parse_public_key(pk).unwrap();
 
         let saddr: SocketAddr = saddr.parse().unwrap();
         let bootstrap_pn = PackedNode::new(saddr, &bootstrap_pk);
         server.add_initial_bootstrap(bootstrap_pn);
     }
 
+    // Map UDP port on the gateway and report our external address to DHT
+    let (external_addr_tx, external_addr_rx) = mpsc::unbounded();
+    let mut port_mapper = PortMapper::new(PortMapperConfig::default());
+    port_mapper.set_external_addr_sink(external_addr_tx);
+    port_mapper.add_port(Protocol::Udp, local_addr.port());
+
+    // Serve statistics to Prometheus
+    let metrics_addr: SocketAddr = "127.0.0.1:9333".parse().unwrap();
+    let metrics_listener = TcpListener::bind(&metrics_addr).expect("Failed to bind metrics listener");
+    let mut metrics = MetricsServer::new(stats.clone());
+    metrics.add_dht_server_gauges(&server);
+    let metrics_future = metrics.run(metrics_listener)
+        .map_err(|e| error!("Metrics server ended with error: {:?}", e));
+
+    // Write all DHT packets to pcap file if TOX_PCAP is set
+    let pcap_future: Box<dyn Future<Item = (), Error = ()> + Send> = match env::var("TOX_PCAP") {
+        Ok(path) => {
+            let (tap_tx, tap_rx) = mpsc::unbounded();
+            server.set_tap_sink(tap_tx);
+            let writer = PcapWriter::new(File::create(path).expect("Failed to create pcap file"))
+                .expect("Failed to write pcap header");
+            Box::new(write_tap(tap_rx, writer).map_err(|e| error!("Pcap writing ended with error: {:?}", e)))
+        },
+        Err(_) => Box::new(future::empty()),
+    };
+
+    let server_c = server.clone();
+    let port_mapping_future = port_mapper.clone().run()
+        .map_err(|e| error!("Port mapping ended with error: {:?}", e))
+        .join(external_addr_rx.for_each(move |addr| {
+            server_c.set_external_addr(Some(addr));
+            Ok(())
+        }))
+        .map(|_| ());
+
     let future = server.run_socket(socket, rx, stats)
         .select(lan_discovery_sender.run().map_err(|e| Error::new(ErrorKind::Other, e.compat())))
         .map(|_| ())
@@ -95,5 +141,15 @@ fn main() {
 
     info!("Running DHT server on {}", local_addr);
 
-    tokio::run(future);
+    let mut runtime = Runtime::new().expect("Failed to create runtime");
+    // Auxiliary services are spawned separately so that their failures
+    // don't stop the DHT server
+    runtime.spawn(port_mapping_future);
+    runtime.spawn(metrics_future);
+    runtime.spawn(pcap_future);
+    runtime.block_on(future).ok();
+
+    // Remove port mappings from the gateway when the server stops
+    runtime.block_on(port_mapper.shutdown())
+        .unwrap_or_else(|e| error!("Failed to remove port mappings: {:?}", e));
 }
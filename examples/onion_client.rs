@@ -14,6 +14,7 @@ use tox::toxcore::dht::packed_node::*;
 use tox::toxcore::dht::packet::*;
 use tox::toxcore::dht::server::Server;
 use tox::toxcore::crypto_core::*;
+use tox::toxcore::toxid::parse_public_key;
 use tox::toxcore::onion::client::*;
 use tox::toxcore::tcp::client::Connections;
 use tox::toxcore::stats::Stats;
@@ -79,18 +80,14 @@ fn main() {
     let onion_client = OnionClient::new(dht_server, tcp_connections, dht_pk_tx, real_sk, real_pk);
 
     for &(pk, saddr) in &BOOTSTRAP_NODES {
-        // get PK bytes of the bootstrap node
-        let bootstrap_pk_bytes: [u8; 32] = FromHex::from_hex(pk).unwrap();
-        // create PK from bytes
-        let bootstrap_pk = PublicKey::from_slice(&bootstrap_pk_bytes).unwrap();
+        let bootstrap_pk = parse_public_key(pk).unwrap();
 
         let node = PackedNode::new(saddr.parse().unwrap(), &bootstrap_pk);
 
         onion_client.add_path_node(node);
     }
 
-    let friend_pk_bytes: [u8; 32] = FromHex::from_hex(FRIEND_PK).unwrap();
-    let friend_pk = PublicKey::from_slice(&friend_pk_bytes).unwrap();
+    let friend_pk = parse_public_key(FRIEND_PK).unwrap();
 
     onion_client.add_friend(friend_pk);
 
@@ -3,10 +3,11 @@ extern crate log;
 
 use tox::toxcore::crypto_core::*;
 use tox::toxcore::tcp::server::{Server, ServerExt};
+use tox::toxcore::metrics::MetricsServer;
 use tox::toxcore::stats::Stats;
 
 use tokio::net::TcpListener;
-use futures::Future;
+use futures::{future, Future};
 
 const TCP_CONNECTIONS_LIMIT: usize = 1024;
 
@@ -30,8 +31,25 @@ fn main() {
     let server = Server::new();
 
     let stats = Stats::new();
+
+    // Serve statistics to Prometheus
+    let metrics_addr = "127.0.0.1:9334".parse().unwrap();
+    let metrics_listener = TcpListener::bind(&metrics_addr).unwrap();
+    let mut metrics = MetricsServer::new(stats.clone());
+    metrics.add_tcp_server_gauges(&server);
+
+    let metrics_future = metrics.run(metrics_listener)
+        .map_err(|e| error!("Metrics server ended with error: {:?}", e));
+
     let future = server.run(listener, server_sk, stats, TCP_CONNECTIONS_LIMIT)
-        .map_err(|_| ());
+        .map_err(|e| error!("Processing ended with error: {:?}", e));
+
+    // Metrics server is spawned separately so that its failure doesn't stop
+    // the TCP server
+    let future = future::lazy(move || {
+        tokio::spawn(metrics_future);
+        future
+    });
 
     tokio::run(future);
 }
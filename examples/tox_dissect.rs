@@ -0,0 +1,72 @@
+// an example of dissecting Tox traffic from pcap or pcapng file
+//
+// usage: tox_dissect [--key SECRET_KEY]... [--pk PUBLIC_KEY]... FILE
+//
+// Secret keys are used to decrypt payloads. Public keys help to decrypt
+// packets that don't contain the sender's key like `CryptoHandshake`.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use hex::FromHex;
+
+use tox::toxcore::crypto_core::*;
+use tox::toxcore::dissect::Dissector;
+use tox::toxcore::dissect::pcap::read_capture;
+
+fn usage() -> ! {
+    eprintln!("usage: tox_dissect [--key SECRET_KEY]... [--pk PUBLIC_KEY]... FILE");
+    process::exit(1);
+}
+
+fn parse_key(hex: Option<String>) -> [u8; 32] {
+    hex.and_then(|hex| FromHex::from_hex(hex).ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn main() {
+    if crypto_init().is_err() {
+        panic!("Crypto initialization failed.");
+    }
+
+    let mut dissector = Dissector::new();
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => {
+                let sk = SecretKey::from_slice(&parse_key(args.next())).unwrap();
+                dissector.add_secret_key(sk);
+            },
+            "--pk" => {
+                let pk = PublicKey::from_slice(&parse_key(args.next())).unwrap();
+                dissector.add_public_key(pk);
+            },
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let data = fs::read(&path).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", path, e);
+        process::exit(1);
+    });
+    let packets = read_capture(&data).unwrap_or_else(|e| {
+        eprintln!("Failed to parse {}: {}", path, e);
+        process::exit(1);
+    });
+
+    for packet in packets {
+        for dissection in dissector.dissect_captured(&packet) {
+            println!("{}.{:06} {} -> {} {}",
+                packet.time.as_secs(),
+                packet.time.subsec_micros(),
+                packet.src,
+                packet.dst,
+                dissection
+            );
+        }
+    }
+}
@@ -33,6 +33,7 @@ pub mod toxcore {
     pub mod time;
     pub mod state_format;
     pub mod toxid;
+    pub mod identity;
     pub mod tcp;
     pub mod dht;
     pub mod onion;
@@ -41,6 +42,12 @@ pub mod toxcore {
     pub mod friend_connection;
     pub mod messenger;
     pub mod stats;
+    pub mod metrics;
+    pub mod events;
+    pub mod port_mapping;
+    pub mod dissect;
+    #[cfg(test)]
+    pub mod simulator;
 }
 
 /// Tox Encrypt Save (a.k.a. **TES**) module. Can be used to ecrypt / decrypt
@@ -53,4 +60,5 @@ pub mod toxencryptsave;
 #[cfg(test)]
 mod toxencryptsave_tests {
     mod encryptsave_tests;
+    mod stream_tests;
 }
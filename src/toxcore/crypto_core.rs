@@ -1,14 +1,104 @@
 //! Functions for the core crypto.
 
-pub use sodiumoxide::randombytes::randombytes_into;
 pub use sodiumoxide::crypto::box_::*;
 pub use sodiumoxide::crypto::hash::{sha256, sha512};
-pub use sodiumoxide::crypto::secretbox;
+
+#[cfg(test)]
+use std::cell::RefCell;
 
 use byteorder::{ByteOrder, LittleEndian, NativeEndian};
+use sodiumoxide::crypto::box_;
+use sodiumoxide::randombytes;
 
 use crate::toxcore::binary_io::*;
 
+/** Secret-key authenticated encryption.
+
+Re-exports [`sodiumoxide::crypto::secretbox`](../../../sodiumoxide/crypto/secretbox/index.html)
+with key and nonce generation that use [`randombytes_into`](fn.randombytes_into.html).
+*/
+pub mod secretbox {
+    pub use sodiumoxide::crypto::secretbox::*;
+
+    use super::randombytes_into;
+
+    /// Generate a random key.
+    pub fn gen_key() -> Key {
+        let mut key = [0; KEYBYTES];
+        randombytes_into(&mut key);
+        Key(key)
+    }
+
+    /// Generate a random nonce.
+    pub fn gen_nonce() -> Nonce {
+        let mut nonce = [0; NONCEBYTES];
+        randombytes_into(&mut nonce);
+        Nonce(nonce)
+    }
+}
+
+/// Source of random bytes that replaces libsodium generator.
+#[cfg(test)]
+pub type RandomBytesSource = Box<dyn FnMut(&mut [u8])>;
+
+#[cfg(test)]
+thread_local! {
+    /// Source of random bytes for the current thread. When it's set all
+    /// random values are taken from it so that the network simulator can
+    /// reproduce runs.
+    static RANDOM_BYTES_SOURCE: RefCell<Option<RandomBytesSource>> = RefCell::new(None);
+}
+
+/// Set the source of random bytes for the current thread. `None` restores
+/// libsodium generator. Returns the previous source.
+#[cfg(test)]
+pub fn set_random_bytes_source(source: Option<RandomBytesSource>) -> Option<RandomBytesSource> {
+    RANDOM_BYTES_SOURCE.with(|cell| cell.replace(source))
+}
+
+/// Fill `buf` from the source of random bytes of the current thread. Returns
+/// `false` if the source is not set.
+#[cfg(test)]
+fn random_bytes_from_source(buf: &mut [u8]) -> bool {
+    RANDOM_BYTES_SOURCE.with(|cell| match *cell.borrow_mut() {
+        Some(ref mut source) => {
+            source(buf);
+            true
+        },
+        None => false,
+    })
+}
+
+/// Fill `buf` with random bytes.
+pub fn randombytes_into(buf: &mut [u8]) {
+    #[cfg(test)]
+    {
+        if random_bytes_from_source(buf) {
+            return;
+        }
+    }
+    randombytes::randombytes_into(buf)
+}
+
+/// Generate a random nonce.
+pub fn gen_nonce() -> Nonce {
+    let mut nonce = [0; NONCEBYTES];
+    randombytes_into(&mut nonce);
+    Nonce(nonce)
+}
+
+/// Randomly generate a secret key and a corresponding public key.
+pub fn gen_keypair() -> (PublicKey, SecretKey) {
+    #[cfg(test)]
+    {
+        let mut seed = [0; SEEDBYTES];
+        if random_bytes_from_source(&mut seed) {
+            return keypair_from_seed(&Seed(seed));
+        }
+    }
+    box_::gen_keypair()
+}
+
 // TODO: check if `#[inline]` is actually useful
 
 /** Run before using crypto.
@@ -65,6 +65,18 @@ impl DecodeError {
     }
 }
 
+impl DecodeErrorKind {
+    /// Name of the error kind. Used to collect statistics.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DecodeErrorKind::TooBigPacket { .. } => "TooBigPacket",
+            DecodeErrorKind::IncompletePacket { .. } => "IncompletePacket",
+            DecodeErrorKind::Deserialize { .. } => "Deserialize",
+            DecodeErrorKind::Io => "Io",
+        }
+    }
+}
+
 error_kind! {
     #[doc = "Error that can happen when encoding `Packet` to bytes."]
     #[derive(Debug)]
@@ -127,20 +139,26 @@ impl Decoder for DhtCodec {
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         let len = buf.len();
-        if len > MAX_DHT_PACKET_SIZE {
-            return Err(DecodeError::too_big_packet(len))
-        }
+        let result = if len > MAX_DHT_PACKET_SIZE {
+            Err(DecodeError::too_big_packet(len))
+        } else {
+            match Packet::from_bytes(buf) {
+                IResult::Incomplete(needed) => Err(DecodeError::incomplete_packet(needed, buf.to_vec())),
+                IResult::Error(error) => Err(DecodeError::deserialize(error, buf.to_vec())),
+                IResult::Done(_, packet) => Ok(packet),
+            }
+        };
 
-        match Packet::from_bytes(buf) {
-            IResult::Incomplete(needed) => Err(DecodeError::incomplete_packet(needed, buf.to_vec())),
-            IResult::Error(error) => Err(DecodeError::deserialize(error, buf.to_vec())),
-            IResult::Done(_, packet) => {
+        match result {
+            Ok(ref packet) => {
                 // Add 1 to incoming counter
                 self.stats.counters.increase_incoming();
-
-                Ok(Some(packet))
-            }
+                self.stats.packets.add_packet(Transport::Udp, Direction::Incoming, packet.name(), len);
+            },
+            Err(ref error) => self.stats.packets.add_decode_error(Transport::Udp, error.kind().name()),
         }
+
+        result.map(Some)
     }
 }
 
@@ -150,10 +168,12 @@ impl Encoder for DhtCodec {
 
     fn encode(&mut self, packet: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
         let mut packet_buf = [0; MAX_DHT_PACKET_SIZE];
+        let name = packet.name();
         packet.to_bytes((&mut packet_buf, 0))
             .map(|(packet_buf, size)| {
                 // Add 1 to outgoing counter
                 self.stats.counters.increase_outgoing();
+                self.stats.packets.add_packet(Transport::Udp, Direction::Outgoing, name, size);
 
                 buf.extend(&packet_buf[..size]);
             })
@@ -381,6 +401,43 @@ mod tests {
         assert_eq!(*res.err().unwrap().kind(), EncodeErrorKind::Serialize { error: GenError::BufferTooSmall(2106) });
     }
 
+    #[test]
+    fn encode_decode_stats() {
+        crypto_init().unwrap();
+        let stats = Stats::new();
+        let mut codec = DhtCodec::new(stats.clone());
+        let mut buf = BytesMut::new();
+        let packet = Packet::PingRequest(PingRequest {
+            pk: gen_keypair().0,
+            nonce: gen_nonce(),
+            payload: vec![42; 88],
+        });
+
+        codec.encode(packet, &mut buf).unwrap();
+        let size = buf.len();
+        codec.decode(&mut buf).unwrap().unwrap();
+
+        let count = PacketCount { packets: 1, bytes: size as u64 };
+        assert_eq!(stats.packets.packet_count(Transport::Udp, Direction::Outgoing, "PingRequest"), count);
+        assert_eq!(stats.packets.packet_count(Transport::Udp, Direction::Incoming, "PingRequest"), count);
+    }
+
+    #[test]
+    fn decode_error_stats() {
+        crypto_init().unwrap();
+        let stats = Stats::new();
+        let mut codec = DhtCodec::new(stats.clone());
+
+        let mut buf = BytesMut::from(&b"\xFF"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(vec![0; MAX_DHT_PACKET_SIZE + 1]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        assert_eq!(stats.packets.decode_errors(Transport::Udp, "Deserialize"), 1);
+        assert_eq!(stats.packets.decode_errors(Transport::Udp, "TooBigPacket"), 1);
+        assert_eq!(stats.counters.incoming(), 0);
+    }
+
     #[test]
     fn codec_is_clonable() {
         crypto_init().unwrap();
@@ -13,6 +13,7 @@ use crate::toxcore::state_format::old::*;
 use crate::toxcore::binary_io::*;
 use crate::toxcore::dht::kbucket::*;
 use crate::toxcore::dht::ktree::*;
+use crate::toxcore::onion::onion_announce::*;
 
 use failure::Fail;
 use nom::{Needed, ErrorKind as NomErrorKind};
@@ -99,6 +100,33 @@ impl DaemonState {
         let nodes_stream = stream::futures_unordered(nodes_sender).then(|_| Ok(()));
         Either::B(nodes_stream.for_each(|()| Ok(())))
     }
+
+    /// Serialize onion announce entries together with secret bytes that are
+    /// used to generate onion ping ids.
+    pub fn serialize_onion_announce(server: &Server) -> Vec<u8> {
+        let state = server.onion_announce.read().state();
+
+        let mut buf = vec![0u8; ONION_ANNOUNCE_STATE_MAX_SIZE];
+        let (_, buf_len) = state.to_bytes((&mut buf, 0)).expect("OnionAnnounceState.to_bytes has failed");
+        buf.truncate(buf_len);
+        buf
+    }
+
+    /// Deserialize onion announce entries and secret bytes and restore them
+    /// so that nodes announced before restart can be found. Timed out entries
+    /// are skipped.
+    pub fn deserialize_onion_announce(server: &Server, serialized_data: &[u8]) -> Result<(), DeserializeError> {
+        let state = match OnionAnnounceState::from_bytes(serialized_data) {
+            IResult::Done(_, state) => state,
+            IResult::Incomplete(needed) =>
+                return Err(DeserializeError::incomplete(needed, serialized_data.to_vec())),
+            IResult::Error(error) =>
+                return Err(DeserializeError::deserialize(error, serialized_data.to_vec())),
+        };
+
+        server.onion_announce.write().restore(state);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -161,4 +189,23 @@ mod tests {
         let serialized_vec = DaemonState::serialize_old(&alice);
         assert!(DaemonState::deserialize_old(&alice, &serialized_vec).wait().is_ok());
     }
+
+    #[test]
+    fn daemon_state_serialize_deserialize_onion_announce() {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let (tx, _rx) = mpsc::channel(1);
+        let alice = Server::new(tx, pk, sk.clone());
+        let (tx, _rx) = mpsc::channel(1);
+        let bob = Server::new(tx, pk, sk);
+
+        let serialized_vec = DaemonState::serialize_onion_announce(&alice);
+        assert_ne!(DaemonState::serialize_onion_announce(&bob), serialized_vec);
+        DaemonState::deserialize_onion_announce(&bob, &serialized_vec).unwrap();
+        assert_eq!(DaemonState::serialize_onion_announce(&bob), serialized_vec);
+
+        // test with incompleted serialized data
+        let res = DaemonState::deserialize_onion_announce(&bob, &serialized_vec[..10]);
+        assert_eq!(*res.err().unwrap().kind(), DeserializeErrorKind::IncompleteData { needed: Needed::Size(32), data: serialized_vec[..10].to_vec() });
+    }
 }
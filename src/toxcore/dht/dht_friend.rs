@@ -217,4 +217,37 @@ mod tests {
         assert!(friend.can_add_to_close(&closer_node));
         assert!(friend.try_add_to_close(closer_node));
     }
+
+    #[test]
+    fn can_and_try_add_to_close_subnet_limit() {
+        crypto_init().unwrap();
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut friend = DhtFriend::new(pk);
+        friend.close_nodes.set_subnet_limit(Some(SubnetLimit::new(2)));
+
+        for i in 0 .. 6 {
+            let addr = SocketAddr::new(format!("1.2.{}.4", i).parse().unwrap(), 12345);
+            let node = PackedNode::new(addr, &PublicKey([i + 2; PUBLICKEYBYTES]));
+            assert!(friend.try_add_to_close(node));
+        }
+        for i in 6 .. 8 {
+            let addr = SocketAddr::new("5.6.7.8".parse().unwrap(), 12345 + u16::from(i));
+            let node = PackedNode::new(addr, &PublicKey([i + 2; PUBLICKEYBYTES]));
+            assert!(friend.try_add_to_close(node));
+        }
+
+        // closer node from the crowded subnet can evict only nodes from the
+        // same subnet
+        let closer_node = PackedNode::new(
+            "5.6.7.9:12345".parse().unwrap(),
+            &PublicKey([1; PUBLICKEYBYTES])
+        );
+        assert!(friend.can_add_to_close(&closer_node));
+        assert!(friend.try_add_to_close(closer_node));
+
+        let subnet = SubnetLimit::new(2);
+        assert_eq!(subnet.count(friend.close_nodes.iter(), &closer_node), 1);
+        assert!(friend.close_nodes.contains(&pk, &PublicKey([7; PUBLICKEYBYTES])));
+        assert!(!friend.close_nodes.contains(&pk, &PublicKey([9; PUBLICKEYBYTES])));
+    }
 }
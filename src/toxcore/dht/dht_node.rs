@@ -6,7 +6,7 @@ Even GOOD node is farther than BAD node, BAD node should be replaced.
 Here, GOOD node is the node responded within 162 seconds, BAD node is the node not responded over 162 seconds.
 */
 
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, Instant};
 
 use crate::toxcore::crypto_core::*;
@@ -200,6 +200,14 @@ impl HasPK for DhtNode {
     }
 }
 
+impl HasIpAddrs for DhtNode {
+    fn ip_addrs(&self) -> Vec<IpAddr> {
+        self.assoc4.saddr.into_iter().map(|saddr| IpAddr::V4(*saddr.ip()))
+            .chain(self.assoc6.saddr.into_iter().map(|saddr| IpAddr::V6(*saddr.ip())))
+            .collect()
+    }
+}
+
 impl KbucketNode for DhtNode {
     type NewNode = PackedNode;
     type CheckNode = PackedNode;
@@ -2,7 +2,7 @@
 Module for utils of IP and Port.
 */
 
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// TODO: replace with https://doc.rust-lang.org/std/net/struct.Ipv4Addr.html#method.is_global when it is stabilized
 pub trait IsGlobal {
@@ -68,6 +68,30 @@ impl IsGlobal for IpAddr {
     }
 }
 
+/// Get the subnet of an IP address, i.e. the address with all bits except
+/// `v4_prefix` leading bits for IPv4 or `v6_prefix` leading bits for IPv6
+/// zeroed. IPv4-mapped IPv6 addresses are treated as IPv4 ones.
+pub fn ip_subnet(ip: IpAddr, v4_prefix: u8, v6_prefix: u8) -> IpAddr {
+    let ip = match ip {
+        IpAddr::V6(ipv6) => match ipv6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(ipv6.to_ipv4().expect("IPv4-mapped address")),
+            _ => ip,
+        },
+        ip => ip,
+    };
+
+    match ip {
+        IpAddr::V4(ipv4) => {
+            let mask = (!0u32).checked_shl(32 - u32::from(v4_prefix.min(32))).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ipv4) & mask))
+        },
+        IpAddr::V6(ipv6) => {
+            let mask = (!0u128).checked_shl(128 - u32::from(v6_prefix.min(128))).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ipv6) & mask))
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,4 +131,26 @@ mod tests {
         let ipv4 = "128.0.0.1".parse().unwrap();
         assert!(IsGlobal::is_global(&IpAddr::V4(ipv4)));
     }
+
+    #[test]
+    fn ip_subnet_v4() {
+        let ip = "1.2.3.4".parse().unwrap();
+        assert_eq!(ip_subnet(ip, 24, 64), "1.2.3.0".parse::<IpAddr>().unwrap());
+        assert_eq!(ip_subnet(ip, 32, 64), ip);
+        assert_eq!(ip_subnet(ip, 0, 64), "0.0.0.0".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn ip_subnet_v6() {
+        let ip = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(ip_subnet(ip, 24, 64), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+        assert_eq!(ip_subnet(ip, 24, 48), "2001:db8:1::".parse::<IpAddr>().unwrap());
+        assert_eq!(ip_subnet(ip, 24, 128), ip);
+    }
+
+    #[test]
+    fn ip_subnet_v4_mapped() {
+        let ip = IpAddr::V6(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped());
+        assert_eq!(ip_subnet(ip, 24, 64), "1.2.3.0".parse::<IpAddr>().unwrap());
+    }
 }
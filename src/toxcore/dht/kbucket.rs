@@ -14,8 +14,10 @@ PK; and additionally used to store nodes closest to friends.
 
 use std::cmp::{Ord, Ordering};
 use std::convert::{From, Into};
+use std::net::IpAddr;
 
 use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::ip_port::ip_subnet;
 use crate::toxcore::dht::packed_node::*;
 
 /** Calculate the [`k-tree`](../ktree/struct.Ktree.html) index of a PK compared
@@ -42,6 +44,27 @@ pub fn kbucket_index(&PublicKey(ref own_pk): &PublicKey,
     None  // PKs are equal
 }
 
+/** Generate a random `PublicKey` that has the given
+[`kbucket index`](./fn.kbucket_index.html) compared to "own" PK.
+
+The first `index` bits of the key are equal to the bits of own PK, the next bit
+differs and the rest bits are random.
+*/
+pub fn random_pk_in_kbucket(own_pk: &PublicKey, index: u8) -> PublicKey {
+    let own_pk = &own_pk.0;
+    let mut pk = [0; PUBLICKEYBYTES];
+    randombytes_into(&mut pk);
+
+    let byte = index as usize / 8;
+    let bit = 0x80 >> (index % 8);
+    // bits that must be equal to own PK bits
+    let prefix = !(0xff >> (index % 8));
+
+    pk[.. byte].copy_from_slice(&own_pk[.. byte]);
+    pk[byte] = (own_pk[byte] & prefix) | (!own_pk[byte] & bit) | (pk[byte] & !prefix & !bit);
+    PublicKey(pk)
+}
+
 /// Trait for functionality related to distance between `PublicKey`s.
 pub trait Distance {
     /// Check whether distance between PK1 and own PK is smaller than distance
@@ -77,13 +100,79 @@ impl HasPK for PackedNode {
     }
 }
 
+/// Anything that has IP addresses.
+pub trait HasIpAddrs {
+    /// IP addresses.
+    fn ip_addrs(&self) -> Vec<IpAddr>;
+}
+
+impl HasIpAddrs for PackedNode {
+    fn ip_addrs(&self) -> Vec<IpAddr> {
+        vec![self.saddr.ip()]
+    }
+}
+
+/// Default number of leading bits of IPv4 address that define a subnet.
+pub const SUBNET_DEFAULT_IPV4_PREFIX: u8 = 24;
+/// Default number of leading bits of IPv6 address that define a subnet.
+pub const SUBNET_DEFAULT_IPV6_PREFIX: u8 = 48;
+
+/** Limit on the number of nodes from the same subnet.
+
+Without such limit an attacker that owns a single subnet can generate many
+keys close to our `PublicKey` and fill our close lists with own nodes (Sybil
+attack).
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SubnetLimit {
+    /// Number of leading bits of IPv4 address that define a subnet.
+    pub ipv4_prefix: u8,
+    /// Number of leading bits of IPv6 address that define a subnet.
+    pub ipv6_prefix: u8,
+    /// Maximum number of nodes from the same subnet.
+    pub max_nodes: u8,
+}
+
+impl SubnetLimit {
+    /// Create new `SubnetLimit` for IPv4 /24 and IPv6 /48 subnets.
+    pub fn new(max_nodes: u8) -> SubnetLimit {
+        SubnetLimit {
+            ipv4_prefix: SUBNET_DEFAULT_IPV4_PREFIX,
+            ipv6_prefix: SUBNET_DEFAULT_IPV6_PREFIX,
+            max_nodes,
+        }
+    }
+
+    /// Check if two nodes have addresses from the same subnet.
+    pub fn is_same_subnet<A: HasIpAddrs, B: HasIpAddrs>(&self, a: &A, b: &B) -> bool {
+        let b_subnets = b.ip_addrs()
+            .into_iter()
+            .map(|ip| ip_subnet(ip, self.ipv4_prefix, self.ipv6_prefix))
+            .collect::<Vec<_>>();
+        a.ip_addrs()
+            .into_iter()
+            .map(|ip| ip_subnet(ip, self.ipv4_prefix, self.ipv6_prefix))
+            .any(|subnet| b_subnets.contains(&subnet))
+    }
+
+    /// Count nodes that have addresses from the same subnet as the given node
+    /// excluding the node itself.
+    pub fn count<'a, A, B, I>(&self, nodes: I, node: &B) -> usize
+        where A: HasPK + HasIpAddrs + 'a, B: HasPK + HasIpAddrs, I: Iterator<Item = &'a A>
+    {
+        nodes
+            .filter(|n| n.pk() != node.pk() && self.is_same_subnet(*n, node))
+            .count()
+    }
+}
+
 /// Node that can be stored in a `Kbucket`.
-pub trait KbucketNode : Sized + HasPK {
+pub trait KbucketNode : Sized + HasPK + HasIpAddrs {
     /// The type of nodes that can be added to a `Kbucket`.
-    type NewNode: HasPK;
+    type NewNode: HasPK + HasIpAddrs;
     /// The type of nodes that can be checked if they can be added to a
     /// `Kbucket`.
-    type CheckNode: HasPK;
+    type CheckNode: HasPK + HasIpAddrs;
 
     /// Check if the node can be updated with a new one.
     fn is_outdated(&self, other: &Self::CheckNode) -> bool;
@@ -131,6 +220,11 @@ one, while furthest is the last one.
 Used in [`Ktree`](../ktree/struct.Ktree.html) for storing nodes close to given
 PK; and additionally used to store nodes closest to friends.
 
+Optionally the number of nodes from the same subnet can be limited with
+[`SubnetLimit`](./struct.SubnetLimit.html). A node from the subnet that already
+has the maximum number of nodes can only replace another node from the same
+subnet so diverse nodes are never evicted in favour of it.
+
 [Spec definition](https://zetok.github.io/tox-spec#updating-k-buckets).
 
 [Kademlia whitepaper](https://pdos.csail.mit.edu/~petar/papers/maymounkov-kademlia-lncs.pdf).
@@ -141,6 +235,13 @@ pub struct Kbucket<Node> {
     pub capacity: u8,
     /// Nodes that kbucket has, sorted by distance to PK.
     pub nodes: Vec<Node>,
+    /// Limit on the number of nodes from the same subnet.
+    subnet_limit: Option<SubnetLimit>,
+    /// Amount of nodes the replacement cache can hold.
+    replacements_capacity: u8,
+    /// Recently seen nodes that didn't fit into the kbucket, sorted by the
+    /// time they were seen; the most recent node is the last one.
+    replacements: Vec<Node>,
 }
 
 impl<Node> Into<Vec<Node>> for Kbucket<Node> {
@@ -154,8 +255,8 @@ pub const KBUCKET_DEFAULT_SIZE: u8 = 8;
 
 impl<NewNode, CheckNode, Node> Kbucket<Node>
 where
-    NewNode: HasPK,
-    CheckNode: HasPK,
+    NewNode: HasPK + HasIpAddrs,
+    CheckNode: HasPK + HasIpAddrs,
     Node: KbucketNode<NewNode = NewNode, CheckNode = CheckNode> + From<NewNode>
 {
     /** Create a new `Kbucket` to store nodes close to the `PublicKey`.
@@ -167,9 +268,120 @@ where
         Kbucket {
             capacity,
             nodes: Vec::with_capacity(capacity as usize),
+            subnet_limit: None,
+            replacements_capacity: 0,
+            replacements: Vec::new(),
         }
     }
 
+    /// Set the amount of nodes the replacement cache can hold. Replacement
+    /// cache is disabled when it's 0 which is the default.
+    pub fn set_replacements_capacity(&mut self, capacity: u8) {
+        self.replacements_capacity = capacity;
+        let len = self.replacements.len();
+        if len > capacity as usize {
+            self.replacements.drain(.. len - capacity as usize);
+        }
+    }
+
+    /// Get nodes from the replacement cache. The most recently seen node is
+    /// the last one.
+    pub fn replacements(&self) -> &[Node] {
+        &self.replacements
+    }
+
+    /// Add a node to the replacement cache. If the cache is full the least
+    /// recently seen node is removed.
+    fn add_replacement(&mut self, node: Node) {
+        if self.replacements_capacity == 0 {
+            return;
+        }
+
+        let pk = node.pk();
+        self.replacements.retain(|n| n.pk() != pk);
+        if self.replacements.len() >= self.replacements_capacity as usize {
+            self.replacements.remove(0);
+        }
+        self.replacements.push(node);
+    }
+
+    /**
+    Replace evictable nodes with the most recently seen nodes from the
+    replacement cache. Nodes from the replacement cache are also used to fill
+    free space of the `Kbucket`. Evictable nodes in the replacement cache are
+    removed from it.
+
+    Note that you must pass the same `base_pk` each call or the internal
+    state will be undefined.
+
+    Returns the number of promoted nodes.
+    */
+    pub fn promote_replacements(&mut self, base_pk: &PublicKey) -> usize {
+        self.promote_replacements_limited(base_pk, |_node, _nodes| false)
+    }
+
+    /**
+    Same as [`promote_replacements`](#method.promote_replacements) but nodes
+    for which `is_crowded` returns `true` are removed from the replacement
+    cache instead of being promoted. `is_crowded` is called with a node from
+    the replacement cache and the current nodes of the `Kbucket` so that
+    limits wider than one `Kbucket` can be enforced.
+
+    Returns the number of promoted nodes.
+    */
+    pub fn promote_replacements_limited<F>(&mut self, base_pk: &PublicKey, mut is_crowded: F) -> usize
+        where F: FnMut(&Node, &[Node]) -> bool
+    {
+        self.replacements.retain(|node| !node.is_evictable());
+
+        let mut promoted = 0;
+        while let Some(node) = self.replacements.pop() {
+            if self.crowded_subnet(&node).is_some() || is_crowded(&node, &self.nodes) {
+                continue;
+            }
+
+            if self.is_full() {
+                match Node::eviction_index(&self.nodes) {
+                    Some(index) => {
+                        debug!(target: "Kbucket",
+                            "Evictable node replaced with a node from the replacement cache.");
+                        self.nodes.remove(index);
+                    },
+                    None => {
+                        self.replacements.push(node);
+                        break;
+                    },
+                }
+            }
+
+            let index = self.nodes.binary_search_by(|n| base_pk.distance(&n.pk(), &node.pk()))
+                .unwrap_or_else(|index| index);
+            self.nodes.insert(index, node);
+            promoted += 1;
+        }
+
+        promoted
+    }
+
+    /// Set limit on the number of nodes from the same subnet. Nodes that are
+    /// already in the `Kbucket` are not affected.
+    pub fn set_subnet_limit(&mut self, subnet_limit: Option<SubnetLimit>) {
+        self.subnet_limit = subnet_limit;
+    }
+
+    /// Get limit on the number of nodes from the same subnet.
+    pub fn subnet_limit(&self) -> Option<SubnetLimit> {
+        self.subnet_limit
+    }
+
+    /// Check if the subnet of the node already has the maximum number of nodes
+    /// in the `Kbucket`. Returns the exceeded limit in this case.
+    pub fn crowded_subnet<T: HasPK + HasIpAddrs>(&self, node: &T) -> Option<SubnetLimit> {
+        self.subnet_limit.filter(|limit|
+            limit.count(self.nodes.iter(), node) >= limit.max_nodes as usize
+        )
+    }
+
     fn find(&self, base_pk: &PublicKey, pk: &PublicKey) -> Option<usize> {
         self.nodes.binary_search_by(|n| base_pk.distance(&n.pk(), pk)).ok()
     }
@@ -197,6 +409,8 @@ where
       that node, and last node is removed from the list.
     - If the node being added is farther away than the nodes in the kbucket or
       `evict` is `false`, it isn't added and `false` is returned.
+    - If the subnet limit is set and the node's subnet already has the maximum
+      number of nodes, it can only replace a node from the same subnet.
 
     Note that you must pass the same `base_pk` each call or the internal
     state will be undefined.
@@ -211,8 +425,29 @@ where
     [`PackedNode`]: ../packed_node/struct.PackedNode.html
     */
     pub fn try_add(&mut self, base_pk: &PublicKey, new_node: NewNode, evict: bool) -> bool {
+        let crowded_subnet = self.crowded_subnet(&new_node);
+        self.try_add_limited(base_pk, new_node, evict, crowded_subnet)
+    }
+
+    /**
+    Try to add [`PackedNode`] to the kbucket like `try_add` does but with the
+    explicitly provided subnet state.
+
+    If `crowded_subnet` is `Some` then the subnet of the node already has the
+    maximum number of allowed nodes. In this case the node can only replace
+    another node from the same subnet that is either evictable or, if `evict`
+    is `true`, farther than the new node.
+
+    [`PackedNode`]: ../packed_node/struct.PackedNode.html
+    */
+    pub fn try_add_limited(&mut self, base_pk: &PublicKey, new_node: NewNode, evict: bool, crowded_subnet: Option<SubnetLimit>) -> bool {
         trace!(target: "Kbucket", "Trying to add PackedNode: {:?}.", new_node.pk());
 
+        // the node will be put back to the replacement cache if it won't fit
+        // into the kbucket
+        let new_pk = new_node.pk();
+        self.replacements.retain(|n| n.pk() != new_pk);
+
         match self.nodes.binary_search_by(|n| base_pk.distance(&n.pk(), &new_node.pk())) {
             Ok(index) => {
                 debug!(target: "Kbucket",
@@ -220,6 +455,24 @@ where
                 self.nodes[index].update(&new_node);
                 true
             },
+            Err(index) if crowded_subnet.is_some() => {
+                let limit = crowded_subnet.expect("crowded_subnet is some");
+                match self.subnet_replacement_index(&limit, &new_node, index, evict) {
+                    Some(replace_index) => {
+                        debug!(target: "Kbucket",
+                            "Subnet limit is reached, the node from the same subnet replaced.");
+                        self.nodes.remove(replace_index);
+                        let index = if replace_index < index { index - 1 } else { index };
+                        self.nodes.insert(index, new_node.into());
+                        true
+                    },
+                    None => {
+                        debug!(target: "Kbucket",
+                            "Subnet limit is reached, node can't be added to the kbucket.");
+                        false
+                    },
+                }
+            },
             Err(index) if !evict || index == self.nodes.len() => {
                 // index is pointing past the end
                 // we are not going to evict the farthest node or the current
@@ -237,6 +490,7 @@ where
                         None => {
                             debug!(target: "Kbucket",
                                 "Node can't be added to the kbucket.");
+                            self.add_replacement(new_node.into());
                             false
                         },
                     }
@@ -255,7 +509,11 @@ where
                 if self.is_full() {
                     debug!(target: "Kbucket",
                         "No free space left in the kbucket, the last node removed.");
-                    self.nodes.pop();
+                    if let Some(node) = self.nodes.pop() {
+                        if !node.is_evictable() {
+                            self.add_replacement(node);
+                        }
+                    }
                 }
                 debug!(target: "Kbucket", "Node inserted inside the kbucket.");
                 self.nodes.insert(index, new_node.into());
@@ -338,10 +596,26 @@ where
     [`PackedNode`]: ./struct.PackedNode.html
     */
     pub fn can_add(&self, base_pk: &PublicKey, new_node: &CheckNode, evict: bool) -> bool {
+        let crowded_subnet = self.crowded_subnet(new_node);
+        self.can_add_limited(base_pk, new_node, evict, crowded_subnet)
+    }
+
+    /// Check whether a [`PackedNode`] can be added to the `Kbucket` like
+    /// `can_add` does but with the explicitly provided subnet state. See
+    /// `try_add_limited` for details.
+    ///
+    /// [`PackedNode`]: ./struct.PackedNode.html
+    pub fn can_add_limited(&self, base_pk: &PublicKey, new_node: &CheckNode, evict: bool, crowded_subnet: Option<SubnetLimit>) -> bool {
         match self.nodes.binary_search_by(|n| base_pk.distance(&n.pk(), &new_node.pk())) {
             Ok(index) =>
                 // if node is bad then we'd want to update it's address
                 self.nodes[index].is_evictable() || self.nodes[index].is_outdated(new_node),
+            Err(index) if crowded_subnet.is_some() => {
+                // subnet of the node is full so it can be added only instead
+                // of a node from the same subnet
+                let limit = crowded_subnet.expect("crowded_subnet is some");
+                self.subnet_replacement_index(&limit, new_node, index, evict).is_some()
+            },
             Err(index) if !evict || index == self.nodes.len() =>
                 // can't find node in the kbucket
                 // we are not going to evict the farthest node or the current
@@ -354,6 +628,24 @@ where
         }
     }
 
+    /// Find the index of a node from the same subnet as the new node that
+    /// can be replaced with it. Evictable nodes are preferred, otherwise the
+    /// farthest node from the same subnet is replaced if it's farther than the
+    /// new node and `evict` is `true`. `index` is the position where the new
+    /// node would be inserted.
+    fn subnet_replacement_index<T: HasIpAddrs>(&self, limit: &SubnetLimit, new_node: &T, index: usize, evict: bool) -> Option<usize> {
+        let same_subnet = |node: &Node| limit.is_same_subnet(node, new_node);
+        self.nodes.iter()
+            .rposition(|node| same_subnet(node) && node.is_evictable())
+            .or_else(|| if evict {
+                self.nodes.iter()
+                    .rposition(same_subnet)
+                    .filter(|&same_subnet_index| same_subnet_index >= index)
+            } else {
+                None
+            })
+    }
+
     /// Create iterator over [`KbucketNode`](./struct.KbucketNode.html)s in
     /// `Ktree`. Nodes that this iterator produces are sorted by distance to a
     /// base `PublicKey` (in ascending order).
@@ -514,6 +806,191 @@ mod tests {
         });
     }
 
+    #[test]
+    fn kbucket_try_add_subnet_limit() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::<DhtNode>::new(4);
+        kbucket.set_subnet_limit(Some(SubnetLimit::new(2)));
+
+        let node_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([3; PUBLICKEYBYTES]));
+        let node_2 = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey([4; PUBLICKEYBYTES]));
+        let node_3 = PackedNode::new("1.2.3.6:12345".parse().unwrap(), &PublicKey([5; PUBLICKEYBYTES]));
+        let node_4 = PackedNode::new("1.2.4.4:12345".parse().unwrap(), &PublicKey([6; PUBLICKEYBYTES]));
+
+        assert!(kbucket.try_add(&pk, node_1, /* evict */ false));
+        assert!(kbucket.try_add(&pk, node_2, /* evict */ false));
+        // the third node from the same /24 subnet can't be added even though
+        // the kbucket has free space
+        assert!(!kbucket.can_add(&pk, &node_3, /* evict */ false));
+        assert!(!kbucket.try_add(&pk, node_3, /* evict */ false));
+        // but a node from another subnet can
+        assert!(kbucket.can_add(&pk, &node_4, /* evict */ false));
+        assert!(kbucket.try_add(&pk, node_4, /* evict */ false));
+        // already added nodes still can be updated
+        let node_1_new = PackedNode::new("1.2.3.7:12345".parse().unwrap(), &node_1.pk);
+        assert!(kbucket.try_add(&pk, node_1_new, /* evict */ false));
+
+        assert_eq!(kbucket.len(), 3);
+    }
+
+    #[test]
+    fn kbucket_try_add_subnet_limit_evict_prefers_diverse_nodes() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::<DhtNode>::new(3);
+        kbucket.set_subnet_limit(Some(SubnetLimit::new(1)));
+
+        let diverse_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([5; PUBLICKEYBYTES]));
+        let diverse_2 = PackedNode::new("1.2.4.4:12345".parse().unwrap(), &PublicKey([6; PUBLICKEYBYTES]));
+        let attacker_1 = PackedNode::new("5.6.7.8:12345".parse().unwrap(), &PublicKey([4; PUBLICKEYBYTES]));
+        let attacker_2 = PackedNode::new("5.6.7.9:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let attacker_3 = PackedNode::new("5.6.7.10:12345".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+
+        assert!(kbucket.try_add(&pk, diverse_1, /* evict */ true));
+        assert!(kbucket.try_add(&pk, diverse_2, /* evict */ true));
+        assert!(kbucket.try_add(&pk, attacker_1, /* evict */ true));
+        assert!(kbucket.is_full());
+
+        // closer attacker's node evicts the farther node from the same subnet
+        // instead of the farthest node
+        assert!(kbucket.can_add(&pk, &attacker_2, /* evict */ true));
+        assert!(kbucket.try_add(&pk, attacker_2, /* evict */ true));
+        assert!(!kbucket.contains(&pk, &attacker_1.pk));
+        assert!(kbucket.contains(&pk, &diverse_1.pk));
+        assert!(kbucket.contains(&pk, &diverse_2.pk));
+
+        // farther attacker's node can't evict anything
+        assert!(!kbucket.can_add(&pk, &attacker_3, /* evict */ true));
+        assert!(!kbucket.try_add(&pk, attacker_3, /* evict */ true));
+
+        let pks = kbucket.iter().map(|node| node.pk).collect::<Vec<_>>();
+        assert_eq!(pks, vec![attacker_2.pk, diverse_1.pk, diverse_2.pk]);
+    }
+
+    #[test]
+    fn kbucket_try_add_subnet_limit_should_replace_bad_nodes_from_same_subnet() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::<DhtNode>::new(2);
+        kbucket.set_subnet_limit(Some(SubnetLimit::new(1)));
+
+        let diverse = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let attacker_1 = PackedNode::new("5.6.7.8:12345".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+        let attacker_2 = PackedNode::new("5.6.7.9:12345".parse().unwrap(), &PublicKey([3; PUBLICKEYBYTES]));
+
+        assert!(kbucket.try_add(&pk, diverse, /* evict */ false));
+        assert!(kbucket.try_add(&pk, attacker_1, /* evict */ false));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1)
+        ));
+
+        // all nodes are bad but only the node from the same subnet is replaced
+        with_default(&clock, &mut enter, |_| {
+            assert!(kbucket.try_add(&pk, attacker_2, /* evict */ false));
+        });
+
+        assert!(kbucket.contains(&pk, &diverse.pk));
+        assert!(!kbucket.contains(&pk, &attacker_1.pk));
+        assert!(kbucket.contains(&pk, &attacker_2.pk));
+    }
+
+    #[test]
+    fn subnet_limit_ipv6() {
+        let limit = SubnetLimit::new(1);
+
+        let node_1 = PackedNode::new("[2001:db8:1:1::1]:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let node_2 = PackedNode::new("[2001:db8:1:2::1]:12345".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+        let node_3 = PackedNode::new("[2001:db8:2::1]:12345".parse().unwrap(), &PublicKey([3; PUBLICKEYBYTES]));
+
+        assert!(limit.is_same_subnet(&node_1, &node_2));
+        assert!(!limit.is_same_subnet(&node_1, &node_3));
+        assert_eq!(limit.count(vec![node_1, node_2, node_3].iter(), &node_1), 1);
+    }
+
+    #[test]
+    fn random_pk_in_kbucket_test() {
+        crypto_init().unwrap();
+        let pk = gen_keypair().0;
+        for index in 0 ..= 255 {
+            let random_pk = random_pk_in_kbucket(&pk, index);
+            assert_eq!(kbucket_index(&pk, &random_pk), Some(index));
+        }
+    }
+
+    #[test]
+    fn kbucket_replacements() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::<DhtNode>::new(1);
+        kbucket.set_replacements_capacity(2);
+
+        let node_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let node_2 = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+        let node_3 = PackedNode::new("1.2.3.6:12345".parse().unwrap(), &PublicKey([3; PUBLICKEYBYTES]));
+        let node_4 = PackedNode::new("1.2.3.7:12345".parse().unwrap(), &PublicKey([4; PUBLICKEYBYTES]));
+
+        assert!(kbucket.try_add(&pk, node_1, /* evict */ false));
+        assert!(!kbucket.try_add(&pk, node_2, /* evict */ false));
+        assert!(!kbucket.try_add(&pk, node_3, /* evict */ false));
+        // node_2 is seen again so it becomes the most recent one
+        assert!(!kbucket.try_add(&pk, node_2, /* evict */ false));
+        let replacements = kbucket.replacements().iter().map(|node| node.pk).collect::<Vec<_>>();
+        assert_eq!(replacements, vec![node_3.pk, node_2.pk]);
+
+        // the least recently seen node is removed from the full cache
+        assert!(!kbucket.try_add(&pk, node_4, /* evict */ false));
+        let replacements = kbucket.replacements().iter().map(|node| node.pk).collect::<Vec<_>>();
+        assert_eq!(replacements, vec![node_2.pk, node_4.pk]);
+
+        // there are no bad nodes so nothing is promoted
+        assert_eq!(kbucket.promote_replacements(&pk), 0);
+        assert!(kbucket.contains(&pk, &node_1.pk));
+    }
+
+    #[test]
+    fn kbucket_promote_replacements() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::<DhtNode>::new(2);
+        kbucket.set_replacements_capacity(2);
+
+        let node_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let node_2 = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+        let node_3 = PackedNode::new("1.2.3.6:12345".parse().unwrap(), &PublicKey([3; PUBLICKEYBYTES]));
+        let node_4 = PackedNode::new("1.2.3.7:12345".parse().unwrap(), &PublicKey([4; PUBLICKEYBYTES]));
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(kbucket.try_add(&pk, node_1, /* evict */ false));
+            assert!(kbucket.try_add(&pk, node_2, /* evict */ false));
+        });
+
+        // nodes 1 and 2 become bad, nodes 3 and 4 are seen recently
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(BAD_NODE_TIMEOUT + 1)));
+        with_default(&clock, &mut enter, |_| {
+            let node = kbucket.get_node_mut(&pk, &node_1.pk).unwrap();
+            node.assoc4.last_resp_time = Some(now + Duration::from_secs(BAD_NODE_TIMEOUT + 1));
+
+            kbucket.add_replacement(node_3.into());
+            kbucket.add_replacement(node_4.into());
+
+            assert_eq!(kbucket.promote_replacements(&pk), 1);
+        });
+
+        let pks = kbucket.iter().map(|node| node.pk).collect::<Vec<_>>();
+        assert_eq!(pks, vec![node_1.pk, node_4.pk]);
+        let replacements = kbucket.replacements().iter().map(|node| node.pk).collect::<Vec<_>>();
+        assert_eq!(replacements, vec![node_3.pk]);
+
+        // replacements that became bad are removed from the cache
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(2 * BAD_NODE_TIMEOUT + 2)));
+        with_default(&clock, &mut enter, |_| {
+            assert_eq!(kbucket.promote_replacements(&pk), 0);
+        });
+        assert!(kbucket.replacements().is_empty());
+    }
+
     // Kbucket::remove()
 
     #[test]
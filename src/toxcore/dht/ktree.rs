@@ -1,10 +1,13 @@
 //! K-buckets structure
 
+use std::time::{Duration, Instant};
+
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::dht_node::*;
 use crate::toxcore::dht::packed_node::*;
 use crate::toxcore::dht::ip_port::IsGlobal;
 use crate::toxcore::dht::kbucket::*;
+use crate::toxcore::time::*;
 
 /** K-buckets structure to hold up to
 [`KBUCKET_MAX_ENTRIES`](./constant.KBUCKET_MAX_ENTRIES.html) *
@@ -14,6 +17,15 @@ own PK.
 Buckets in ktree are sorted by closeness to the PK; closest bucket is the last
 one, while furthest is the first one.
 
+Every kbucket has a small replacement cache of recently seen nodes that
+didn't fit into it. These nodes replace bad nodes of the kbucket so that the
+`Ktree` recovers quickly after churn. Kbuckets that haven't been updated for a
+while should be refreshed by searching for a random key from their range.
+
+The number of nodes from the same subnet can be limited both per
+[`Kbucket`](./struct.Kbucket.html) and for the whole `Ktree` with
+[`SubnetLimit`](./struct.SubnetLimit.html).
+
 Further reading: [Tox spec](https://zetok.github.io/tox-spec#k-buckets).
 
 The name references to the kademlia binary tree from
@@ -25,6 +37,11 @@ pub struct Ktree {
     pk: PublicKey,
     /// List of [`Kbucket`](./struct.Kbucket.html)s.
     pub kbuckets: Vec<Kbucket<DhtNode>>,
+    /// Limit on the number of nodes from the same subnet in the whole `Ktree`.
+    subnet_limit: Option<SubnetLimit>,
+    /// Time when a node was added to or updated in every kbucket or when the
+    /// kbucket was refreshed.
+    refresh_times: Vec<Instant>,
 }
 
 /** Maximum number of [`Kbucket`](./struct.Kbucket.html)s that [`Ktree`]
@@ -37,16 +54,51 @@ Realistically, not even half of that will be ever used, given how
 */
 pub const KBUCKET_MAX_ENTRIES: u8 = ::std::u8::MAX;
 
+/// Number of nodes replacement cache of every
+/// [`Kbucket`](./struct.Kbucket.html) in [`Ktree`](./struct.Ktree.html) can
+/// hold.
+pub const KBUCKET_REPLACEMENTS_SIZE: u8 = 4;
+
 impl Ktree {
     /// Create a new `Ktree`.
     pub fn new(pk: &PublicKey) -> Self {
         trace!(target: "Ktree", "Creating new Ktree with PK: {:?}", pk);
+        let mut kbucket = Kbucket::new(KBUCKET_DEFAULT_SIZE);
+        kbucket.set_replacements_capacity(KBUCKET_REPLACEMENTS_SIZE);
         Ktree {
             pk: *pk,
-            kbuckets: vec![Kbucket::new(KBUCKET_DEFAULT_SIZE); KBUCKET_MAX_ENTRIES as usize]
+            kbuckets: vec![kbucket; KBUCKET_MAX_ENTRIES as usize],
+            subnet_limit: None,
+            refresh_times: vec![clock_now(); KBUCKET_MAX_ENTRIES as usize],
+        }
+    }
+
+    /// Set limit on the number of nodes from the same subnet in the whole
+    /// `Ktree`. Nodes that are already in the `Ktree` are not affected.
+    pub fn set_subnet_limit(&mut self, subnet_limit: Option<SubnetLimit>) {
+        self.subnet_limit = subnet_limit;
+    }
+
+    /// Set limit on the number of nodes from the same subnet in every
+    /// [`Kbucket`](./struct.Kbucket.html). Nodes that are already in the
+    /// `Ktree` are not affected.
+    pub fn set_kbucket_subnet_limit(&mut self, subnet_limit: Option<SubnetLimit>) {
+        for kbucket in &mut self.kbuckets {
+            kbucket.set_subnet_limit(subnet_limit);
         }
     }
 
+    /// Check if the subnet of the node already has the maximum number of nodes
+    /// either in the whole `Ktree` or in the [`Kbucket`] with the given index.
+    /// Returns the exceeded limit in this case.
+    ///
+    /// [`Kbucket`]: ./struct.Kbucket.html
+    fn crowded_subnet(&self, node: &PackedNode, index: usize) -> Option<SubnetLimit> {
+        self.subnet_limit
+            .filter(|limit| limit.count(self.iter(), node) >= limit.max_nodes as usize)
+            .or_else(|| self.kbuckets[index].crowded_subnet(node))
+    }
+
     /// Get reference to a `DhtNode` by it's `PublicKey`.
     pub fn get_node(&self, pk: &PublicKey) -> Option<&DhtNode> {
         self.kbucket_index(pk).and_then(|index|
@@ -83,6 +135,8 @@ impl Ktree {
       number of kbuckets.
     * [`Kbucket`](./struct.Kbucket.html) to which it is added has free space
       or added node is closer to the PK than other node in the kbucket.
+    * subnet limits are not exceeded or the node can replace a node from the
+      same subnet.
 
     Returns `true` if node was added successfully, `false` otherwise.
     */
@@ -91,7 +145,14 @@ impl Ktree {
         trace!(target: "Ktree", "With PN: {:?}; and self: {:?}", node, self);
 
         match self.kbucket_index(&node.pk) {
-            Some(index) => self.kbuckets[index].try_add(&self.pk, node, /* evict */ false),
+            Some(index) => {
+                let crowded_subnet = self.crowded_subnet(&node, index);
+                let added = self.kbuckets[index].try_add_limited(&self.pk, node, /* evict */ false, crowded_subnet);
+                if added {
+                    self.refresh_times[index] = clock_now();
+                }
+                added
+            },
             None => {
                 trace!("Failed to add node: {:?}", node);
                 false
@@ -155,7 +216,8 @@ impl Ktree {
     Naive check whether a [`PackedNode`] can be added to the `Ktree`.
 
     Returns `true` if [`Kbucket`] where node could be placed is not full
-    and node is not already in the [`Kbucket`].
+    and node is not already in the [`Kbucket`] and subnet limits are not
+    exceeded.
 
     Otherwise `false` is returned.
 
@@ -165,11 +227,19 @@ impl Ktree {
     pub fn can_add(&self, new_node: &PackedNode) -> bool {
         match self.kbucket_index(&new_node.pk) {
             None => false,
-            Some(i) =>
-                self.kbuckets[i].can_add(&self.pk, new_node, /* evict */ false),
+            Some(i) => {
+                let crowded_subnet = self.crowded_subnet(new_node, i);
+                self.kbuckets[i].can_add_limited(&self.pk, new_node, /* evict */ false, crowded_subnet)
+            },
         }
     }
 
+    /** Get the number of nodes in all `kbuckets`.
+    */
+    pub fn len(&self) -> usize {
+        self.kbuckets.iter().map(|kbucket| kbucket.len()).sum()
+    }
+
     /** Check if `Ktree` is empty.
 
     Returns `true` if all `kbuckets` are empty, `false`
@@ -197,6 +267,54 @@ impl Ktree {
             .flat_map(|kbucket| kbucket.iter_mut())
     }
 
+    /// Replace bad nodes in all kbuckets with nodes from their replacement
+    /// caches. Nodes from subnets that already have the maximum number of
+    /// nodes in the whole `Ktree` are not promoted. Returns the number of
+    /// promoted nodes.
+    pub fn promote_replacements(&mut self) -> usize {
+        let pk = self.pk;
+        let subnet_limit = self.subnet_limit;
+        let mut promoted = 0;
+        for index in 0 .. self.kbuckets.len() {
+            let (before, rest) = self.kbuckets.split_at_mut(index);
+            let (kbucket, after) = rest.split_first_mut().expect("index is lower than the number of kbuckets");
+            promoted += kbucket.promote_replacements_limited(&pk, |node, nodes| match subnet_limit {
+                Some(limit) => {
+                    let others = before.iter().chain(after.iter()).flat_map(|kbucket| kbucket.iter());
+                    limit.count(others.chain(nodes.iter()), node) >= limit.max_nodes as usize
+                },
+                None => false,
+            });
+        }
+        promoted
+    }
+
+    /** Get random `PublicKey`s to search for in order to refresh kbuckets that
+    haven't been updated for `interval`.
+
+    Only kbuckets that are not closer to own PK than the closest non-empty
+    kbucket are refreshed since there are hardly any nodes with keys that close
+    to own PK.
+    Refresh timers of returned kbuckets are reset.
+    */
+    pub fn kbuckets_to_refresh(&mut self, interval: Duration) -> Vec<PublicKey> {
+        let last_index = match self.kbuckets.iter().rposition(|kbucket| !kbucket.is_empty()) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+
+        let now = clock_now();
+        let pk = self.pk;
+        self.refresh_times[..= last_index].iter_mut()
+            .enumerate()
+            .filter(|(_, time)| now - **time >= interval)
+            .map(|(index, time)| {
+                *time = now;
+                random_pk_in_kbucket(&pk, index as u8)
+            })
+            .collect()
+    }
+
     /// Check if all nodes in Ktree are discarded
     pub fn is_all_discarded(&self) -> bool {
         self.iter()
@@ -214,7 +332,7 @@ mod tests {
     use tokio_executor;
     use tokio_timer::clock::*;
 
-    use crate::toxcore::time::ConstNow;
+    use crate::toxcore::time::{ConstNow, MutNow};
 
     // Ktree::new()
 
@@ -277,6 +395,205 @@ mod tests {
         assert!(!ktree.try_add(node));
     }
 
+    #[test]
+    fn ktree_try_add_kbucket_subnet_limit() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+        ktree.set_kbucket_subnet_limit(Some(SubnetLimit::new(2)));
+
+        for i in 0 .. 2 {
+            let mut pk = [i + 2; PUBLICKEYBYTES];
+            pk[0] = 255;
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            assert!(ktree.try_add(PackedNode::new(addr, &PublicKey(pk))));
+        }
+
+        // the first kbucket already has 2 nodes from 1.2.3.0/24
+        let mut node_pk = [10; PUBLICKEYBYTES];
+        node_pk[0] = 255;
+        let node = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey(node_pk));
+        assert!(!ktree.can_add(&node));
+        assert!(!ktree.try_add(node));
+
+        // but the node from the same subnet can be added to another kbucket
+        let node = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        assert!(ktree.can_add(&node));
+        assert!(ktree.try_add(node));
+    }
+
+    #[test]
+    fn ktree_try_add_subnet_limit() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+        ktree.set_subnet_limit(Some(SubnetLimit::new(3)));
+
+        // nodes from the same subnet in different kbuckets
+        for i in 0u8 .. 3 {
+            let node_pk = PublicKey([0x80 >> i; PUBLICKEYBYTES]);
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            assert!(ktree.try_add(PackedNode::new(addr, &node_pk)));
+        }
+
+        let node = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey([0x10; PUBLICKEYBYTES]));
+        assert!(!ktree.can_add(&node));
+        assert!(!ktree.try_add(node));
+
+        let node = PackedNode::new("1.2.4.5:12345".parse().unwrap(), &PublicKey([0x10; PUBLICKEYBYTES]));
+        assert!(ktree.can_add(&node));
+        assert!(ktree.try_add(node));
+    }
+
+    #[test]
+    fn ktree_try_add_subnet_limit_should_replace_bad_nodes_from_same_subnet() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+        ktree.set_subnet_limit(Some(SubnetLimit::new(1)));
+
+        let node_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([0x80; PUBLICKEYBYTES]));
+        let node_2 = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey([0x81; PUBLICKEYBYTES]));
+        let node_3 = PackedNode::new("1.2.3.6:12345".parse().unwrap(), &PublicKey([0x40; PUBLICKEYBYTES]));
+
+        assert!(ktree.try_add(node_1));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            // bad node from the same subnet in the same kbucket can be replaced
+            assert!(ktree.try_add(node_2));
+            // but there is no node from the same subnet in another kbucket
+            assert!(!ktree.try_add(node_3));
+        });
+
+        assert!(!ktree.contains(&node_1.pk));
+        assert!(ktree.contains(&node_2.pk));
+    }
+
+    #[test]
+    fn ktree_promote_replacements() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            for i in 0 .. 8 {
+                let mut pk = [i + 2; PUBLICKEYBYTES];
+                pk[0] = 255;
+                let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+                assert!(ktree.try_add(PackedNode::new(addr, &PublicKey(pk))));
+            }
+        });
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(BAD_NODE_TIMEOUT / 2)));
+        let mut replacement_pk = [1; PUBLICKEYBYTES];
+        replacement_pk[0] = 255;
+        let replacement = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey(replacement_pk));
+        with_default(&clock, &mut enter, |_| {
+            // the first kbucket is full so the node is put to the replacement cache
+            assert!(!ktree.try_add(replacement));
+            assert_eq!(ktree.promote_replacements(), 0);
+        });
+        assert_eq!(ktree.kbuckets[0].replacements().len(), 1);
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(BAD_NODE_TIMEOUT + 1)));
+        with_default(&clock, &mut enter, |_| {
+            // all nodes in the kbucket become bad but the replacement is still good
+            assert_eq!(ktree.promote_replacements(), 1);
+        });
+        assert!(ktree.contains(&replacement.pk));
+        assert!(ktree.kbuckets[0].replacements().is_empty());
+    }
+
+    #[test]
+    fn ktree_promote_replacements_subnet_limit() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+        ktree.set_subnet_limit(Some(SubnetLimit::new(2)));
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            for i in 0 .. 8 {
+                let mut pk = [i + 2; PUBLICKEYBYTES];
+                pk[0] = 255;
+                let addr = SocketAddr::new(format!("1.2.{}.4", i).parse().unwrap(), 12345);
+                assert!(ktree.try_add(PackedNode::new(addr, &PublicKey(pk))));
+            }
+        });
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(BAD_NODE_TIMEOUT / 2)));
+        let mut replacement_pk = [1; PUBLICKEYBYTES];
+        replacement_pk[0] = 255;
+        let replacement = PackedNode::new("5.6.7.8:12345".parse().unwrap(), &PublicKey(replacement_pk));
+        with_default(&clock, &mut enter, |_| {
+            // the first kbucket is full so the node is put to the replacement cache
+            assert!(!ktree.try_add(replacement));
+            // other kbucket gets the maximum number of nodes from the subnet
+            // of the replacement
+            for i in 0 .. 2 {
+                let mut pk = [i + 2; PUBLICKEYBYTES];
+                pk[0] = 127;
+                let addr = SocketAddr::new(format!("5.6.7.{}", i + 1).parse().unwrap(), 12345);
+                assert!(ktree.try_add(PackedNode::new(addr, &PublicKey(pk))));
+            }
+        });
+        assert_eq!(ktree.kbuckets[0].replacements().len(), 1);
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(BAD_NODE_TIMEOUT + 1)));
+        with_default(&clock, &mut enter, |_| {
+            // all nodes in the first kbucket become bad but the replacement
+            // would exceed the subnet limit of the ktree
+            assert_eq!(ktree.promote_replacements(), 0);
+        });
+        assert!(!ktree.contains(&replacement.pk));
+        assert!(ktree.kbuckets[0].replacements().is_empty());
+    }
+
+    #[test]
+    fn ktree_kbuckets_to_refresh() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+
+        let now = Instant::now();
+        let time = MutNow::new(now);
+        let clock = Clock::new_with_now(time.clone());
+        let mut enter = tokio_executor::enter().unwrap();
+
+        with_default(&clock, &mut enter, |_| {
+            let mut ktree = Ktree::new(&pk);
+            let interval = Duration::from_secs(600);
+
+            // nothing to refresh in empty ktree
+            time.set(now + interval);
+            assert!(ktree.kbuckets_to_refresh(interval).is_empty());
+
+            let node_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([0xff; PUBLICKEYBYTES]));
+            let node_2 = PackedNode::new("1.2.3.4:12346".parse().unwrap(), &PublicKey([0x20; PUBLICKEYBYTES]));
+            assert!(ktree.try_add(node_1));
+            assert!(ktree.try_add(node_2));
+
+            // kbuckets 0 and 2 are just updated so only kbucket 1 is stale
+            let search_pks = ktree.kbuckets_to_refresh(interval);
+            assert_eq!(search_pks.len(), 1);
+            assert_eq!(kbucket_index(&pk, &search_pks[0]), Some(1));
+            // refresh timer is reset
+            assert!(ktree.kbuckets_to_refresh(interval).is_empty());
+
+            time.set(now + interval * 2);
+            let indices = ktree.kbuckets_to_refresh(interval)
+                .iter()
+                .map(|search_pk| kbucket_index(&pk, search_pk).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(indices, vec![0, 1, 2]);
+        });
+    }
+
     // Ktree::remove()
 
     #[test]
@@ -302,6 +619,23 @@ mod tests {
         assert!(ktree.is_empty());
     }
 
+    #[test]
+    fn ktree_len() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+        assert_eq!(ktree.len(), 0);
+
+        for i in 1 .. 4 {
+            let node = PackedNode::new(
+                SocketAddr::new(format!("1.2.3.{}", i).parse().unwrap(), 12345),
+                &PublicKey([i; PUBLICKEYBYTES])
+            );
+            assert!(ktree.try_add(node));
+        }
+
+        assert_eq!(ktree.len(), 3);
+    }
+
     // Ktree::get_closest()
 
     #[test]
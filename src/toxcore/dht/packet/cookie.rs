@@ -3,8 +3,6 @@
 
 use nom::be_u64;
 
-use std::time::SystemTime;
-
 use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::time::*;
@@ -54,7 +52,7 @@ impl Cookie {
     /// Create new `Cookie`
     pub fn new(real_pk: PublicKey, dht_pk: PublicKey) -> Cookie {
         Cookie {
-            time: unix_time(SystemTime::now()),
+            time: unix_time(clock_system_time()),
             real_pk,
             dht_pk,
         }
@@ -66,7 +64,7 @@ impl Cookie {
 
     */
     pub fn is_timed_out(&self) -> bool {
-        self.time + COOKIE_TIMEOUT < unix_time(SystemTime::now())
+        self.time + COOKIE_TIMEOUT < unix_time(clock_system_time())
     }
 }
 
@@ -371,11 +371,10 @@ impl ToBytes for DhtPkAnnouncePayload {
 impl DhtPkAnnouncePayload {
     /// Create new `DhtPkAnnouncePayload` with `no_reply` set to current time.
     pub fn new(dht_pk: PublicKey, nodes: Vec<TcpUdpPackedNode>) -> Self {
-        use std::time::SystemTime;
-        use crate::toxcore::time::unix_time;
+        use crate::toxcore::time::{clock_system_time, unix_time};
 
         DhtPkAnnouncePayload {
-            no_reply: unix_time(SystemTime::now()),
+            no_reply: unix_time(clock_system_time()),
             dht_pk,
             nodes,
         }
@@ -84,6 +84,35 @@ pub enum Packet {
     BootstrapInfo(BootstrapInfo)
 }
 
+impl Packet {
+    /// Name of the packet kind. Used to collect statistics.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Packet::PingRequest(_) => "PingRequest",
+            Packet::PingResponse(_) => "PingResponse",
+            Packet::NodesRequest(_) => "NodesRequest",
+            Packet::NodesResponse(_) => "NodesResponse",
+            Packet::CookieRequest(_) => "CookieRequest",
+            Packet::CookieResponse(_) => "CookieResponse",
+            Packet::CryptoHandshake(_) => "CryptoHandshake",
+            Packet::CryptoData(_) => "CryptoData",
+            Packet::DhtRequest(_) => "DhtRequest",
+            Packet::LanDiscovery(_) => "LanDiscovery",
+            Packet::OnionRequest0(_) => "OnionRequest0",
+            Packet::OnionRequest1(_) => "OnionRequest1",
+            Packet::OnionRequest2(_) => "OnionRequest2",
+            Packet::OnionAnnounceRequest(_) => "OnionAnnounceRequest",
+            Packet::OnionAnnounceResponse(_) => "OnionAnnounceResponse",
+            Packet::OnionDataRequest(_) => "OnionDataRequest",
+            Packet::OnionDataResponse(_) => "OnionDataResponse",
+            Packet::OnionResponse3(_) => "OnionResponse3",
+            Packet::OnionResponse2(_) => "OnionResponse2",
+            Packet::OnionResponse1(_) => "OnionResponse1",
+            Packet::BootstrapInfo(_) => "BootstrapInfo",
+        }
+    }
+}
+
 impl ToBytes for Packet {
     fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
         match *self {
@@ -1,10 +1,9 @@
 //! Managing requests IDs and timeouts.
 
-use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::time::{Duration, Instant};
 
-use crate::toxcore::utils::gen_ping_id;
+use crate::toxcore::utils::{gen_ping_id, HashMap};
 use crate::toxcore::time::*;
 
 /// Struct that stores and manages requests IDs and timeouts. Every request ID
@@ -22,7 +21,7 @@ impl<T> RequestQueue<T> {
     /// Create new `RequestQueue`.
     pub fn new(timeout: Duration) -> Self {
         RequestQueue {
-            ping_map: HashMap::new(),
+            ping_map: HashMap::default(),
             timeout,
         }
     }
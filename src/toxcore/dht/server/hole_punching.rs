@@ -2,12 +2,20 @@
 Module for hole-punching.
 
 https://zetok.github.io/tox-spec/#hole-punching
+
+Besides the port guessing algorithms described in the spec this module
+tries to predict the behaviour of friend's NAT from ports returned by its
+close nodes. If the NAT allocates ports sequentially we probe the ports that
+it's going to allocate next. If it allocates ports randomly we probe random
+ports: since both sides do it simultaneously after the `NatPingRequest`
+exchange there is a good chance that some probes meet (birthday paradox).
 */
 
 use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, Instant};
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 
+use crate::toxcore::crypto_core::random_u32;
 use crate::toxcore::dht::dht_friend::*;
 use crate::toxcore::dht::server::*;
 use crate::toxcore::utils::*;
@@ -18,12 +26,148 @@ pub const PUNCH_INTERVAL: u64 = 3;
 /// Interval in seconds to reset counter of hole punching attempts.
 pub const RESET_PUNCH_INTERVAL: u64 = 40;
 /// Maximum number of ports to use for every round of hole punching. Note that
-/// we have 2 different guessing algorithms so each one of them will use this
-/// number of ports.
+/// we have several different guessing algorithms so each one of them will use
+/// this number of ports.
 const MAX_PORTS_TO_PUNCH: u32 = 48;
 /// After this number of hole punching attempts we will use advanced port
 /// guessing algorithm besides simple algorithm.
 const MAX_NORMAL_PUNCHING_TRIES: u32 = 5;
+/// Maximum stride between sequentially allocated ports.
+const MAX_PORT_STRIDE: u16 = 16;
+/// Maximum average number of ports allocated by sequential NAT between two
+/// observed ports. Usually these ports are allocated for other destinations
+/// between requests of close nodes.
+const MAX_SEQUENTIAL_GAP: u32 = 32;
+/// The lowest port that is used for random probing. Lower ports are usually
+/// not allocated by NATs.
+const MIN_RANDOM_PORT: u32 = 1024;
+/// The highest UDP port.
+const MAX_PORT: u32 = 65535;
+
+/// Port guessing algorithm used for hole punching.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PunchStrategy {
+    /// NAT preserves the same port for all destinations so we use only it.
+    SamePort,
+    /// Ports with some neighborhood returned by close nodes of a friend.
+    Neighborhood,
+    /// Ports that sequential NAT is going to allocate next.
+    Sequential,
+    /// Random ports for NATs with random port allocation.
+    Random,
+    /// All ports sequentially starting from 1024.
+    Linear,
+}
+
+/// Port allocation behaviour of friend's NAT guessed from ports returned by
+/// its close nodes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PortAllocation {
+    /// NAT uses the same port for all destinations.
+    SamePort(u16),
+    /// NAT allocates ports sequentially with constant stride.
+    Sequential {
+        /// The highest observed port.
+        last_port: u16,
+        /// Difference between two consecutively allocated ports.
+        stride: u16,
+    },
+    /// NAT allocates ports randomly.
+    Random,
+}
+
+impl PortAllocation {
+    /// Guess port allocation behaviour from the list of observed ports.
+    /// Returns `None` if the list is empty.
+    pub fn from_ports(ports: &[u16]) -> Option<PortAllocation> {
+        let mut ports = ports.to_vec();
+        ports.sort_unstable();
+        ports.dedup();
+
+        let (first_port, last_port) = match (ports.first(), ports.last()) {
+            (Some(&first_port), Some(&last_port)) => (first_port, last_port),
+            _ => return None,
+        };
+
+        if first_port == last_port {
+            return Some(PortAllocation::SamePort(first_port));
+        }
+
+        let stride = ports.windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .fold(0, gcd);
+        let slots = u32::from((last_port - first_port) / stride);
+
+        if stride <= MAX_PORT_STRIDE && slots <= MAX_SEQUENTIAL_GAP * (ports.len() as u32 - 1) {
+            Some(PortAllocation::Sequential { last_port, stride })
+        } else {
+            Some(PortAllocation::Random)
+        }
+    }
+}
+
+/// Greatest common divisor.
+fn gcd(a: u16, b: u16) -> u16 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Statistics of a single hole punching strategy.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StrategyStats {
+    /// Number of hole punching rounds where this strategy was used.
+    pub rounds: u64,
+    /// Number of `PingRequest` packets sent by this strategy.
+    pub probes: u64,
+    /// Number of successfully punched holes.
+    pub successes: u64,
+}
+
+impl StrategyStats {
+    /// Ratio of successful rounds to all rounds. `None` if the strategy was
+    /// never used.
+    pub fn success_rate(&self) -> Option<f64> {
+        if self.rounds == 0 {
+            None
+        } else {
+            Some(self.successes as f64 / self.rounds as f64)
+        }
+    }
+}
+
+/// Statistics of hole punching for every strategy.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PunchingStats {
+    strategies: HashMap<PunchStrategy, StrategyStats>,
+}
+
+impl PunchingStats {
+    /// Get statistics of the strategy.
+    pub fn get(&self, strategy: PunchStrategy) -> StrategyStats {
+        self.strategies.get(&strategy).cloned().unwrap_or_default()
+    }
+
+    /// Add statistics from another object.
+    pub fn merge(&mut self, other: &PunchingStats) {
+        for (&strategy, other_stats) in &other.strategies {
+            let stats = self.strategies.entry(strategy).or_default();
+            stats.rounds += other_stats.rounds;
+            stats.probes += other_stats.probes;
+            stats.successes += other_stats.successes;
+        }
+    }
+
+    /// Count hole punching round where the strategy was used.
+    fn add_round(&mut self, strategy: PunchStrategy, probes: usize) {
+        let stats = self.strategies.entry(strategy).or_default();
+        stats.rounds += 1;
+        stats.probes += probes as u64;
+    }
+
+    /// Count successfully punched hole.
+    fn add_success(&mut self, strategy: PunchStrategy) {
+        self.strategies.entry(strategy).or_default().successes += 1;
+    }
+}
 
 /// Struct for hole punching.
 #[derive(Clone, Debug)]
@@ -50,9 +194,16 @@ pub struct HolePunching {
     pub first_punching_index: u32,
     /// Another factor variable for guessing NAT port.
     pub last_punching_index: u32,
+    /// Number of ports predicted for sequential NAT in previous rounds.
+    pub sequential_punching_index: u32,
     /// Ping id that is used to send `NatPingRequest` packets. It's refreshed
     /// every time we receive valid `NatPingResponse` packet.
     pub ping_id: u64,
+    /// Addresses used in the last round of hole punching with strategies
+    /// that guessed them.
+    pub punched_addrs: HashMap<SocketAddr, PunchStrategy>,
+    /// Statistics of hole punching strategies.
+    pub stats: PunchingStats,
 }
 
 impl Default for HolePunching {
@@ -67,20 +218,23 @@ impl HolePunching {
         HolePunching {
             is_punching_done: true,
             num_punch_tries: 0,
-            last_recv_ping_time: Instant::now(),
+            last_recv_ping_time: clock_now(),
             last_send_ping_time: None,
             last_punching_time: None,
             first_punching_index: 0,
             last_punching_index: 0,
+            sequential_punching_index: 0,
             ping_id: gen_ping_id(),
+            punched_addrs: HashMap::default(),
+            stats: PunchingStats::default(),
         }
     }
 
     /// Run next round of hole punching if necessary, i.e. if:
     /// - hole punching is not done
     /// - `PUNCH_INTERVAL` seconds elapsed since last hole punching round
-    /// - friend successfully responded to `NatPingRequest` (defined by `is_punching_done`)
-    /// - friend successfully send `NatPingRequest` to us
+    /// - friend successfully responded to `NatPingRequest` or sent
+    ///   `NatPingRequest` to us (defined by `is_punching_done`)
     ///
     /// This function returns list of addresses to which we should send
     ///`PingRequest` packet.
@@ -100,6 +254,7 @@ impl HolePunching {
                     self.num_punch_tries = 0;
                     self.first_punching_index = 0;
                     self.last_punching_index = 0;
+                    self.sequential_punching_index = 0;
                 }
 
                 let ports_to_try = HolePunching::get_nat_ports(&addrs, ip);
@@ -115,6 +270,16 @@ impl HolePunching {
         }
     }
 
+    /// Check if the address from which a friend responded to our
+    /// `PingRequest` was guessed by hole punching. If so count the success of
+    /// the strategy that guessed it and return this strategy.
+    pub fn handle_punch_success(&mut self, addr: SocketAddr) -> Option<PunchStrategy> {
+        let strategy = self.punched_addrs.get(&addr).cloned()?;
+        self.punched_addrs.clear();
+        self.stats.add_success(strategy);
+        Some(strategy)
+    }
+
     /// Calculate the most common IP i.e. a overlapping IP of a friend returned
     /// by his close nodes. If number of occurrences of the most common IP
     /// exceeds `need_num` number return it. `need_num` is normally 4 which is
@@ -122,7 +287,7 @@ impl HolePunching {
     /// nodes return same IP with different port we consider that friend is
     /// behind NAT.
     fn get_common_ip(addrs: &[SocketAddr], need_num: u32) -> Option<IpAddr> {
-        let mut occurrences = HashMap::new();
+        let mut occurrences = HashMap::default();
 
         for addr in addrs {
             *occurrences.entry(addr.ip()).or_insert(0) += 1;
@@ -178,34 +343,85 @@ impl HolePunching {
         }).collect()
     }
 
+    /// Port prediction for NATs with sequential port allocation. It uses
+    /// ports that the NAT is going to allocate after the highest observed
+    /// port. Every next round continues where the previous one stopped.
+    fn sequential_hole_punching(&self, last_port: u16, stride: u16, ip: IpAddr) -> Vec<SocketAddr> {
+        (1..=MAX_PORTS_TO_PUNCH)
+            .map(|i| u32::from(last_port) + u32::from(stride) * (i + self.sequential_punching_index))
+            .take_while(|&port| port <= MAX_PORT)
+            .map(|port| SocketAddr::new(ip, port as u16))
+            .collect()
+    }
+
+    /// Port guessing for NATs with random port allocation. It uses random
+    /// ports starting from 1024.
+    fn random_hole_punching(&self, ip: IpAddr) -> Vec<SocketAddr> {
+        let ports_count = MAX_PORT + 1 - MIN_RANDOM_PORT;
+        (0..MAX_PORTS_TO_PUNCH).map(|_| {
+            let port = MIN_RANDOM_PORT + random_u32() % ports_count;
+            SocketAddr::new(ip, port as u16)
+        }).collect()
+    }
+
+    /// Add addresses guessed by the strategy to the list of addresses for
+    /// the current round skipping already added ones.
+    fn add_punch_addrs(&mut self, strategy: PunchStrategy, new_addrs: Vec<SocketAddr>, addrs: &mut Vec<SocketAddr>) {
+        let len = addrs.len();
+        for addr in new_addrs {
+            if let Entry::Vacant(entry) = self.punched_addrs.entry(addr) {
+                entry.insert(strategy);
+                addrs.push(addr);
+            }
+        }
+        if addrs.len() > len {
+            self.stats.add_round(strategy, addrs.len() - len);
+        }
+    }
+
+    /// Add addresses guessed by simple port guessing algorithm.
+    fn neighborhood_punch_addrs(&mut self, ports: &[u16], ip: IpAddr, addrs: &mut Vec<SocketAddr>) {
+        let neighborhood_addrs = self.first_hole_punching(ports, ip);
+        self.first_punching_index += MAX_PORTS_TO_PUNCH;
+        self.add_punch_addrs(PunchStrategy::Neighborhood, neighborhood_addrs, addrs);
+    }
+
     /// Get addresses for hole punching using different port guessing
     /// algorithms.
     ///
     /// This function returns list of addresses to which we should send
     ///`PingRequest` packet.
     fn punch_addrs(&mut self, ports: &[u16], ip: IpAddr) -> Vec<SocketAddr> {
-        if ports.is_empty() {
-            return Vec::new()
-        }
-
-        let first_port = ports[0];
-        let num_ports = ports.len();
-        let num_same_port = ports.iter().filter(|port| **port == first_port).count();
-
-        let mut addrs = if num_same_port == num_ports {
-            vec![SocketAddr::new(ip, first_port)]
-        } else {
-            let addrs = self.first_hole_punching(ports, ip);
-            self.first_punching_index += MAX_PORTS_TO_PUNCH;
-            addrs
+        let allocation = match PortAllocation::from_ports(ports) {
+            Some(allocation) => allocation,
+            None => return Vec::new(),
         };
 
-        if self.num_punch_tries > MAX_NORMAL_PUNCHING_TRIES {
-            addrs.append(&mut self.last_hole_punching(ip));
-            self.last_punching_index += MAX_PORTS_TO_PUNCH - (MAX_PORTS_TO_PUNCH / 2);
-        };
+        self.punched_addrs.clear();
+        let mut addrs = Vec::new();
+
+        match allocation {
+            PortAllocation::SamePort(port) =>
+                self.add_punch_addrs(PunchStrategy::SamePort, vec![SocketAddr::new(ip, port)], &mut addrs),
+            PortAllocation::Sequential { last_port, stride } => {
+                let sequential_addrs = self.sequential_hole_punching(last_port, stride, ip);
+                self.sequential_punching_index += MAX_PORTS_TO_PUNCH;
+                self.add_punch_addrs(PunchStrategy::Sequential, sequential_addrs, &mut addrs);
+                self.neighborhood_punch_addrs(ports, ip, &mut addrs);
+            },
+            PortAllocation::Random => {
+                self.neighborhood_punch_addrs(ports, ip, &mut addrs);
+                let random_addrs = self.random_hole_punching(ip);
+                self.add_punch_addrs(PunchStrategy::Random, random_addrs, &mut addrs);
+            },
+        }
 
-        addrs.dedup();
+        // scanning all ports makes no sense for random allocation
+        if allocation != PortAllocation::Random && self.num_punch_tries > MAX_NORMAL_PUNCHING_TRIES {
+            let linear_addrs = self.last_hole_punching(ip);
+            self.last_punching_index += MAX_PORTS_TO_PUNCH - (MAX_PORTS_TO_PUNCH / 2);
+            self.add_punch_addrs(PunchStrategy::Linear, linear_addrs, &mut addrs);
+        }
 
         self.num_punch_tries = self.num_punch_tries.saturating_add(1);
 
@@ -303,4 +519,131 @@ mod tests {
 
         assert!(!hole_punch.next_punch_addrs(&addrs).is_empty());
     }
+
+    #[test]
+    fn port_allocation_same_port() {
+        assert_eq!(PortAllocation::from_ports(&[]), None);
+        assert_eq!(PortAllocation::from_ports(&[33445, 33445, 33445]), Some(PortAllocation::SamePort(33445)));
+    }
+
+    #[test]
+    fn port_allocation_sequential() {
+        assert_eq!(
+            PortAllocation::from_ports(&[40010, 40001, 40004, 40003]),
+            Some(PortAllocation::Sequential { last_port: 40010, stride: 1 })
+        );
+        assert_eq!(
+            PortAllocation::from_ports(&[1000, 1016, 1064]),
+            Some(PortAllocation::Sequential { last_port: 1064, stride: 16 })
+        );
+    }
+
+    #[test]
+    fn port_allocation_random() {
+        assert_eq!(PortAllocation::from_ports(&[1000, 50000]), Some(PortAllocation::Random));
+        assert_eq!(PortAllocation::from_ports(&[40001, 52313, 18234, 40002]), Some(PortAllocation::Random));
+        // stride is fine but the ports are too sparse
+        assert_eq!(PortAllocation::from_ports(&[1000, 2000]), Some(PortAllocation::Random));
+    }
+
+    fn friend_addrs(ports: &[u16]) -> Vec<SocketAddr> {
+        ports.iter().map(|&port| SocketAddr::new("1.2.3.4".parse().unwrap(), port)).collect()
+    }
+
+    #[test]
+    fn hole_punch_same_port() {
+        let addrs = friend_addrs(&[33445; 5]);
+
+        let mut hole_punch = HolePunching::new();
+        hole_punch.is_punching_done = false;
+
+        assert_eq!(hole_punch.next_punch_addrs(&addrs), vec!["1.2.3.4:33445".parse().unwrap()]);
+        assert_eq!(hole_punch.stats.get(PunchStrategy::SamePort).rounds, 1);
+        assert_eq!(hole_punch.stats.get(PunchStrategy::Neighborhood).rounds, 0);
+    }
+
+    #[test]
+    fn hole_punch_sequential() {
+        let addrs = friend_addrs(&[40001, 40003, 40004, 40007, 40010]);
+
+        let mut hole_punch = HolePunching::new();
+        hole_punch.is_punching_done = false;
+
+        let punch_addrs = hole_punch.next_punch_addrs(&addrs);
+        // predicted ports go first
+        let expected = (40011 .. 40011 + MAX_PORTS_TO_PUNCH as u16)
+            .map(|port| SocketAddr::new("1.2.3.4".parse().unwrap(), port))
+            .collect::<Vec<_>>();
+        assert_eq!(&punch_addrs[.. MAX_PORTS_TO_PUNCH as usize], expected.as_slice());
+        assert_eq!(hole_punch.punched_addrs[&expected[0]], PunchStrategy::Sequential);
+        assert_eq!(hole_punch.punched_addrs[&addrs[0]], PunchStrategy::Neighborhood);
+        assert_eq!(hole_punch.sequential_punching_index, MAX_PORTS_TO_PUNCH);
+
+        let stats = hole_punch.stats.get(PunchStrategy::Sequential);
+        assert_eq!(stats.rounds, 1);
+        assert_eq!(stats.probes, u64::from(MAX_PORTS_TO_PUNCH));
+        assert_eq!(hole_punch.stats.get(PunchStrategy::Random).rounds, 0);
+    }
+
+    #[test]
+    fn hole_punch_sequential_port_overflow() {
+        let hole_punch = HolePunching::new();
+        let addrs = hole_punch.sequential_hole_punching(65530, 2, "1.2.3.4".parse().unwrap());
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[1].port(), 65534);
+    }
+
+    #[test]
+    fn hole_punch_random() {
+        let addrs = friend_addrs(&[1111, 22222, 44444, 55555, 12345]);
+
+        let mut hole_punch = HolePunching::new();
+        hole_punch.is_punching_done = false;
+        hole_punch.num_punch_tries = MAX_NORMAL_PUNCHING_TRIES + 1;
+
+        let punch_addrs = hole_punch.next_punch_addrs(&addrs);
+        assert!(hole_punch.punched_addrs.iter()
+            .filter(|&(_, &strategy)| strategy == PunchStrategy::Random)
+            .all(|(addr, _)| addr.port() >= 1024));
+        assert_eq!(hole_punch.punched_addrs.len(), punch_addrs.len());
+        assert!(hole_punch.stats.get(PunchStrategy::Random).probes > 0);
+        // linear scan is not used for random allocation
+        assert_eq!(hole_punch.stats.get(PunchStrategy::Linear).rounds, 0);
+    }
+
+    #[test]
+    fn hole_punch_success() {
+        let addrs = friend_addrs(&[40001, 40003, 40004, 40007, 40010]);
+
+        let mut hole_punch = HolePunching::new();
+        hole_punch.is_punching_done = false;
+        hole_punch.next_punch_addrs(&addrs);
+
+        assert_eq!(hole_punch.handle_punch_success("1.2.3.4:1".parse().unwrap()), None);
+        assert_eq!(hole_punch.handle_punch_success("1.2.3.4:40012".parse().unwrap()), Some(PunchStrategy::Sequential));
+        // the round is finished
+        assert!(hole_punch.punched_addrs.is_empty());
+        assert_eq!(hole_punch.handle_punch_success("1.2.3.4:40013".parse().unwrap()), None);
+
+        let stats = hole_punch.stats.get(PunchStrategy::Sequential);
+        assert_eq!(stats.successes, 1);
+        assert_eq!(stats.success_rate(), Some(1.0));
+        assert_eq!(hole_punch.stats.get(PunchStrategy::Neighborhood).success_rate(), Some(0.0));
+        assert_eq!(hole_punch.stats.get(PunchStrategy::Linear).success_rate(), None);
+    }
+
+    #[test]
+    fn punching_stats_merge() {
+        let mut stats_1 = PunchingStats::default();
+        stats_1.add_round(PunchStrategy::Random, 48);
+        stats_1.add_success(PunchStrategy::Random);
+        let mut stats_2 = PunchingStats::default();
+        stats_2.add_round(PunchStrategy::Random, 10);
+        stats_2.add_round(PunchStrategy::SamePort, 1);
+
+        stats_1.merge(&stats_2);
+
+        assert_eq!(stats_1.get(PunchStrategy::Random), StrategyStats { rounds: 2, probes: 58, successes: 1 });
+        assert_eq!(stats_1.get(PunchStrategy::SamePort), StrategyStats { rounds: 1, probes: 1, successes: 0 });
+    }
 }
@@ -4,6 +4,7 @@ This module works on top of other modules.
 */
 
 pub mod hole_punching;
+pub mod rate_limiter;
 mod errors;
 
 use failure::Fail;
@@ -14,7 +15,6 @@ use parking_lot::RwLock;
 use tokio::timer::Interval;
 use tokio::util::FutureExt;
 
-use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -35,10 +35,13 @@ use crate::toxcore::ip_port::*;
 use crate::toxcore::dht::dht_friend::*;
 use crate::toxcore::dht::dht_node::*;
 use crate::toxcore::dht::server::hole_punching::*;
+use crate::toxcore::dht::server::rate_limiter::*;
 use crate::toxcore::tcp::packet::OnionRequest;
 use crate::toxcore::net_crypto::*;
 use crate::toxcore::dht::ip_port::IsGlobal;
 use crate::toxcore::utils::*;
+use crate::toxcore::events::*;
+use crate::toxcore::dissect::TapTx;
 use crate::toxcore::dht::server::errors::*;
 
 /// Shorthand for the transmit half of the message channel.
@@ -72,6 +75,12 @@ pub const PRECOMPUTED_LRU_CACHE_SIZE: usize = KBUCKET_DEFAULT_SIZE as usize * KB
     KBUCKET_DEFAULT_SIZE as usize * (2 + 10); // For friend's close_nodes of 2 fake friends + 10 friends reserved
 /// How often DHT main loop should be called.
 const MAIN_LOOP_INTERVAL: u64 = 1;
+/// Interval in seconds after which a kbucket of close nodes list that hasn't
+/// been updated is refreshed.
+pub const KBUCKET_REFRESH_INTERVAL: u64 = 600;
+/// Maximum nodes to send `NodesRequest` packet with a random key when
+/// refreshing a kbucket.
+const MAX_TO_REFRESH_KBUCKET: u8 = 2;
 
 /// Struct that contains necessary data for `BootstrapInfo` packet.
 #[derive(Clone)]
@@ -83,6 +92,17 @@ struct ServerBootstrapInfo {
     motd_cb: Arc<Fn(&Server) -> Vec<u8> + Send + Sync>,
 }
 
+/// Limits on the number of nodes from the same subnet in close nodes lists.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SubnetLimits {
+    /// Limit for every `Kbucket` of the close nodes list.
+    pub kbucket: Option<SubnetLimit>,
+    /// Limit for the whole close nodes list.
+    pub ktree: Option<SubnetLimit>,
+    /// Limit for close nodes lists of friends.
+    pub friend: Option<SubnetLimit>,
+}
+
 /**
 Own DHT node data.
 
@@ -118,6 +138,10 @@ pub struct Server {
     pub tx: Tx,
     /// Sink to send friend's `SocketAddr` when it gets known.
     friend_saddr_sink: Option<mpsc::UnboundedSender<PackedNode>>,
+    /// Sink to send events about internal state changes.
+    event_tx: Option<EventTx>,
+    /// Sink to send raw bytes of received and sent packets to.
+    pub(crate) tap: Option<TapTx>,
     /// Struct that stores and manages requests IDs and timeouts.
     request_queue: Arc<RwLock<RequestQueue<PublicKey>>>,
     /// Close nodes list which contains nodes close to own DHT `PublicKey`.
@@ -125,7 +149,7 @@ pub struct Server {
     /// Symmetric key used for onion return encryption.
     onion_symmetric_key: Arc<RwLock<secretbox::Key>>,
     /// Onion announce struct to handle `OnionAnnounce` and `OnionData` packets.
-    onion_announce: Arc<RwLock<OnionAnnounce>>,
+    pub(crate) onion_announce: Arc<RwLock<OnionAnnounce>>,
 
     fake_friends_keys: Vec<PublicKey>,
     /// Friends list used to store friends related data like close nodes per
@@ -176,6 +200,16 @@ pub struct Server {
     /// Lru cache for precomputed keys. It stores precomputed keys to avoid
     /// redundant calculations.
     precomputed_keys: PrecomputedCache,
+    /// Per-IP rate limiter for incoming requests. Requests that exceed the
+    /// budget are dropped without response to prevent amplification attacks.
+    /// `None` if rate limiting is disabled.
+    rate_limiter: Option<RateLimiter>,
+    /// Limits on the number of nodes from the same subnet in close nodes
+    /// lists. They are applied to close nodes of friends as well.
+    subnet_limits: SubnetLimits,
+    /// Our external address that was assigned by gateway when UDP port was
+    /// mapped. `None` if port mapping is disabled or failed.
+    external_addr: Arc<RwLock<Option<SocketAddr>>>,
 }
 
 impl Server {
@@ -207,6 +241,8 @@ impl Server {
             pk,
             tx,
             friend_saddr_sink: None,
+            event_tx: None,
+            tap: None,
             request_queue: Arc::new(RwLock::new(RequestQueue::new(Duration::from_secs(PING_TIMEOUT)))),
             close_nodes: Arc::new(RwLock::new(Ktree::new(&pk))),
             onion_symmetric_key: Arc::new(RwLock::new(secretbox::gen_key())),
@@ -224,6 +260,9 @@ impl Server {
             is_ipv6_enabled: false,
             initial_bootstrap: Vec::new(),
             precomputed_keys,
+            rate_limiter: None,
+            subnet_limits: SubnetLimits::default(),
+            external_addr: Arc::new(RwLock::new(None)),
         }
     }
 
@@ -283,6 +322,7 @@ impl Server {
         let close_nodes = self.close_nodes.read();
 
         let mut friend = DhtFriend::new(friend_pk);
+        friend.close_nodes.set_subnet_limit(self.subnet_limits.friend);
         let close_nodes = Server::get_closest_inner(&close_nodes, &friends, &friend.pk, 4, true);
 
         for &node in close_nodes.iter() {
@@ -292,6 +332,16 @@ impl Server {
         friends.insert(friend_pk, friend);
     }
 
+    /// Get hole punching statistics of all strategies summed over all
+    /// friends.
+    pub fn hole_punching_stats(&self) -> PunchingStats {
+        let mut stats = PunchingStats::default();
+        for friend in self.friends.read().values() {
+            stats.merge(&friend.hole_punch.stats);
+        }
+        stats
+    }
+
     /// Remove a friend from the DHT friends list to stop looking for it's IP
     /// address.
     pub fn remove_friend(&self, friend_pk: PublicKey) {
@@ -302,7 +352,7 @@ impl Server {
     /// method iterates over all nodes from close nodes list, close nodes of
     /// friends and bootstrap nodes and sends `NodesRequest` packets if
     /// necessary.
-    fn dht_main_loop(&self) -> impl Future<Item = (), Error = RunError> + Send {
+    pub(crate) fn dht_main_loop(&self) -> impl Future<Item = (), Error = RunError> + Send {
         // Check if we should send `NodesRequest` packet to a random node. This
         // request is sent every second 5 times and then every 20 seconds.
         fn send_random_request(last_nodes_req_time: &mut Instant, random_requests_count: &mut u32) -> bool {
@@ -322,6 +372,12 @@ impl Server {
 
         request_queue.clear_timed_out();
 
+        // Replace bad nodes with recently seen ones from replacement caches
+        let promoted = close_nodes.promote_replacements();
+        if promoted > 0 {
+            debug!("Promoted {} nodes from replacement caches", promoted);
+        }
+
         // Send NodesRequest packets to nodes from the Server
         let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut nodes_to_bootstrap, self.pk);
         let ping_close_nodes = self.ping_close_nodes(&mut request_queue, close_nodes.iter_mut(), self.pk);
@@ -330,6 +386,7 @@ impl Server {
         } else {
             Either::B(future::ok(()))
         };
+        let refresh_kbuckets = self.refresh_kbuckets(&mut request_queue, &mut close_nodes);
 
         // Send NodesRequest packets to nodes from every DhtFriend
         let send_nodes_req_to_friends = friends.values_mut().map(|friend| {
@@ -349,15 +406,16 @@ impl Server {
             ping_close_nodes,
             send_nodes_req_random,
             future::join_all(send_nodes_req_to_friends),
-            send_nat_ping_req
+            send_nat_ping_req.join(refresh_kbuckets)
         ).map(|_| ()).map_err(|e| e.context(RunErrorKind::SendTo).into())
     }
 
     /// Run DHT periodical tasks. Result future will never be completed
     /// successfully.
     pub fn run(self) -> impl Future<Item = (), Error = RunError> + Send {
-        self.clone().run_pings_sending().join4(
+        self.clone().run_pings_sending().join5(
             self.clone().run_onion_key_refreshing(),
+            self.clone().run_onion_announce_cleanup(),
             self.clone().run_main_loop(),
             self.run_bootstrap_requests_sending()
         ).map(|_| ())
@@ -395,7 +453,7 @@ impl Server {
     /// Check if all nodes in Ktree are discarded (including the case when
     /// it's empty) and if so then send `NodesRequest` packet to nodes from
     /// initial bootstrap list and from Ktree.
-    fn send_bootstrap_requests(&self) -> impl Future<Item = (), Error = mpsc::SendError<(Packet, SocketAddr)>> + Send {
+    pub(crate) fn send_bootstrap_requests(&self) -> impl Future<Item = (), Error = mpsc::SendError<(Packet, SocketAddr)>> + Send {
         let mut request_queue = self.request_queue.write();
         let close_nodes = self.close_nodes.read();
 
@@ -448,6 +506,22 @@ impl Server {
             })
     }
 
+    /// Remove timed out onion announce entries periodically. Result future
+    /// will never be completed successfully.
+    fn run_onion_announce_cleanup(self) -> impl Future<Item = (), Error = RunError> + Send {
+        let interval = Duration::from_secs(ONION_ANNOUNCE_CLEANUP_INTERVAL);
+        let wakeups = Interval::new(Instant::now() + interval, interval);
+        wakeups
+            .map_err(|e| e.context(RunErrorKind::Wakeup).into())
+            .for_each(move |_instant| {
+                let removed = self.onion_announce.write().remove_timed_out_entries();
+                if removed > 0 {
+                    trace!("Removed {} timed out onion announce entries", removed);
+                }
+                future::ok(())
+            })
+    }
+
     /// Run ping sending periodically. Result future will never be completed
     /// successfully.
     fn run_pings_sending(self) -> impl Future<Item = (), Error = RunError> + Send {
@@ -463,7 +537,7 @@ impl Server {
     }
 
     /// Send `PingRequest` packets to nodes from `nodes_to_ping` list.
-    fn send_pings(&self) -> impl Future<Item = (), Error = mpsc::SendError<(Packet, SocketAddr)>> + Send {
+    pub(crate) fn send_pings(&self) -> impl Future<Item = (), Error = mpsc::SendError<(Packet, SocketAddr)>> + Send {
         let nodes_to_ping = mem::replace(
             &mut *self.nodes_to_ping.write(),
             Kbucket::<PackedNode>::new(MAX_TO_PING)
@@ -571,6 +645,25 @@ impl Server {
         Box::new(self.send_nodes_req(&random_node, request_queue, pk))
     }
 
+    /// Send `NodesRequest` packets with random keys from ranges of kbuckets
+    /// that haven't been updated for `KBUCKET_REFRESH_INTERVAL` seconds. They
+    /// are sent to the closest to these keys good nodes.
+    fn refresh_kbuckets(&self, request_queue: &mut RequestQueue<PublicKey>, close_nodes: &mut Ktree)
+        -> impl Future<Item = (), Error = mpsc::SendError<(Packet, SocketAddr)>> + Send {
+        let search_pks = close_nodes.kbuckets_to_refresh(Duration::from_secs(KBUCKET_REFRESH_INTERVAL));
+
+        let mut futures = Vec::new();
+        for search_pk in search_pks {
+            trace!("Refreshing kbucket with random key {:?}", search_pk);
+            let nodes = close_nodes.get_closest(&search_pk, MAX_TO_REFRESH_KBUCKET, false);
+            for node in nodes.iter() {
+                futures.push(self.send_nodes_req(node, request_queue, search_pk));
+            }
+        }
+
+        future::join_all(futures).map(|_| ())
+    }
+
     /// Ping node with `NodesRequest` packet with self DHT `PublicKey`.
     pub fn ping_node(&self, node: &PackedNode) -> impl Future<Item = (), Error = PingError> + Send {
         let mut request_queue = self.request_queue.write();
@@ -688,6 +781,15 @@ impl Server {
 
     /// Function to handle incoming packets and send responses if necessary.
     pub fn handle_packet(&self, packet: Packet, addr: SocketAddr) -> impl Future<Item = (), Error = HandlePacketError> + Send {
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            if let Some(class) = PacketClass::from_packet(&packet) {
+                if !rate_limiter.check(addr.ip(), class) {
+                    trace!("Dropping {:?} packet from {}: rate limit exceeded", class, addr);
+                    return Box::new(future::ok(())) as Box<dyn Future<Item=_, Error=_> + Send>;
+                }
+            }
+        }
+
         match packet {
             Packet::PingRequest(packet) => Box::new(self.handle_ping_req(&packet, addr)) as Box<dyn Future<Item=_, Error=_> + Send>,
             Packet::PingResponse(packet) => Box::new(self.handle_ping_resp(&packet, addr)),
@@ -755,7 +857,10 @@ impl Server {
     /// Add node to close list after we received a response from it. If it's a
     /// friend then send it's IP address to appropriate sink.
     fn try_add_to_close(&self, close_nodes: &mut Ktree, friends: &mut HashMap<PublicKey, DhtFriend>, node: PackedNode) -> impl Future<Item = (), Error = HandlePacketError> {
-        close_nodes.try_add(node);
+        let is_new = !close_nodes.contains(&node.pk);
+        if close_nodes.try_add(node) && is_new {
+            emit(&self.event_tx, Event::CloseNodeAdded { pk: node.pk, saddr: node.saddr });
+        }
         for friend in friends.values_mut() {
             friend.try_add_to_close(node);
         }
@@ -792,6 +897,12 @@ impl Server {
             let mut close_nodes = self.close_nodes.write();
             let mut friends = self.friends.write();
 
+            if let Some(friend) = friends.get_mut(&packet.pk) {
+                if let Some(strategy) = friend.hole_punch.handle_punch_success(addr) {
+                    debug!("Punched hole to friend {:?} on {} using {:?} strategy", packet.pk, addr, strategy);
+                }
+            }
+
             Either::B(self.try_add_to_close(&mut close_nodes, &mut friends, PackedNode::new(addr, &packet.pk)))
         } else {
             Either::A(future::err(
@@ -1025,6 +1136,9 @@ impl Server {
         };
 
         friend.hole_punch.last_recv_ping_time = clock_now();
+        // The friend is going to punch holes to us after our response so we
+        // should punch holes to the friend simultaneously.
+        friend.hole_punch.is_punching_done = false;
 
         let resp_payload = DhtRequestPayload::NatPingResponse(NatPingResponse {
             id: payload.id,
@@ -1439,10 +1553,68 @@ impl Server {
         self.friend_saddr_sink = Some(friend_saddr_sink);
     }
 
+    /// Set sink to send events about internal state changes.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Set sink to send raw bytes of received and sent packets to. Packets
+    /// are tapped with their addresses when the server is run on a socket.
+    pub fn set_tap_sink(&mut self, tap: TapTx) {
+        self.tap = Some(tap);
+    }
+
     /// Get `PrecomputedKey`s cache.
     pub fn get_precomputed_keys(&self) -> PrecomputedCache {
         self.precomputed_keys.clone()
     }
+
+    /// Set per-IP rate limiter for incoming requests.
+    pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = Some(rate_limiter);
+    }
+
+    /// Get per-IP rate limiter for incoming requests if it's enabled.
+    pub fn get_rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    /// Set our external address that was assigned by gateway when UDP port
+    /// was mapped.
+    pub fn set_external_addr(&self, addr: Option<SocketAddr>) {
+        *self.external_addr.write() = addr;
+    }
+
+    /// Get our external address that was assigned by gateway when UDP port
+    /// was mapped.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        *self.external_addr.read()
+    }
+
+    /// Get the number of onion nodes announced to us.
+    pub fn onion_announce_entries_count(&self) -> usize {
+        self.onion_announce.read().entries_count()
+    }
+
+    /// Set maximum number of onion announce entries from the same IP address.
+    /// The address is the one of the onion relay that forwarded the announce
+    /// request, not of the announcing client, so the limit should be generous.
+    /// `None` means unlimited which is the default.
+    pub fn set_max_onion_announce_entries_per_ip(&mut self, max_entries_per_ip: Option<usize>) {
+        self.onion_announce.write().set_max_entries_per_ip(max_entries_per_ip);
+    }
+
+    /// Set limits on the number of nodes from the same subnet in close nodes
+    /// lists. Nodes that are already in the lists are not affected.
+    pub fn set_subnet_limits(&mut self, subnet_limits: SubnetLimits) {
+        let mut close_nodes = self.close_nodes.write();
+        close_nodes.set_kbucket_subnet_limit(subnet_limits.kbucket);
+        close_nodes.set_subnet_limit(subnet_limits.ktree);
+        for friend in self.friends.write().values_mut() {
+            friend.close_nodes.set_subnet_limit(subnet_limits.friend);
+        }
+        self.subnet_limits = subnet_limits;
+    }
 }
 
 #[cfg(test)]
@@ -1499,6 +1671,25 @@ mod tests {
         assert!(inserted_friend.nodes_to_bootstrap.contains(&friend_pk, &bob_pk));
     }
 
+    #[test]
+    fn add_friend_with_subnet_limits() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let subnet_limits = SubnetLimits {
+            kbucket: Some(SubnetLimit::new(2)),
+            ktree: Some(SubnetLimit::new(4)),
+            friend: Some(SubnetLimit::new(1)),
+        };
+        alice.set_subnet_limits(subnet_limits);
+
+        let friend_pk = gen_keypair().0;
+        alice.add_friend(friend_pk);
+
+        let friends = alice.friends.read();
+        assert!(friends.values().all(|friend| friend.close_nodes.subnet_limit() == subnet_limits.friend));
+        assert_eq!(alice.close_nodes.read().kbuckets[0].subnet_limit(), subnet_limits.kbucket);
+    }
+
     #[test]
     fn readd_friend() {
         let (alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
@@ -1692,6 +1883,53 @@ mod tests {
         assert_eq!(node.assoc4.last_resp_time.unwrap(), time);
     }
 
+    #[test]
+    fn handle_ping_resp_punched_hole() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        alice.add_friend(bob_pk);
+        alice.friends.write().get_mut(&bob_pk).unwrap().hole_punch.punched_addrs
+            .insert(addr, PunchStrategy::Sequential);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(ping_resp, addr).wait().unwrap();
+
+        assert!(alice.friends.read()[&bob_pk].hole_punch.punched_addrs.is_empty());
+        let stats = alice.hole_punching_stats();
+        assert_eq!(stats.get(PunchStrategy::Sequential).successes, 1);
+        assert_eq!(stats.get(PunchStrategy::Random).successes, 0);
+    }
+
+    #[test]
+    fn handle_ping_resp_close_node_added_event() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let (event_tx, event_rx) = mpsc::unbounded();
+        alice.set_event_sink(event_tx);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(ping_resp, addr).wait().unwrap();
+
+        // the node is already in the close list so no new event is emitted
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+        alice.handle_packet(ping_resp, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert_eq!(event_rx.collect().wait().unwrap(), vec![Event::CloseNodeAdded { pk: bob_pk, saddr: addr }]);
+    }
+
     #[test]
     fn handle_ping_resp_not_a_friend() {
         let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
@@ -1926,6 +2164,52 @@ mod tests {
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::GetPayload);
     }
 
+    #[test]
+    fn set_external_addr() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+        assert_eq!(alice.external_addr(), None);
+
+        let external_addr = "1.2.3.4:33445".parse().unwrap();
+        alice.set_external_addr(Some(external_addr));
+        assert_eq!(alice.external_addr(), Some(external_addr));
+        // clones share the address
+        assert_eq!(alice.clone().external_addr(), Some(external_addr));
+    }
+
+    #[test]
+    fn handle_nodes_req_rate_limited() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let mut config = RateLimiterConfig::default();
+        config.nodes = RateLimit { burst: 1, rate: 1 };
+        alice.set_rate_limiter(RateLimiter::new(config));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        let now = Instant::now();
+        let clock = Clock::new_with_now(ConstNow(now));
+        let mut enter = tokio_executor::enter().unwrap();
+
+        with_default(&clock, &mut enter, |_| {
+            alice.handle_packet(nodes_req.clone(), addr).wait().unwrap();
+            alice.handle_packet(nodes_req, addr).wait().unwrap();
+        });
+
+        let rate_limiter = alice.get_rate_limiter().unwrap();
+        assert_eq!(rate_limiter.dropped(PacketClass::Nodes), 1);
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        // only the first request should be answered
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        let (packet, addr_to_send) = packets[0].clone();
+        assert_eq!(addr_to_send, addr);
+        unpack!(packet, Packet::NodesResponse);
+    }
+
     // handle_nodes_resp
     #[test]
     fn handle_nodes_resp() {
@@ -2258,6 +2542,8 @@ mod tests {
         let friends = alice.friends.read();
 
         assert_eq!(friends[&bob_pk].hole_punch.last_recv_ping_time, time);
+        // we should punch holes simultaneously with the friend
+        assert!(!friends[&bob_pk].hole_punch.is_punching_done);
     }
 
     // handle_nat_ping_response
@@ -3340,6 +3626,49 @@ mod tests {
         assert!(rx.collect().wait().unwrap().is_empty());
     }
 
+    #[test]
+    fn refresh_kbuckets() {
+        let (alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
+
+        let now = Instant::now() + Duration::from_secs(KBUCKET_REFRESH_INTERVAL + 1);
+
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &bob_pk);
+        {
+            let mut close_nodes = alice.close_nodes.write();
+            assert!(close_nodes.try_add(pn));
+            // bob should be good when kbuckets are refreshed
+            close_nodes.get_node_mut(&bob_pk).unwrap().assoc4.last_resp_time = Some(now);
+        }
+        let bob_index = kbucket_index(&alice.pk, &bob_pk).unwrap();
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            let mut request_queue = alice.request_queue.write();
+            let mut close_nodes = alice.close_nodes.write();
+            alice.refresh_kbuckets(&mut request_queue, &mut close_nodes).wait().unwrap();
+            // kbuckets are refreshed only once per interval
+            alice.refresh_kbuckets(&mut request_queue, &mut close_nodes).wait().unwrap();
+        });
+
+        let precomp = precompute(&alice.pk, &bob_sk);
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        // every kbucket that is not closer than bob's one should be refreshed
+        let mut indices = rx.collect().wait().unwrap().into_iter().map(|(packet, addr)| {
+            assert_eq!(addr, pn.saddr);
+            let nodes_req = unpack!(packet, Packet::NodesRequest);
+            let nodes_req_payload = nodes_req.get_payload(&precomp).unwrap();
+            kbucket_index(&nodes_req.pk, &nodes_req_payload.pk).unwrap()
+        }).collect::<Vec<_>>();
+        indices.sort();
+
+        assert_eq!(indices, (0 ..= bob_index).collect::<Vec<_>>());
+    }
+
     #[test]
     fn ping_nodes_to_bootstrap_of_friend() {
         let (alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
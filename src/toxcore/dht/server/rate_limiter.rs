@@ -0,0 +1,369 @@
+/*!
+Module for per-IP rate limiting of incoming DHT packets.
+
+Some DHT packets like `NodesRequest`, `CookieRequest` or onion requests are
+answered with packets that are bigger than the requests themselves. Without
+any limits a DHT node can be used to reflect and amplify traffic towards a
+spoofed source address. `RateLimiter` keeps a token bucket per source IP
+address (per /64 subnet for IPv6) and per class of packets so that every
+source gets only a limited budget of responses.
+*/
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::toxcore::dht::ip_port::ip_subnet;
+use crate::toxcore::dht::packet::Packet;
+use crate::toxcore::time::*;
+
+/// Number of leading bits of IPv4 address that identify a single source.
+const RATE_LIMIT_IPV4_PREFIX: u8 = 32;
+/// Number of leading bits of IPv6 address that identify a single source.
+/// Usually a single host owns the whole /64 subnet.
+const RATE_LIMIT_IPV6_PREFIX: u8 = 64;
+/// Default maximum number of sources for which token buckets are stored.
+pub const RATE_LIMIT_DEFAULT_CAPACITY: usize = 4096;
+
+/// Class of packets that share the same rate limit budget.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PacketClass {
+    /// `NodesRequest` packets.
+    Nodes,
+    /// `OnionRequest0` packets. `OnionRequest1` and `OnionRequest2` packets
+    /// come from the previous onion node that relays requests of many
+    /// clients so they are not limited.
+    Onion,
+    /// `CookieRequest` packets.
+    Cookie,
+    /// `BootstrapInfo` packets.
+    BootstrapInfo,
+}
+
+impl PacketClass {
+    /// Get the class of a packet. Returns `None` if the packet is not rate
+    /// limited.
+    pub fn from_packet(packet: &Packet) -> Option<PacketClass> {
+        match *packet {
+            Packet::NodesRequest(_) => Some(PacketClass::Nodes),
+            Packet::OnionRequest0(_) => Some(PacketClass::Onion),
+            Packet::CookieRequest(_) => Some(PacketClass::Cookie),
+            Packet::BootstrapInfo(_) => Some(PacketClass::BootstrapInfo),
+            _ => None,
+        }
+    }
+}
+
+/// Budget of a token bucket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    /// Maximum number of packets that can be accepted at once.
+    pub burst: u32,
+    /// Number of packets per second that are added to the budget.
+    pub rate: u32,
+}
+
+/// Configuration of `RateLimiter` with budgets for every `PacketClass`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimiterConfig {
+    /// Budget for `NodesRequest` packets.
+    pub nodes: RateLimit,
+    /// Budget for `OnionRequest0` packets.
+    pub onion: RateLimit,
+    /// Budget for `CookieRequest` packets.
+    pub cookie: RateLimit,
+    /// Budget for `BootstrapInfo` packets.
+    pub bootstrap_info: RateLimit,
+    /// Maximum number of sources for which token buckets are stored. When
+    /// this number is exceeded the least recently seen source is forgotten.
+    pub capacity: usize,
+}
+
+impl Default for RateLimiterConfig {
+    fn default() -> Self {
+        RateLimiterConfig {
+            nodes: RateLimit { burst: 32, rate: 8 },
+            onion: RateLimit { burst: 64, rate: 32 },
+            cookie: RateLimit { burst: 16, rate: 4 },
+            bootstrap_info: RateLimit { burst: 4, rate: 1 },
+            capacity: RATE_LIMIT_DEFAULT_CAPACITY,
+        }
+    }
+}
+
+impl RateLimiterConfig {
+    /// Get budget for the class of packets.
+    pub fn limit(&self, class: PacketClass) -> RateLimit {
+        match class {
+            PacketClass::Nodes => self.nodes,
+            PacketClass::Onion => self.onion,
+            PacketClass::Cookie => self.cookie,
+            PacketClass::BootstrapInfo => self.bootstrap_info,
+        }
+    }
+}
+
+/// Token bucket that refills with constant rate.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TokenBucket {
+    /// Number of available tokens.
+    tokens: f64,
+    /// Time when tokens were refilled last time.
+    last_refill_time: Instant,
+}
+
+impl TokenBucket {
+    /// Create new full `TokenBucket`.
+    pub(crate) fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            tokens: f64::from(limit.burst),
+            last_refill_time: clock_now(),
+        }
+    }
+
+    /// Refill the bucket and try to take one token from it. Returns `true` if
+    /// the token was taken.
+    pub(crate) fn try_take(&mut self, limit: RateLimit) -> bool {
+        self.try_take_n(limit, 1)
+    }
+
+    /// Refill the bucket and try to take `n` tokens from it. Returns `true`
+    /// if the tokens were taken. Nothing is taken if there are not enough
+    /// tokens.
+    pub(crate) fn try_take_n(&mut self, limit: RateLimit, n: u32) -> bool {
+        let n = f64::from(n);
+        let now = clock_now();
+        let elapsed = now - self.last_refill_time;
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.tokens = (self.tokens + elapsed * f64::from(limit.rate)).min(f64::from(limit.burst));
+        self.last_refill_time = now;
+
+        if self.tokens >= n {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct RateLimiterInner {
+    /// Token buckets for every source and class of packets.
+    buckets: LruCache<(IpAddr, PacketClass), TokenBucket>,
+    /// Number of dropped packets for every class of packets.
+    dropped: HashMap<PacketClass, u64>,
+}
+
+/// Per-IP rate limiter with a separate token bucket for every
+/// `PacketClass`.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: RateLimiterConfig,
+    inner: Arc<Mutex<RateLimiterInner>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(RateLimiterConfig::default())
+    }
+}
+
+impl RateLimiter {
+    /// Create new `RateLimiter` with the given configuration.
+    pub fn new(config: RateLimiterConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            inner: Arc::new(Mutex::new(RateLimiterInner {
+                buckets: LruCache::new(config.capacity),
+                dropped: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Get the configuration of this `RateLimiter`.
+    pub fn config(&self) -> RateLimiterConfig {
+        self.config
+    }
+
+    /// Check if a packet of the given class from the given IP address fits
+    /// into the budget. Returns `false` and counts the packet as dropped if
+    /// the budget is exhausted.
+    pub fn check(&self, ip: IpAddr, class: PacketClass) -> bool {
+        let limit = self.config.limit(class);
+        let key = (ip_subnet(ip, RATE_LIMIT_IPV4_PREFIX, RATE_LIMIT_IPV6_PREFIX), class);
+        let mut inner = self.inner.lock();
+
+        let allowed = if let Some(bucket) = inner.buckets.get_mut(&key) {
+            bucket.try_take(limit)
+        } else {
+            let mut bucket = TokenBucket::new(limit);
+            let allowed = bucket.try_take(limit);
+            inner.buckets.put(key, bucket);
+            allowed
+        };
+
+        if !allowed {
+            *inner.dropped.entry(class).or_insert(0) += 1;
+        }
+
+        allowed
+    }
+
+    /// Get the number of dropped packets of the given class.
+    pub fn dropped(&self, class: PacketClass) -> u64 {
+        self.inner.lock().dropped.get(&class).cloned().unwrap_or(0)
+    }
+
+    /// Get the total number of dropped packets of all classes.
+    pub fn dropped_total(&self) -> u64 {
+        self.inner.lock().dropped.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use crate::toxcore::crypto_core::*;
+    use crate::toxcore::dht::packet::*;
+    use crate::toxcore::onion::packet::*;
+
+    fn config() -> RateLimiterConfig {
+        RateLimiterConfig {
+            nodes: RateLimit { burst: 2, rate: 1 },
+            onion: RateLimit { burst: 3, rate: 1 },
+            cookie: RateLimit { burst: 1, rate: 1 },
+            bootstrap_info: RateLimit { burst: 1, rate: 1 },
+            capacity: 16,
+        }
+    }
+
+    #[test]
+    fn packet_class() {
+        let packet = Packet::BootstrapInfo(BootstrapInfo {
+            version: 42,
+            motd: vec![1, 2, 3],
+        });
+        assert_eq!(PacketClass::from_packet(&packet), Some(PacketClass::BootstrapInfo));
+
+        let packet = Packet::LanDiscovery(LanDiscovery {
+            pk: gen_keypair().0,
+        });
+        assert_eq!(PacketClass::from_packet(&packet), None);
+
+        let packet = Packet::OnionRequest0(OnionRequest0 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        });
+        assert_eq!(PacketClass::from_packet(&packet), Some(PacketClass::Onion));
+
+        // relayed onion requests are not limited
+        let packet = Packet::OnionRequest1(OnionRequest1 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_1_SIZE - secretbox::NONCEBYTES],
+            },
+        });
+        assert_eq!(PacketClass::from_packet(&packet), None);
+    }
+
+    #[test]
+    fn check_burst() {
+        let limiter = RateLimiter::new(config());
+        let ip = "1.2.3.4".parse().unwrap();
+
+        let now = Instant::now();
+        let clock = Clock::new_with_now(ConstNow(now));
+        let mut enter = tokio_executor::enter().unwrap();
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(limiter.check(ip, PacketClass::Nodes));
+            assert!(limiter.check(ip, PacketClass::Nodes));
+            assert!(!limiter.check(ip, PacketClass::Nodes));
+            // other classes have their own budgets
+            assert!(limiter.check(ip, PacketClass::Cookie));
+            assert!(!limiter.check(ip, PacketClass::Cookie));
+        });
+
+        assert_eq!(limiter.dropped(PacketClass::Nodes), 1);
+        assert_eq!(limiter.dropped(PacketClass::Cookie), 1);
+        assert_eq!(limiter.dropped(PacketClass::Onion), 0);
+        assert_eq!(limiter.dropped_total(), 2);
+    }
+
+    #[test]
+    fn check_refill() {
+        let limiter = RateLimiter::new(config());
+        let ip = "1.2.3.4".parse().unwrap();
+
+        let now = Instant::now();
+        let time = MutNow::new(now);
+        let clock = Clock::new_with_now(time.clone());
+        let mut enter = tokio_executor::enter().unwrap();
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(limiter.check(ip, PacketClass::Nodes));
+            assert!(limiter.check(ip, PacketClass::Nodes));
+            assert!(!limiter.check(ip, PacketClass::Nodes));
+
+            time.set(now + Duration::from_secs(1));
+            assert!(limiter.check(ip, PacketClass::Nodes));
+            assert!(!limiter.check(ip, PacketClass::Nodes));
+
+            // budget can't exceed burst size
+            time.set(now + Duration::from_secs(100));
+            assert!(limiter.check(ip, PacketClass::Nodes));
+            assert!(limiter.check(ip, PacketClass::Nodes));
+            assert!(!limiter.check(ip, PacketClass::Nodes));
+        });
+    }
+
+    #[test]
+    fn check_different_ips() {
+        let limiter = RateLimiter::new(config());
+        let ip_1 = "1.2.3.4".parse().unwrap();
+        let ip_2 = "1.2.3.5".parse().unwrap();
+
+        let now = Instant::now();
+        let clock = Clock::new_with_now(ConstNow(now));
+        let mut enter = tokio_executor::enter().unwrap();
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(limiter.check(ip_1, PacketClass::Cookie));
+            assert!(!limiter.check(ip_1, PacketClass::Cookie));
+            assert!(limiter.check(ip_2, PacketClass::Cookie));
+        });
+    }
+
+    #[test]
+    fn check_ipv6_subnet() {
+        let limiter = RateLimiter::new(config());
+        let ip_1 = "2001:db8::1".parse().unwrap();
+        let ip_2 = "2001:db8::2".parse().unwrap();
+        let ip_3 = "2001:db8:0:1::1".parse().unwrap();
+
+        let now = Instant::now();
+        let clock = Clock::new_with_now(ConstNow(now));
+        let mut enter = tokio_executor::enter().unwrap();
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(limiter.check(ip_1, PacketClass::Cookie));
+            // the same /64 subnet shares the budget
+            assert!(!limiter.check(ip_2, PacketClass::Cookie));
+            assert!(limiter.check(ip_3, PacketClass::Cookie));
+        });
+    }
+}
@@ -8,10 +8,12 @@ use futures::sync::mpsc::Receiver;
 use tokio::net::{UdpSocket, UdpFramed};
 use failure::Fail;
 
+use crate::toxcore::binary_io::*;
 use crate::toxcore::dht::codec::*;
 use crate::toxcore::dht::packet::Packet;
 use crate::toxcore::dht::server::Server;
-use crate::toxcore::stats::Stats;
+use crate::toxcore::dissect::{tap, TapTx};
+use crate::toxcore::stats::{Direction, Stats, Transport};
 
 /// Extension trait for running DHT server on `UdpSocket`.
 pub trait ServerExt {
@@ -24,9 +26,10 @@ impl ServerExt for Server {
         let udp_addr = socket.local_addr()
             .expect("Failed to get socket address");
 
-        let codec = DhtCodec::new(stats);
+        let codec = DhtCodec::new(stats.clone());
         let (sink, stream) = UdpFramed::new(socket, codec).split();
 
+        let tap_tx = self.tap.clone();
         let self_c = self.clone();
         let network_reader = stream.then(future::ok).filter(|event|
             match event {
@@ -39,6 +42,7 @@ impl ServerExt for Server {
             }
         ).and_then(|event| event).for_each(move |(packet, addr)| {
             trace!("Received packet {:?}", packet);
+            tap_packet(self_c.tap.as_ref(), Direction::Incoming, udp_addr, addr, &packet);
             self_c.handle_packet(packet, addr).or_else(|err| {
                 error!("Failed to handle packet: {:?}", err);
                 future::ok(())
@@ -48,7 +52,12 @@ impl ServerExt for Server {
         let network_writer = rx
             .map_err(|()| unreachable!("rx can't fail"))
             // filter out IPv6 packets if node is running in IPv4 mode
-            .filter(move |&(ref _packet, addr)| !(udp_addr.is_ipv4() && addr.is_ipv6()))
+            .filter(move |&(ref _packet, addr)| if udp_addr.is_ipv4() && addr.is_ipv6() {
+                stats.packets.increase_ipv6_dropped();
+                false
+            } else {
+                true
+            })
             .fold(sink, move |sink, (packet, mut addr)| {
                 if udp_addr.is_ipv6() {
                     if let IpAddr::V4(ip) = addr.ip() {
@@ -56,6 +65,7 @@ impl ServerExt for Server {
                     }
                 }
                 trace!("Sending packet {:?} to {:?}", packet, addr);
+                tap_packet(tap_tx.as_ref(), Direction::Outgoing, udp_addr, addr, &packet);
                 sink.send((packet, addr))
                     .map_err(|e| Error::new(ErrorKind::Other, e.compat()))
             })
@@ -73,6 +83,18 @@ impl ServerExt for Server {
     }
 }
 
+/// Send serialized packet to the tap if it's set. Codec doesn't know
+/// addresses of packets so they are tapped here. Packets that can't be
+/// decoded are not tapped.
+fn tap_packet(tap_tx: Option<&TapTx>, direction: Direction, local: SocketAddr, remote: SocketAddr, packet: &Packet) {
+    if tap_tx.is_some() {
+        let mut buf = [0; MAX_DHT_PACKET_SIZE];
+        if let Ok((_, size)) = packet.to_bytes((&mut buf, 0)) {
+            tap(tap_tx, Transport::Udp, direction, local, remote, &buf[.. size]);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -82,6 +104,7 @@ mod tests {
 
     use crate::toxcore::crypto_core::*;
     use crate::toxcore::dht::packet::*;
+    use crate::toxcore::stats::{Direction, Transport};
 
     #[test]
     fn run_socket() {
@@ -92,7 +115,9 @@ mod tests {
 
         let (tx, rx) = mpsc::channel(32);
 
-        let server = Server::new(tx, server_pk, server_sk);
+        let mut server = Server::new(tx, server_pk, server_sk);
+        let (tap_tx, tap_rx) = mpsc::unbounded();
+        server.set_tap_sink(tap_tx);
 
         // Bind server socket
         let server_addr = "127.0.0.1:0".parse().unwrap();
@@ -100,16 +125,17 @@ mod tests {
         let server_addr = server_socket.local_addr().unwrap();
 
         let stats = Stats::new();
-        let server_future = server.run_socket(server_socket, rx, stats);
+        let server_future = server.run_socket(server_socket, rx, stats.clone());
 
         // Bind client socket to communicate with the server
         let client_addr = "127.0.0.1:0".parse().unwrap();
         let client_socket = UdpSocket::bind(&client_addr).unwrap();
+        let client_addr = client_socket.local_addr().unwrap();
 
         // Send invalid request first to ensure that the server won't crash
         let client_future = client_socket.send_dgram(&[42; 123][..], &server_addr).and_then(move |(client_socket, _)| {
             let stats = Stats::new();
-            let codec = DhtCodec::new(stats);
+            let codec = DhtCodec::new(stats.clone());
             let (sink, stream) = UdpFramed::new(client_socket, codec).split();
 
             // Send ping request
@@ -141,5 +167,15 @@ mod tests {
         }).map_err(|_| ());
 
         tokio::run(future);
+
+        assert_eq!(stats.packets.decode_errors(Transport::Udp, "Deserialize"), 1);
+        assert_eq!(stats.packets.packet_count(Transport::Udp, Direction::Incoming, "PingRequest").packets, 1);
+
+        // invalid request is not tapped since it can't be decoded
+        let (tapped, _tap_rx) = tap_rx.into_future().wait().map_err(|_| ()).unwrap();
+        let tapped = tapped.unwrap();
+        assert_eq!((tapped.transport, tapped.direction), (Transport::Udp, Direction::Incoming));
+        assert_eq!((tapped.local, tapped.remote), (server_addr, client_addr));
+        assert_eq!(tapped.data[0], 0x00);
     }
 }
@@ -0,0 +1,963 @@
+/*! Dissector of Tox traffic.
+
+Packets can be taken either from capture files (see [`pcap`](./pcap/index.html))
+or from taps that can be attached to DHT `Server`, TCP `Server` and TCP `Codec`
+with `set_tap_sink`. Every packet is decoded into human readable form. When secret
+keys of participants are added to the `Dissector` encrypted payloads are
+decrypted as well. Long term and DHT keys are enough to decrypt DHT, onion and
+handshake packets. Session keys of `net_crypto` and TCP connections are
+ephemeral so `CryptoData` packets and TCP frames from capture files can be
+decrypted only when session secret keys are added. Taps attached to TCP
+`Codec` capture packets already decrypted.
+*/
+
+pub mod pcap;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use futures::sync::mpsc;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packet::{self as dht, DhtRequestPayload};
+use crate::toxcore::onion::packet::*;
+use crate::toxcore::stats::{Direction, Transport};
+use crate::toxcore::tcp::handshake::{ClientHandshake, ServerHandshake, HandshakePayload, CLIENT_HANDSHAKE_SIZE, SERVER_HANDSHAKE_SIZE};
+use crate::toxcore::tcp::packet as tcp;
+
+use self::pcap::{CapturedPacket, Payload};
+
+/// Sink to send tapped packets to.
+pub type TapTx = mpsc::UnboundedSender<TapPacket>;
+
+/// Raw packet captured by a codec tap.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TapPacket {
+    /// Time when the packet was captured.
+    pub time: SystemTime,
+    /// Transport of the packet. DHT packets are captured as they are sent
+    /// over UDP, TCP packets are captured before encryption or after
+    /// decryption.
+    pub transport: Transport,
+    /// Whether the packet was received or sent.
+    pub direction: Direction,
+    /// Address of our side of the connection.
+    pub local: SocketAddr,
+    /// Address of the peer the packet was received from or sent to.
+    pub remote: SocketAddr,
+    /// Bytes of the packet.
+    pub data: Vec<u8>,
+}
+
+/// Send packet to the tap if it's set. Packets are dropped when the receiver
+/// is gone.
+pub(crate) fn tap(tap_tx: Option<&TapTx>, transport: Transport, direction: Direction, local: SocketAddr, remote: SocketAddr, data: &[u8]) {
+    if let Some(tap_tx) = tap_tx {
+        let packet = TapPacket {
+            time: SystemTime::now(),
+            transport,
+            direction,
+            local,
+            remote,
+            data: data.to_vec(),
+        };
+        if tap_tx.unbounded_send(packet).is_err() {
+            trace!("Failed to send tapped packet: receiver is dropped");
+        }
+    }
+}
+
+/// Human readable description of a packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Dissection {
+    /// One line summary with the packet kind.
+    pub summary: String,
+    /// Decoded fields and decrypted payloads.
+    pub details: Vec<String>,
+}
+
+impl Dissection {
+    fn new(summary: String) -> Dissection {
+        Dissection {
+            summary,
+            details: Vec::new(),
+        }
+    }
+
+    fn detail(&mut self, detail: String) {
+        self.details.push(detail);
+    }
+
+    fn not_decrypted(&mut self, what: &str) {
+        self.details.push(format!("{}: <not decrypted>", what));
+    }
+}
+
+impl fmt::Display for Dissection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.summary)?;
+        for detail in &self.details {
+            write!(f, "\n    {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format bytes as uppercase hex string.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Format `PublicKey` as uppercase hex string.
+fn pk_hex(pk: &PublicKey) -> String {
+    hex(pk.as_ref())
+}
+
+/// Session of `net_crypto` connection learned from `CryptoHandshake`.
+#[derive(Clone, Debug)]
+struct CryptoSession {
+    /// Session `PublicKey` of the side that sent the handshake.
+    session_pk: PublicKey,
+    /// Nonce of the next `CryptoData` packet sent by this side.
+    nonce: Nonce,
+}
+
+/// State of one direction of TCP connection.
+#[derive(Clone, Debug, Default)]
+struct TcpHalf {
+    /// Sequence number of the next expected byte.
+    next_seq: Option<u32>,
+    /// Received but not yet parsed bytes.
+    buffer: Vec<u8>,
+    /// Whether the handshake from this side was parsed.
+    handshake_done: bool,
+    /// Session `PublicKey` and `Nonce` of this side.
+    session: Option<(PublicKey, Nonce)>,
+}
+
+/// State of TCP connection to a relay.
+#[derive(Clone, Debug, Default)]
+struct TcpConnection {
+    /// Data sent by the client.
+    client: TcpHalf,
+    /// Data sent by the server.
+    server: TcpHalf,
+    /// Key that encrypts handshakes.
+    handshake_key: Option<PrecomputedKey>,
+    /// Key that encrypts frames after handshake.
+    channel_key: Option<PrecomputedKey>,
+}
+
+/// Decoder of Tox packets into human readable form.
+#[derive(Default)]
+pub struct Dissector {
+    /// Secret keys that can be used to decrypt packets.
+    secret_keys: HashMap<PublicKey, SecretKey>,
+    /// All `PublicKey`s seen in packets.
+    known_pks: HashSet<PublicKey>,
+    /// Cache of precomputed keys by their and our `PublicKey`s.
+    precomputed: HashMap<(PublicKey, PublicKey), PrecomputedKey>,
+    /// Sessions of `net_crypto` connections by source and destination
+    /// addresses of `CryptoHandshake` packets.
+    crypto_sessions: HashMap<(SocketAddr, SocketAddr), CryptoSession>,
+    /// TCP connections by client and server addresses.
+    tcp_connections: HashMap<(SocketAddr, SocketAddr), TcpConnection>,
+}
+
+impl Dissector {
+    /// Create new `Dissector` without secret keys.
+    pub fn new() -> Dissector {
+        Dissector::default()
+    }
+
+    /// Add secret key that will be used to decrypt packets. It can be long
+    /// term, DHT or session key.
+    pub fn add_secret_key(&mut self, sk: SecretKey) {
+        let pk = sk.public_key();
+        self.known_pks.insert(pk);
+        self.secret_keys.insert(pk, sk);
+    }
+
+    /// Add `PublicKey` of a participant. Some packets like `CryptoHandshake`
+    /// don't contain `PublicKey` of the sender so it should be known in
+    /// advance to decrypt them.
+    pub fn add_public_key(&mut self, pk: PublicKey) {
+        self.known_pks.insert(pk);
+    }
+
+    /// Get precomputed key for their `PublicKey` and our secret key.
+    fn precomputed(&mut self, their_pk: PublicKey, our_pk: PublicKey) -> Option<PrecomputedKey> {
+        let sk = self.secret_keys.get(&our_pk)?;
+        Some(self.precomputed.entry((their_pk, our_pk))
+            .or_insert_with(|| precompute(&their_pk, sk))
+            .clone())
+    }
+
+    /// Keys that could be used to encrypt packet sent by or to the owner of
+    /// the `PublicKey` when the other side is unknown.
+    fn candidate_keys(&mut self, pk: PublicKey) -> Vec<PrecomputedKey> {
+        self.known_pks.insert(pk);
+        let ours = self.secret_keys.keys().cloned().filter(|&our_pk| our_pk != pk).collect::<Vec<_>>();
+        let mut keys = ours.into_iter()
+            .flat_map(|our_pk| self.precomputed(pk, our_pk))
+            .collect::<Vec<_>>();
+        if self.secret_keys.contains_key(&pk) {
+            let theirs = self.known_pks.iter().cloned().filter(|&their_pk| their_pk != pk).collect::<Vec<_>>();
+            keys.extend(theirs.into_iter().flat_map(|their_pk| self.precomputed(their_pk, pk)));
+        }
+        keys
+    }
+
+    /// Key for the pair of `PublicKey`s if the secret key of either of them
+    /// is known.
+    fn pair_key(&mut self, first_pk: PublicKey, second_pk: PublicKey) -> Option<PrecomputedKey> {
+        self.known_pks.insert(first_pk);
+        self.known_pks.insert(second_pk);
+        self.precomputed(first_pk, second_pk)
+            .or_else(|| self.precomputed(second_pk, first_pk))
+    }
+
+    /// All keys between known `PublicKey`s and our secret keys.
+    fn all_keys(&mut self) -> Vec<PrecomputedKey> {
+        let ours = self.secret_keys.keys().cloned().collect::<Vec<_>>();
+        let theirs = self.known_pks.iter().cloned().collect::<Vec<_>>();
+        let mut keys = Vec::new();
+        for &our_pk in &ours {
+            for &their_pk in &theirs {
+                if their_pk != our_pk {
+                    keys.extend(self.precomputed(their_pk, our_pk));
+                }
+            }
+        }
+        keys
+    }
+
+    /// Dissect packet read from a capture file. TCP segments are reassembled
+    /// so that several dissections or none can be returned. Decrypted TCP
+    /// packets written by `PcapWriter::write_tap_packet` are dissected
+    /// without decryption.
+    pub fn dissect_captured(&mut self, packet: &CapturedPacket) -> Vec<Dissection> {
+        match packet.payload {
+            Payload::DecryptedTcp(ref data) =>
+                vec![self.dissect_tcp_packet(data)],
+            Payload::Udp(ref data) =>
+                vec![self.dissect_udp(packet.src, packet.dst, data)],
+            Payload::Tcp { seq, syn, ref data } =>
+                self.dissect_tcp_segment(packet.src, packet.dst, seq, syn, data),
+        }
+    }
+
+    /// Dissect packet captured by a codec tap.
+    pub fn dissect_tap_packet(&mut self, packet: &TapPacket) -> Dissection {
+        let mut dissection = match packet.transport {
+            Transport::Udp => {
+                let (src, dst) = match packet.direction {
+                    Direction::Incoming => (packet.remote, packet.local),
+                    Direction::Outgoing => (packet.local, packet.remote),
+                };
+                self.dissect_udp(src, dst, &packet.data)
+            },
+            Transport::Tcp => self.dissect_tcp_packet(&packet.data),
+        };
+        dissection.summary = format!("{} {}", packet.direction.name(), dissection.summary);
+        dissection
+    }
+
+    /// Dissect DHT packet sent over UDP.
+    pub fn dissect_udp(&mut self, src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Dissection {
+        let packet = match dht::Packet::from_bytes(data).to_full_result() {
+            Ok(packet) => packet,
+            Err(_) => return Dissection::new(format!("Unknown UDP packet of {} bytes: {}", data.len(), hex(data))),
+        };
+        let mut dissection = Dissection::new(format!("{} ({} bytes)", packet.name(), data.len()));
+        match packet {
+            dht::Packet::PingRequest(ref packet) => {
+                dissection.detail(format!("pk: {}", pk_hex(&packet.pk)));
+                let keys = self.candidate_keys(packet.pk);
+                self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok());
+            },
+            dht::Packet::PingResponse(ref packet) => {
+                dissection.detail(format!("pk: {}", pk_hex(&packet.pk)));
+                let keys = self.candidate_keys(packet.pk);
+                self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok());
+            },
+            dht::Packet::NodesRequest(ref packet) => {
+                dissection.detail(format!("pk: {}", pk_hex(&packet.pk)));
+                let keys = self.candidate_keys(packet.pk);
+                self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok());
+            },
+            dht::Packet::NodesResponse(ref packet) => {
+                dissection.detail(format!("pk: {}", pk_hex(&packet.pk)));
+                let keys = self.candidate_keys(packet.pk);
+                if let Some(payload) = self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok()) {
+                    self.known_pks.extend(payload.nodes.iter().map(|node| node.pk));
+                }
+            },
+            dht::Packet::CookieRequest(ref packet) => {
+                dissection.detail(format!("pk: {}", pk_hex(&packet.pk)));
+                let keys = self.candidate_keys(packet.pk);
+                if let Some(payload) = self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok()) {
+                    self.known_pks.insert(payload.pk);
+                }
+            },
+            dht::Packet::CookieResponse(ref packet) => {
+                let keys = self.all_keys();
+                self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok());
+            },
+            dht::Packet::CryptoHandshake(ref packet) => {
+                let keys = self.all_keys();
+                if let Some(payload) = self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok()) {
+                    self.crypto_sessions.insert((src, dst), CryptoSession {
+                        session_pk: payload.session_pk,
+                        nonce: payload.base_nonce,
+                    });
+                }
+            },
+            dht::Packet::CryptoData(ref packet) => {
+                dissection.detail(format!("nonce last bytes: {}", packet.nonce_last_bytes));
+                self.dissect_crypto_data(&mut dissection, src, dst, packet);
+            },
+            dht::Packet::DhtRequest(ref packet) => {
+                dissection.detail(format!("receiver pk: {}", pk_hex(&packet.rpk)));
+                dissection.detail(format!("sender pk: {}", pk_hex(&packet.spk)));
+                let keys = self.pair_key(packet.spk, packet.rpk).into_iter().collect();
+                if let Some(DhtRequestPayload::DhtPkAnnounce(announce)) =
+                    self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok()) {
+                    let keys = self.candidate_keys(announce.real_pk);
+                    self.decrypt_dht_pk_announce(&mut dissection, keys, &announce);
+                }
+            },
+            dht::Packet::LanDiscovery(ref packet) => {
+                self.known_pks.insert(packet.pk);
+                dissection.detail(format!("pk: {}", pk_hex(&packet.pk)));
+            },
+            dht::Packet::OnionRequest0(ref packet) => {
+                dissection.detail(format!("temporary pk: {}", pk_hex(&packet.temporary_pk)));
+                let keys = self.candidate_keys(packet.temporary_pk);
+                if let Some(payload) = self.decrypt(&mut dissection, keys, |key| packet.get_payload(key).ok()) {
+                    self.dissect_onion_layer_1(&mut dissection, packet.nonce, payload.temporary_pk, &payload.inner);
+                }
+            },
+            dht::Packet::OnionRequest1(ref packet) => {
+                dissection.detail(format!("temporary pk: {}", pk_hex(&packet.temporary_pk)));
+                dissection.detail(format!("onion return: {} bytes", packet.onion_return.payload.len()));
+                self.dissect_onion_layer_1(&mut dissection, packet.nonce, packet.temporary_pk, &packet.payload);
+            },
+            dht::Packet::OnionRequest2(ref packet) => {
+                dissection.detail(format!("temporary pk: {}", pk_hex(&packet.temporary_pk)));
+                dissection.detail(format!("onion return: {} bytes", packet.onion_return.payload.len()));
+                self.dissect_onion_layer_2(&mut dissection, packet.nonce, packet.temporary_pk, &packet.payload);
+            },
+            dht::Packet::OnionAnnounceRequest(ref packet) => {
+                self.dissect_inner_onion_request(&mut dissection, &InnerOnionRequest::InnerOnionAnnounceRequest(packet.inner.clone()));
+            },
+            dht::Packet::OnionDataRequest(ref packet) => {
+                self.dissect_inner_onion_request(&mut dissection, &InnerOnionRequest::InnerOnionDataRequest(packet.inner.clone()));
+            },
+            dht::Packet::OnionAnnounceResponse(ref packet) => {
+                self.dissect_inner_onion_response(&mut dissection, &InnerOnionResponse::OnionAnnounceResponse(packet.clone()));
+            },
+            dht::Packet::OnionDataResponse(ref packet) => {
+                self.dissect_inner_onion_response(&mut dissection, &InnerOnionResponse::OnionDataResponse(packet.clone()));
+            },
+            dht::Packet::OnionResponse3(ref packet) => {
+                self.dissect_inner_onion_response(&mut dissection, &packet.payload);
+            },
+            dht::Packet::OnionResponse2(ref packet) => {
+                self.dissect_inner_onion_response(&mut dissection, &packet.payload);
+            },
+            dht::Packet::OnionResponse1(ref packet) => {
+                self.dissect_inner_onion_response(&mut dissection, &packet.payload);
+            },
+            dht::Packet::BootstrapInfo(ref packet) => {
+                dissection.detail(format!("version: {}", packet.version));
+                dissection.detail(format!("motd: {}", String::from_utf8_lossy(&packet.motd)));
+            },
+        }
+        dissection
+    }
+
+    /// Try to decrypt payload with every key adding the result to the
+    /// dissection.
+    fn decrypt<T, F>(&self, dissection: &mut Dissection, keys: Vec<PrecomputedKey>, get_payload: F) -> Option<T>
+        where T: fmt::Debug, F: Fn(&PrecomputedKey) -> Option<T>
+    {
+        match keys.iter().filter_map(|key| get_payload(key)).next() {
+            Some(payload) => {
+                dissection.detail(format!("payload: {:?}", payload));
+                Some(payload)
+            },
+            None => {
+                dissection.not_decrypted("payload");
+                None
+            },
+        }
+    }
+
+    /// Decrypt payload of `DhtPkAnnounce` adding it to the dissection.
+    fn decrypt_dht_pk_announce(&mut self, dissection: &mut Dissection, keys: Vec<PrecomputedKey>, announce: &dht::DhtPkAnnounce) {
+        match keys.iter().filter_map(|key| announce.get_payload(key).ok()).next() {
+            Some(payload) => {
+                self.known_pks.insert(payload.dht_pk);
+                dissection.detail(format!("dht pk announce payload: {:?}", payload));
+            },
+            None => dissection.not_decrypted("dht pk announce payload"),
+        }
+    }
+
+    /// Decrypt `CryptoData` using session learned from `CryptoHandshake`.
+    fn dissect_crypto_data(&mut self, dissection: &mut Dissection, src: SocketAddr, dst: SocketAddr, packet: &dht::CryptoData) {
+        let (our_session, their_session) = match (self.crypto_sessions.get(&(src, dst)), self.crypto_sessions.get(&(dst, src))) {
+            (Some(our_session), Some(their_session)) => (our_session.clone(), their_session.clone()),
+            _ => return dissection.not_decrypted("payload"),
+        };
+        let key = match self.pair_key(our_session.session_pk, their_session.session_pk) {
+            Some(key) => key,
+            None => return dissection.not_decrypted("payload"),
+        };
+
+        let cur_last_bytes = dht::CryptoData::nonce_last_bytes(our_session.nonce);
+        let (diff, _) = packet.nonce_last_bytes.overflowing_sub(cur_last_bytes);
+        let mut nonce = our_session.nonce;
+        increment_nonce_number(&mut nonce, u64::from(diff));
+
+        match packet.get_payload(&key, &nonce) {
+            Ok(payload) => {
+                if let Some(session) = self.crypto_sessions.get_mut(&(src, dst)) {
+                    session.nonce = nonce;
+                }
+                dissection.detail(format!("payload: {:?}", payload));
+            },
+            Err(_) => dissection.not_decrypted("payload"),
+        }
+    }
+
+    /// Decrypt the second onion layer that is sent to the second node of the
+    /// path.
+    fn dissect_onion_layer_1(&mut self, dissection: &mut Dissection, nonce: Nonce, temporary_pk: PublicKey, payload: &[u8]) {
+        let packet = OnionRequest1 {
+            nonce,
+            temporary_pk,
+            payload: payload.to_vec(),
+            onion_return: OnionReturn { nonce: secretbox::gen_nonce(), payload: Vec::new() },
+        };
+        let keys = self.candidate_keys(temporary_pk);
+        match keys.iter().filter_map(|key| packet.get_payload(key).ok()).next() {
+            Some(payload) => {
+                dissection.detail(format!("layer 1: to {:?}, temporary pk: {}", payload.ip_port, pk_hex(&payload.temporary_pk)));
+                self.dissect_onion_layer_2(dissection, nonce, payload.temporary_pk, &payload.inner);
+            },
+            None => dissection.not_decrypted("layer 1"),
+        }
+    }
+
+    /// Decrypt the third onion layer that is sent to the third node of the
+    /// path.
+    fn dissect_onion_layer_2(&mut self, dissection: &mut Dissection, nonce: Nonce, temporary_pk: PublicKey, payload: &[u8]) {
+        let packet = OnionRequest2 {
+            nonce,
+            temporary_pk,
+            payload: payload.to_vec(),
+            onion_return: OnionReturn { nonce: secretbox::gen_nonce(), payload: Vec::new() },
+        };
+        let keys = self.candidate_keys(temporary_pk);
+        match keys.iter().filter_map(|key| packet.get_payload(key).ok()).next() {
+            Some(payload) => {
+                dissection.detail(format!("layer 2: to {:?}", payload.ip_port));
+                self.dissect_inner_onion_request(dissection, &payload.inner);
+            },
+            None => dissection.not_decrypted("layer 2"),
+        }
+    }
+
+    /// Decrypt request that is delivered to the announce node.
+    fn dissect_inner_onion_request(&mut self, dissection: &mut Dissection, inner: &InnerOnionRequest) {
+        match *inner {
+            InnerOnionRequest::InnerOnionAnnounceRequest(ref request) => {
+                dissection.detail(format!("announce request from {}", pk_hex(&request.pk)));
+                let keys = self.candidate_keys(request.pk);
+                match keys.iter().filter_map(|key| request.get_payload(key).ok()).next() {
+                    Some(payload) => {
+                        self.known_pks.insert(payload.search_pk);
+                        dissection.detail(format!("announce payload: {:?}", payload));
+                    },
+                    None => dissection.not_decrypted("announce payload"),
+                }
+            },
+            InnerOnionRequest::InnerOnionDataRequest(ref request) => {
+                dissection.detail(format!("data request to {}, temporary pk: {}",
+                    pk_hex(&request.destination_pk), pk_hex(&request.temporary_pk)));
+                let key = self.pair_key(request.temporary_pk, request.destination_pk);
+                match key.and_then(|key| request.get_payload(&key).ok()) {
+                    Some(payload) => self.dissect_onion_data(dissection, request.nonce, request.destination_pk, &payload),
+                    None => dissection.not_decrypted("data payload"),
+                }
+            },
+        }
+    }
+
+    /// Decrypt response that is delivered to the client through the onion
+    /// path.
+    fn dissect_inner_onion_response(&mut self, dissection: &mut Dissection, inner: &InnerOnionResponse) {
+        match *inner {
+            InnerOnionResponse::OnionAnnounceResponse(ref response) => {
+                dissection.detail(format!("announce response, sendback data: {}", response.sendback_data));
+                let keys = self.all_keys();
+                match keys.iter().filter_map(|key| response.get_payload(key).ok()).next() {
+                    Some(payload) => {
+                        self.known_pks.extend(payload.nodes.iter().map(|node| node.pk));
+                        dissection.detail(format!("announce payload: {:?}", payload));
+                    },
+                    None => dissection.not_decrypted("announce payload"),
+                }
+            },
+            InnerOnionResponse::OnionDataResponse(ref response) => {
+                dissection.detail(format!("data response, temporary pk: {}", pk_hex(&response.temporary_pk)));
+                let keys = self.candidate_keys(response.temporary_pk);
+                match keys.iter().filter_map(|key| response.get_payload(key).ok()).next() {
+                    Some(payload) => {
+                        let keys = self.candidate_keys(payload.real_pk);
+                        match keys.iter().filter_map(|key| payload.get_payload(&response.nonce, key).ok()).next() {
+                            Some(inner) => dissection.detail(format!("data from {}: {:?}", pk_hex(&payload.real_pk), inner)),
+                            None => dissection.not_decrypted("data"),
+                        }
+                    },
+                    None => dissection.not_decrypted("data payload"),
+                }
+            },
+        }
+    }
+
+    /// Decrypt data sent through the onion to the owner of `destination_pk`.
+    fn dissect_onion_data(&mut self, dissection: &mut Dissection, nonce: Nonce, destination_pk: PublicKey, payload: &OnionDataResponsePayload) {
+        let key = self.pair_key(payload.real_pk, destination_pk);
+        match key.and_then(|key| payload.get_payload(&nonce, &key).ok()) {
+            Some(inner) => dissection.detail(format!("data from {}: {:?}", pk_hex(&payload.real_pk), inner)),
+            None => dissection.not_decrypted("data"),
+        }
+    }
+
+    /// Dissect decrypted TCP packet.
+    pub fn dissect_tcp_packet(&mut self, data: &[u8]) -> Dissection {
+        match tcp::Packet::from_bytes(data).to_full_result() {
+            Ok(packet) => {
+                let mut dissection = Dissection::new(format!("TCP {} ({} bytes)", packet.name(), data.len()));
+                match packet {
+                    tcp::Packet::RouteRequest(ref packet) => self.known_pks.insert(packet.pk),
+                    tcp::Packet::RouteResponse(ref packet) => self.known_pks.insert(packet.pk),
+                    _ => false,
+                };
+                dissection.detail(format!("{:?}", packet));
+                dissection
+            },
+            Err(_) => Dissection::new(format!("Unknown TCP packet of {} bytes: {}", data.len(), hex(data))),
+        }
+    }
+
+    /// Dissect TCP segment from a capture file. Segments are reassembled into
+    /// stream, then handshakes and encrypted frames are parsed from it.
+    /// Segments that arrive out of order are ignored.
+    pub fn dissect_tcp_segment(&mut self, src: SocketAddr, dst: SocketAddr, seq: u32, syn: bool, data: &[u8]) -> Vec<Dissection> {
+        // the side that sends the first segment is considered as the client
+        let (key, from_client) = if self.tcp_connections.contains_key(&(dst, src)) {
+            ((dst, src), false)
+        } else {
+            ((src, dst), true)
+        };
+        let mut connection = self.tcp_connections.remove(&key).unwrap_or_default();
+
+        {
+            let half = if from_client { &mut connection.client } else { &mut connection.server };
+            let mut next_seq = match half.next_seq {
+                Some(next_seq) if !syn => next_seq,
+                _ => seq.wrapping_add(syn as u32),
+            };
+            let data_seq = seq.wrapping_add(syn as u32);
+            // skip the part that was already received
+            let offset = next_seq.wrapping_sub(data_seq);
+            if (offset as usize) <= data.len() {
+                half.buffer.extend_from_slice(&data[offset as usize ..]);
+                next_seq = data_seq.wrapping_add(data.len() as u32);
+            }
+            half.next_seq = Some(next_seq);
+        }
+
+        let mut dissections = Vec::new();
+        loop {
+            let dissection = if from_client {
+                self.parse_tcp_client_data(&mut connection)
+            } else {
+                self.parse_tcp_server_data(&mut connection)
+            };
+            match dissection {
+                Some(dissection) => dissections.push(dissection),
+                None => break,
+            }
+        }
+        self.tcp_connections.insert(key, connection);
+        dissections
+    }
+
+    /// Parse the next handshake or frame sent by the client.
+    fn parse_tcp_client_data(&mut self, connection: &mut TcpConnection) -> Option<Dissection> {
+        if connection.client.handshake_done {
+            return self.parse_tcp_frame(connection, true);
+        }
+        if connection.client.buffer.len() < CLIENT_HANDSHAKE_SIZE {
+            return None;
+        }
+        let data = connection.client.buffer.drain(.. CLIENT_HANDSHAKE_SIZE).collect::<Vec<_>>();
+        connection.client.handshake_done = true;
+        let handshake = match ClientHandshake::from_bytes(&data).to_full_result() {
+            Ok(handshake) => handshake,
+            Err(_) => return Some(Dissection::new(format!("Invalid TCP client handshake: {}", hex(&data)))),
+        };
+        let mut dissection = Dissection::new(format!("TCP ClientHandshake ({} bytes)", data.len()));
+        dissection.detail(format!("pk: {}", pk_hex(&handshake.pk)));
+        let keys = self.candidate_keys(handshake.pk);
+        let decrypted = keys.into_iter().filter_map(|key| {
+            let payload = decrypt_data_symmetric(&key, &handshake.nonce, &handshake.payload).ok()?;
+            let payload = HandshakePayload::from_bytes(&payload).to_full_result().ok()?;
+            Some((key, payload))
+        }).next();
+        match decrypted {
+            Some((key, payload)) => {
+                dissection.detail(format!("payload: {:?}", payload));
+                connection.handshake_key = Some(key);
+                connection.client.session = Some((payload.session_pk, payload.session_nonce));
+            },
+            None => dissection.not_decrypted("payload"),
+        }
+        Some(dissection)
+    }
+
+    /// Parse the next handshake or frame sent by the server.
+    fn parse_tcp_server_data(&mut self, connection: &mut TcpConnection) -> Option<Dissection> {
+        if connection.server.handshake_done {
+            return self.parse_tcp_frame(connection, false);
+        }
+        if connection.server.buffer.len() < SERVER_HANDSHAKE_SIZE {
+            return None;
+        }
+        let data = connection.server.buffer.drain(.. SERVER_HANDSHAKE_SIZE).collect::<Vec<_>>();
+        connection.server.handshake_done = true;
+        let handshake = match ServerHandshake::from_bytes(&data).to_full_result() {
+            Ok(handshake) => handshake,
+            Err(_) => return Some(Dissection::new(format!("Invalid TCP server handshake: {}", hex(&data)))),
+        };
+        let mut dissection = Dissection::new(format!("TCP ServerHandshake ({} bytes)", data.len()));
+        let payload = connection.handshake_key.as_ref()
+            .and_then(|key| decrypt_data_symmetric(key, &handshake.nonce, &handshake.payload).ok())
+            .and_then(|payload| HandshakePayload::from_bytes(&payload).to_full_result().ok());
+        match payload {
+            Some(payload) => {
+                dissection.detail(format!("payload: {:?}", payload));
+                connection.server.session = Some((payload.session_pk, payload.session_nonce));
+                if let Some((client_session_pk, _)) = connection.client.session {
+                    connection.channel_key = self.pair_key(client_session_pk, payload.session_pk);
+                }
+            },
+            None => dissection.not_decrypted("payload"),
+        }
+        Some(dissection)
+    }
+
+    /// Parse the next encrypted frame sent by the client or the server.
+    fn parse_tcp_frame(&mut self, connection: &mut TcpConnection, from_client: bool) -> Option<Dissection> {
+        let half = if from_client { &mut connection.client } else { &mut connection.server };
+        let (consumed, encrypted) = match tcp::EncryptedPacket::from_bytes(&half.buffer) {
+            IResult::Done(rest, encrypted) => (half.buffer.len() - rest.len(), encrypted),
+            IResult::Incomplete(_) => return None,
+            IResult::Error(_) => {
+                let data = half.buffer.split_off(0);
+                return Some(Dissection::new(format!("Invalid TCP frame: {}", hex(&data))));
+            },
+        };
+        half.buffer.drain(.. consumed);
+
+        let decrypted = match (connection.channel_key.as_ref(), half.session.as_mut()) {
+            (Some(key), Some((_, nonce))) => {
+                let decrypted = decrypt_data_symmetric(key, nonce, &encrypted.payload).ok();
+                increment_nonce(nonce);
+                decrypted
+            },
+            _ => None,
+        };
+        match decrypted {
+            Some(data) => Some(self.dissect_tcp_packet(&data)),
+            None => {
+                let mut dissection = Dissection::new(format!("TCP encrypted frame ({} bytes)", consumed));
+                dissection.not_decrypted("packet");
+                Some(dissection)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{Future, Stream};
+
+    use crate::toxcore::dht::packet::*;
+    use crate::toxcore::ip_port::IpPort;
+    use crate::toxcore::tcp::handshake::*;
+
+    fn encode(packet: &Packet) -> Vec<u8> {
+        let mut buf = [0; 2048];
+        let (_, size) = packet.to_bytes((&mut buf, 0)).unwrap();
+        buf[.. size].to_vec()
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn dissect_ping_request() {
+        crypto_init().unwrap();
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let precomputed = precompute(&bob_pk, &alice_sk);
+        let packet = Packet::PingRequest(PingRequest::new(&precomputed, &alice_pk, &PingRequestPayload { id: 42 }));
+        let data = encode(&packet);
+
+        let mut dissector = Dissector::new();
+        let dissection = dissector.dissect_udp(addr(1), addr(2), &data);
+        assert!(dissection.summary.starts_with("PingRequest"));
+        assert!(dissection.details.contains(&"payload: <not decrypted>".to_owned()));
+
+        dissector.add_secret_key(bob_sk);
+        let dissection = dissector.dissect_udp(addr(1), addr(2), &data);
+        assert!(dissection.details.contains(&"payload: PingRequestPayload { id: 42 }".to_owned()));
+    }
+
+    #[test]
+    fn dissect_unknown() {
+        let mut dissector = Dissector::new();
+        let dissection = dissector.dissect_udp(addr(1), addr(2), &[255, 1, 2]);
+        assert_eq!(dissection.summary, "Unknown UDP packet of 3 bytes: FF0102");
+        assert_eq!(format!("{}", dissection), "Unknown UDP packet of 3 bytes: FF0102");
+    }
+
+    #[test]
+    fn dissect_bootstrap_info() {
+        let packet = Packet::BootstrapInfo(BootstrapInfo { version: 42, motd: b"hello".to_vec() });
+        let mut dissector = Dissector::new();
+        let dissection = dissector.dissect_udp(addr(1), addr(2), &encode(&packet));
+        assert_eq!(dissection.details, vec!["version: 42".to_owned(), "motd: hello".to_owned()]);
+    }
+
+    #[test]
+    fn dissect_onion_layers() {
+        crypto_init().unwrap();
+        let (node_1_pk, node_1_sk) = gen_keypair();
+        let (node_2_pk, node_2_sk) = gen_keypair();
+        let (node_3_pk, node_3_sk) = gen_keypair();
+        let (client_pk, client_sk) = gen_keypair();
+        let (announce_node_pk, announce_node_sk) = gen_keypair();
+        let search_pk = gen_keypair().0;
+        let announce_payload = OnionAnnounceRequestPayload {
+            ping_id: sha256::hash(&[1, 2, 3]),
+            search_pk,
+            data_pk: gen_keypair().0,
+            sendback_data: 42,
+        };
+        let inner = InnerOnionRequest::InnerOnionAnnounceRequest(InnerOnionAnnounceRequest::new(
+            &precompute(&announce_node_pk, &client_sk), &client_pk, &announce_payload
+        ));
+        // wrap the request into 3 layers the same way as onion client does
+        let nonce = gen_nonce();
+        let (temporary_1_pk, temporary_1_sk) = gen_keypair();
+        let (temporary_2_pk, temporary_2_sk) = gen_keypair();
+        let (temporary_3_pk, temporary_3_sk) = gen_keypair();
+        let mut buf = [0; 2048];
+        let (_, size) = OnionRequest2Payload { ip_port: IpPort::from_udp_saddr(addr(4)), inner }
+            .to_bytes((&mut buf, 0)).unwrap();
+        let encrypted = seal_precomputed(&buf[.. size], &nonce, &precompute(&node_3_pk, &temporary_3_sk));
+        let (_, size) = OnionRequest1Payload { ip_port: IpPort::from_udp_saddr(addr(3)), temporary_pk: temporary_3_pk, inner: encrypted }
+            .to_bytes((&mut buf, 0)).unwrap();
+        let encrypted = seal_precomputed(&buf[.. size], &nonce, &precompute(&node_2_pk, &temporary_2_sk));
+        let (_, size) = OnionRequest0Payload { ip_port: IpPort::from_udp_saddr(addr(2)), temporary_pk: temporary_2_pk, inner: encrypted }
+            .to_bytes((&mut buf, 0)).unwrap();
+        let encrypted = seal_precomputed(&buf[.. size], &nonce, &precompute(&node_1_pk, &temporary_1_sk));
+        let packet = Packet::OnionRequest0(OnionRequest0 { nonce, temporary_pk: temporary_1_pk, payload: encrypted });
+        let data = encode(&packet);
+
+        let mut dissector = Dissector::new();
+        dissector.add_secret_key(node_1_sk);
+        let dissection = dissector.dissect_udp(addr(0), addr(1), &data);
+        assert!(dissection.summary.starts_with("OnionRequest0"));
+        assert!(dissection.details[1].starts_with("payload: OnionRequest0Payload"));
+        assert_eq!(dissection.details[2], "layer 1: <not decrypted>");
+
+        dissector.add_secret_key(node_2_sk);
+        dissector.add_secret_key(node_3_sk);
+        dissector.add_secret_key(announce_node_sk);
+        let dissection = dissector.dissect_udp(addr(0), addr(1), &data);
+        assert!(dissection.details[2].starts_with("layer 1: to"));
+        assert!(dissection.details[3].starts_with("layer 2: to"));
+        assert_eq!(dissection.details[4], format!("announce request from {}", pk_hex(&client_pk)));
+        assert_eq!(dissection.details[5], format!("announce payload: {:?}", announce_payload));
+        assert!(dissector.known_pks.contains(&search_pk));
+    }
+
+    #[test]
+    fn dissect_dht_request_with_dht_pk_announce() {
+        crypto_init().unwrap();
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let (alice_real_pk, alice_real_sk) = gen_keypair();
+        let (bob_real_pk, bob_real_sk) = gen_keypair();
+        let announce_payload = DhtPkAnnouncePayload::new(alice_pk, Vec::new());
+        let announce = DhtPkAnnounce::new(&precompute(&bob_real_pk, &alice_real_sk), alice_real_pk, &announce_payload);
+        let packet = Packet::DhtRequest(DhtRequest::new(
+            &precompute(&bob_pk, &alice_sk),
+            &bob_pk,
+            &alice_pk,
+            &DhtRequestPayload::DhtPkAnnounce(announce),
+        ));
+
+        let mut dissector = Dissector::new();
+        dissector.add_secret_key(bob_sk);
+        dissector.add_secret_key(bob_real_sk);
+        let dissection = dissector.dissect_udp(addr(1), addr(2), &encode(&packet));
+        assert!(dissection.details.iter().any(|detail| detail.starts_with("payload: DhtPkAnnounce")));
+        assert!(dissection.details.iter().any(|detail| detail.starts_with("dht pk announce payload: DhtPkAnnouncePayload")));
+    }
+
+    #[test]
+    fn dissect_crypto_data() {
+        crypto_init().unwrap();
+        let (alice_real_pk, alice_real_sk) = gen_keypair();
+        let (bob_real_pk, bob_real_sk) = gen_keypair();
+        let (alice_session_pk, alice_session_sk) = gen_keypair();
+        let (bob_session_pk, _bob_session_sk) = gen_keypair();
+        let alice_nonce = gen_nonce();
+        let bob_nonce = gen_nonce();
+        let real_key = precompute(&bob_real_pk, &alice_real_sk);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; 88],
+        };
+        let alice_handshake = CryptoHandshake::new(&real_key, &CryptoHandshakePayload {
+            base_nonce: alice_nonce,
+            session_pk: alice_session_pk,
+            cookie_hash: cookie.hash(),
+            cookie: cookie.clone(),
+        }, cookie.clone());
+        let bob_handshake = CryptoHandshake::new(&real_key, &CryptoHandshakePayload {
+            base_nonce: bob_nonce,
+            session_pk: bob_session_pk,
+            cookie_hash: cookie.hash(),
+            cookie: cookie.clone(),
+        }, cookie);
+        let session_key = precompute(&bob_session_pk, &alice_session_sk);
+        let mut nonce = alice_nonce;
+        increment_nonce_number(&mut nonce, 3);
+        let data_payload = CryptoDataPayload {
+            buffer_start: 1,
+            packet_number: 2,
+            data: vec![64, 42],
+        };
+        let data = CryptoData::new(&session_key, nonce, &data_payload);
+
+        let mut dissector = Dissector::new();
+        dissector.add_public_key(alice_real_pk);
+        dissector.add_secret_key(bob_real_sk);
+        dissector.add_secret_key(alice_session_sk);
+        dissector.dissect_udp(addr(1), addr(2), &encode(&Packet::CryptoHandshake(alice_handshake)));
+        dissector.dissect_udp(addr(2), addr(1), &encode(&Packet::CryptoHandshake(bob_handshake)));
+        let dissection = dissector.dissect_udp(addr(1), addr(2), &encode(&Packet::CryptoData(data)));
+        assert_eq!(dissection.details.last().unwrap(), &format!("payload: {:?}", data_payload));
+    }
+
+    #[test]
+    fn dissect_tcp_stream() {
+        crypto_init().unwrap();
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let (client_session, common_key, client_handshake) =
+            create_client_handshake(&client_pk, &client_sk, &server_pk).unwrap();
+        let (_server_channel, _, server_handshake) =
+            handle_client_handshake(&server_sk, &client_handshake).unwrap();
+        let client_channel = handle_server_handshake(&common_key, &client_session, &server_handshake).unwrap();
+
+        let mut client_data = vec![0; CLIENT_HANDSHAKE_SIZE];
+        client_handshake.to_bytes((&mut client_data, 0)).unwrap();
+        let mut server_data = vec![0; SERVER_HANDSHAKE_SIZE];
+        server_handshake.to_bytes((&mut server_data, 0)).unwrap();
+
+        let packet = tcp::Packet::PingRequest(tcp::PingRequest { ping_id: 42 });
+        let mut packet_buf = [0; 64];
+        let (_, size) = packet.to_bytes((&mut packet_buf, 0)).unwrap();
+        let encrypted = tcp::EncryptedPacket { payload: client_channel.encrypt(&packet_buf[.. size]) };
+        let mut frame = vec![0; 2 + encrypted.payload.len()];
+        encrypted.to_bytes((&mut frame, 0)).unwrap();
+
+        let mut dissector = Dissector::new();
+        dissector.add_secret_key(server_sk);
+        // session secret key of the client is not known
+        let client_addr = addr(1);
+        let server_addr = addr(2);
+        assert!(dissector.dissect_tcp_segment(client_addr, server_addr, 100, true, &[]).is_empty());
+        // handshake split in two segments
+        assert!(dissector.dissect_tcp_segment(client_addr, server_addr, 101, false, &client_data[.. 50]).is_empty());
+        let dissections = dissector.dissect_tcp_segment(client_addr, server_addr, 151, false, &client_data[50 ..]);
+        assert_eq!(dissections.len(), 1);
+        assert!(dissections[0].summary.starts_with("TCP ClientHandshake"));
+        assert!(dissections[0].details[1].starts_with("payload: HandshakePayload"));
+
+        let dissections = dissector.dissect_tcp_segment(server_addr, client_addr, 500, false, &server_data);
+        assert_eq!(dissections.len(), 1);
+        assert!(dissections[0].details[0].starts_with("payload: HandshakePayload"));
+
+        // retransmitted segment is ignored
+        let seq = 101 + CLIENT_HANDSHAKE_SIZE as u32;
+        let dissections = dissector.dissect_tcp_segment(client_addr, server_addr, seq, false, &frame);
+        assert_eq!(dissections.len(), 1);
+        assert!(dissections[0].summary.starts_with("TCP encrypted frame"));
+        assert!(dissector.dissect_tcp_segment(client_addr, server_addr, seq, false, &frame).is_empty());
+    }
+
+    #[test]
+    fn dissect_tap_packet() {
+        let packet = tcp::Packet::PongResponse(tcp::PongResponse { ping_id: 42 });
+        let mut packet_buf = [0; 64];
+        let (_, size) = packet.to_bytes((&mut packet_buf, 0)).unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        let local = "127.0.0.1:33445".parse().unwrap();
+        let remote = "1.2.3.4:12345".parse().unwrap();
+        tap(Some(&tx), Transport::Tcp, Direction::Incoming, local, remote, &packet_buf[.. size]);
+
+        let (tap_packet, _) = rx.into_future().wait().unwrap();
+        let tap_packet = tap_packet.unwrap();
+        let mut dissector = Dissector::new();
+        let dissection = dissector.dissect_tap_packet(&tap_packet);
+        assert_eq!(dissection.summary, "incoming TCP PongResponse (9 bytes)");
+        assert_eq!(dissection.details, vec!["PongResponse(PongResponse { ping_id: 42 })".to_owned()]);
+    }
+
+    #[test]
+    fn dissect_captured_decrypted_tcp() {
+        let packet = tcp::Packet::PongResponse(tcp::PongResponse { ping_id: 42 });
+        let mut packet_buf = [0; 64];
+        let (_, size) = packet.to_bytes((&mut packet_buf, 0)).unwrap();
+        let captured = CapturedPacket {
+            time: Default::default(),
+            src: "1.2.3.4:12345".parse().unwrap(),
+            dst: "127.0.0.1:33445".parse().unwrap(),
+            payload: Payload::DecryptedTcp(packet_buf[.. size].to_vec()),
+        };
+
+        let mut dissector = Dissector::new();
+        let dissections = dissector.dissect_captured(&captured);
+        assert_eq!(dissections.len(), 1);
+        assert_eq!(dissections[0].summary, "TCP PongResponse (9 bytes)");
+    }
+}
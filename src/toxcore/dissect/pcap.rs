@@ -0,0 +1,663 @@
+/*! Reading and writing of capture files.
+
+The reader understands both classic pcap and pcapng files with Ethernet, raw
+IP, Linux cooked and loopback link layers and extracts UDP datagrams and TCP
+segments from them. The writer produces classic pcap files with raw IP link
+layer that can be opened in Wireshark.
+*/
+
+use std::io::{Error as IoError, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use failure::Fail;
+use futures::{Future, Stream};
+use futures::sync::mpsc;
+
+use crate::toxcore::dissect::*;
+use crate::toxcore::stats::{Direction, Transport};
+
+/// Magic number of classic pcap file with microsecond timestamps.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// Magic number of classic pcap file with nanosecond timestamps.
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// Type of pcapng section header block.
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+/// Type of pcapng interface description block.
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+/// Type of pcapng simple packet block.
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+/// Type of pcapng enhanced packet block.
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+/// Magic number that defines byte order of pcapng section.
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// Code of pcapng option that defines timestamps resolution.
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+/// BSD loopback encapsulation.
+const LINKTYPE_NULL: u32 = 0;
+/// Ethernet link layer.
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Raw IP packets without link layer.
+const LINKTYPE_RAW: u32 = 101;
+/// Linux cooked capture.
+const LINKTYPE_LINUX_SLL: u32 = 113;
+/// Raw IPv4 packets.
+const LINKTYPE_IPV4: u32 = 228;
+/// Raw IPv6 packets.
+const LINKTYPE_IPV6: u32 = 229;
+/// Linux cooked capture v2.
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// Maximum size of captured packets that the writer declares.
+const PCAP_SNAPLEN: u32 = 65535;
+
+/// UDP protocol number.
+const IP_PROTO_UDP: u8 = 17;
+/// TCP protocol number.
+const IP_PROTO_TCP: u8 = 6;
+/// Protocol number of tapped decrypted TCP packets in pcap files. It's one of
+/// the numbers reserved for experimentation by RFC 3692. Decrypted TCP
+/// packets are written with UDP header so that every packet stays a separate
+/// record with addresses of the connection.
+pub const IP_PROTO_TAP_TCP: u8 = 253;
+
+error_kind! {
+    #[doc = "Error that can happen when reading capture file."]
+    #[derive(Debug)]
+    PcapError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    PcapErrorKind {
+        #[doc = "File doesn't start with pcap or pcapng magic number."]
+        #[fail(display = "Unknown capture file format")]
+        UnknownFormat,
+        #[doc = "File ends in the middle of a header or a record."]
+        #[fail(display = "Unexpected end of capture file")]
+        Truncated,
+        #[doc = "Packet block refers to interface that wasn't described."]
+        #[fail(display = "Unknown interface id: {}", id)]
+        UnknownInterface {
+            #[doc = "Interface id."]
+            id: u32,
+        },
+    }
+}
+
+/// Transport layer payload of a captured packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Payload {
+    /// UDP datagram.
+    Udp(Vec<u8>),
+    /// Decrypted TCP packet written by `PcapWriter::write_tap_packet`.
+    DecryptedTcp(Vec<u8>),
+    /// TCP segment.
+    Tcp {
+        /// Sequence number of the first byte of data.
+        seq: u32,
+        /// Whether SYN flag is set.
+        syn: bool,
+        /// Data of the segment.
+        data: Vec<u8>,
+    },
+}
+
+/// IP packet with UDP or TCP payload read from a capture file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CapturedPacket {
+    /// Time since Unix epoch when the packet was captured.
+    pub time: Duration,
+    /// Source address.
+    pub src: SocketAddr,
+    /// Destination address.
+    pub dst: SocketAddr,
+    /// UDP or TCP payload.
+    pub payload: Payload,
+}
+
+/// Cursor over capture file data.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    little_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PcapError> {
+        if self.data.len() - self.pos < len {
+            return Err(PcapErrorKind::Truncated.into());
+        }
+        let slice = &self.data[self.pos .. self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u16(&mut self) -> Result<u16, PcapError> {
+        let bytes = self.take(2)?;
+        Ok(if self.little_endian { LittleEndian::read_u16(bytes) } else { BigEndian::read_u16(bytes) })
+    }
+
+    fn u32(&mut self) -> Result<u32, PcapError> {
+        let bytes = self.take(4)?;
+        Ok(if self.little_endian { LittleEndian::read_u32(bytes) } else { BigEndian::read_u32(bytes) })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+/// Read all UDP and TCP packets from pcap or pcapng file. Records that don't
+/// contain UDP or TCP over IP are skipped.
+pub fn read_capture(data: &[u8]) -> Result<Vec<CapturedPacket>, PcapError> {
+    if data.len() < 4 {
+        return Err(PcapErrorKind::Truncated.into());
+    }
+    match (LittleEndian::read_u32(data), BigEndian::read_u32(data)) {
+        (PCAPNG_SECTION_HEADER, _) => read_pcapng(data),
+        (PCAP_MAGIC, _) => read_pcap(data, true, false),
+        (PCAP_MAGIC_NANOS, _) => read_pcap(data, true, true),
+        (_, PCAP_MAGIC) => read_pcap(data, false, false),
+        (_, PCAP_MAGIC_NANOS) => read_pcap(data, false, true),
+        _ => Err(PcapErrorKind::UnknownFormat.into()),
+    }
+}
+
+/// Read classic pcap file.
+fn read_pcap(data: &[u8], little_endian: bool, nanos: bool) -> Result<Vec<CapturedPacket>, PcapError> {
+    let mut reader = Reader { data, pos: 0, little_endian };
+    reader.take(20)?;
+    let linktype = reader.u32()?;
+
+    let mut packets = Vec::new();
+    while !reader.is_empty() {
+        let secs = reader.u32()?;
+        let frac = reader.u32()?;
+        let captured_len = reader.u32()? as usize;
+        let _original_len = reader.u32()?;
+        let record = reader.take(captured_len)?;
+
+        let time = Duration::from_secs(u64::from(secs)) + if nanos {
+            Duration::from_nanos(u64::from(frac))
+        } else {
+            Duration::from_micros(u64::from(frac))
+        };
+        packets.extend(parse_link(linktype, record, time));
+    }
+    Ok(packets)
+}
+
+/// Interface described in pcapng file.
+struct Interface {
+    linktype: u32,
+    /// Timestamps resolution as stored in `if_tsresol` option.
+    tsresol: u8,
+}
+
+/// Convert pcapng timestamp to duration according to resolution.
+fn pcapng_time(timestamp: u64, tsresol: u8) -> Duration {
+    let units_per_sec = if tsresol & 0x80 == 0 {
+        10u128.pow(u32::from(tsresol))
+    } else {
+        1u128 << (tsresol & 0x7f)
+    };
+    let nanos = u128::from(timestamp) * 1_000_000_000 / units_per_sec;
+    Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+}
+
+/// Read pcapng file.
+fn read_pcapng(data: &[u8]) -> Result<Vec<CapturedPacket>, PcapError> {
+    let mut reader = Reader { data, pos: 0, little_endian: true };
+    let mut interfaces = Vec::new();
+    let mut packets = Vec::new();
+
+    while !reader.is_empty() {
+        let block_start = reader.pos;
+        let block_type = reader.u32()?;
+        if block_type == PCAPNG_SECTION_HEADER {
+            // byte order magic follows the block length
+            let magic = reader.data.get(block_start + 8 .. block_start + 12)
+                .ok_or_else(|| PcapError::from(PcapErrorKind::Truncated))?;
+            reader.little_endian = LittleEndian::read_u32(magic) == PCAPNG_BYTE_ORDER_MAGIC;
+            interfaces.clear();
+        }
+        let block_len = reader.u32()? as usize;
+        if block_len < 12 {
+            return Err(PcapErrorKind::Truncated.into());
+        }
+        let body = reader.take(block_len - 12)?;
+        reader.u32()?;
+
+        let mut body_reader = Reader { data: body, pos: 0, little_endian: reader.little_endian };
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let linktype = u32::from(body_reader.u16()?);
+                body_reader.take(6)?;
+                let mut tsresol = 6;
+                while body_reader.data.len() - body_reader.pos >= 4 {
+                    let code = body_reader.u16()?;
+                    let len = body_reader.u16()? as usize;
+                    let value = body_reader.take(len)?;
+                    body_reader.take((4 - len % 4) % 4)?;
+                    if code == PCAPNG_OPTION_TSRESOL && len == 1 {
+                        tsresol = value[0];
+                    }
+                }
+                interfaces.push(Interface { linktype, tsresol });
+            },
+            PCAPNG_ENHANCED_PACKET => {
+                let id = body_reader.u32()?;
+                let interface = interfaces.get(id as usize)
+                    .ok_or_else(|| PcapError::from(PcapErrorKind::UnknownInterface { id }))?;
+                let timestamp = u64::from(body_reader.u32()?) << 32 | u64::from(body_reader.u32()?);
+                let captured_len = body_reader.u32()? as usize;
+                let _original_len = body_reader.u32()?;
+                let record = body_reader.take(captured_len)?;
+                packets.extend(parse_link(interface.linktype, record, pcapng_time(timestamp, interface.tsresol)));
+            },
+            PCAPNG_SIMPLE_PACKET => {
+                let interface = interfaces.first()
+                    .ok_or_else(|| PcapError::from(PcapErrorKind::UnknownInterface { id: 0 }))?;
+                let original_len = body_reader.u32()? as usize;
+                let record = &body[4 .. 4 + original_len.min(body.len() - 4)];
+                packets.extend(parse_link(interface.linktype, record, Duration::from_secs(0)));
+            },
+            _ => {},
+        }
+    }
+    Ok(packets)
+}
+
+/// Strip link layer header and parse IP packet.
+fn parse_link(linktype: u32, data: &[u8], time: Duration) -> Option<CapturedPacket> {
+    let ip_packet = match linktype {
+        LINKTYPE_NULL => data.get(4 ..)?,
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = BigEndian::read_u16(data.get(12 .. 14)?);
+            let mut offset = 14;
+            // skip VLAN tag
+            if ethertype == 0x8100 {
+                ethertype = BigEndian::read_u16(data.get(16 .. 18)?);
+                offset = 18;
+            }
+            if ethertype != 0x0800 && ethertype != 0x86dd {
+                return None;
+            }
+            data.get(offset ..)?
+        },
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => data,
+        LINKTYPE_LINUX_SLL => data.get(16 ..)?,
+        LINKTYPE_LINUX_SLL2 => data.get(20 ..)?,
+        _ => return None,
+    };
+    parse_ip(ip_packet, time)
+}
+
+/// Parse IPv4 or IPv6 packet with UDP or TCP payload.
+fn parse_ip(data: &[u8], time: Duration) -> Option<CapturedPacket> {
+    let (src_ip, dst_ip, protocol, payload): (IpAddr, IpAddr, _, _) = match data.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(data[0] & 0x0f) * 4;
+            let total_len = usize::from(BigEndian::read_u16(data.get(2 .. 4)?));
+            let fragment = BigEndian::read_u16(data.get(6 .. 8)?);
+            // fragmented packets are not reassembled
+            if fragment & 0x3fff != 0 {
+                return None;
+            }
+            let mut src = [0; 4];
+            src.copy_from_slice(data.get(12 .. 16)?);
+            let mut dst = [0; 4];
+            dst.copy_from_slice(data.get(16 .. 20)?);
+            let payload = data.get(header_len .. total_len.min(data.len()))?;
+            (Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into(), data[9], payload)
+        },
+        6 => {
+            let payload_len = usize::from(BigEndian::read_u16(data.get(4 .. 6)?));
+            let mut src = [0; 16];
+            src.copy_from_slice(data.get(8 .. 24)?);
+            let mut dst = [0; 16];
+            dst.copy_from_slice(data.get(24 .. 40)?);
+            let payload = data.get(40 .. (40 + payload_len).min(data.len()))?;
+            (Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into(), data[6], payload)
+        },
+        _ => return None,
+    };
+
+    let src_port = BigEndian::read_u16(payload.get(0 .. 2)?);
+    let dst_port = BigEndian::read_u16(payload.get(2 .. 4)?);
+    let payload = match protocol {
+        IP_PROTO_UDP => {
+            let len = usize::from(BigEndian::read_u16(payload.get(4 .. 6)?));
+            Payload::Udp(payload.get(8 .. len.min(payload.len()))?.to_vec())
+        },
+        IP_PROTO_TAP_TCP => {
+            let len = usize::from(BigEndian::read_u16(payload.get(4 .. 6)?));
+            Payload::DecryptedTcp(payload.get(8 .. len.min(payload.len()))?.to_vec())
+        },
+        IP_PROTO_TCP => {
+            let seq = BigEndian::read_u32(payload.get(4 .. 8)?);
+            let header_len = usize::from(payload.get(12)? >> 4) * 4;
+            let syn = payload.get(13)? & 0x02 != 0;
+            Payload::Tcp { seq, syn, data: payload.get(header_len ..)?.to_vec() }
+        },
+        _ => return None,
+    };
+
+    Some(CapturedPacket {
+        time,
+        src: SocketAddr::new(src_ip, src_port),
+        dst: SocketAddr::new(dst_ip, dst_port),
+        payload,
+    })
+}
+
+/// Compute internet checksum of the data.
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in chunks {
+        for pair in chunk.chunks(2) {
+            let word = if pair.len() == 2 {
+                BigEndian::read_u16(pair)
+            } else {
+                u16::from(pair[0]) << 8
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Build IP packet with UDP datagram. `protocol` is put to IP header so that
+/// the datagram can be marked as tapped decrypted TCP packet.
+fn build_udp_packet(protocol: u8, src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + data.len();
+    let mut udp = vec![0; 8];
+    BigEndian::write_u16(&mut udp[0 .. 2], src.port());
+    BigEndian::write_u16(&mut udp[2 .. 4], dst.port());
+    BigEndian::write_u16(&mut udp[4 .. 6], udp_len as u16);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut packet = vec![0; 20];
+            packet[0] = 0x45;
+            BigEndian::write_u16(&mut packet[2 .. 4], (20 + udp_len) as u16);
+            // don't fragment
+            packet[6] = 0x40;
+            packet[8] = 64;
+            packet[9] = protocol;
+            packet[12 .. 16].copy_from_slice(&src_ip.octets());
+            packet[16 .. 20].copy_from_slice(&dst_ip.octets());
+            let header_checksum = checksum(&[&packet]);
+            BigEndian::write_u16(&mut packet[10 .. 12], header_checksum);
+            // UDP checksum is optional for IPv4
+            packet.extend_from_slice(&udp);
+            packet.extend_from_slice(data);
+            packet
+        },
+        (src_ip, dst_ip) => {
+            let src_ip = to_ipv6(src_ip);
+            let dst_ip = to_ipv6(dst_ip);
+            let mut packet = vec![0; 40];
+            packet[0] = 0x60;
+            BigEndian::write_u16(&mut packet[4 .. 6], udp_len as u16);
+            packet[6] = protocol;
+            packet[7] = 64;
+            packet[8 .. 24].copy_from_slice(&src_ip.octets());
+            packet[24 .. 40].copy_from_slice(&dst_ip.octets());
+            let mut pseudo_header = [0; 8];
+            BigEndian::write_u32(&mut pseudo_header[0 .. 4], udp_len as u32);
+            pseudo_header[7] = protocol;
+            let udp_checksum = match checksum(&[&packet[8 .. 40], &pseudo_header, &udp, data]) {
+                0 => 0xffff,
+                udp_checksum => udp_checksum,
+            };
+            BigEndian::write_u16(&mut udp[6 .. 8], udp_checksum);
+            packet.extend_from_slice(&udp);
+            packet.extend_from_slice(data);
+            packet
+        },
+    }
+}
+
+/// Convert IP address to IPv6 mapping IPv4 addresses.
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Writer of classic pcap files with raw IP link layer.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Create new `PcapWriter` writing pcap file header.
+    pub fn new(mut writer: W) -> Result<PcapWriter<W>, IoError> {
+        let mut header = [0; 24];
+        LittleEndian::write_u32(&mut header[0 .. 4], PCAP_MAGIC);
+        LittleEndian::write_u16(&mut header[4 .. 6], 2);
+        LittleEndian::write_u16(&mut header[6 .. 8], 4);
+        LittleEndian::write_u32(&mut header[16 .. 20], PCAP_SNAPLEN);
+        LittleEndian::write_u32(&mut header[20 .. 24], LINKTYPE_RAW);
+        writer.write_all(&header)?;
+        Ok(PcapWriter { writer })
+    }
+
+    /// Write UDP datagram.
+    pub fn write_udp(&mut self, time: SystemTime, src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Result<(), IoError> {
+        self.write_datagram(time, IP_PROTO_UDP, src, dst, data)
+    }
+
+    /// Write datagram with UDP header and the given IP protocol number.
+    fn write_datagram(&mut self, time: SystemTime, protocol: u8, src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Result<(), IoError> {
+        let packet = build_udp_packet(protocol, src, dst, data);
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut header = [0; 16];
+        LittleEndian::write_u32(&mut header[0 .. 4], time.as_secs() as u32);
+        LittleEndian::write_u32(&mut header[4 .. 8], time.subsec_micros());
+        LittleEndian::write_u32(&mut header[8 .. 12], packet.len() as u32);
+        LittleEndian::write_u32(&mut header[12 .. 16], packet.len() as u32);
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&packet)
+    }
+
+    /// Write packet captured by a tap between addresses of the connection.
+    /// DHT packets are written as UDP datagrams and decrypted TCP packets
+    /// are written with `IP_PROTO_TAP_TCP` protocol number.
+    pub fn write_tap_packet(&mut self, packet: &TapPacket) -> Result<(), IoError> {
+        let protocol = match packet.transport {
+            Transport::Udp => IP_PROTO_UDP,
+            Transport::Tcp => IP_PROTO_TAP_TCP,
+        };
+        let (src, dst) = match packet.direction {
+            Direction::Incoming => (packet.remote, packet.local),
+            Direction::Outgoing => (packet.local, packet.remote),
+        };
+        self.write_datagram(packet.time, protocol, src, dst, &packet.data)
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), IoError> {
+        self.writer.flush()
+    }
+
+    /// Get the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Write packets captured by codec taps to pcap file until all taps are
+/// dropped.
+pub fn write_tap<W: Write>(rx: mpsc::UnboundedReceiver<TapPacket>, mut writer: PcapWriter<W>) -> impl Future<Item = (), Error = IoError> {
+    rx.map_err(|()| unreachable!("rx can't fail"))
+        .for_each(move |packet| {
+            writer.write_tap_packet(&packet)?;
+            writer.flush()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tap_packet(transport: Transport, direction: Direction, local: SocketAddr, remote: SocketAddr, data: Vec<u8>) -> TapPacket {
+        TapPacket {
+            time: UNIX_EPOCH + Duration::new(1_500_000_000, 123_000),
+            transport,
+            direction,
+            local,
+            remote,
+            data,
+        }
+    }
+
+    #[test]
+    fn write_read_pcap() {
+        let local_udp: SocketAddr = "192.168.1.2:33445".parse().unwrap();
+        let remote_udp: SocketAddr = "1.2.3.4:33446".parse().unwrap();
+        let local_tcp: SocketAddr = "[2001:db8::1]:3389".parse().unwrap();
+        let remote_tcp: SocketAddr = "[2001:db8::2]:54321".parse().unwrap();
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_tap_packet(&tap_packet(Transport::Udp, Direction::Incoming, local_udp, remote_udp, vec![1, 2, 3])).unwrap();
+        writer.write_tap_packet(&tap_packet(Transport::Tcp, Direction::Outgoing, local_tcp, remote_tcp, vec![4, 5])).unwrap();
+        let src: SocketAddr = "[::1]:1".parse().unwrap();
+        let dst: SocketAddr = "1.2.3.4:2".parse().unwrap();
+        writer.write_udp(UNIX_EPOCH, src, dst, &[6]).unwrap();
+        let data = writer.into_inner();
+
+        let packets = read_capture(&data).unwrap();
+
+        assert_eq!(packets, vec![
+            CapturedPacket {
+                time: Duration::new(1_500_000_000, 123_000),
+                src: remote_udp,
+                dst: local_udp,
+                payload: Payload::Udp(vec![1, 2, 3]),
+            },
+            CapturedPacket {
+                time: Duration::new(1_500_000_000, 123_000),
+                src: local_tcp,
+                dst: remote_tcp,
+                payload: Payload::DecryptedTcp(vec![4, 5]),
+            },
+            CapturedPacket {
+                time: Duration::from_secs(0),
+                src,
+                dst: SocketAddr::new(IpAddr::V6(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped()), 2),
+                payload: Payload::Udp(vec![6]),
+            },
+        ]);
+    }
+
+    #[test]
+    fn ipv4_header_checksum() {
+        let packet = build_udp_packet(IP_PROTO_UDP, "1.2.3.4:1".parse().unwrap(), "5.6.7.8:2".parse().unwrap(), &[]);
+        assert_eq!(checksum(&[&packet[.. 20]]), 0);
+    }
+
+    #[test]
+    fn read_pcap_big_endian_ethernet() {
+        let ip_packet = build_udp_packet(IP_PROTO_UDP, "1.2.3.4:1".parse().unwrap(), "5.6.7.8:2".parse().unwrap(), &[42]);
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&ip_packet);
+
+        let mut data = vec![0; 24];
+        BigEndian::write_u32(&mut data[0 .. 4], PCAP_MAGIC_NANOS);
+        BigEndian::write_u32(&mut data[20 .. 24], LINKTYPE_ETHERNET);
+        let mut record = [0; 16];
+        BigEndian::write_u32(&mut record[0 .. 4], 1);
+        BigEndian::write_u32(&mut record[4 .. 8], 2);
+        BigEndian::write_u32(&mut record[8 .. 12], frame.len() as u32);
+        BigEndian::write_u32(&mut record[12 .. 16], frame.len() as u32);
+        data.extend_from_slice(&record);
+        data.extend_from_slice(&frame);
+
+        let packets = read_capture(&data).unwrap();
+        assert_eq!(packets, vec![CapturedPacket {
+            time: Duration::new(1, 2),
+            src: "1.2.3.4:1".parse().unwrap(),
+            dst: "5.6.7.8:2".parse().unwrap(),
+            payload: Payload::Udp(vec![42]),
+        }]);
+    }
+
+    #[test]
+    fn read_pcapng_tcp() {
+        let mut tcp = vec![0; 20];
+        BigEndian::write_u16(&mut tcp[0 .. 2], 1);
+        BigEndian::write_u16(&mut tcp[2 .. 4], 2);
+        BigEndian::write_u32(&mut tcp[4 .. 8], 100);
+        tcp[12] = 5 << 4;
+        tcp[13] = 0x02;
+        tcp.extend_from_slice(&[1, 2, 3]);
+        let mut ip_packet = vec![0; 20];
+        ip_packet[0] = 0x45;
+        BigEndian::write_u16(&mut ip_packet[2 .. 4], (20 + tcp.len()) as u16);
+        ip_packet[9] = IP_PROTO_TCP;
+        ip_packet[12 .. 16].copy_from_slice(&[1, 2, 3, 4]);
+        ip_packet[16 .. 20].copy_from_slice(&[5, 6, 7, 8]);
+        ip_packet.extend_from_slice(&tcp);
+        let captured_len = ip_packet.len() as u32;
+        while ip_packet.len() % 4 != 0 {
+            ip_packet.push(0);
+        }
+
+        let mut data = Vec::new();
+        // section header block
+        let mut block = [0; 28];
+        LittleEndian::write_u32(&mut block[0 .. 4], PCAPNG_SECTION_HEADER);
+        LittleEndian::write_u32(&mut block[4 .. 8], 28);
+        LittleEndian::write_u32(&mut block[8 .. 12], PCAPNG_BYTE_ORDER_MAGIC);
+        LittleEndian::write_u16(&mut block[12 .. 14], 1);
+        LittleEndian::write_u32(&mut block[24 .. 28], 28);
+        data.extend_from_slice(&block);
+        // interface description block with nanosecond resolution
+        let mut block = [0; 32];
+        LittleEndian::write_u32(&mut block[0 .. 4], PCAPNG_INTERFACE_DESCRIPTION);
+        LittleEndian::write_u32(&mut block[4 .. 8], 32);
+        LittleEndian::write_u16(&mut block[8 .. 10], LINKTYPE_RAW as u16);
+        LittleEndian::write_u16(&mut block[16 .. 18], PCAPNG_OPTION_TSRESOL);
+        LittleEndian::write_u16(&mut block[18 .. 20], 1);
+        block[20] = 9;
+        LittleEndian::write_u32(&mut block[28 .. 32], 32);
+        data.extend_from_slice(&block);
+        // enhanced packet block
+        let block_len = 32 + ip_packet.len();
+        let mut block = vec![0; 28];
+        LittleEndian::write_u32(&mut block[0 .. 4], PCAPNG_ENHANCED_PACKET);
+        LittleEndian::write_u32(&mut block[4 .. 8], block_len as u32);
+        LittleEndian::write_u32(&mut block[16 .. 20], 1_000_000_007);
+        LittleEndian::write_u32(&mut block[20 .. 24], captured_len);
+        LittleEndian::write_u32(&mut block[24 .. 28], captured_len);
+        block.extend_from_slice(&ip_packet);
+        let mut block_len_bytes = [0; 4];
+        LittleEndian::write_u32(&mut block_len_bytes, block_len as u32);
+        block.extend_from_slice(&block_len_bytes);
+        data.extend_from_slice(&block);
+
+        let packets = read_capture(&data).unwrap();
+        assert_eq!(packets, vec![CapturedPacket {
+            time: Duration::new(1, 7),
+            src: "1.2.3.4:1".parse().unwrap(),
+            dst: "5.6.7.8:2".parse().unwrap(),
+            payload: Payload::Tcp { seq: 100, syn: true, data: vec![1, 2, 3] },
+        }]);
+    }
+
+    #[test]
+    fn read_unknown_format() {
+        let error = read_capture(&[1, 2, 3, 4, 5]).err().unwrap();
+        assert_eq!(*error.kind(), PcapErrorKind::UnknownFormat);
+        let error = read_capture(&[1]).err().unwrap();
+        assert_eq!(*error.kind(), PcapErrorKind::Truncated);
+    }
+}
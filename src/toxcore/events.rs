@@ -0,0 +1,126 @@
+/*! Structured events describing internal state changes of toxcore modules.
+
+Modules that emit events have a `set_event_sink` method. When the sink is set
+events are sent to it as they happen, so that dashboards and integration tests
+can observe behaviour of a node without scraping log lines.
+*/
+
+use std::net::SocketAddr;
+
+use futures::sync::mpsc;
+
+use crate::toxcore::crypto_core::*;
+
+/// Sink to send events to.
+pub type EventTx = mpsc::UnboundedSender<Event>;
+
+/// Event that happened in one of toxcore modules.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// New node was added to DHT close nodes list.
+    CloseNodeAdded {
+        /// `PublicKey` of the node.
+        pk: PublicKey,
+        /// Address of the node.
+        saddr: SocketAddr,
+    },
+    /// DHT `PublicKey` of a friend was learned either from onion or from
+    /// `CryptoHandshake` packet.
+    FriendDhtPkLearned {
+        /// Long term `PublicKey` of the friend.
+        real_pk: PublicKey,
+        /// DHT `PublicKey` of the friend.
+        dht_pk: PublicKey,
+    },
+    /// New random onion path was built.
+    OnionPathBuilt {
+        /// `PublicKey`s of nodes the path consists of.
+        path_id: [PublicKey; 3],
+        /// Whether the path is used for friends searching.
+        friend: bool,
+    },
+    /// Onion path timed out and was removed.
+    OnionPathTimedOut {
+        /// `PublicKey`s of nodes the path consists of.
+        path_id: [PublicKey; 3],
+        /// Whether the path is used for friends searching.
+        friend: bool,
+    },
+    /// Crypto connection to a friend was established.
+    CryptoConnectionEstablished {
+        /// Long term `PublicKey` of the friend.
+        real_pk: PublicKey,
+    },
+    /// Crypto connection to a friend was killed or timed out.
+    CryptoConnectionLost {
+        /// Long term `PublicKey` of the friend.
+        real_pk: PublicKey,
+    },
+    /// Connection to a TCP relay was established.
+    TcpRelayConnected {
+        /// `PublicKey` of the TCP relay.
+        relay_pk: PublicKey,
+    },
+    /// Connection to a TCP relay was lost.
+    TcpRelayDisconnected {
+        /// `PublicKey` of the TCP relay.
+        relay_pk: PublicKey,
+    },
+    /// Client connected to our TCP relay.
+    TcpClientConnected {
+        /// `PublicKey` of the client.
+        pk: PublicKey,
+        /// Address of the client.
+        saddr: SocketAddr,
+    },
+    /// Client disconnected from our TCP relay.
+    TcpClientDisconnected {
+        /// `PublicKey` of the client.
+        pk: PublicKey,
+    },
+}
+
+/// Send event to the sink if it's set. Events are dropped when the receiver
+/// is gone.
+pub(crate) fn emit(event_tx: &Option<EventTx>, event: Event) {
+    if let Some(ref event_tx) = *event_tx {
+        if event_tx.unbounded_send(event).is_err() {
+            trace!("Failed to send event: receiver is dropped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{Future, Stream};
+
+    #[test]
+    fn emit_without_sink() {
+        crypto_init().unwrap();
+        emit(&None, Event::TcpRelayConnected { relay_pk: gen_keypair().0 });
+    }
+
+    #[test]
+    fn emit_with_sink() {
+        crypto_init().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        let relay_pk = gen_keypair().0;
+
+        emit(&Some(tx), Event::TcpRelayConnected { relay_pk });
+
+        let (event, _rx) = rx.into_future().wait().unwrap();
+        assert_eq!(event, Some(Event::TcpRelayConnected { relay_pk }));
+    }
+
+    #[test]
+    fn emit_receiver_dropped() {
+        crypto_init().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        drop(rx);
+
+        // should not panic
+        emit(&Some(tx), Event::TcpRelayDisconnected { relay_pk: gen_keypair().0 });
+    }
+}
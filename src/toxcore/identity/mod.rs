@@ -0,0 +1,267 @@
+/*! Deterministic identity derived from a seed.
+
+Long term keypair and `NoSpam` can be derived from a 32 bytes
+[`IdentitySeed`](./struct.IdentitySeed.html). The seed can be written down as
+24 words from the [BIP39](https://github.com/bitcoin/bips/blob/master/bip-0039.mediawiki)
+English word list, so users can restore their identity on a new device without
+the `.tox` file.
+
+The seed is used as BIP39 entropy directly, mnemonic is not stretched with
+PBKDF2 into a longer BIP39 seed. So the words are compatible with BIP39 tools
+only as an encoding of 32 bytes.
+*/
+
+mod english;
+
+use std::fmt;
+
+use failure::Fail;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::toxid::{NoSpam, NOSPAMBYTES};
+
+use self::english::{WORDS, WORDS_COUNT};
+
+/// Number of bytes of [`IdentitySeed`](./struct.IdentitySeed.html).
+pub const IDENTITY_SEED_BYTES: usize = 32;
+
+/// Number of words in mnemonic of [`IdentitySeed`](./struct.IdentitySeed.html).
+pub const MNEMONIC_WORDS: usize = 24;
+
+/// Number of bits encoded by one mnemonic word.
+const BITS_PER_WORD: usize = 11;
+
+/// Number of letters that uniquely identify a word in the word list.
+const UNIQUE_PREFIX_LEN: usize = 4;
+
+/// Context mixed into seed to derive `NoSpam` so that it's independent of the
+/// keypair.
+const NOSPAM_CONTEXT: &[u8] = b"tox-rs identity nospam";
+
+error_kind! {
+    #[doc = "Error that can happen when restoring `IdentitySeed` from mnemonic."]
+    #[derive(Debug)]
+    MnemonicError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    MnemonicErrorKind {
+        #[doc = "Mnemonic doesn't consist of 24 words."]
+        #[fail(display = "Mnemonic should consist of 24 words, got {}", count)]
+        InvalidWordCount {
+            #[doc = "Number of words in mnemonic."]
+            count: usize,
+        },
+        #[doc = "Word is not in the word list."]
+        #[fail(display = "Unknown word {:?} at position {}", word, position)]
+        UnknownWord {
+            #[doc = "Position of the word counting from 1."]
+            position: usize,
+            #[doc = "The unknown word."]
+            word: String,
+        },
+        #[doc = "Checksum encoded in the last word doesn't match. Usually it means that words are misspelled or swapped."]
+        #[fail(display = "Mnemonic checksum mismatch")]
+        ChecksumMismatch,
+    }
+}
+
+/** Seed from which long term keypair and `NoSpam` are derived.
+
+E.g.
+
+```
+use self::tox::toxcore::crypto_core::crypto_init;
+use self::tox::toxcore::identity::IdentitySeed;
+
+crypto_init().unwrap();
+let seed = IdentitySeed::random();
+let mnemonic = seed.to_mnemonic();
+assert_eq!(mnemonic.split(' ').count(), 24);
+
+// restore on another device
+let restored = IdentitySeed::from_mnemonic(&mnemonic).unwrap();
+assert_eq!(restored.keypair(), seed.keypair());
+assert_eq!(restored.nospam(), seed.nospam());
+```
+*/
+#[derive(Clone, Eq, PartialEq)]
+pub struct IdentitySeed(pub [u8; IDENTITY_SEED_BYTES]);
+
+impl IdentitySeed {
+    /// Generate random `IdentitySeed`.
+    pub fn random() -> Self {
+        let mut seed = [0; IDENTITY_SEED_BYTES];
+        randombytes_into(&mut seed);
+        IdentitySeed(seed)
+    }
+
+    /// Derive long term keypair from the seed.
+    pub fn keypair(&self) -> (PublicKey, SecretKey) {
+        keypair_from_seed(&Seed(self.0))
+    }
+
+    /// Derive `NoSpam` from the seed.
+    pub fn nospam(&self) -> NoSpam {
+        let mut data = Vec::with_capacity(NOSPAM_CONTEXT.len() + IDENTITY_SEED_BYTES);
+        data.extend_from_slice(NOSPAM_CONTEXT);
+        data.extend_from_slice(&self.0);
+        let digest = sha256::hash(&data);
+        let mut nospam = [0; NOSPAMBYTES];
+        nospam.copy_from_slice(&digest.0[.. NOSPAMBYTES]);
+        NoSpam(nospam)
+    }
+
+    /** Encode the seed as 24 words separated by spaces.
+
+    Every word encodes 11 bits. The first 256 bits are the seed and the last
+    8 bits are the first byte of SHA256 hash of the seed.
+    */
+    pub fn to_mnemonic(&self) -> String {
+        let checksum = [sha256::hash(&self.0).0[0]];
+        let mut bits = self.0.iter().chain(&checksum)
+            .flat_map(|byte| (0 .. 8).rev().map(move |i| (byte >> i) & 1 == 1));
+        let mut words = Vec::with_capacity(MNEMONIC_WORDS);
+        for _ in 0 .. MNEMONIC_WORDS {
+            let index = (0 .. BITS_PER_WORD).fold(0, |index, _| index << 1 | bits.next().unwrap() as usize);
+            words.push(WORDS[index]);
+        }
+        words.join(" ")
+    }
+
+    /** Restore the seed from mnemonic created by `to_mnemonic`.
+
+    Words are case insensitive and can be separated by any whitespace. Since
+    every word is uniquely identified by its first 4 letters, they can be
+    abbreviated to at least 4 letters.
+    */
+    pub fn from_mnemonic(mnemonic: &str) -> Result<Self, MnemonicError> {
+        let words = mnemonic.split_whitespace().collect::<Vec<_>>();
+        if words.len() != MNEMONIC_WORDS {
+            return Err(MnemonicErrorKind::InvalidWordCount { count: words.len() }.into());
+        }
+
+        let mut bytes = [0; IDENTITY_SEED_BYTES + 1];
+        let mut bit = 0;
+        for (position, word) in words.iter().enumerate() {
+            let index = word_index(word).ok_or_else(|| MnemonicError::from(MnemonicErrorKind::UnknownWord {
+                position: position + 1,
+                word: (*word).to_owned(),
+            }))?;
+            for i in (0 .. BITS_PER_WORD).rev() {
+                if (index >> i) & 1 == 1 {
+                    bytes[bit / 8] |= 0x80 >> (bit % 8);
+                }
+                bit += 1;
+            }
+        }
+
+        let mut seed = [0; IDENTITY_SEED_BYTES];
+        seed.copy_from_slice(&bytes[.. IDENTITY_SEED_BYTES]);
+        if sha256::hash(&seed).0[0] != bytes[IDENTITY_SEED_BYTES] {
+            return Err(MnemonicErrorKind::ChecksumMismatch.into());
+        }
+        Ok(IdentitySeed(seed))
+    }
+}
+
+/// Find index of the word in the word list. The word can be abbreviated to
+/// at least `UNIQUE_PREFIX_LEN` letters.
+fn word_index(word: &str) -> Option<usize> {
+    let word = word.to_lowercase();
+    // words with the same prefix are adjacent since the list is sorted
+    let index = match WORDS.binary_search(&word.as_str()) {
+        Ok(index) => return Some(index),
+        Err(index) => index,
+    };
+    if word.len() >= UNIQUE_PREFIX_LEN && index < WORDS_COUNT && WORDS[index].starts_with(&word) {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+/// Secret seed is never printed.
+impl fmt::Debug for IdentitySeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IdentitySeed(****)")
+    }
+}
+
+impl Drop for IdentitySeed {
+    fn drop(&mut self) {
+        sodiumoxide::utils::memzero(&mut self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_sorted() {
+        assert!(WORDS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn bip39_vectors() {
+        let vectors = [
+            ([0x00; IDENTITY_SEED_BYTES], "abandon abandon abandon abandon abandon abandon abandon abandon \
+                abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+                abandon abandon abandon abandon art"),
+            ([0x7f; IDENTITY_SEED_BYTES], "legal winner thank year wave sausage worth useful legal winner \
+                thank year wave sausage worth useful legal winner thank year wave sausage worth title"),
+            ([0xff; IDENTITY_SEED_BYTES], "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo \
+                zoo zoo zoo zoo zoo zoo zoo vote"),
+        ];
+        for (seed, mnemonic) in vectors.iter() {
+            let mnemonic = mnemonic.split_whitespace().collect::<Vec<_>>().join(" ");
+            assert_eq!(IdentitySeed(*seed).to_mnemonic(), mnemonic);
+            assert_eq!(IdentitySeed::from_mnemonic(&mnemonic).unwrap(), IdentitySeed(*seed));
+        }
+    }
+
+    #[test]
+    fn derivation_is_deterministic() {
+        crypto_init().unwrap();
+        let seed = IdentitySeed([42; IDENTITY_SEED_BYTES]);
+        let (pk, sk) = seed.keypair();
+        assert_eq!(sk.public_key(), pk);
+        assert_eq!(seed.clone().keypair(), (pk, sk));
+        assert_eq!(seed.clone().nospam(), seed.nospam());
+        assert_ne!(IdentitySeed([43; IDENTITY_SEED_BYTES]).keypair().0, pk);
+    }
+
+    #[test]
+    fn from_mnemonic_abbreviated() {
+        crypto_init().unwrap();
+        let seed = IdentitySeed::random();
+        let mnemonic = seed.to_mnemonic().split(' ')
+            .map(|word| word.chars().take(UNIQUE_PREFIX_LEN).collect::<String>().to_uppercase())
+            .collect::<Vec<_>>()
+            .join("\n  ");
+        assert_eq!(IdentitySeed::from_mnemonic(&mnemonic).unwrap(), seed);
+    }
+
+    #[test]
+    fn from_mnemonic_invalid() {
+        let error = IdentitySeed::from_mnemonic("abandon abandon").err().unwrap();
+        assert_eq!(*error.kind(), MnemonicErrorKind::InvalidWordCount { count: 2 });
+
+        let mnemonic = IdentitySeed([0; IDENTITY_SEED_BYTES]).to_mnemonic().replacen("abandon", "aba", 1);
+        let error = IdentitySeed::from_mnemonic(&mnemonic).err().unwrap();
+        assert_eq!(*error.kind(), MnemonicErrorKind::UnknownWord { position: 1, word: "aba".to_owned() });
+
+        let mnemonic = IdentitySeed([0; IDENTITY_SEED_BYTES]).to_mnemonic().replacen("abandon", "foobar", 1);
+        let error = IdentitySeed::from_mnemonic(&mnemonic).err().unwrap();
+        assert_eq!(*error.kind(), MnemonicErrorKind::UnknownWord { position: 1, word: "foobar".to_owned() });
+
+        let mnemonic = IdentitySeed([0; IDENTITY_SEED_BYTES]).to_mnemonic().replacen("abandon", "ability", 1);
+        let error = IdentitySeed::from_mnemonic(&mnemonic).err().unwrap();
+        assert_eq!(*error.kind(), MnemonicErrorKind::ChecksumMismatch);
+    }
+
+    #[test]
+    fn debug_hides_seed() {
+        assert_eq!(format!("{:?}", IdentitySeed([1; IDENTITY_SEED_BYTES])), "IdentitySeed(****)");
+    }
+}
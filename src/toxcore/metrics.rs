@@ -0,0 +1,298 @@
+/*!
+Export of statistics and gauges in Prometheus text format.
+
+`MetricsServer` renders counters collected by [`Stats`] together with
+registered gauges and serves them over a tiny HTTP listener so that node
+operators can scrape them with Prometheus.
+
+[`Stats`]: ../stats/struct.Stats.html
+*/
+
+use std::fmt::Write;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use futures::future::Either;
+use tokio;
+use tokio::io::{read, write_all};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::FutureExt;
+use tokio::timer::Delay;
+
+use crate::toxcore::dht::server::Server as DhtServer;
+use crate::toxcore::net_crypto::NetCrypto;
+use crate::toxcore::stats::*;
+use crate::toxcore::tcp::server::Server as TcpServer;
+use crate::toxcore::time::*;
+
+/// Maximum size of HTTP request that we read.
+const METRICS_MAX_REQUEST_SIZE: usize = 1024;
+
+/// Timeout in seconds for serving one HTTP request.
+const METRICS_HTTP_TIMEOUT: u64 = 10;
+
+/// Delay in milliseconds before accepting next connection after a failed
+/// accept, e.g. when the process has run out of file descriptors.
+const METRICS_ACCEPT_ERROR_DELAY: u64 = 100;
+
+/// Content type of Prometheus text format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Gauge with a value that is computed on every scrape.
+#[derive(Clone)]
+struct Gauge {
+    /// Name of the metric
+    name: &'static str,
+    /// Description of the metric
+    help: &'static str,
+    /// Function that returns current value
+    value: Arc<dyn Fn() -> usize + Send + Sync>,
+}
+
+/// Renders statistics in Prometheus text format and serves them over HTTP.
+#[derive(Clone)]
+pub struct MetricsServer {
+    /// Counters of incoming/outgoing packets
+    stats: Stats,
+    /// Registered gauges
+    gauges: Vec<Gauge>,
+}
+
+impl MetricsServer {
+    /// Create new `MetricsServer` that exports the given `Stats`.
+    pub fn new(stats: Stats) -> MetricsServer {
+        MetricsServer {
+            stats,
+            gauges: Vec::new(),
+        }
+    }
+
+    /// Add a gauge with a value that is computed on every scrape.
+    pub fn add_gauge<F>(&mut self, name: &'static str, help: &'static str, value: F)
+        where F: Fn() -> usize + Send + Sync + 'static
+    {
+        self.gauges.push(Gauge {
+            name,
+            help,
+            value: Arc::new(value),
+        });
+    }
+
+    /// Add gauges for the size of DHT close nodes list and the number of
+    /// announced onion nodes.
+    pub fn add_dht_server_gauges(&mut self, server: &DhtServer) {
+        let server_c = server.clone();
+        self.add_gauge(
+            "tox_dht_close_nodes",
+            "Number of nodes in DHT close nodes list.",
+            move || server_c.close_nodes.read().len()
+        );
+        let server_c = server.clone();
+        self.add_gauge(
+            "tox_onion_announce_entries",
+            "Number of onion nodes announced to us.",
+            move || server_c.onion_announce_entries_count()
+        );
+    }
+
+    /// Add gauge for the number of clients connected to TCP relay.
+    pub fn add_tcp_server_gauges(&mut self, server: &TcpServer) {
+        let server = server.clone();
+        self.add_gauge(
+            "tox_tcp_connected_clients",
+            "Number of clients connected to TCP relay.",
+            move || server.connected_clients_count()
+        );
+    }
+
+    /// Add gauge for the number of net_crypto connections.
+    pub fn add_net_crypto_gauges(&mut self, net_crypto: &NetCrypto) {
+        let net_crypto = net_crypto.clone();
+        self.add_gauge(
+            "tox_net_crypto_connections",
+            "Number of crypto connections to friends.",
+            move || net_crypto.connections_count()
+        );
+    }
+
+    /// Render all counters and gauges in Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_header(&mut out, "tox_packets_total", "Total number of packets.", "counter");
+        writeln!(out, "tox_packets_total{{direction=\"incoming\"}} {}", self.stats.counters.incoming()).unwrap();
+        writeln!(out, "tox_packets_total{{direction=\"outgoing\"}} {}", self.stats.counters.outgoing()).unwrap();
+
+        let packet_counts = self.stats.packets.packet_counts();
+
+        write_header(&mut out, "tox_packets_by_kind_total", "Number of packets by transport, direction and kind.", "counter");
+        for &(transport, direction, kind, count) in &packet_counts {
+            writeln!(out, "tox_packets_by_kind_total{{transport=\"{}\",direction=\"{}\",kind=\"{}\"}} {}",
+                transport.name(), direction.name(), kind, count.packets).unwrap();
+        }
+
+        write_header(&mut out, "tox_bytes_by_kind_total", "Size of packets in bytes by transport, direction and kind.", "counter");
+        for &(transport, direction, kind, count) in &packet_counts {
+            writeln!(out, "tox_bytes_by_kind_total{{transport=\"{}\",direction=\"{}\",kind=\"{}\"}} {}",
+                transport.name(), direction.name(), kind, count.bytes).unwrap();
+        }
+
+        write_header(&mut out, "tox_bytes_total", "Size of packets in bytes by transport and direction.", "counter");
+        for &transport in &[Transport::Udp, Transport::Tcp] {
+            for &direction in &[Direction::Incoming, Direction::Outgoing] {
+                writeln!(out, "tox_bytes_total{{transport=\"{}\",direction=\"{}\"}} {}",
+                    transport.name(), direction.name(), self.stats.packets.bytes(transport, direction)).unwrap();
+            }
+        }
+
+        write_header(&mut out, "tox_decode_errors_total", "Number of packets that can't be decoded by transport and error kind.", "counter");
+        for (transport, kind, count) in self.stats.packets.decode_error_counts() {
+            writeln!(out, "tox_decode_errors_total{{transport=\"{}\",kind=\"{}\"}} {}",
+                transport.name(), kind, count).unwrap();
+        }
+
+        write_header(&mut out, "tox_ipv6_dropped_total", "Number of outgoing IPv6 packets dropped in IPv4 mode.", "counter");
+        writeln!(out, "tox_ipv6_dropped_total {}", self.stats.packets.ipv6_dropped()).unwrap();
+
+        for gauge in &self.gauges {
+            write_header(&mut out, gauge.name, gauge.help, "gauge");
+            writeln!(out, "{} {}", gauge.name, (gauge.value)()).unwrap();
+        }
+
+        out
+    }
+
+    /// Build HTTP response for the given request.
+    fn response(&self, request: &[u8]) -> Vec<u8> {
+        let request = String::from_utf8_lossy(request);
+        let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/")) | (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), Some(_)) => ("404 Not Found", "Not Found\n".to_owned()),
+            _ => ("400 Bad Request", "Bad Request\n".to_owned()),
+        };
+        format!(
+            "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            METRICS_CONTENT_TYPE,
+            body.len(),
+            body
+        ).into_bytes()
+    }
+
+    /// Serve one HTTP request.
+    fn serve(&self, stream: TcpStream) -> impl Future<Item = (), Error = IoError> + Send {
+        let self_c = self.clone();
+        read(stream, vec![0; METRICS_MAX_REQUEST_SIZE])
+            .and_then(move |(stream, buf, size)| write_all(stream, self_c.response(&buf[..size])))
+            .map(|_| ())
+            .timeout(Duration::from_secs(METRICS_HTTP_TIMEOUT))
+            .map_err(|e| e.into_inner().unwrap_or_else(|| IoError::new(IoErrorKind::TimedOut, "Metrics request timed out")))
+    }
+
+    /// Run HTTP listener that serves metrics on `/metrics` path. Failed
+    /// accepts are logged and don't stop the listener.
+    pub fn run(self, listener: TcpListener) -> impl Future<Item = (), Error = IoError> + Send {
+        listener.incoming()
+            .then(|result| match result {
+                Ok(stream) => Either::A(future::ok(Some(stream))),
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    let delay = Delay::new(clock_now() + Duration::from_millis(METRICS_ACCEPT_ERROR_DELAY));
+                    Either::B(delay.then(|_| Ok(None)))
+                },
+            })
+            .filter_map(|stream| stream)
+            .for_each(move |stream| {
+                let peer_addr = stream.peer_addr();
+                tokio::spawn(self.serve(stream).or_else(move |e| {
+                    debug!("Failed to serve metrics to {:?}: {}", peer_addr, e);
+                    future::ok(())
+                }));
+                Ok(())
+            })
+    }
+}
+
+/// Write HELP and TYPE lines of a metric.
+fn write_header(out: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write as IoWrite};
+    use std::net::TcpStream as StdTcpStream;
+
+    use tokio::runtime::Runtime;
+
+    fn http_get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = StdTcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn render() {
+        let stats = Stats::new();
+        stats.counters.increase_incoming();
+        stats.packets.add_packet(Transport::Udp, Direction::Incoming, "PingRequest", 113);
+        stats.packets.add_decode_error(Transport::Tcp, "DecryptError");
+        stats.packets.increase_ipv6_dropped();
+
+        let mut metrics = MetricsServer::new(stats);
+        metrics.add_gauge("tox_test_gauge", "Test gauge.", || 42);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE tox_packets_total counter\n"));
+        assert!(text.contains("tox_packets_total{direction=\"incoming\"} 1\n"));
+        assert!(text.contains("tox_packets_total{direction=\"outgoing\"} 0\n"));
+        assert!(text.contains("tox_packets_by_kind_total{transport=\"udp\",direction=\"incoming\",kind=\"PingRequest\"} 1\n"));
+        assert!(text.contains("tox_bytes_by_kind_total{transport=\"udp\",direction=\"incoming\",kind=\"PingRequest\"} 113\n"));
+        assert!(text.contains("tox_bytes_total{transport=\"udp\",direction=\"incoming\"} 113\n"));
+        assert!(text.contains("tox_bytes_total{transport=\"tcp\",direction=\"outgoing\"} 0\n"));
+        assert!(text.contains("tox_decode_errors_total{transport=\"tcp\",kind=\"DecryptError\"} 1\n"));
+        assert!(text.contains("tox_ipv6_dropped_total 1\n"));
+        assert!(text.contains("# HELP tox_test_gauge Test gauge.\n# TYPE tox_test_gauge gauge\ntox_test_gauge 42\n"));
+    }
+
+    #[test]
+    fn server_gauges() {
+        let stats = Stats::new();
+        let mut metrics = MetricsServer::new(stats);
+        metrics.add_tcp_server_gauges(&TcpServer::new());
+
+        let text = metrics.render();
+        assert!(text.contains("tox_tcp_connected_clients 0\n"));
+    }
+
+    #[test]
+    fn run() {
+        let stats = Stats::new();
+        stats.counters.increase_outgoing();
+        let metrics = MetricsServer::new(stats);
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(metrics.run(listener).map_err(|e| panic!("Metrics server failed: {}", e)));
+
+        let response = http_get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("tox_packets_total{direction=\"outgoing\"} 1\n"));
+
+        let response = http_get(addr, "/unknown");
+        assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        runtime.shutdown_now().wait().unwrap();
+    }
+}
@@ -0,0 +1,819 @@
+/*! Congestion control for crypto connections.
+
+Congestion controller decides how many lossless packets per second a crypto
+connection is allowed to send. It doesn't affect the wire format so peers can
+use different algorithms. Two algorithms are provided:
+
+- `QueueCongestionControl` is the algorithm used by c-toxcore. It estimates the
+  rate of delivered packets and slows down when the send queue grows.
+- `DelayCongestionControl` is a delay-based algorithm similar to LEDBAT. It
+  keeps the queuing delay introduced by the connection below a target value
+  and doesn't treat random losses as congestion.
+
+*/
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::crypto_connection::*;
+
+/// How many last sizes of `send_array` should be recorded for congestion
+/// control.
+pub const CONGESTION_QUEUE_ARRAY_SIZE: usize = 12;
+
+/// How many numbers of sent lossless packets should be recorded for congestion
+/// control. It should be bigger than `CONGESTION_QUEUE_ARRAY_SIZE` due to rtt.
+pub const CONGESTION_LAST_SENT_ARRAY_SIZE: usize = CONGESTION_QUEUE_ARRAY_SIZE * 2;
+
+/// Minimum packets rate per second.
+pub const CRYPTO_PACKET_MIN_RATE: f64 = 4.0;
+
+/// Timeout for increasing speed after congestion event.
+pub const CONGESTION_EVENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// If the send queue grows so that it will take more than 2 seconds to send all
+/// its packet we will reduce send rate.
+pub const SEND_QUEUE_CLEARANCE_TIME: f64 = 2.0;
+
+/// Minimum packets in the send queue to reduce send rate.
+pub const CRYPTO_MIN_QUEUE_LENGTH: u32 = 64;
+
+/// Queuing delay that `DelayCongestionControl` tries to keep.
+pub const DELAY_TARGET: Duration = Duration::from_millis(100);
+
+/// Maximum relative change of the congestion window of `DelayCongestionControl`
+/// per one RTT.
+pub const DELAY_GAIN: f64 = 0.1;
+
+/// Multiplier of the congestion window of `DelayCongestionControl` when resent
+/// packets come with the grown queue.
+pub const DELAY_LOSS_DECREASE: f64 = 0.7;
+
+/// Weight of the last measurement for the smoothed rate of sent packets of
+/// `DelayCongestionControl`.
+pub const DELAY_SENT_RATE_WEIGHT: f64 = 0.2;
+
+/// Interval after which the oldest minimum of RTT is forgotten by
+/// `DelayCongestionControl`. It allows base delay to grow when the route
+/// changes.
+pub const DELAY_BASE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Stats of a crypto connection that are passed to a congestion controller
+/// every `PACKET_COUNTER_AVERAGE_INTERVAL`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CongestionStats {
+    /// Current time.
+    pub now: Instant,
+    /// Time since the previous stats calculation.
+    pub dt: Duration,
+    /// Number of packets in `send_array` including not sent yet.
+    pub send_array_len: u32,
+    /// Number of sent lossless packets since the previous stats calculation.
+    /// It does not include resent packets.
+    pub packets_sent: u32,
+    /// Number of resent lossless packets since the previous stats
+    /// calculation.
+    pub packets_resent: u32,
+    /// The lowest RTT of the connection.
+    pub rtt: Duration,
+}
+
+/// Algorithm that calculates send rates of a crypto connection.
+pub trait CongestionControl: fmt::Debug + Send + Sync {
+    /// Name of the algorithm.
+    fn name(&self) -> &'static str;
+
+    /// Handle RTT of a packet that was just confirmed by the peer.
+    fn on_rtt_sample(&mut self, _rtt: Duration) {}
+
+    /// Recalculate send rates. It's called every
+    /// `PACKET_COUNTER_AVERAGE_INTERVAL`.
+    fn update(&mut self, stats: &CongestionStats);
+
+    /// Rate of sending new lossless packets per second.
+    fn send_rate(&self) -> f64;
+
+    /// Rate of resending requested lossless packets per second.
+    fn send_rate_requested(&self) -> f64;
+
+    /// Clone the controller into a box.
+    fn box_clone(&self) -> Box<dyn CongestionControl>;
+}
+
+impl Clone for Box<dyn CongestionControl> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// Derived `PartialEq` of structs containing boxed trait objects needs this
+// impl, see https://github.com/rust-lang/rust/issues/31740
+impl PartialEq<&Self> for Box<dyn CongestionControl> {
+    fn eq(&self, other: &&Self) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq for Box<dyn CongestionControl> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name() &&
+            self.send_rate() == other.send_rate() &&
+            self.send_rate_requested() == other.send_rate_requested()
+    }
+}
+
+/// Convert `Duration` to seconds.
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// Add packets that can be sent with the rate during `dt` to the number of
+/// packets that are allowed to be sent. The number is limited so that the
+/// connection can't send a burst after a long idle period.
+pub fn refill_packets_left(packets_left: f64, rate: f64, dt: Duration) -> f64 {
+    let packets = rate * as_secs_f64(dt);
+    let max = packets * 4.0 + f64::from(CRYPTO_MIN_QUEUE_LENGTH);
+    (packets_left + packets).min(max)
+}
+
+/** Queue based congestion control used by c-toxcore.
+
+It counts packets that were delivered during the last
+`CONGESTION_QUEUE_ARRAY_SIZE` intervals and sends 20% faster than this rate
+unless the send queue is too big to be sent in `SEND_QUEUE_CLEARANCE_TIME`.
+
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueCongestionControl {
+    /// Current position in `last_send_array_sizes` and `last_num_packets` arrays.
+    last_sendqueue_counter: u32,
+    /// Last sizes of `send_array`.
+    last_send_array_sizes: [u32; CONGESTION_QUEUE_ARRAY_SIZE],
+    /// Last sent packets counts.
+    last_num_packets_sent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
+    /// Last resent packets counts.
+    last_num_packets_resent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
+    /// Congestion event is a time when we couldn't send all packets due to
+    /// slow connection.
+    last_congestion_event: Option<Instant>,
+    /// Estimated packets send rate.
+    packet_send_rate: f64,
+    /// Estimated requested packets send rate.
+    packet_send_rate_requested: f64,
+}
+
+impl Default for QueueCongestionControl {
+    fn default() -> Self {
+        QueueCongestionControl {
+            last_sendqueue_counter: 0,
+            last_send_array_sizes: [0; CONGESTION_QUEUE_ARRAY_SIZE],
+            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_congestion_event: None,
+            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
+            packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+        }
+    }
+}
+
+impl QueueCongestionControl {
+    /// Create new `QueueCongestionControl`.
+    pub fn new() -> QueueCongestionControl {
+        QueueCongestionControl::default()
+    }
+}
+
+impl CongestionControl for QueueCongestionControl {
+    fn name(&self) -> &'static str {
+        "queue"
+    }
+
+    fn update(&mut self, stats: &CongestionStats) {
+        let pos = self.last_sendqueue_counter as usize % CONGESTION_QUEUE_ARRAY_SIZE;
+        let n_p_pos = self.last_sendqueue_counter as usize % CONGESTION_LAST_SENT_ARRAY_SIZE;
+        self.last_sendqueue_counter = (self.last_sendqueue_counter + 1) %
+            // divide by the common multiple to prevent overflow
+            (CONGESTION_QUEUE_ARRAY_SIZE * CONGESTION_LAST_SENT_ARRAY_SIZE) as u32;
+
+        let send_array_len = stats.send_array_len;
+
+        self.last_send_array_sizes[pos] = send_array_len;
+        self.last_num_packets_sent[n_p_pos] = stats.packets_sent;
+        self.last_num_packets_resent[n_p_pos] = stats.packets_resent;
+
+        // How changed the size of send_array per CONGESTION_QUEUE_ARRAY_SIZE * PACKET_COUNTER_AVERAGE_INTERVAL interval
+        let sum = send_array_len as i32 - self.last_send_array_sizes[(pos + 1) % CONGESTION_QUEUE_ARRAY_SIZE] as i32;
+
+        // The maximum allowed delay
+        const CONGESTION_MAX_DELAY: usize = CONGESTION_LAST_SENT_ARRAY_SIZE - CONGESTION_QUEUE_ARRAY_SIZE;
+
+        // Based on rtt offset in number of positions for last_num_packets arrays (one position equals 50 ms)
+        let delay = ((
+            stats.rtt.as_secs() * 1000 +
+                u64::from(stats.rtt.subsec_millis()) +
+                PACKET_COUNTER_AVERAGE_INTERVAL_MS / 2 // add half of the interval to make delay rounded
+        ) / PACKET_COUNTER_AVERAGE_INTERVAL_MS) as usize;
+        let delay = delay.min(CONGESTION_MAX_DELAY);
+
+        // Total number of sent packets per CONGESTION_QUEUE_ARRAY_SIZE * PACKET_COUNTER_AVERAGE_INTERVAL interval
+        // For instance if the delay is 3 elements marked with '+' will be taken ('x' is the current pos):
+        // ...++++++++++++..x......
+        let mut total_sent = 0;
+        let mut total_resent = 0;
+        for i in 0 .. CONGESTION_QUEUE_ARRAY_SIZE {
+            let i = (n_p_pos + (CONGESTION_MAX_DELAY - delay) + i) % CONGESTION_LAST_SENT_ARRAY_SIZE;
+            total_sent += self.last_num_packets_sent[i] as i32;
+            total_resent += self.last_num_packets_resent[i] as i32;
+        }
+
+        if sum > 0 {
+            // send_array increased i.e. we sent more packets that was delivered
+            // decrease total_sent packets by this number so that it includes only delivered packets
+            total_sent -= sum;
+        } else if total_resent > -sum {
+            // send_array decreased and not all resent packets were delivered
+            // use this number to count only delivered packets
+            total_resent = -sum;
+        }
+
+        // Average number of successfully delivered packets per second
+        let coeff = 1000.0 / (CONGESTION_QUEUE_ARRAY_SIZE as f64 * PACKET_COUNTER_AVERAGE_INTERVAL_MS as f64);
+        let min_speed = (f64::from(total_sent) * coeff).max(CRYPTO_PACKET_MIN_RATE);
+        let min_speed_request = f64::from(total_sent + total_resent) * coeff;
+
+        // Time necessary to send all packets from send queue
+        let send_array_time = f64::from(send_array_len) / min_speed;
+
+        // And, finally, estimated packets send rate
+        let packet_send_rate = if send_array_time > SEND_QUEUE_CLEARANCE_TIME && send_array_len > CRYPTO_MIN_QUEUE_LENGTH {
+            // It will take more than SEND_QUEUE_CLEARANCE_TIME seconds to send
+            // all packets from send queue. Reduce packets send rate in this case
+            min_speed / (send_array_time / SEND_QUEUE_CLEARANCE_TIME)
+        } else if self.last_congestion_event.map_or(true, |time| (stats.now - time) > CONGESTION_EVENT_TIMEOUT) {
+            // Congestion event happened long ago so increase packets send rate
+            min_speed * 1.2
+        } else {
+            // Congestion event happened recently so decrease packets send rate
+            min_speed * 0.9
+        };
+        let packet_send_rate = packet_send_rate.max(CRYPTO_PACKET_MIN_RATE);
+        let packet_send_rate_requested = min_speed_request * 1.2;
+        let packet_send_rate_requested = packet_send_rate_requested.max(packet_send_rate);
+
+        self.packet_send_rate = packet_send_rate;
+        self.packet_send_rate_requested = packet_send_rate_requested;
+    }
+
+    fn send_rate(&self) -> f64 {
+        self.packet_send_rate
+    }
+
+    fn send_rate_requested(&self) -> f64 {
+        self.packet_send_rate_requested
+    }
+
+    fn box_clone(&self) -> Box<dyn CongestionControl> {
+        Box::new(self.clone())
+    }
+}
+
+/** Delay based congestion control similar to LEDBAT.
+
+The lowest RTT is considered as the delay of an empty path. Everything above
+it is the delay of packets waiting in queues of routers. The congestion window
+grows while the queuing delay is below `DELAY_TARGET` and shrinks when it's
+above proportionally to the difference. The send rate is the congestion window
+divided by the current RTT so it drops as soon as queues start to grow. Since
+queues are kept short the latency of the connection stays low even when it's
+saturated.
+
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct DelayCongestionControl {
+    /// The lowest RTT since `base_delay_time`.
+    base_delay: Option<Duration>,
+    /// The lowest RTT of the previous `DELAY_BASE_INTERVAL`.
+    prev_base_delay: Option<Duration>,
+    /// Time when `base_delay` started to be collected.
+    base_delay_time: Option<Instant>,
+    /// The lowest RTT since `round_start`.
+    current_delay: Option<Duration>,
+    /// Time when the current round started. The congestion window is changed
+    /// once per round which lasts one RTT so that the effect of the previous
+    /// change can be seen.
+    round_start: Option<Instant>,
+    /// Number of resent packets since `round_start`.
+    round_resent: u32,
+    /// Smoothed rate of sent packets.
+    sent_rate: f64,
+    /// Number of packets that can be sent during one RTT.
+    congestion_window: f64,
+    /// Whether the congestion window is doubled every RTT. Slow start ends
+    /// when the queuing delay reaches half of `DELAY_TARGET` for the first
+    /// time.
+    slow_start: bool,
+    /// Packets send rate.
+    packet_send_rate: f64,
+}
+
+impl Default for DelayCongestionControl {
+    fn default() -> Self {
+        DelayCongestionControl {
+            base_delay: None,
+            prev_base_delay: None,
+            base_delay_time: None,
+            current_delay: None,
+            round_start: None,
+            round_resent: 0,
+            sent_rate: 0.0,
+            congestion_window: CRYPTO_PACKET_MIN_RATE * as_secs_f64(DEFAULT_RTT),
+            slow_start: true,
+            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
+        }
+    }
+}
+
+impl DelayCongestionControl {
+    /// Create new `DelayCongestionControl`.
+    pub fn new() -> DelayCongestionControl {
+        DelayCongestionControl::default()
+    }
+
+    /// Get the delay of an empty path.
+    fn base_delay(&self) -> Option<Duration> {
+        match (self.base_delay, self.prev_base_delay) {
+            (Some(base_delay), Some(prev_base_delay)) => Some(base_delay.min(prev_base_delay)),
+            (base_delay, prev_base_delay) => base_delay.or(prev_base_delay),
+        }
+    }
+
+    /// Forget the oldest minimum of RTT if `DELAY_BASE_INTERVAL` elapsed.
+    fn rotate_base_delay(&mut self, now: Instant) {
+        match self.base_delay_time {
+            Some(time) if now - time < DELAY_BASE_INTERVAL => {},
+            Some(_) => {
+                self.prev_base_delay = self.base_delay.take();
+                self.base_delay_time = Some(now);
+            },
+            None => self.base_delay_time = Some(now),
+        }
+    }
+}
+
+impl CongestionControl for DelayCongestionControl {
+    fn name(&self) -> &'static str {
+        "delay"
+    }
+
+    fn on_rtt_sample(&mut self, rtt: Duration) {
+        self.base_delay = Some(self.base_delay.map_or(rtt, |delay| delay.min(rtt)));
+        self.current_delay = Some(self.current_delay.map_or(rtt, |delay| delay.min(rtt)));
+    }
+
+    fn update(&mut self, stats: &CongestionStats) {
+        self.rotate_base_delay(stats.now);
+
+        let dt = as_secs_f64(stats.dt);
+        if dt > 0.0 {
+            let sent_rate = f64::from(stats.packets_sent) / dt;
+            self.sent_rate += (sent_rate - self.sent_rate) * DELAY_SENT_RATE_WEIGHT;
+        }
+
+        self.round_resent += stats.packets_resent;
+        let round_start = *self.round_start.get_or_insert(stats.now);
+
+        let (current_delay, base_delay) = match (self.current_delay, self.base_delay()) {
+            (Some(current_delay), Some(base_delay)) => (current_delay, base_delay),
+            // nothing was confirmed so we don't know anything about the path
+            _ => return,
+        };
+
+        if stats.now - round_start < current_delay {
+            return;
+        }
+
+        self.current_delay = None;
+        self.round_start = Some(stats.now);
+        let round_resent = self.round_resent;
+        self.round_resent = 0;
+
+        let queuing_delay = as_secs_f64(current_delay - base_delay);
+        let target = as_secs_f64(DELAY_TARGET);
+        let off_target = (target - queuing_delay) / target;
+
+        if queuing_delay > target / 2.0 {
+            self.slow_start = false;
+        }
+
+        if round_resent > 0 && queuing_delay > target / 2.0 {
+            // Packets are lost because the queue has overflown
+            self.congestion_window *= DELAY_LOSS_DECREASE;
+        } else if self.sent_rate < self.packet_send_rate / 2.0 && off_target >= 0.0 {
+            // Don't increase the window if the connection doesn't use the
+            // allowed rate since there is no evidence that the path can
+            // handle it
+        } else if self.slow_start {
+            self.congestion_window *= 2.0;
+        } else {
+            // Like LEDBAT the window can't be decreased more than twice per
+            // RTT
+            self.congestion_window *= (1.0 + DELAY_GAIN * off_target).max(0.5);
+        }
+
+        let packet_send_rate = self.congestion_window / as_secs_f64(current_delay);
+        self.packet_send_rate = packet_send_rate.max(CRYPTO_PACKET_MIN_RATE);
+        self.congestion_window = self.packet_send_rate * as_secs_f64(current_delay);
+    }
+
+    fn send_rate(&self) -> f64 {
+        self.packet_send_rate
+    }
+
+    fn send_rate_requested(&self) -> f64 {
+        self.packet_send_rate
+    }
+
+    fn box_clone(&self) -> Box<dyn CongestionControl> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    use crate::toxcore::simulator::SimRng;
+
+    fn stats(now: Instant, send_array_len: u32, packets_sent: u32, packets_resent: u32) -> CongestionStats {
+        CongestionStats {
+            now,
+            dt: PACKET_COUNTER_AVERAGE_INTERVAL,
+            send_array_len,
+            packets_sent,
+            packets_resent,
+            rtt: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn box_clone_eq() {
+        let queue: Box<dyn CongestionControl> = Box::new(QueueCongestionControl::new());
+        let delay: Box<dyn CongestionControl> = Box::new(DelayCongestionControl::new());
+        assert_eq!(queue.clone(), queue);
+        assert_eq!(delay.clone(), delay);
+        assert!(queue != delay);
+    }
+
+    #[test]
+    fn queue_increases_rate() {
+        let mut cc = QueueCongestionControl::new();
+        let now = Instant::now();
+
+        for i in 0 .. CONGESTION_LAST_SENT_ARRAY_SIZE as u32 {
+            cc.update(&stats(now + PACKET_COUNTER_AVERAGE_INTERVAL * i, 0, 10, 0));
+        }
+
+        // 10 packets every 50 ms and 20% more
+        assert!((cc.send_rate() - 240.0).abs() < 0.001);
+        assert!((cc.send_rate_requested() - 240.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn queue_decreases_rate_for_big_queue() {
+        let mut cc = QueueCongestionControl::new();
+        let now = Instant::now();
+
+        for i in 0 .. CONGESTION_LAST_SENT_ARRAY_SIZE as u32 {
+            cc.update(&stats(now + PACKET_COUNTER_AVERAGE_INTERVAL * i, 1000, 10, 0));
+        }
+
+        // 1000 packets can't be sent with 200 packets per second in 2 seconds
+        assert!((cc.send_rate() - 80.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_without_samples() {
+        let mut cc = DelayCongestionControl::new();
+        let now = Instant::now();
+
+        cc.update(&stats(now, 10, 10, 0));
+        cc.update(&stats(now + Duration::from_secs(1), 10, 10, 0));
+
+        assert_eq!(cc.send_rate(), CRYPTO_PACKET_MIN_RATE);
+    }
+
+    /// Make `DelayCongestionControl` with the base delay of 100 ms, the
+    /// congestion window of 10 packets, finished slow start and started
+    /// round.
+    fn delay_congestion_control(now: Instant) -> DelayCongestionControl {
+        let mut cc = DelayCongestionControl::new();
+        cc.base_delay = Some(Duration::from_millis(100));
+        cc.update(&stats(now, 10, 5, 0));
+        cc.congestion_window = 10.0;
+        cc.slow_start = false;
+        cc.packet_send_rate = 100.0;
+        cc.sent_rate = 100.0;
+        cc
+    }
+
+    #[test]
+    fn delay_slow_start() {
+        let now = Instant::now();
+        let mut cc = delay_congestion_control(now);
+        cc.slow_start = true;
+        cc.on_rtt_sample(Duration::from_millis(100));
+
+        cc.update(&stats(now + Duration::from_millis(100), 10, 5, 0));
+
+        assert!(cc.slow_start);
+        assert!((cc.send_rate() - 200.0).abs() < 0.001);
+
+        cc.on_rtt_sample(Duration::from_millis(160));
+        cc.update(&stats(now + Duration::from_millis(300), 10, 5, 0));
+
+        assert!(!cc.slow_start);
+    }
+
+    #[test]
+    fn delay_changes_rate_once_per_round() {
+        let now = Instant::now();
+        let mut cc = delay_congestion_control(now);
+        cc.on_rtt_sample(Duration::from_millis(100));
+
+        cc.update(&stats(now + PACKET_COUNTER_AVERAGE_INTERVAL, 10, 5, 0));
+
+        assert!((cc.send_rate() - 100.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_increases_rate_below_target() {
+        let now = Instant::now();
+        let mut cc = delay_congestion_control(now);
+        cc.on_rtt_sample(Duration::from_millis(100));
+
+        cc.update(&stats(now + Duration::from_millis(100), 10, 5, 0));
+
+        assert!((cc.send_rate() - 110.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_keeps_rate_when_not_used() {
+        let now = Instant::now();
+        let mut cc = delay_congestion_control(now);
+        cc.sent_rate = 0.0;
+        cc.on_rtt_sample(Duration::from_millis(100));
+
+        cc.update(&stats(now + Duration::from_millis(100), 1, 1, 0));
+
+        assert!((cc.send_rate() - 100.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_decreases_rate_above_target() {
+        let now = Instant::now();
+        let mut cc = delay_congestion_control(now);
+
+        cc.on_rtt_sample(Duration::from_millis(250));
+        cc.update(&stats(now + Duration::from_millis(250), 10, 5, 0));
+
+        // queuing delay is 150 ms which is 50% above the target so the
+        // window is decreased by 5%
+        assert!((cc.congestion_window - 9.5).abs() < 0.001);
+        assert!((cc.send_rate() - 38.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_ignores_losses_without_queue() {
+        let now = Instant::now();
+        let mut cc = delay_congestion_control(now);
+        cc.on_rtt_sample(Duration::from_millis(100));
+
+        cc.update(&stats(now + Duration::from_millis(100), 10, 5, 5));
+
+        assert!((cc.send_rate() - 110.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_decreases_rate_for_losses_with_queue() {
+        let now = Instant::now();
+        let mut cc = delay_congestion_control(now);
+
+        cc.on_rtt_sample(Duration::from_millis(180));
+        cc.update(&stats(now + PACKET_COUNTER_AVERAGE_INTERVAL, 10, 5, 5));
+        cc.update(&stats(now + Duration::from_millis(200), 10, 5, 0));
+
+        assert!((cc.congestion_window - 7.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_base_delay_expires() {
+        let now = Instant::now();
+        let mut cc = delay_congestion_control(now);
+
+        cc.on_rtt_sample(Duration::from_millis(200));
+        cc.update(&stats(now + DELAY_BASE_INTERVAL + Duration::from_secs(1), 10, 5, 0));
+        assert_eq!(cc.base_delay(), Some(Duration::from_millis(100)));
+        cc.on_rtt_sample(Duration::from_millis(200));
+        cc.update(&stats(now + DELAY_BASE_INTERVAL * 2 + Duration::from_secs(2), 10, 5, 0));
+
+        assert_eq!(cc.base_delay(), Some(Duration::from_millis(200)));
+    }
+
+    /// Path with a bottleneck router between two peers.
+    #[derive(Clone, Copy)]
+    struct SimLink {
+        /// Packets per second the bottleneck can pass.
+        capacity: f64,
+        /// One-way propagation delay.
+        delay: Duration,
+        /// Probability of random loss of a packet.
+        loss: f64,
+        /// Maximum number of packets in the queue of the bottleneck.
+        buffer: usize,
+    }
+
+    /// Results of transferring data via `SimLink`.
+    #[derive(Debug)]
+    struct SimResult {
+        /// Delivered unique packets per second.
+        throughput: f64,
+        /// Average one-way delay of delivered packets.
+        latency: Duration,
+    }
+
+    /// Packet in flight.
+    struct Flight {
+        /// Index of the packet.
+        index: u32,
+        /// Time when the packet was sent.
+        sent_time: Instant,
+        /// Time when the confirmation reaches the sender or when the sender
+        /// requests it again if the packet is lost.
+        ack_time: Instant,
+        /// Whether the packet is lost.
+        lost: bool,
+    }
+
+    /** Send data as fast as the congestion controller allows via the link for
+    the given time.
+
+    The sender refills its budget the same way `CryptoConnection` does and
+    always has `CRYPTO_MIN_QUEUE_LENGTH` packets waiting to be sent. The
+    receiver confirms each packet immediately, lost packets are requested
+    again after RTT.
+
+    */
+    fn simulate(mut cc: Box<dyn CongestionControl>, link: SimLink, duration: Duration) -> SimResult {
+        const STEP: Duration = Duration::from_millis(1);
+
+        let mut rng = SimRng::new(42);
+        let start = Instant::now();
+        let end = start + duration;
+        let mut now = start;
+
+        let mut next_index = 0u32;
+        let mut unsent = CRYPTO_MIN_QUEUE_LENGTH;
+        let mut requested = VecDeque::new();
+        let mut flights: VecDeque<Flight> = VecDeque::new();
+        // departure times of packets in the queue of the bottleneck
+        let mut queue = VecDeque::new();
+        let mut link_free_time = start;
+
+        let mut packets_left = f64::from(CRYPTO_MIN_QUEUE_LENGTH);
+        let mut packets_left_requested = f64::from(CRYPTO_MIN_QUEUE_LENGTH);
+        let mut packets_sent = 0;
+        let mut packets_resent = 0;
+        let mut stats_time = start;
+        let mut rtt = DEFAULT_RTT;
+
+        let mut delivered = 0u32;
+        let mut total_delay = Duration::from_secs(0);
+
+        while now < end {
+            // handle confirmations and losses
+            while let Some(flight) = flights.pop_front() {
+                if flight.ack_time > now {
+                    flights.push_front(flight);
+                    break;
+                }
+                if flight.lost {
+                    requested.push_back(flight.index);
+                } else {
+                    let sample = now - flight.sent_time;
+                    rtt = rtt.min(sample);
+                    cc.on_rtt_sample(sample);
+                }
+            }
+
+            if now - stats_time >= PACKET_COUNTER_AVERAGE_INTERVAL {
+                let dt = now - stats_time;
+                cc.update(&CongestionStats {
+                    now,
+                    dt,
+                    send_array_len: flights.len() as u32 + requested.len() as u32 + unsent,
+                    packets_sent,
+                    packets_resent,
+                    rtt,
+                });
+                packets_sent = 0;
+                packets_resent = 0;
+                stats_time = now;
+
+                packets_left = refill_packets_left(packets_left, cc.send_rate(), dt);
+                packets_left_requested = refill_packets_left(packets_left_requested, cc.send_rate_requested(), dt);
+            }
+
+            let mut to_send = Vec::new();
+            while packets_left_requested >= 1.0 {
+                match requested.pop_front() {
+                    Some(index) => to_send.push(index),
+                    None => break,
+                }
+                packets_left_requested -= 1.0;
+                packets_resent += 1;
+            }
+            while packets_left >= 1.0 && unsent > 0 {
+                to_send.push(next_index);
+                next_index += 1;
+                unsent -= 1;
+                packets_left -= 1.0;
+                packets_sent += 1;
+            }
+            unsent = CRYPTO_MIN_QUEUE_LENGTH;
+
+            for index in to_send {
+                while let Some(&time) = queue.front() {
+                    if time > now {
+                        break;
+                    }
+                    queue.pop_front();
+                }
+                let arrival_time = if queue.len() < link.buffer && !rng.gen_bool(link.loss) {
+                    let departure_time = link_free_time.max(now) + Duration::from_nanos((1_000_000_000.0 / link.capacity) as u64);
+                    link_free_time = departure_time;
+                    queue.push_back(departure_time);
+                    Some(departure_time + link.delay)
+                } else {
+                    None
+                };
+                if let Some(arrival_time) = arrival_time {
+                    delivered += 1;
+                    total_delay += arrival_time - now;
+                }
+                let ack_time = arrival_time.unwrap_or(now + rtt) + link.delay;
+                let position = flights.iter().rposition(|flight| flight.ack_time <= ack_time).map_or(0, |i| i + 1);
+                flights.insert(position, Flight {
+                    index,
+                    sent_time: now,
+                    ack_time,
+                    lost: arrival_time.is_none(),
+                });
+            }
+
+            now += STEP;
+        }
+
+        SimResult {
+            throughput: f64::from(delivered) / as_secs_f64(duration),
+            latency: total_delay / delivered,
+        }
+    }
+
+    fn lossy_link(loss: f64) -> SimLink {
+        SimLink {
+            capacity: 500.0,
+            delay: Duration::from_millis(50),
+            loss,
+            buffer: 500,
+        }
+    }
+
+    #[test]
+    fn simulate_queue() {
+        let result = simulate(Box::new(QueueCongestionControl::new()), lossy_link(0.02), Duration::from_secs(60));
+
+        assert!(result.throughput > 400.0, "{:?}", result);
+    }
+
+    #[test]
+    fn simulate_delay() {
+        let result = simulate(Box::new(DelayCongestionControl::new()), lossy_link(0.02), Duration::from_secs(60));
+
+        assert!(result.throughput > 400.0, "{:?}", result);
+        assert!(result.latency < Duration::from_millis(250), "{:?}", result);
+    }
+
+    #[test]
+    fn simulate_delay_has_lower_latency() {
+        for &loss in &[0.0, 0.01, 0.02, 0.05, 0.1] {
+            let link = lossy_link(loss);
+            let queue = simulate(Box::new(QueueCongestionControl::new()), link, Duration::from_secs(60));
+            let delay = simulate(Box::new(DelayCongestionControl::new()), link, Duration::from_secs(60));
+
+            assert!(delay.latency < queue.latency / 2, "loss: {}, {:?} {:?}", loss, queue, delay);
+            assert!(delay.throughput > queue.throughput * 0.9, "loss: {}, {:?} {:?}", loss, queue, delay);
+        }
+    }
+}
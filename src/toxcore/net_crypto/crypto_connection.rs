@@ -4,6 +4,7 @@ use std::convert::Into;
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, Instant};
 
+use super::congestion::*;
 use super::packets_array::*;
 
 use crate::toxcore::dht::ip_port::IsGlobal;
@@ -37,31 +38,13 @@ pub const PACKET_COUNTER_AVERAGE_INTERVAL_MS: u64 = 50;
 /// The dT for the average packet receiving rate calculations.
 pub const PACKET_COUNTER_AVERAGE_INTERVAL: Duration = Duration::from_millis(PACKET_COUNTER_AVERAGE_INTERVAL_MS);
 
-/// How many last sizes of `send_array` should be recorded for congestion
-/// control.
-pub const CONGESTION_QUEUE_ARRAY_SIZE: usize = 12;
-
-/// How many numbers of sent lossless packets should be recorded for congestion
-/// control. It should be bigger than `CONGESTION_QUEUE_ARRAY_SIZE` due to rtt.
-pub const CONGESTION_LAST_SENT_ARRAY_SIZE: usize = CONGESTION_QUEUE_ARRAY_SIZE * 2;
-
-/// Minimum packets rate per second.
-pub const CRYPTO_PACKET_MIN_RATE: f64 = 4.0;
-
-/// Timeout for increasing speed after congestion event.
-pub const CONGESTION_EVENT_TIMEOUT: Duration = Duration::from_secs(1);
-
-/// If the send queue grows so that it will take more than 2 seconds to send all
-/// its packet we will reduce send rate.
-pub const SEND_QUEUE_CLEARANCE_TIME: f64 = 2.0;
-
-/// Minimum packets in the send queue to reduce send rate.
-pub const CRYPTO_MIN_QUEUE_LENGTH: u32 = 64;
-
 /// Ratio of recv queue size / recv packet rate (in seconds) times
 /// the number of ms between request packets to send at that ratio.
 pub const REQUEST_PACKETS_COMPARE_CONSTANT: f64 = 0.125 * 100.0;
 
+/// Interval between sending `ConnectionInfo` snapshots to the stats sink.
+pub const CONNECTION_INFO_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Packet that should be sent every second. Depending on `ConnectionStatus` it
 /// can be `CookieRequest` or `CryptoHandshake`
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -261,6 +244,48 @@ impl<T: Into<SocketAddr> + Copy> ConnectionAddr<T> {
     }
 }
 
+/// Path that is used to send packets to the peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionPath {
+    /// Direct UDP connection to the address, either IPv4 or IPv6.
+    Udp(SocketAddr),
+    /// Connection over TCP relays.
+    Tcp,
+}
+
+/// Snapshot of transport statistics of a crypto connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    /// Whether the connection is established.
+    pub established: bool,
+    /// Path that is used to send packets.
+    pub path: ConnectionPath,
+    /// Round trip time.
+    pub rtt: Duration,
+    /// Rate of receiving lossless packets.
+    pub packet_recv_rate: f64,
+    /// Estimated packets send rate.
+    pub packet_send_rate: f64,
+    /// Estimated requested packets send rate.
+    pub packet_send_rate_requested: f64,
+    /// Number of sent lossless packets that weren't confirmed by the peer yet.
+    pub send_array_len: u32,
+    /// Number of received lossless packets that can't be handled yet because
+    /// some previous packets are missing.
+    pub recv_array_len: u32,
+    /// Number of packets that weren't sent yet because of the send rate limit.
+    pub packets_unsent: u32,
+    /// Total number of sent lossless packets. It does not include resent
+    /// packets.
+    pub packets_sent: u64,
+    /// Total number of resent lossless packets.
+    pub packets_resent: u64,
+    /// Total number of received lossless packets.
+    pub packets_received: u64,
+    /// Name of the congestion control algorithm.
+    pub congestion_control: &'static str,
+}
+
 /** Secure connection to send data between two friends that provides encryption,
 ordered delivery, and perfect forward secrecy.
 
@@ -295,6 +320,8 @@ pub struct CryptoConnection {
     pub rtt: Duration,
     /// Time when the last request packet was sent.
     pub request_packet_sent_time: Option<Instant>,
+    /// Time when the last `ConnectionInfo` snapshot was sent to the stats sink.
+    pub info_sent_time: Option<Instant>,
 
     // Stats for congestion control
 
@@ -306,23 +333,31 @@ pub struct CryptoConnection {
     pub packets_sent: u32,
     /// Number of resent lossless packets.
     pub packets_resent: u32,
-    /// Current position in `last_send_array_sizes` and `last_num_packets` arrays.
-    pub last_sendqueue_counter: u32,
-    /// Last sizes of `send_array`.
-    pub last_send_array_sizes: [u32; CONGESTION_QUEUE_ARRAY_SIZE],
-    /// Last sent packets counts.
-    pub last_num_packets_sent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
-    /// Last resent packets counts.
-    pub last_num_packets_resent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
-    /// Congestion event is a time when we couldn't send all packets due to
-    /// slow connection.
-    pub last_congestion_event: Option<Instant>,
+    /// Total number of received lossless packets before the last rate
+    /// calculation.
+    pub total_packets_received: u64,
+    /// Total number of sent lossless packets before the last rate calculation.
+    pub total_packets_sent: u64,
+    /// Total number of resent lossless packets before the last rate
+    /// calculation.
+    pub total_packets_resent: u64,
     /// Rate of receiving lossless packets.
     pub packet_recv_rate: f64,
     /// Estimated packets send rate.
     pub packet_send_rate: f64,
     /// Estimated requested packets send rate.
     pub packet_send_rate_requested: f64,
+    /// Number of new lossless packets that can be sent according to
+    /// `packet_send_rate`.
+    pub packets_left: f64,
+    /// Number of requested lossless packets that can be resent according to
+    /// `packet_send_rate_requested`.
+    pub packets_left_requested: f64,
+    /// Number of packets at the end of `send_array` that weren't sent yet
+    /// because of the send rate limit.
+    pub packets_unsent: u32,
+    /// Algorithm that calculates send rates.
+    pub congestion_control: Box<dyn CongestionControl>,
 }
 
 impl CryptoConnection {
@@ -355,18 +390,21 @@ impl CryptoConnection {
             recv_array: PacketsArray::new(),
             rtt: DEFAULT_RTT,
             request_packet_sent_time: None,
+            info_sent_time: None,
             stats_calculation_time: clock_now(),
             packets_received: 0,
             packets_sent: 0,
             packets_resent: 0,
-            last_sendqueue_counter: 0,
-            last_send_array_sizes: [0; CONGESTION_QUEUE_ARRAY_SIZE],
-            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
-            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
-            last_congestion_event: None,
+            total_packets_received: 0,
+            total_packets_sent: 0,
+            total_packets_resent: 0,
             packet_recv_rate: 0.0,
             packet_send_rate: CRYPTO_PACKET_MIN_RATE,
             packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            packets_left: f64::from(CRYPTO_MIN_QUEUE_LENGTH),
+            packets_left_requested: f64::from(CRYPTO_MIN_QUEUE_LENGTH),
+            packets_unsent: 0,
+            congestion_control: Box::new(QueueCongestionControl::new()),
         }
     }
 
@@ -414,18 +452,21 @@ impl CryptoConnection {
             recv_array: PacketsArray::new(),
             rtt: DEFAULT_RTT,
             request_packet_sent_time: None,
+            info_sent_time: None,
             stats_calculation_time: clock_now(),
             packets_received: 0,
             packets_sent: 0,
             packets_resent: 0,
-            last_sendqueue_counter: 0,
-            last_send_array_sizes: [0; CONGESTION_QUEUE_ARRAY_SIZE],
-            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
-            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
-            last_congestion_event: None,
+            total_packets_received: 0,
+            total_packets_sent: 0,
+            total_packets_resent: 0,
             packet_recv_rate: 0.0,
             packet_send_rate: CRYPTO_PACKET_MIN_RATE,
             packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            packets_left: f64::from(CRYPTO_MIN_QUEUE_LENGTH),
+            packets_left_requested: f64::from(CRYPTO_MIN_QUEUE_LENGTH),
+            packets_unsent: 0,
+            congestion_control: Box::new(QueueCongestionControl::new()),
         }
     }
 
@@ -539,85 +580,38 @@ impl CryptoConnection {
         self.packet_recv_rate = f64::from(self.packets_received) / (dt.as_secs() as f64 + f64::from(dt.subsec_millis()) / 1000.0);
     }
 
-    /// Calculate packets send rate.
+    /// Calculate packets send rates using congestion controller and refill
+    /// the numbers of packets that can be sent.
     fn calculate_send_rate(&mut self, now: Instant) {
-        let pos = self.last_sendqueue_counter as usize % CONGESTION_QUEUE_ARRAY_SIZE;
-        let n_p_pos = self.last_sendqueue_counter as usize % CONGESTION_LAST_SENT_ARRAY_SIZE;
-        self.last_sendqueue_counter = (self.last_sendqueue_counter + 1) %
-            // divide by the common multiple to prevent overflow
-            (CONGESTION_QUEUE_ARRAY_SIZE * CONGESTION_LAST_SENT_ARRAY_SIZE) as u32;
-
-        let send_array_len = self.send_array.len();
-
-        self.last_send_array_sizes[pos] = send_array_len;
-        self.last_num_packets_sent[n_p_pos] = self.packets_sent;
-        self.last_num_packets_resent[n_p_pos] = self.packets_resent;
-
-        // How changed the size of send_array per CONGESTION_QUEUE_ARRAY_SIZE * PACKET_COUNTER_AVERAGE_INTERVAL interval
-        let sum = send_array_len as i32 - self.last_send_array_sizes[(pos + 1) % CONGESTION_QUEUE_ARRAY_SIZE] as i32;
-
-        // The maximum allowed delay
-        const CONGESTION_MAX_DELAY: usize = CONGESTION_LAST_SENT_ARRAY_SIZE - CONGESTION_QUEUE_ARRAY_SIZE;
-
-        // Based on rtt offset in number of positions for last_num_packets arrays (one position equals 50 ms)
-        let delay = ((
-            self.rtt.as_secs() * 1000 +
-                u64::from(self.rtt.subsec_millis()) +
-                PACKET_COUNTER_AVERAGE_INTERVAL_MS / 2 // add half of the interval to make delay rounded
-        ) / PACKET_COUNTER_AVERAGE_INTERVAL_MS) as usize;
-        let delay = delay.min(CONGESTION_MAX_DELAY);
-
-        // Total number of sent packets per CONGESTION_QUEUE_ARRAY_SIZE * PACKET_COUNTER_AVERAGE_INTERVAL interval
-        // For instance if the delay is 3 elements marked with '+' will be taken ('x' is the current pos):
-        // ...++++++++++++..x......
-        let mut total_sent = 0;
-        let mut total_resent = 0;
-        for i in 0 .. CONGESTION_QUEUE_ARRAY_SIZE {
-            let i = (n_p_pos + (CONGESTION_MAX_DELAY - delay) + i) % CONGESTION_LAST_SENT_ARRAY_SIZE;
-            total_sent += self.last_num_packets_sent[i] as i32;
-            total_resent += self.last_num_packets_resent[i] as i32;
-        }
-
-        if sum > 0 {
-            // send_array increased i.e. we sent more packets that was delivered
-            // decrease total_sent packets by this number so that it includes only delivered packets
-            total_sent -= sum;
-        } else if total_resent > -sum {
-            // send_array decreased and not all resent packets were delivered
-            // use this number to count only delivered packets
-            total_resent = -sum;
-        }
-
-        // Average number of successfully delivered packets per second
-        let coeff = 1000.0 / (CONGESTION_QUEUE_ARRAY_SIZE as f64 * PACKET_COUNTER_AVERAGE_INTERVAL_MS as f64);
-        let min_speed = (f64::from(total_sent) * coeff).max(CRYPTO_PACKET_MIN_RATE);
-        let min_speed_request = f64::from(total_sent + total_resent) * coeff;
-
-        // Time necessary to send all packets from send queue
-        let send_array_time = f64::from(send_array_len) / min_speed;
-
-        // And, finally, estimated packets send rate
-        let packet_send_rate = if send_array_time > SEND_QUEUE_CLEARANCE_TIME && send_array_len > CRYPTO_MIN_QUEUE_LENGTH {
-            // It will take more than SEND_QUEUE_CLEARANCE_TIME seconds to send
-            // all packets from send queue. Reduce packets send rate in this case
-            min_speed / (send_array_time / SEND_QUEUE_CLEARANCE_TIME)
-        } else if self.last_congestion_event.map_or(true, |time| (now - time) > CONGESTION_EVENT_TIMEOUT) {
-            // Congestion event happened long ago so increase packets send rate
-            min_speed * 1.2
-        } else {
-            // Congestion event happened recently so decrease packets send rate
-            min_speed * 0.9
+        let dt = now - self.stats_calculation_time;
+        let stats = CongestionStats {
+            now,
+            dt,
+            send_array_len: self.send_array.len(),
+            packets_sent: self.packets_sent,
+            packets_resent: self.packets_resent,
+            rtt: self.rtt,
         };
-        let packet_send_rate = packet_send_rate.max(CRYPTO_PACKET_MIN_RATE);
-        let packet_send_rate_requested = min_speed_request * 1.2;
-        let packet_send_rate_requested = packet_send_rate_requested.max(packet_send_rate);
+        self.congestion_control.update(&stats);
+
+        self.packet_send_rate = self.congestion_control.send_rate();
+        self.packet_send_rate_requested = self.congestion_control.send_rate_requested();
+        self.packets_left = refill_packets_left(self.packets_left, self.packet_send_rate, dt);
+        self.packets_left_requested = refill_packets_left(self.packets_left_requested, self.packet_send_rate_requested, dt);
+    }
 
-        self.packet_send_rate = packet_send_rate;
-        self.packet_send_rate_requested = packet_send_rate_requested;
+    /// Set algorithm that calculates send rates.
+    pub fn set_congestion_control(&mut self, congestion_control: Box<dyn CongestionControl>) {
+        self.packet_send_rate = congestion_control.send_rate();
+        self.packet_send_rate_requested = congestion_control.send_rate_requested();
+        self.congestion_control = congestion_control;
     }
 
     /// Reset congestion counters after they were used for stats calculation.
     fn reset_congestion_counters(&mut self, now: Instant) {
+        self.total_packets_received += u64::from(self.packets_received);
+        self.total_packets_sent += u64::from(self.packets_sent);
+        self.total_packets_resent += u64::from(self.packets_resent);
         self.packets_received = 0;
         self.packets_sent = 0;
         self.packets_resent = 0;
@@ -659,6 +653,34 @@ impl CryptoConnection {
             _ => false,
         }
     }
+
+    /// Get the path that is used to send packets. Packets are sent via TCP
+    /// relays when direct UDP connection is dead.
+    pub fn path(&self) -> ConnectionPath {
+        match self.get_udp_addr() {
+            Some(addr) if self.is_udp_alive() => ConnectionPath::Udp(addr),
+            _ => ConnectionPath::Tcp,
+        }
+    }
+
+    /// Get snapshot of transport statistics of this connection.
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            established: self.is_established(),
+            path: self.path(),
+            rtt: self.rtt,
+            packet_recv_rate: self.packet_recv_rate,
+            packet_send_rate: self.packet_send_rate,
+            packet_send_rate_requested: self.packet_send_rate_requested,
+            send_array_len: self.send_array.len(),
+            recv_array_len: self.recv_array.len(),
+            packets_unsent: self.packets_unsent,
+            packets_sent: self.total_packets_sent + u64::from(self.packets_sent),
+            packets_resent: self.total_packets_resent + u64::from(self.packets_resent),
+            packets_received: self.total_packets_received + u64::from(self.packets_received),
+            congestion_control: self.congestion_control.name(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -819,6 +841,9 @@ mod tests {
         assert_eq!(connection.packets_received, 0);
         assert_eq!(connection.packets_sent, 0);
         assert_eq!(connection.packets_resent, 0);
+        assert_eq!(connection.total_packets_received, 300);
+        assert_eq!(connection.total_packets_sent, 200);
+        assert_eq!(connection.total_packets_resent, 100);
         assert_eq!(connection.stats_calculation_time, next_now);
         // on windows instant stores floating point numbers internally
         // error in 1 ms gives 123 packets/s error
@@ -1073,4 +1098,83 @@ mod tests {
 
         assert_eq!(connection.get_udp_addr(), None);
     }
+
+    #[test]
+    fn path_udp() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "[2606::1111]:12345".parse().unwrap();
+
+        connection.set_udp_addr(addr);
+
+        assert_eq!(connection.path(), ConnectionPath::Udp(addr));
+    }
+
+    #[test]
+    fn path_tcp() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        assert_eq!(connection.path(), ConnectionPath::Tcp);
+
+        let addr = "1.2.3.4:12345".parse().unwrap();
+
+        connection.set_udp_addr(addr);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(Instant::now() + UDP_DIRECT_TIMEOUT + Duration::from_secs(1)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert_eq!(connection.path(), ConnectionPath::Tcp);
+        });
+    }
+
+    #[test]
+    fn info() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "1.2.3.4:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        connection.rtt = Duration::from_millis(42);
+        connection.send_array.buffer_end = 7;
+        connection.recv_array.buffer_end = 3;
+        connection.packets_unsent = 2;
+        connection.packets_sent = 5;
+        connection.packets_resent = 1;
+        connection.packets_received = 3;
+        connection.total_packets_sent = 100;
+        connection.total_packets_resent = 10;
+        connection.total_packets_received = 50;
+
+        let info = connection.info();
+
+        assert!(!info.established);
+        assert_eq!(info.path, ConnectionPath::Udp(addr));
+        assert_eq!(info.rtt, Duration::from_millis(42));
+        assert_eq!(info.send_array_len, 7);
+        assert_eq!(info.recv_array_len, 3);
+        assert_eq!(info.packets_unsent, 2);
+        assert_eq!(info.packets_sent, 105);
+        assert_eq!(info.packets_resent, 11);
+        assert_eq!(info.packets_received, 53);
+        assert_eq!(info.congestion_control, "queue");
+    }
 }
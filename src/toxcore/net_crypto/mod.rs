@@ -10,15 +10,16 @@ TCP relays.
 
 */
 
+mod congestion;
 mod crypto_connection;
 mod packets_array;
 pub mod errors;
 
+pub use self::congestion::*;
 pub use self::crypto_connection::*;
 use self::packets_array::*;
 use self::errors::*;
 
-use std::collections::{HashMap, HashSet};
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -37,8 +38,10 @@ use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::packet::*;
 use crate::toxcore::dht::precomputed_cache::*;
+use crate::toxcore::events::*;
 use crate::toxcore::io_tokio::*;
 use crate::toxcore::time::*;
+use crate::toxcore::utils::{HashMap, HashSet};
 
 /// Maximum size of `Packet` when we try to send it to UDP address even if
 /// it's considered dead.
@@ -87,6 +90,14 @@ type LosslessTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
 /// packet.
 type LossyTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
 
+/// Shorthand for the transmit half of the message channel for sending
+/// `ConnectionInfo` snapshots. The key is a long term public key of the peer.
+type ConnectionInfoTx = mpsc::UnboundedSender<(PublicKey, ConnectionInfo)>;
+
+/// Shorthand for the function that creates congestion controllers for new
+/// connections.
+type CongestionControlFactory = Arc<dyn Fn() -> Box<dyn CongestionControl> + Send + Sync>;
+
 /// Arguments for creating new `NetCrypto`.
 #[derive(Clone)]
 pub struct NetCryptoNewArgs {
@@ -137,6 +148,11 @@ pub struct NetCrypto {
     /// Sink to send lossy packets. The key is a long term public key of the
     /// peer that sent this packet.
     lossy_tx: LossyTx,
+    /// Sink to send events about internal state changes.
+    event_tx: Option<EventTx>,
+    /// Sink to periodically send `ConnectionInfo` snapshots of established
+    /// connections.
+    connection_info_tx: Option<ConnectionInfoTx>,
     /// Our DHT `PublicKey`
     dht_pk: PublicKey,
     /// Our DHT `SecretKey`
@@ -158,6 +174,8 @@ pub struct NetCrypto {
     /// Lru cache for precomputed keys. It stores precomputed keys to avoid
     /// redundant calculations.
     precomputed_keys: PrecomputedCache,
+    /// Function that creates congestion controllers for new connections.
+    congestion_control: CongestionControlFactory,
 }
 
 impl NetCrypto {
@@ -168,15 +186,18 @@ impl NetCrypto {
             dht_pk_tx: args.dht_pk_tx,
             lossless_tx: args.lossless_tx,
             lossy_tx: args.lossy_tx,
+            event_tx: None,
+            connection_info_tx: None,
             dht_pk: args.dht_pk,
             dht_sk: args.dht_sk,
             real_pk: args.real_pk,
             real_sk: args.real_sk,
             symmetric_key: secretbox::gen_key(),
-            friends: Arc::new(RwLock::new(HashSet::new())),
-            connections: Arc::new(RwLock::new(HashMap::new())),
-            keys_by_addr: Arc::new(RwLock::new(HashMap::new())),
+            friends: Arc::new(RwLock::new(HashSet::default())),
+            connections: Arc::new(RwLock::new(HashMap::default())),
+            keys_by_addr: Arc::new(RwLock::new(HashMap::default())),
             precomputed_keys: args.precomputed_keys,
+            congestion_control: Arc::new(|| Box::new(QueueCongestionControl::new()) as Box<dyn CongestionControl>),
         }
     }
 
@@ -190,6 +211,35 @@ impl NetCrypto {
         self.friends.write().remove(&real_pk);
     }
 
+    /// Set sink to send events about internal state changes.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Set sink to send `ConnectionInfo` snapshots of established connections
+    /// every `CONNECTION_INFO_INTERVAL`.
+    pub fn set_connection_info_sink(&mut self, connection_info_tx: ConnectionInfoTx) {
+        self.connection_info_tx = Some(connection_info_tx);
+    }
+
+    /// Set function that creates congestion controllers for new connections.
+    /// By default `QueueCongestionControl` is used.
+    pub fn set_congestion_control<F>(&mut self, congestion_control: F)
+        where F: Fn() -> Box<dyn CongestionControl> + Send + Sync + 'static
+    {
+        self.congestion_control = Arc::new(congestion_control);
+    }
+
+    /// Get the number of crypto connections to friends.
+    pub fn connections_count(&self) -> usize {
+        self.connections.read().len()
+    }
+
+    /// Get snapshot of transport statistics of the connection to a friend.
+    pub fn connection_info(&self, real_pk: PublicKey) -> Option<ConnectionInfo> {
+        self.connections.read().get(&real_pk).map(|connection| connection.read().info())
+    }
+
     /// Add connection to a friend when its DHT `PublicKey` is known.
     pub fn add_connection(&self, peer_real_pk: PublicKey, peer_dht_pk: PublicKey) {
         let mut connections = self.connections.write();
@@ -199,13 +249,14 @@ impl NetCrypto {
         }
 
         let dht_precomputed_key = precompute(&peer_dht_pk, &self.dht_sk);
-        let connection = CryptoConnection::new(
+        let mut connection = CryptoConnection::new(
             &dht_precomputed_key,
             self.dht_pk,
             self.real_pk,
             peer_real_pk,
             peer_dht_pk
         );
+        connection.set_congestion_control((self.congestion_control)());
         let connection = Arc::new(RwLock::new(connection));
         connections.insert(peer_real_pk, connection);
     }
@@ -229,6 +280,7 @@ impl NetCrypto {
         if let Some(connection) = self.connections.write().remove(&real_pk) {
             let mut connection = connection.write();
             self.clear_keys_by_addr(&connection);
+            emit(&self.event_tx, Event::CryptoConnectionLost { real_pk });
             if connection.is_established() || connection.is_not_confirmed() {
                 let packet_number = connection.send_array.buffer_end;
                 Either::A(self.send_data_packet(&mut connection, vec![PACKET_ID_KILL], packet_number)
@@ -278,10 +330,16 @@ impl NetCrypto {
             let packet_number = connection.send_array.buffer_end;
             if let Err(e) = connection.send_array.push_back(SentPacket::new(packet.clone())) {
                 Either::B(future::err(e.context(SendLosslessPacketErrorKind::FullSendArray).into()))
-            } else {
+            } else if connection.packets_unsent == 0 && connection.packets_left >= 1.0 {
+                connection.packets_left -= 1.0;
                 connection.packets_sent += 1;
                 Either::A(self.send_data_packet(&mut connection, packet, packet_number)
                     .map_err(|e| e.context(SendLosslessPacketErrorKind::SendTo).into()))
+            } else {
+                // The send rate limit is reached so the packet will be sent
+                // later by the main loop
+                connection.packets_unsent += 1;
+                Either::B(future::ok(()))
             }
         } else {
             Either::B(future::err(SendLosslessPacketErrorKind::NoConnection.into()))
@@ -428,6 +486,7 @@ impl NetCrypto {
             return Box::new(future::err(HandlePacketError::from(HandlePacketErrorKind::InvalidRealPk)))
         }
         if cookie.dht_pk != connection.peer_dht_pk {
+            emit(&self.event_tx, Event::FriendDhtPkLearned { real_pk: connection.peer_real_pk, dht_pk: cookie.dht_pk });
             return Box::new(
                 send_to(&self.dht_pk_tx, (connection.peer_real_pk, cookie.dht_pk))
                     .map_err(|e| e.context(HandlePacketErrorKind::SendToDhtpk).into())
@@ -491,6 +550,7 @@ impl NetCrypto {
             payload.cookie,
             &self.symmetric_key,
         );
+        connection.set_congestion_control((self.congestion_control)());
         if let Some(addr) = addr {
             connection.set_udp_addr(addr);
             self.keys_by_addr.write().insert((addr.ip(), addr.port()), cookie.real_pk);
@@ -498,6 +558,8 @@ impl NetCrypto {
         let connection = Arc::new(RwLock::new(connection));
         self.connections.write().insert(cookie.real_pk, connection);
 
+        emit(&self.event_tx, Event::FriendDhtPkLearned { real_pk: cookie.real_pk, dht_pk: cookie.dht_pk });
+
         Either::B(send_to(&self.dht_pk_tx, (cookie.real_pk, cookie.dht_pk))
             .map_err(|e| e.context(HandlePacketErrorKind::SendToDhtpk).into()))
     }
@@ -662,6 +724,7 @@ impl NetCrypto {
         if let Err(e) = connection.send_array.set_buffer_start(payload.buffer_start) {
             return Box::new(future::err(e.context(HandlePacketErrorKind::PacketsArrayError).into()))
         }
+        connection.packets_unsent = connection.packets_unsent.min(connection.send_array.len());
 
         // And get the ID of the packet
         let packet_id = match payload.data.first() {
@@ -673,6 +736,7 @@ impl NetCrypto {
             // Kill the connection
             self.connections.write().remove(&connection.peer_real_pk);
             self.clear_keys_by_addr(&connection);
+            emit(&self.event_tx, Event::CryptoConnectionLost { real_pk: connection.peer_real_pk });
             return Box::new(future::ok(()));
         }
 
@@ -682,6 +746,9 @@ impl NetCrypto {
         }
 
         // TODO: connection status notification
+        if !connection.is_established() {
+            emit(&self.event_tx, Event::CryptoConnectionEstablished { real_pk: connection.peer_real_pk });
+        }
 
         connection.status = ConnectionStatus::Established {
             sent_nonce,
@@ -719,6 +786,7 @@ impl NetCrypto {
         if let Some(last_sent_time) = last_sent_time {
             // Update rtt if it's become lower
             let elapsed = clock_elapsed(last_sent_time);
+            connection.congestion_control.on_rtt_sample(elapsed);
             if elapsed < connection.rtt {
                 connection.rtt = elapsed;
             }
@@ -809,16 +877,40 @@ impl NetCrypto {
         self.send_data_packet(connection, data, packet_number)
     }
 
-    /// Send packets that were requested.
+    /// Send packets that were requested and packets that weren't sent yet
+    /// because of the send rate limit. The number of sent packets is limited
+    /// by the send rates calculated by congestion controller.
     fn send_requested_packets(&self, connection: &mut CryptoConnection) -> impl Future<Item = (), Error = SendDataError> + Send {
         let now = clock_now();
-        let packets = connection.send_array.iter_mut()
-            .filter(|(_, packet)| packet.requested)
-            .map(|(i, packet)| {
+        let buffer_end = connection.send_array.buffer_end;
+        let packets_unsent = connection.packets_unsent;
+        let mut packets = Vec::new();
+
+        for (i, packet) in connection.send_array.iter_mut() {
+            if connection.packets_left_requested < 1.0 || buffer_end.overflowing_sub(i).0 <= packets_unsent {
+                break;
+            }
+            if packet.requested {
+                packet.requested = false;
+                packet.sent_time = now;
+                connection.packets_left_requested -= 1.0;
+                connection.packets_resent += 1;
+                packets.push((i, packet.data.clone()));
+            }
+        }
+
+        while connection.packets_unsent > 0 && connection.packets_left >= 1.0 {
+            let i = buffer_end.overflowing_sub(connection.packets_unsent).0;
+            connection.packets_unsent -= 1;
+            if let Some(packet) = connection.send_array.get_mut(i) {
                 packet.requested = false;
                 packet.sent_time = now;
-                (i, packet.data.clone())
-            }).collect::<Vec<_>>();
+                connection.packets_left -= 1.0;
+                connection.packets_sent += 1;
+                packets.push((i, packet.data.clone()));
+            }
+        }
+
         let futures = packets.into_iter().map(|(i, data)|
             self.send_data_packet(connection, data, i)
         ).collect::<Vec<_>>();
@@ -826,7 +918,7 @@ impl NetCrypto {
     }
 
     /// The main loop that should be run at least 20 times per second
-    fn main_loop(&self) -> impl Future<Item = (), Error = SendDataError> + Send {
+    pub(crate) fn main_loop(&self) -> impl Future<Item = (), Error = SendDataError> + Send {
         let mut connections = self.connections.write();
         let mut keys_by_addr = self.keys_by_addr.write();
         let mut futures: Vec<Box<dyn Future<Item = _, Error = _> + Send>> = Vec::new();
@@ -848,6 +940,8 @@ impl NetCrypto {
                     futures.push(Box::new(self.send_data_packet(&mut connection, vec![PACKET_ID_KILL], packet_number)));
                 }
 
+                emit(&self.event_tx, Event::CryptoConnectionLost { real_pk: connection.peer_real_pk });
+
                 return false;
             }
 
@@ -875,9 +969,21 @@ impl NetCrypto {
                     }
                 }
 
-                // TODO: either use send_rate or remove it
                 connection.update_congestion_stats();
 
+                if let Some(ref connection_info_tx) = self.connection_info_tx {
+                    let should_send = match connection.info_sent_time {
+                        Some(time) => clock_elapsed(time) >= CONNECTION_INFO_INTERVAL,
+                        None => true,
+                    };
+                    if should_send {
+                        connection.info_sent_time = Some(clock_now());
+                        if connection_info_tx.unbounded_send((connection.peer_real_pk, connection.info())).is_err() {
+                            trace!("Failed to send connection info: receiver is dropped");
+                        }
+                    }
+                }
+
                 futures.push(Box::new(self.send_requested_packets(&mut connection)));
             }
 
@@ -2418,6 +2524,78 @@ mod tests {
         assert!(net_crypto.keys_by_addr.read().is_empty());
     }
 
+    #[test]
+    fn handle_crypto_data_events() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let mut net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk: real_sk.clone(),
+            precomputed_keys,
+        });
+        net_crypto.set_event_sink(event_tx);
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let received_nonce = gen_nonce();
+        let cookie = EncryptedCookie::new(&secretbox::gen_key(), &Cookie::new(real_pk, dht_pk));
+        let connection = CryptoConnection::new_not_confirmed(
+            &real_sk,
+            peer_real_pk,
+            peer_dht_pk,
+            received_nonce,
+            peer_session_pk,
+            cookie,
+            &secretbox::gen_key()
+        );
+        let session_precomputed_key = unpack!(connection.status.clone(), ConnectionStatus::NotConfirmed, session_precomputed_key);
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        // the first packet establishes the connection
+        net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, /* udp */ true).wait().unwrap();
+        net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, /* udp */ true).wait().unwrap();
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_KILL]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, /* udp */ true).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(net_crypto);
+
+        assert_eq!(event_rx.collect().wait().unwrap(), vec![
+            Event::CryptoConnectionEstablished { real_pk: peer_real_pk },
+            Event::CryptoConnectionLost { real_pk: peer_real_pk },
+        ]);
+    }
+
     #[test]
     fn handle_crypto_data_request() {
         crypto_init().unwrap();
@@ -3233,6 +3411,76 @@ mod tests {
         assert_eq!(payload.data, data);
     }
 
+    #[test]
+    fn main_loop_sends_connection_info() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (connection_info_tx, connection_info_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let mut net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+        net_crypto.set_connection_info_sink(connection_info_tx);
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key,
+        };
+        connection.request_packet_sent_time = Some(Instant::now());
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            net_crypto.main_loop().wait().unwrap();
+            // the interval didn't pass so the second snapshot shouldn't be sent
+            net_crypto.main_loop().wait().unwrap();
+        });
+
+        // Necessary to drop connection_info_tx so that
+        // connection_info_rx.collect() can be finished
+        drop(net_crypto);
+
+        let infos = connection_info_rx.collect().wait().unwrap();
+        assert_eq!(infos.len(), 1);
+
+        let (pk, info) = infos[0].clone();
+        assert_eq!(pk, peer_real_pk);
+        assert!(info.established);
+        assert_eq!(info.path, ConnectionPath::Udp(addr));
+    }
+
     #[test]
     fn send_status_packet_established() {
         crypto_init().unwrap();
@@ -3552,6 +3800,81 @@ mod tests {
         assert_eq!(udp_rx.collect().wait().unwrap().len(), 2);
     }
 
+    #[test]
+    fn send_requested_packets_unsent() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(4);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key,
+        };
+
+        let now = Instant::now();
+        for i in 0 .. 3 {
+            connection.send_array.push_back(SentPacket {
+                data: vec![42; 123],
+                sent_time: now,
+                requested: false,
+            }).unwrap();
+            assert_eq!(connection.send_array.buffer_end, i + 1);
+        }
+        // the last 3 packets were not sent but only 2 can be sent now
+        connection.packets_unsent = 3;
+        connection.packets_left = 2.5;
+
+        let next_now = now + Duration::from_secs(1);
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(next_now));
+
+        with_default(&clock, &mut enter, |_| {
+            net_crypto.send_requested_packets(&mut connection).wait().unwrap();
+        });
+
+        assert_eq!(connection.packets_unsent, 1);
+        assert_eq!(connection.packets_sent, 2);
+        assert!((connection.packets_left - 0.5).abs() < f64::EPSILON);
+        assert_eq!(connection.send_array.get(0).unwrap().sent_time, next_now);
+        assert_eq!(connection.send_array.get(1).unwrap().sent_time, next_now);
+        assert_eq!(connection.send_array.get(2).unwrap().sent_time, now);
+
+        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
+        drop(net_crypto.udp_tx);
+
+        assert_eq!(udp_rx.collect().wait().unwrap().len(), 2);
+    }
+
     #[test]
     fn send_lossless() {
         crypto_init().unwrap();
@@ -3623,6 +3946,70 @@ mod tests {
         assert_eq!(payload.data, data);
     }
 
+    #[test]
+    fn send_lossless_rate_limited() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(2);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key,
+        };
+        connection.packets_left = 0.0;
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        let data = vec![16, 42];
+
+        net_crypto.send_lossless(peer_real_pk, data.clone()).wait().unwrap();
+
+        let connection = connection.read();
+
+        // the packet should be added to send_array but not sent
+
+        assert_eq!(connection.packets_sent, 0);
+        assert_eq!(connection.packets_unsent, 1);
+        assert_eq!(connection.send_array.buffer[0].clone().unwrap().data, data);
+
+        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
+        drop(net_crypto.udp_tx);
+
+        assert!(udp_rx.collect().wait().unwrap().is_empty());
+    }
+
     #[test]
     fn send_lossless_no_connection() {
         crypto_init().unwrap();
@@ -3718,6 +4105,76 @@ mod tests {
         assert_eq!(cookie_request_payload.pk, real_pk);
     }
 
+    #[test]
+    fn add_connection_with_congestion_control() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let mut net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+        net_crypto.set_congestion_control(|| Box::new(DelayCongestionControl::new()));
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        assert_eq!(connection.congestion_control.name(), "delay");
+    }
+
+    #[test]
+    fn connection_info() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+
+        assert_eq!(net_crypto.connection_info(peer_real_pk), None);
+
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let info = net_crypto.connection_info(peer_real_pk).unwrap();
+
+        assert!(!info.established);
+        assert_eq!(info.path, ConnectionPath::Tcp);
+        assert_eq!(info.rtt, DEFAULT_RTT);
+        assert_eq!(info.packets_sent, 0);
+    }
+
     #[test]
     fn add_connection_already_exists() {
         crypto_init().unwrap();
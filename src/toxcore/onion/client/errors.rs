@@ -64,6 +64,37 @@ error_kind! {
         #[doc = "Failed to handle DHT `PublicKey` announce."]
         #[fail(display = "Failed to handle DHT PublicKey announce")]
         DhtPkAnnounce,
+        #[doc = "No friend with PK specified in OnionDataResponse."]
+        #[fail(display = "No friend with PK specified in OnionDataResponse")]
+        NoFriendWithPk,
+        #[doc = "Invalid no_reply of custom data."]
+        #[fail(display = "Invalid no_reply of custom data")]
+        InvalidNoReply,
+        #[doc = "Send custom data error."]
+        #[fail(display = "Send custom data error")]
+        SendTo,
+    }
+}
+
+error_kind! {
+    #[doc = "Error that can happen when sending custom data to a friend via onion."]
+    #[derive(Debug)]
+    SendCustomDataError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, failure::Fail)]
+    SendCustomDataErrorKind {
+        #[doc = "No friend with such PK."]
+        #[fail(display = "No friend with such PK")]
+        NoFriendWithPk,
+        #[doc = "Data is empty or too big."]
+        #[fail(display = "Data is empty or too big")]
+        InvalidSize,
+        #[doc = "No known nodes the friend is announced to."]
+        #[fail(display = "No known nodes the friend is announced to")]
+        NoNodes,
+        #[doc = "Send packet(s) error."]
+        #[fail(display = "Send packet(s) error")]
+        SendTo,
     }
 }
 
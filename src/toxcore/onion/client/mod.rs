@@ -5,8 +5,7 @@ mod nodes_pool;
 mod onion_path;
 mod paths_pool;
 
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -24,6 +23,7 @@ use crate::toxcore::dht::packet::*;
 use crate::toxcore::dht::request_queue::RequestQueue;
 use crate::toxcore::dht::server::{Server as DhtServer};
 use crate::toxcore::dht::kbucket::*;
+use crate::toxcore::events::*;
 use crate::toxcore::io_tokio::*;
 use crate::toxcore::ip_port::*;
 use crate::toxcore::onion::client::errors::*;
@@ -34,12 +34,18 @@ use crate::toxcore::onion::packet::*;
 use crate::toxcore::packed_node::*;
 use crate::toxcore::tcp::client::{Connections as TcpConnections};
 use crate::toxcore::time::*;
+use crate::toxcore::utils::HashMap;
 
 /// Shorthand for the transmit half of the message channel for sending DHT
 /// `PublicKey` when it gets known. The first key is a long term key, the second
 /// key is a DHT key.
 type DhtPkTx = mpsc::UnboundedSender<(PublicKey, PublicKey)>;
 
+/// Shorthand for the transmit half of the message channel for sending custom
+/// data received from friends via onion. The key is a long term key of the
+/// friend.
+type CustomDataTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
+
 /// Number of friend's close nodes to store.
 const MAX_ONION_FRIEND_NODES: u8 = 8;
 
@@ -121,6 +127,11 @@ struct OnionFriend {
     /// `no_reply` from last DHT `PublicKey` announce packet used to prevent
     /// reply attacks.
     last_no_reply: u64,
+    /// `no_reply` from last `OnionCustomData` packet received from this friend
+    /// used to prevent reply attacks.
+    last_custom_data_no_reply: u64,
+    /// `no_reply` of last `OnionCustomData` packet sent to this friend.
+    sent_custom_data_no_reply: u64,
     /// Time when our DHT `PublicKey` was sent to this friend via onion last
     /// time.
     last_dht_pk_onion_sent: Option<Instant>,
@@ -131,6 +142,9 @@ struct OnionFriend {
     search_count: u32,
     /// Time when this friend was seen online last time
     last_seen: Option<Instant>,
+    /// Whether we have a crypto connection with this friend. We don't search
+    /// connected friends.
+    connected: bool,
 }
 
 impl OnionFriend {
@@ -144,14 +158,89 @@ impl OnionFriend {
             temporary_sk,
             close_nodes: Kbucket::new(MAX_ONION_FRIEND_NODES),
             last_no_reply: 0,
+            last_custom_data_no_reply: 0,
+            sent_custom_data_no_reply: 0,
             last_dht_pk_onion_sent: None,
             last_dht_pk_dht_sent: None,
             search_count: 0,
             last_seen: None,
+            connected: false,
+        }
+    }
+
+    /// Get status of this friend for diagnostics.
+    fn info(&self) -> OnionFriendInfo {
+        OnionFriendInfo {
+            real_pk: self.real_pk,
+            dht_pk: self.dht_pk,
+            connected: self.connected,
+            close_nodes_count: self.close_nodes.iter().filter(|node| !node.is_timed_out()).count(),
+            data_pk_nodes_count: self.close_nodes.iter()
+                .filter(|node| !node.is_timed_out() && node.data_pk.is_some())
+                .count(),
+            search_count: self.search_count,
+            last_seen: self.last_seen,
         }
     }
 }
 
+/// Status of a friend we are looking for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionFriendInfo {
+    /// Friend's long term `PublicKey`.
+    pub real_pk: PublicKey,
+    /// Friend's DHT `PublicKey` if it's known.
+    pub dht_pk: Option<PublicKey>,
+    /// Whether the friend is marked as connected so it's not searched.
+    pub connected: bool,
+    /// Number of alive nodes close to friend's long term `PublicKey`.
+    pub close_nodes_count: usize,
+    /// Number of alive close nodes that know friend's data `PublicKey` i.e.
+    /// the friend is announced to them.
+    pub data_pk_nodes_count: usize,
+    /// How many times we sent search requests to friend's close nodes.
+    pub search_count: u32,
+    /// Time when this friend was seen online last time.
+    pub last_seen: Option<Instant>,
+}
+
+/// Status of the pool of random onion paths.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PathsPoolInfo {
+    /// Number of nodes that can be used to build random paths.
+    pub path_nodes_count: usize,
+    /// Number of alive paths used for ourselves announcing.
+    pub self_paths_count: usize,
+    /// Number of alive paths used for friends searching.
+    pub friend_paths_count: usize,
+    /// Number of alive paths that are considered stable.
+    pub stable_paths_count: usize,
+    /// Number of nodes that can't be used to build paths because paths with
+    /// them fail consistently.
+    pub blacklisted_nodes_count: usize,
+}
+
+/// Snapshot of the onion client state for diagnostics.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionClientInfo {
+    /// Number of alive nodes we announce ourselves to.
+    pub announce_nodes_count: usize,
+    /// Number of alive nodes we are announced to.
+    pub announced_nodes_count: usize,
+    /// Status of the pool of random onion paths.
+    pub paths_pool: PathsPoolInfo,
+    /// Status of friends we are looking for.
+    pub friends: Vec<OnionFriendInfo>,
+}
+
+impl OnionClientInfo {
+    /// Check if we are announced to at least one node so friends can find
+    /// us.
+    pub fn is_announced(&self) -> bool {
+        self.announced_nodes_count > 0
+    }
+}
+
 /// Type for onion close nodes.
 #[derive(Clone, Debug)]
 struct OnionNode {
@@ -185,6 +274,12 @@ impl HasPK for OnionNode {
     }
 }
 
+impl HasIpAddrs for OnionNode {
+    fn ip_addrs(&self) -> Vec<IpAddr> {
+        vec![self.saddr.ip()]
+    }
+}
+
 impl KbucketNode for OnionNode {
     type NewNode = OnionNode;
     type CheckNode = PackedNode;
@@ -296,7 +391,7 @@ impl OnionClientState {
             paths_pool: PathsPool::new(),
             announce_list: Kbucket::new(MAX_ONION_ANNOUNCE_NODES),
             announce_requests: RequestQueue::new(ANNOUNCE_TIMEOUT),
-            friends: HashMap::new(),
+            friends: HashMap::default(),
         }
     }
 }
@@ -312,6 +407,10 @@ pub struct OnionClient {
     /// Sink to send DHT `PublicKey` when it gets known. The first key is a long
     /// term key, the second key is a DHT key.
     dht_pk_tx: DhtPkTx,
+    /// Sink to send events about internal state changes.
+    event_tx: Option<EventTx>,
+    /// Sink to send custom data received from friends.
+    custom_data_tx: Option<CustomDataTx>,
     /// Our long term `SecretKey`.
     real_sk: SecretKey,
     /// Our long term `PublicKey`.
@@ -339,6 +438,8 @@ impl OnionClient {
             dht,
             tcp_connections,
             dht_pk_tx,
+            event_tx: None,
+            custom_data_tx: None,
             real_sk,
             real_pk,
             data_sk,
@@ -347,6 +448,17 @@ impl OnionClient {
         }
     }
 
+    /// Set sink to send events about internal state changes.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.state.lock().paths_pool.set_event_sink(event_tx.clone());
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Set sink to send custom data received from friends via onion.
+    pub fn set_custom_data_sink(&mut self, custom_data_tx: CustomDataTx) {
+        self.custom_data_tx = Some(custom_data_tx);
+    }
+
     /// Check if a node was pinged recently.
     fn is_pinged_recently(&self, pk: PublicKey, search_pk: PublicKey, request_queue: &RequestQueue<AnnounceRequestData>) -> bool {
         let check_pks = |data: &AnnounceRequestData| -> bool {
@@ -492,6 +604,9 @@ impl OnionClient {
         }
 
         friend.last_no_reply = dht_pk_announce.no_reply;
+        if friend.dht_pk != Some(dht_pk_announce.dht_pk) {
+            emit(&self.event_tx, Event::FriendDhtPkLearned { real_pk: friend_pk, dht_pk: dht_pk_announce.dht_pk });
+        }
         friend.dht_pk = Some(dht_pk_announce.dht_pk);
         friend.last_seen = Some(clock_now());
 
@@ -530,10 +645,65 @@ impl OnionClient {
                 Either::B(self.handle_dht_pk_announce(payload.real_pk, dht_pk_announce)
                     .map_err(|e| e.context(HandleDataResponseErrorKind::DhtPkAnnounce).into())),
             OnionDataResponseInnerPayload::FriendRequest(_) =>
-                Either::A(future::ok(()))
+                Either::A(future::ok(())),
+            OnionDataResponseInnerPayload::CustomData(custom_data) =>
+                Either::A(self.handle_custom_data(payload.real_pk, custom_data)),
         }
     }
 
+    /// Handle custom data received from a friend via onion.
+    fn handle_custom_data(&self, friend_pk: PublicKey, custom_data: OnionCustomData) -> future::FutureResult<(), HandleDataResponseError> {
+        let mut state = self.state.lock();
+
+        let friend = match state.friends.get_mut(&friend_pk) {
+            Some(friend) => friend,
+            None => return future::err(HandleDataResponseErrorKind::NoFriendWithPk.into()),
+        };
+
+        if custom_data.no_reply <= friend.last_custom_data_no_reply {
+            return future::err(HandleDataResponseErrorKind::InvalidNoReply.into());
+        }
+
+        friend.last_custom_data_no_reply = custom_data.no_reply;
+
+        if let Some(ref custom_data_tx) = self.custom_data_tx {
+            if custom_data_tx.unbounded_send((friend_pk, custom_data.data)).is_err() {
+                return future::err(HandleDataResponseErrorKind::SendTo.into());
+            }
+        }
+
+        future::ok(())
+    }
+
+    /** Send application-defined data to a friend via onion. Data is sent
+    through all known nodes the friend is announced to so it can be delivered
+    without established crypto connection. Delivery is not guaranteed.
+    */
+    pub fn send_custom_data(&self, friend_pk: PublicKey, data: Vec<u8>) -> impl Future<Item = (), Error = SendCustomDataError> + Send {
+        if data.is_empty() || data.len() > MAX_ONION_CUSTOM_DATA_SIZE {
+            return Either::A(future::err(SendCustomDataErrorKind::InvalidSize.into()));
+        }
+
+        let state = &mut *self.state.lock();
+
+        let friend = match state.friends.get_mut(&friend_pk) {
+            Some(friend) => friend,
+            None => return Either::A(future::err(SendCustomDataErrorKind::NoFriendWithPk.into())),
+        };
+
+        let custom_data = OnionCustomData::new(data, friend.sent_custom_data_no_reply);
+        friend.sent_custom_data_no_reply = custom_data.no_reply;
+        let inner_payload = OnionDataResponseInnerPayload::CustomData(custom_data);
+        let packets = self.send_onion_data(friend, &mut state.paths_pool, &inner_payload);
+
+        if packets.is_empty() {
+            return Either::A(future::err(SendCustomDataErrorKind::NoNodes.into()));
+        }
+
+        Either::B(send_all_to(&self.dht.tx, stream::iter_ok(packets))
+            .map_err(|e| e.context(SendCustomDataErrorKind::SendTo).into()))
+    }
+
     /// Add new node to random nodes pool to use them to build random paths.
     pub fn add_path_node(&self, node: PackedNode) {
         let mut state = self.state.lock();
@@ -541,13 +711,58 @@ impl OnionClient {
         state.paths_pool.path_nodes.put(node);
     }
 
-    /// Add new node to random nodes pool to use them to build random paths.
+    /// Add friend to the list of friends we are looking for.
     pub fn add_friend(&self, real_pk: PublicKey) {
         let mut state = self.state.lock();
 
         state.friends.insert(real_pk, OnionFriend::new(real_pk));
     }
 
+    /// Remove friend from the list of friends we are looking for.
+    pub fn remove_friend(&self, real_pk: PublicKey) {
+        let mut state = self.state.lock();
+
+        state.friends.remove(&real_pk);
+    }
+
+    /** Set connection status of a friend. Connected friends are not searched
+    and our DHT `PublicKey` is not sent to them. When the connection is lost
+    the friend is considered seen at this moment and searching starts over.
+    */
+    pub fn set_friend_connected(&self, real_pk: PublicKey, connected: bool) {
+        let mut state = self.state.lock();
+
+        let friend = match state.friends.get_mut(&real_pk) {
+            Some(friend) => friend,
+            None => return,
+        };
+
+        if friend.connected && !connected {
+            friend.last_seen = Some(clock_now());
+            friend.last_no_reply = 0;
+            friend.search_count = 0;
+        }
+
+        friend.connected = connected;
+    }
+
+    /// Get snapshot of the onion client state for diagnostics.
+    pub fn info(&self) -> OnionClientInfo {
+        let state = self.state.lock();
+
+        let mut friends = state.friends.values().map(OnionFriend::info).collect::<Vec<_>>();
+        friends.sort_by_key(|friend| friend.real_pk);
+
+        OnionClientInfo {
+            announce_nodes_count: state.announce_list.iter().filter(|node| !node.is_timed_out()).count(),
+            announced_nodes_count: state.announce_list.iter()
+                .filter(|node| !node.is_timed_out() && node.announce_status == AnnounceStatus::Announced)
+                .count(),
+            paths_pool: state.paths_pool.info(),
+            friends,
+        }
+    }
+
     /// Generic function for sending search and announce requests to close nodes.
     fn ping_close_nodes(
         close_nodes: &mut Kbucket<OnionNode>,
@@ -691,8 +906,21 @@ impl OnionClient {
     fn send_dht_pk_onion(&self, friend: &mut OnionFriend, paths_pool: &mut PathsPool) -> Vec<(Packet, SocketAddr)> {
         let dht_pk_announce = DhtPkAnnouncePayload::new(self.dht.pk, self.dht_pk_nodes());
         let inner_payload = OnionDataResponseInnerPayload::DhtPkAnnounce(dht_pk_announce);
+
+        let packets = self.send_onion_data(friend, paths_pool, &inner_payload);
+
+        if !packets.is_empty() {
+            friend.last_dht_pk_onion_sent = Some(clock_now());
+        }
+
+        packets
+    }
+
+    /// Create onion data packets for all known nodes a friend is announced
+    /// to.
+    fn send_onion_data(&self, friend: &OnionFriend, paths_pool: &mut PathsPool, inner_payload: &OnionDataResponseInnerPayload) -> Vec<(Packet, SocketAddr)> {
         let nonce = gen_nonce();
-        let payload = OnionDataResponsePayload::new(&precompute(&friend.real_pk, &self.real_sk), self.real_pk, &nonce, &inner_payload);
+        let payload = OnionDataResponsePayload::new(&precompute(&friend.real_pk, &self.real_sk), self.real_pk, &nonce, inner_payload);
 
         let mut packets = Vec::new();
 
@@ -721,10 +949,6 @@ impl OnionClient {
             packets.push((Packet::OnionRequest0(onion_request), path.nodes[0].saddr));
         }
 
-        if !packets.is_empty() {
-            friend.last_dht_pk_onion_sent = Some(clock_now());
-        }
-
         packets
     }
 
@@ -756,7 +980,9 @@ impl OnionClient {
         let mut packets = Vec::new();
 
         for friend in state.friends.values_mut() {
-            // TODO: if is_online
+            if friend.connected {
+                continue;
+            }
 
             let announce_packet_data = AnnouncePacketData {
                 packet_sk: &friend.temporary_sk,
@@ -812,6 +1038,16 @@ impl OnionClient {
         }
     }
 
+    /// Populate nodes pool, announce ourselves and search friends. Should be
+    /// called every second.
+    pub(crate) fn main_loop(&self) -> impl Future<Item = (), Error = RunError> + Send {
+        let mut state = self.state.lock();
+        self.populate_path_nodes(&mut state);
+        let announce_future = self.announce_loop(&mut state);
+        let friends_future = self.friends_loop(&mut state);
+        announce_future.join(friends_future).map(|_| ())
+    }
+
     /// Run periodical announcements and friends searching.
     pub fn run(self) -> impl Future<Item = (), Error = RunError> + Send {
         let interval = Duration::from_secs(1);
@@ -820,11 +1056,7 @@ impl OnionClient {
             .map_err(|e| e.context(RunErrorKind::Wakeup).into())
             .for_each(move |_instant| {
                 trace!("Onion client sender wake up");
-                let mut state = self.state.lock();
-                self.populate_path_nodes(&mut state);
-                let announce_future = self.announce_loop(&mut state);
-                let friends_future = self.friends_loop(&mut state);
-                announce_future.join(friends_future).map(|_| ())
+                self.main_loop()
             })
     }
 }
@@ -918,7 +1150,7 @@ mod tests {
             paths_pool: PathsPool::new(),
             announce_list: Kbucket::new(8),
             announce_requests: RequestQueue::new(Duration::from_secs(42)),
-            friends: HashMap::new(),
+            friends: HashMap::default(),
         };
 
         let _onion_client_state_c = onion_client_state.clone();
@@ -1081,6 +1313,126 @@ mod tests {
         assert_eq!(state.friends[&friend_pk].real_pk, friend_pk);
     }
 
+    #[test]
+    fn remove_friend() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, dht_pk_tx, real_sk, real_pk);
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        onion_client.add_friend(friend_pk);
+        onion_client.remove_friend(friend_pk);
+
+        let state = onion_client.state.lock();
+        assert!(!state.friends.contains_key(&friend_pk));
+    }
+
+    #[test]
+    fn set_friend_connected() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, dht_pk_tx, real_sk, real_pk);
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let mut friend = OnionFriend::new(friend_pk);
+        friend.last_no_reply = 42;
+        friend.search_count = 42;
+        onion_client.state.lock().friends.insert(friend_pk, friend);
+
+        onion_client.set_friend_connected(friend_pk, true);
+
+        {
+            let state = onion_client.state.lock();
+            let friend = &state.friends[&friend_pk];
+            assert!(friend.connected);
+            assert_eq!(friend.last_no_reply, 42);
+            assert_eq!(friend.search_count, 42);
+            assert!(friend.last_seen.is_none());
+        }
+
+        onion_client.set_friend_connected(friend_pk, false);
+
+        let state = onion_client.state.lock();
+        let friend = &state.friends[&friend_pk];
+        assert!(!friend.connected);
+        assert_eq!(friend.last_no_reply, 0);
+        assert_eq!(friend.search_count, 0);
+        assert!(friend.last_seen.is_some());
+    }
+
+    #[test]
+    fn info() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, dht_pk_tx, real_sk, real_pk);
+
+        let info = onion_client.info();
+        assert!(!info.is_announced());
+        assert!(info.friends.is_empty());
+
+        let now = Instant::now();
+        let mut node = OnionNode {
+            pk: gen_keypair().0,
+            saddr: "127.0.0.1:12345".parse().unwrap(),
+            path_id: [gen_keypair().0, gen_keypair().0, gen_keypair().0],
+            ping_id: None,
+            data_pk: None,
+            unsuccessful_pings: 0,
+            added_time: now,
+            ping_time: now,
+            response_time: now,
+            announce_status: AnnounceStatus::Announced,
+        };
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let friend_dht_pk = gen_keypair().0;
+        let mut friend = OnionFriend::new(friend_pk);
+        friend.dht_pk = Some(friend_dht_pk);
+
+        {
+            let mut state = onion_client.state.lock();
+            state.announce_list.try_add(&real_pk, node.clone(), true);
+            node.pk = gen_keypair().0;
+            node.announce_status = AnnounceStatus::Found;
+            node.data_pk = Some(gen_keypair().0);
+            friend.close_nodes.try_add(&friend_pk, node.clone(), true);
+            node.pk = gen_keypair().0;
+            node.data_pk = None;
+            friend.close_nodes.try_add(&friend_pk, node, true);
+            state.friends.insert(friend_pk, friend);
+        }
+
+        let info = onion_client.info();
+        assert!(info.is_announced());
+        assert_eq!(info.announce_nodes_count, 1);
+        assert_eq!(info.announced_nodes_count, 1);
+        assert_eq!(info.paths_pool.path_nodes_count, 0);
+        assert_eq!(info.friends, vec![OnionFriendInfo {
+            real_pk: friend_pk,
+            dht_pk: Some(friend_dht_pk),
+            connected: false,
+            close_nodes_count: 2,
+            data_pk_nodes_count: 1,
+            search_count: 0,
+            last_seen: None,
+        }]);
+    }
+
     #[test]
     fn handle_announce_response_announced() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -1095,7 +1447,7 @@ mod tests {
         let mut state = onion_client.state.lock();
 
         // map needed to decrypt onion packets later
-        let mut key_by_addr = HashMap::new();
+        let mut key_by_addr = HashMap::default();
         let addr = "127.0.0.1".parse().unwrap();
         for i in 0 .. 3 {
             let saddr = SocketAddr::new(addr, 12346 + i);
@@ -1290,7 +1642,7 @@ mod tests {
         state.friends.insert(friend_pk, friend);
 
         // map needed to decrypt onion packets later
-        let mut key_by_addr = HashMap::new();
+        let mut key_by_addr = HashMap::default();
         let addr = "127.0.0.1".parse().unwrap();
         for i in 0 .. 3 {
             let saddr = SocketAddr::new(addr, 12346 + i);
@@ -1588,6 +1940,84 @@ mod tests {
         assert_eq!(received_dht_pk, friend_dht_pk);
     }
 
+    #[test]
+    fn handle_data_response_dht_pk_announce_event() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let mut onion_client = OnionClient::new(dht, tcp_connections, dht_pk_tx, real_sk.clone(), real_pk);
+        onion_client.set_event_sink(event_tx);
+
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let (friend_real_pk, friend_real_sk) = gen_keypair();
+
+        onion_client.add_friend(friend_real_pk);
+
+        let dht_pk_announce_payload = DhtPkAnnouncePayload::new(friend_dht_pk, vec![]);
+        let onion_data_response_inner_payload = OnionDataResponseInnerPayload::DhtPkAnnounce(dht_pk_announce_payload);
+        let nonce = gen_nonce();
+        let onion_data_response_payload = OnionDataResponsePayload::new(&precompute(&real_pk, &friend_real_sk), friend_real_pk, &nonce, &onion_data_response_inner_payload);
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let onion_data_response = OnionDataResponse::new(&precompute(&onion_client.data_pk, &temporary_sk), temporary_pk, nonce, &onion_data_response_payload);
+
+        onion_client.handle_data_response(&onion_data_response).wait().unwrap();
+
+        let (event, _event_rx) = event_rx.into_future().wait().unwrap();
+        assert_eq!(event, Some(Event::FriendDhtPkLearned { real_pk: friend_real_pk, dht_pk: friend_dht_pk }));
+    }
+
+    #[test]
+    fn handle_data_response_custom_data() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (custom_data_tx, custom_data_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let mut onion_client = OnionClient::new(dht, tcp_connections, dht_pk_tx, real_sk.clone(), real_pk);
+        onion_client.set_custom_data_sink(custom_data_tx);
+
+        let (friend_real_pk, friend_real_sk) = gen_keypair();
+
+        onion_client.add_friend(friend_real_pk);
+
+        let onion_data_response_inner_payload = OnionDataResponseInnerPayload::CustomData(OnionCustomData {
+            no_reply: 42,
+            data: vec![42; 123],
+        });
+        let nonce = gen_nonce();
+        let onion_data_response_payload = OnionDataResponsePayload::new(&precompute(&real_pk, &friend_real_sk), friend_real_pk, &nonce, &onion_data_response_inner_payload);
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let onion_data_response = OnionDataResponse::new(&precompute(&onion_client.data_pk, &temporary_sk), temporary_pk, nonce, &onion_data_response_payload);
+
+        onion_client.handle_data_response(&onion_data_response).wait().unwrap();
+
+        let (received, _custom_data_rx) = custom_data_rx.into_future().wait().unwrap();
+        let (received_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_real_pk, friend_real_pk);
+        assert_eq!(received_data, vec![42; 123]);
+
+        let state = onion_client.state.lock();
+        assert_eq!(state.friends[&friend_real_pk].last_custom_data_no_reply, 42);
+        drop(state);
+
+        // replayed custom data is not accepted
+        let error = onion_client.handle_data_response(&onion_data_response).wait().err().unwrap();
+        assert_eq!(error.kind(), &HandleDataResponseErrorKind::InvalidNoReply);
+
+        // custom data from unknown nodes is not accepted
+        onion_client.remove_friend(friend_real_pk);
+        let error = onion_client.handle_data_response(&onion_data_response).wait().err().unwrap();
+        assert_eq!(error.kind(), &HandleDataResponseErrorKind::NoFriendWithPk);
+    }
+
     #[test]
     fn handle_data_response_dht_pk_announce_no_friend_with_pk() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -1704,7 +2134,7 @@ mod tests {
         let mut state = onion_client.state.lock();
 
         // map needed to decrypt onion packets later
-        let mut key_by_addr = HashMap::new();
+        let mut key_by_addr = HashMap::default();
         let addr = "127.0.0.1".parse().unwrap();
         for i in 0 .. 3 {
             let saddr = SocketAddr::new(addr, 12346 + i);
@@ -1751,7 +2181,7 @@ mod tests {
         let mut state = onion_client.state.lock();
 
         // map needed to decrypt onion packets later
-        let mut key_by_addr = HashMap::new();
+        let mut key_by_addr = HashMap::default();
         let addr = "127.0.0.1".parse().unwrap();
         for i in 0 .. 3 {
             let saddr = SocketAddr::new(addr, 12346 + i);
@@ -1764,7 +2194,7 @@ mod tests {
         let ping_id = sha256::hash(&[1, 2, 3]);
         let now = Instant::now();
 
-        let mut nodes_key_by_addr = HashMap::new();
+        let mut nodes_key_by_addr = HashMap::default();
         for i in 0 .. MAX_ONION_ANNOUNCE_NODES {
             let saddr = SocketAddr::new(addr, 23456 + u16::from(i));
             let path = state.paths_pool.random_path(false).unwrap();
@@ -1835,7 +2265,7 @@ mod tests {
         state.friends.insert(friend_pk, friend);
 
         // map needed to decrypt onion packets later
-        let mut key_by_addr = HashMap::new();
+        let mut key_by_addr = HashMap::default();
         let addr = "127.0.0.1".parse().unwrap();
         for i in 0 .. 3 {
             let saddr = SocketAddr::new(addr, 12346 + i);
@@ -1884,7 +2314,7 @@ mod tests {
         let friend_temporary_pk = friend.temporary_pk;
 
         // map needed to decrypt onion packets later
-        let mut key_by_addr = HashMap::new();
+        let mut key_by_addr = HashMap::default();
         let addr = "127.0.0.1".parse().unwrap();
         for i in 0 .. 3 {
             let saddr = SocketAddr::new(addr, 12346 + i);
@@ -1896,7 +2326,7 @@ mod tests {
 
         let now = Instant::now();
 
-        let mut nodes_key_by_addr = HashMap::new();
+        let mut nodes_key_by_addr = HashMap::default();
         for i in 0 .. MAX_ONION_FRIEND_NODES {
             let saddr = SocketAddr::new(addr, 23456 + u16::from(i));
             let path = state.paths_pool.random_path(false).unwrap();
@@ -1948,6 +2378,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn friends_loop_connected() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, udp_rx) = mpsc::channel(MAX_ONION_ANNOUNCE_NODES as usize / 2);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, dht_pk_tx, real_sk.clone(), real_pk);
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        onion_client.add_friend(friend_pk);
+        onion_client.set_friend_connected(friend_pk, true);
+
+        let mut state = onion_client.state.lock();
+
+        let addr = "127.0.0.1".parse().unwrap();
+        for i in 0 .. 3 {
+            let saddr = SocketAddr::new(addr, 12346 + i);
+            let node = PackedNode::new(saddr, &gen_keypair().0);
+            state.paths_pool.path_nodes.put(node);
+        }
+
+        onion_client.friends_loop(&mut state).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(state);
+        drop(onion_client);
+
+        assert!(udp_rx.collect().wait().unwrap().is_empty());
+    }
+
     #[test]
     fn send_dht_pk_onion() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -1965,7 +2428,7 @@ mod tests {
         let mut friend = OnionFriend::new(friend_pk);
 
         // map needed to decrypt onion packets later
-        let mut key_by_addr = HashMap::new();
+        let mut key_by_addr = HashMap::default();
         let addr = "127.0.0.1".parse().unwrap();
         for i in 0 .. 3 {
             let saddr = SocketAddr::new(addr, 12346 + i);
@@ -1978,7 +2441,7 @@ mod tests {
         let now = Instant::now();
 
         let (data_pk, data_sk) = gen_keypair();
-        let mut nodes_key_by_addr = HashMap::new();
+        let mut nodes_key_by_addr = HashMap::default();
         for i in 0 .. MAX_ONION_FRIEND_NODES {
             let saddr = SocketAddr::new(addr, 23456 + u16::from(i));
             let path = state.paths_pool.random_path(false).unwrap();
@@ -2034,6 +2497,112 @@ mod tests {
         }
     }
 
+    #[test]
+    fn send_custom_data() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, udp_rx) = mpsc::channel(MAX_ONION_FRIEND_NODES as usize);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, dht_pk_tx, real_sk.clone(), real_pk);
+
+        let mut state = onion_client.state.lock();
+
+        let (friend_pk, friend_sk) = gen_keypair();
+        let mut friend = OnionFriend::new(friend_pk);
+
+        // map needed to decrypt onion packets later
+        let mut key_by_addr = HashMap::default();
+        let addr = "127.0.0.1".parse().unwrap();
+        for i in 0 .. 3 {
+            let saddr = SocketAddr::new(addr, 12346 + i);
+            let (pk, sk) = gen_keypair();
+            key_by_addr.insert(saddr, sk);
+            let node = PackedNode::new(saddr, &pk);
+            state.paths_pool.path_nodes.put(node);
+        }
+
+        let now = Instant::now();
+
+        let (data_pk, data_sk) = gen_keypair();
+        for i in 0 .. MAX_ONION_FRIEND_NODES {
+            let saddr = SocketAddr::new(addr, 23456 + u16::from(i));
+            let path = state.paths_pool.random_path(false).unwrap();
+            let node = OnionNode {
+                pk: gen_keypair().0,
+                saddr,
+                path_id: path.id(),
+                ping_id: None,
+                data_pk: Some(data_pk),
+                unsuccessful_pings: 0,
+                added_time: now,
+                ping_time: now,
+                response_time: now,
+                announce_status: AnnounceStatus::Found,
+            };
+            assert!(friend.close_nodes.try_add(&real_pk, node, true));
+        }
+
+        state.friends.insert(friend_pk, friend);
+        drop(state);
+
+        onion_client.send_custom_data(friend_pk, vec![42; 123]).wait().unwrap();
+
+        let no_reply = onion_client.state.lock().friends[&friend_pk].sent_custom_data_no_reply;
+        assert!(no_reply > 0);
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(onion_client);
+
+        let packets = udp_rx.collect().wait().unwrap();
+
+        assert_eq!(packets.len(), MAX_ONION_FRIEND_NODES as usize);
+
+        for (packet, addr_to_send) in packets {
+            let packet = unpack!(packet, Packet::OnionRequest0);
+            let payload = unpack_onion_packet(packet, addr_to_send, &key_by_addr);
+            let packet = unpack!(payload.inner, InnerOnionRequest::InnerOnionDataRequest);
+            assert_eq!(packet.destination_pk, friend_pk);
+            let payload = packet.get_payload(&precompute(&packet.temporary_pk, &data_sk)).unwrap();
+            assert_eq!(payload.real_pk, real_pk);
+            let payload = payload.get_payload(&packet.nonce, &precompute(&real_pk, &friend_sk)).unwrap();
+            let payload = unpack!(payload, OnionDataResponseInnerPayload::CustomData);
+            assert_eq!(payload.no_reply, no_reply);
+            assert_eq!(payload.data, vec![42; 123]);
+        }
+    }
+
+    #[test]
+    fn send_custom_data_errors() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, dht_pk_tx, real_sk, real_pk);
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        let error = onion_client.send_custom_data(friend_pk, vec![42; 123]).wait().err().unwrap();
+        assert_eq!(error.kind(), &SendCustomDataErrorKind::NoFriendWithPk);
+
+        onion_client.add_friend(friend_pk);
+
+        let error = onion_client.send_custom_data(friend_pk, Vec::new()).wait().err().unwrap();
+        assert_eq!(error.kind(), &SendCustomDataErrorKind::InvalidSize);
+
+        let error = onion_client.send_custom_data(friend_pk, vec![42; MAX_ONION_CUSTOM_DATA_SIZE + 1]).wait().err().unwrap();
+        assert_eq!(error.kind(), &SendCustomDataErrorKind::InvalidSize);
+
+        // friend's close nodes are not known yet
+        let error = onion_client.send_custom_data(friend_pk, vec![42; 123]).wait().err().unwrap();
+        assert_eq!(error.kind(), &SendCustomDataErrorKind::NoNodes);
+    }
+
     #[test]
     fn send_dht_pk_dht_request() {
         let (dht_pk, dht_sk) = gen_keypair();
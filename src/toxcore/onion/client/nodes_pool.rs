@@ -48,6 +48,11 @@ impl NodesPool {
         }
     }
 
+    /// Iterate over stored nodes.
+    pub fn iter(&self) -> impl Iterator<Item = &PackedNode> {
+        self.nodes.iter()
+    }
+
     /// The number of stored nodes in the pool.
     pub fn len(&self) -> usize {
         self.nodes.len()
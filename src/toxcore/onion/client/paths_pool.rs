@@ -1,11 +1,17 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::time::{Duration, Instant};
 
 use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::ip_port::ip_subnet;
+use crate::toxcore::dht::kbucket::{SUBNET_DEFAULT_IPV4_PREFIX, SUBNET_DEFAULT_IPV6_PREFIX};
 use crate::toxcore::dht::packed_node::PackedNode;
+use crate::toxcore::events::*;
 use crate::toxcore::onion::client::nodes_pool::*;
 use crate::toxcore::onion::client::onion_path::*;
 use crate::toxcore::time::*;
-use crate::toxcore::onion::client::TIME_TO_STABLE;
+use crate::toxcore::onion::client::{TIME_TO_STABLE, PathsPoolInfo};
 
 /// Onion path is considered invalid after this number of unsuccessful attempts
 /// to use it.
@@ -26,6 +32,24 @@ const ONION_PATH_MAX_LIFETIME: u64 = 1200;
 /// Minimum size of nodes pool to generate random path.
 const MIN_NODES_POOL_SIZE: usize = 3;
 
+/// Number of random candidates to generate when building a new path. The
+/// candidate with the best score is used.
+const ONION_PATH_BUILD_CANDIDATES: usize = 4;
+
+/// Node is blacklisted after this number of consecutive failed paths it was
+/// part of.
+const ONION_NODE_MAX_FAILURES: u32 = 3;
+
+/// How long a node stays blacklisted.
+const ONION_NODE_BLACKLIST_TIME: u64 = 600;
+
+/// Get the subnet of a path node. Hops of a path should be from different
+/// subnets when the nodes pool allows it so that a single party is less
+/// likely to control the whole path.
+fn node_subnet(node: &PackedNode) -> IpAddr {
+    ip_subnet(node.saddr.ip(), SUBNET_DEFAULT_IPV4_PREFIX, SUBNET_DEFAULT_IPV6_PREFIX)
+}
+
 /// Onion path that is stored for later usage.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct StoredOnionPath {
@@ -41,6 +65,12 @@ pub struct StoredOnionPath {
     /// How many times we attempted to use this path without receiving a
     /// response.
     pub attempts: u32,
+    /// How many times this path was used to send an onion packet.
+    pub uses: u32,
+    /// How many responses we received via this path.
+    pub successes: u32,
+    /// Smoothed round trip time of this path.
+    pub rtt: Option<Duration>,
 }
 
 impl StoredOnionPath {
@@ -53,12 +83,18 @@ impl StoredOnionPath {
             last_used: now,
             last_success: None,
             attempts: ONION_PATH_MAX_NO_RESPONSE_USES / 2,
+            uses: 1,
+            successes: 0,
+            rtt: None,
         }
     }
 
-    /// Update last success time and attempts number.
+    /// Update last success time, attempts number and round trip time.
     pub fn update_success(&mut self) {
+        let rtt = clock_elapsed(self.last_used);
+        self.rtt = Some(self.rtt.map_or(rtt, |old_rtt| (old_rtt * 7 + rtt) / 8));
         self.last_success = Some(clock_now());
+        self.successes = self.successes.saturating_add(1);
         self.attempts = 0;
     }
 
@@ -67,18 +103,38 @@ impl StoredOnionPath {
         self.last_success.is_none()
     }
 
-    /// Check if this path is timed out.
-    pub fn is_timed_out(&self) -> bool {
+    /// Check if this path stopped responding.
+    pub fn is_failed(&self) -> bool {
         let timeout = if self.is_new() {
             Duration::from_secs(ONION_PATH_FIRST_TIMEOUT)
         } else {
             Duration::from_secs(ONION_PATH_TIMEOUT)
         };
 
-        self.attempts >= ONION_PATH_MAX_NO_RESPONSE_USES && clock_elapsed(self.last_used) >= timeout ||
+        self.attempts >= ONION_PATH_MAX_NO_RESPONSE_USES && clock_elapsed(self.last_used) >= timeout
+    }
+
+    /// Check if this path is timed out.
+    pub fn is_timed_out(&self) -> bool {
+        self.is_failed() ||
             clock_elapsed(self.creation_time) >= Duration::from_secs(ONION_PATH_MAX_LIFETIME)
     }
 
+    /** Score of this path from 0 to 1. Higher is better.
+
+    It's a success rate of the path multiplied by a latency factor. The
+    success rate is estimated as `(successes + 1) / (uses + 2)` so new paths
+    get neutral score. The latency factor is `1 / (1 + rtt)` where `rtt` is in
+    seconds.
+
+    */
+    pub fn score(&self) -> f64 {
+        let successes = f64::from(self.successes.min(self.uses));
+        let success_rate = (successes + 1.0) / (f64::from(self.uses) + 2.0);
+        let rtt = self.rtt.map_or(0.0, |rtt| rtt.as_secs() as f64 + f64::from(rtt.subsec_millis()) / 1000.0);
+        success_rate / (1.0 + rtt)
+    }
+
     /// Path is considered stable after `TIME_TO_STABLE` seconds since it was
     /// added to a close list if we receive responses from it.
     pub fn is_stable(&self) -> bool {
@@ -91,6 +147,34 @@ impl StoredOnionPath {
     pub fn use_path(&mut self) {
         self.last_used = clock_now();
         self.attempts += 1;
+        self.uses = self.uses.saturating_add(1);
+    }
+}
+
+/// Reputation of a node used to build onion paths.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct NodeStats {
+    /// Number of responses received via paths with this node.
+    successes: u32,
+    /// Number of consecutive failed paths with this node.
+    failures: u32,
+    /// Time until which this node can't be used to build paths.
+    blacklisted_until: Option<Instant>,
+}
+
+impl NodeStats {
+    /// Check if this node can't be used to build paths.
+    fn is_blacklisted(&self) -> bool {
+        match self.blacklisted_until {
+            Some(time) => clock_now() < time,
+            None => false,
+        }
+    }
+
+    /// Score of this node from 0 to 1. Higher is better.
+    fn score(&self) -> f64 {
+        let successes = f64::from(self.successes);
+        (successes + 1.0) / (successes + f64::from(self.failures) + 2.0)
     }
 }
 
@@ -103,6 +187,10 @@ pub struct PathsPool {
     self_paths: Vec<StoredOnionPath>,
     /// List of used random onion paths for friends searching.
     friend_paths: Vec<StoredOnionPath>,
+    /// Reputation of nodes that were used to build paths.
+    node_stats: HashMap<PublicKey, NodeStats>,
+    /// Sink to send events about built and timed out paths.
+    event_tx: Option<EventTx>,
 }
 
 impl PathsPool {
@@ -112,10 +200,24 @@ impl PathsPool {
             path_nodes: NodesPool::new(),
             self_paths: Vec::new(),
             friend_paths: Vec::new(),
+            node_stats: HashMap::new(),
+            event_tx: None,
         }
     }
 
-    /// Get random path. Can be either one of existent paths or newly generated.
+    /// Set sink to send events about built and timed out paths.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx);
+    }
+
+    /** Get random path. Can be either one of existent paths or newly
+    generated.
+
+    When an existent path is chosen two random paths are compared and the one
+    with the better score is used. This way good paths are preferred while
+    the load is still spread between paths.
+
+    */
     pub fn random_path(&mut self, friend: bool) -> Option<OnionPath> {
         let paths = if friend {
             &mut self.friend_paths
@@ -123,45 +225,136 @@ impl PathsPool {
             &mut self.self_paths
         };
 
-        paths.retain(|stored_path| !stored_path.is_timed_out());
+        let event_tx = &self.event_tx;
+        let node_stats = &mut self.node_stats;
+        paths.retain(|stored_path| if stored_path.is_timed_out() {
+            if stored_path.is_failed() {
+                PathsPool::add_failure(node_stats, stored_path);
+            }
+            emit(event_tx, Event::OnionPathTimedOut { path_id: stored_path.path.id(), friend });
+            false
+        } else {
+            true
+        });
 
         let path_number = random_limit_usize(NUMBER_ONION_PATHS);
         if path_number >= paths.len() {
-            if self.path_nodes.len() < MIN_NODES_POOL_SIZE {
-                return None;
-            }
-            // non-empty nodes pool will always return some node
-            let node_1 = self.path_nodes.rand().unwrap();
-            let mut node_2;
-            loop {
-                node_2 = self.path_nodes.rand().unwrap();
-                if node_2 != node_1 {
-                    break;
-                }
-            }
-            let mut node_3;
-            loop {
-                node_3 = self.path_nodes.rand().unwrap();
-                if node_3 != node_1 && node_3 != node_2 {
-                    break;
-                }
-            }
-            let path_id = [node_1.pk, node_2.pk, node_3.pk];
+            let nodes = PathsPool::best_candidate(&self.path_nodes, &self.node_stats)?;
+            let path_id = [nodes[0].pk, nodes[1].pk, nodes[2].pk];
             if let Some(stored_path) = paths.iter_mut().find(|stored_path| stored_path.path.id() == path_id) {
                 stored_path.use_path();
                 Some(stored_path.path.clone())
             } else {
-                let path = OnionPath::new([node_1, node_2, node_3]);
+                let path = OnionPath::new(nodes);
                 let stored_path = StoredOnionPath::new(path.clone());
                 paths.push(stored_path);
+                emit(&self.event_tx, Event::OnionPathBuilt { path_id, friend });
                 Some(path)
             }
         } else {
+            let other_number = random_limit_usize(paths.len());
+            let path_number = match paths[other_number].score().partial_cmp(&paths[path_number].score()) {
+                Some(Ordering::Greater) => other_number,
+                _ => path_number,
+            };
             paths[path_number].use_path();
             Some(paths[path_number].path.clone())
         }
     }
 
+    /// Count a failure for all nodes of a failed path and blacklist nodes
+    /// that fail consistently.
+    fn add_failure(node_stats: &mut HashMap<PublicKey, NodeStats>, stored_path: &StoredOnionPath) {
+        for node in &stored_path.path.nodes {
+            let stats = node_stats.entry(node.public_key).or_default();
+            stats.failures += 1;
+            if stats.failures >= ONION_NODE_MAX_FAILURES {
+                stats.failures = 0;
+                stats.blacklisted_until = Some(clock_now() + Duration::from_secs(ONION_NODE_BLACKLIST_TIME));
+            }
+        }
+    }
+
+    /// Pick a random node from the list that satisfies the predicate.
+    fn random_node<P: Fn(&PackedNode) -> bool>(nodes: &[PackedNode], predicate: P) -> Option<PackedNode> {
+        let nodes = nodes.iter().filter(|node| predicate(node)).collect::<Vec<_>>();
+        if nodes.is_empty() {
+            None
+        } else {
+            Some(*nodes[random_limit_usize(nodes.len())])
+        }
+    }
+
+    /** Generate a few random candidates for a new path and return the one with
+    the best score.
+
+    Blacklisted nodes are used only when the pool has less than
+    `MIN_NODES_POOL_SIZE` other nodes. In this case the pool is topped up with
+    blacklisted nodes that have the best score so that a few failures in a
+    small pool don't stop paths building altogether. Hops of a candidate are
+    from different subnets as far as the nodes pool allows it, i.e. if the
+    pool has nodes from at least 3 subnets all hops are from different subnets
+    and if it has nodes from 2 subnets at least two hops are from different
+    subnets.
+
+    */
+    fn best_candidate(path_nodes: &NodesPool, node_stats: &HashMap<PublicKey, NodeStats>) -> Option<[PackedNode; 3]> {
+        let is_blacklisted = |node: &PackedNode| match node_stats.get(&node.pk) {
+            Some(stats) => stats.is_blacklisted(),
+            None => false,
+        };
+        let node_score = |node: &PackedNode| node_stats.get(&node.pk).map_or(0.5, NodeStats::score);
+
+        let (mut nodes, mut blacklisted): (Vec<_>, Vec<_>) = path_nodes.iter()
+            .cloned()
+            .partition(|node| !is_blacklisted(node));
+        if nodes.len() < MIN_NODES_POOL_SIZE {
+            blacklisted.sort_by(|node_1, node_2|
+                node_score(node_2).partial_cmp(&node_score(node_1)).unwrap_or(Ordering::Equal)
+            );
+            let missing = MIN_NODES_POOL_SIZE - nodes.len();
+            nodes.extend(blacklisted.into_iter().take(missing));
+        }
+        if nodes.len() < MIN_NODES_POOL_SIZE {
+            return None;
+        }
+
+        let subnets_count = nodes.iter().map(node_subnet).collect::<HashSet<_>>().len();
+
+        let mut best: Option<([PackedNode; 3], f64)> = None;
+        for _ in 0 .. ONION_PATH_BUILD_CANDIDATES {
+            let node_1 = PathsPool::random_node(&nodes, |_| true)?;
+            let node_2 = PathsPool::random_node(&nodes, |node|
+                node.pk != node_1.pk && (subnets_count < 2 || node_subnet(node) != node_subnet(&node_1))
+            );
+            let node_2 = if let Some(node_2) = node_2 {
+                node_2
+            } else {
+                continue
+            };
+            let node_3 = PathsPool::random_node(&nodes, |node|
+                node.pk != node_1.pk && node.pk != node_2.pk &&
+                    (subnets_count < 3 || node_subnet(node) != node_subnet(&node_1) && node_subnet(node) != node_subnet(&node_2))
+            );
+            let node_3 = if let Some(node_3) = node_3 {
+                node_3
+            } else {
+                continue
+            };
+
+            let score = node_score(&node_1) * node_score(&node_2) * node_score(&node_3);
+            let is_better = match best {
+                Some((_, best_score)) => score > best_score,
+                None => true,
+            };
+            if is_better {
+                best = Some(([node_1, node_2, node_3], score));
+            }
+        }
+
+        best.map(|(nodes, _)| nodes)
+    }
+
     /// Get path by its `OnionPathId` and mark it as used. If there is no path
     /// with such id a new path will be generated.
     pub fn use_path(&mut self, path_id: OnionPathId, friend: bool) -> Option<OnionPath> {
@@ -211,8 +404,32 @@ impl PathsPool {
             // re-add path nodes to the cache as they are still valid
             for node in &path.path.nodes {
                 self.path_nodes.put(PackedNode::new(node.saddr, &node.public_key));
+                let stats = self.node_stats.entry(node.public_key).or_default();
+                stats.successes = stats.successes.saturating_add(1);
+                stats.failures = 0;
             }
         }
+
+        // forget nodes that are neither in the pool nor blacklisted
+        let path_nodes = &self.path_nodes;
+        self.node_stats.retain(|pk, stats| stats.is_blacklisted() || path_nodes.iter().any(|node| node.pk == *pk));
+    }
+
+    /// Get status of this pool for diagnostics.
+    pub fn info(&self) -> PathsPoolInfo {
+        let alive_paths = |paths: &[StoredOnionPath]| paths.iter()
+            .filter(|stored_path| !stored_path.is_timed_out())
+            .count();
+        let stable_paths = self.self_paths.iter().chain(self.friend_paths.iter())
+            .filter(|stored_path| !stored_path.is_timed_out() && stored_path.is_stable())
+            .count();
+        PathsPoolInfo {
+            path_nodes_count: self.path_nodes.len(),
+            self_paths_count: alive_paths(&self.self_paths),
+            friend_paths_count: alive_paths(&self.friend_paths),
+            stable_paths_count: stable_paths,
+            blacklisted_nodes_count: self.node_stats.values().filter(|stats| stats.is_blacklisted()).count(),
+        }
     }
 }
 
@@ -226,6 +443,11 @@ impl Default for PathsPool {
 mod tests {
     use super::*;
 
+    use futures::{Future, Stream};
+    use futures::sync::mpsc;
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
     #[test]
     fn new() {
         let mut paths_pool = PathsPool::new();
@@ -397,4 +619,243 @@ mod tests {
         paths_pool.friend_paths.push(stored_path.clone());
         assert_eq!(paths_pool.get_stored_path(path_id, true), Some(&stored_path));
     }
+
+    #[test]
+    fn info() {
+        let mut paths_pool = PathsPool::new();
+        assert_eq!(paths_pool.info(), PathsPoolInfo {
+            path_nodes_count: 0,
+            self_paths_count: 0,
+            friend_paths_count: 0,
+            stable_paths_count: 0,
+            blacklisted_nodes_count: 0,
+        });
+
+        for _ in 0 .. MIN_NODES_POOL_SIZE {
+            let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+            paths_pool.path_nodes.put(node);
+        }
+        let path = paths_pool.random_path(false).unwrap();
+        paths_pool.random_path(true).unwrap();
+        paths_pool.set_timeouts(path.id(), false);
+
+        assert_eq!(paths_pool.info(), PathsPoolInfo {
+            path_nodes_count: MIN_NODES_POOL_SIZE,
+            self_paths_count: 1,
+            friend_paths_count: 1,
+            stable_paths_count: 0,
+            blacklisted_nodes_count: 0,
+        });
+
+        let mut enter = tokio_executor::enter().unwrap();
+        // time when the path is stable
+        let clock = Clock::new_with_now(ConstNow(
+            clock_now() + Duration::from_secs(TIME_TO_STABLE)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            let info = paths_pool.info();
+            assert_eq!(info.self_paths_count, 1);
+            assert_eq!(info.friend_paths_count, 1);
+            // the friend path never received a response so it's not stable
+            assert_eq!(info.stable_paths_count, 1);
+        });
+    }
+
+    #[test]
+    fn stored_path_score() {
+        let node_1 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let node_2 = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+        let node_3 = PackedNode::new("127.0.0.1:12347".parse().unwrap(), &gen_keypair().0);
+        let path = OnionPath::new([node_1, node_2, node_3]);
+        let mut stored_path = StoredOnionPath::new(path);
+
+        let new_score = stored_path.score();
+        assert!((new_score - 1.0 / 3.0).abs() < 1e-9);
+
+        stored_path.update_success();
+        let success_score = stored_path.score();
+        assert!(success_score > new_score);
+
+        stored_path.use_path();
+        stored_path.use_path();
+        assert!(stored_path.score() < success_score);
+
+        // slow path has worse score
+        let mut slow_path = stored_path.clone();
+        slow_path.rtt = Some(Duration::from_secs(2));
+        assert!(slow_path.score() < stored_path.score());
+    }
+
+    #[test]
+    fn random_path_prefers_better_path() {
+        let mut paths_pool = PathsPool::new();
+        for _ in 0 .. NUMBER_ONION_PATHS {
+            let node_1 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+            let node_2 = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+            let node_3 = PackedNode::new("127.0.0.1:12347".parse().unwrap(), &gen_keypair().0);
+            let path = OnionPath::new([node_1, node_2, node_3]);
+            let mut stored_path = StoredOnionPath::new(path);
+            stored_path.uses = 100;
+            paths_pool.self_paths.push(stored_path);
+        }
+        paths_pool.self_paths[0].successes = 100;
+        let good_path_id = paths_pool.self_paths[0].path.id();
+
+        let good_path_uses = (0 .. 1000)
+            .filter(|_| paths_pool.random_path(false).unwrap().id() == good_path_id)
+            .count();
+        // a path is chosen with 1/6 probability without scoring and with
+        // 11/36 probability when the best of two random paths is chosen
+        assert!(good_path_uses > 1000 / NUMBER_ONION_PATHS + 50);
+    }
+
+    #[test]
+    fn random_path_subnet_diversity() {
+        let mut paths_pool = PathsPool::new();
+        for i in 0 .. 8 {
+            let node = PackedNode::new(format!("1.2.3.{}:12345", i).parse().unwrap(), &gen_keypair().0);
+            paths_pool.path_nodes.put(node);
+        }
+        for i in 0 .. 2 {
+            let node = PackedNode::new(format!("1.2.{}.1:12345", 4 + i).parse().unwrap(), &gen_keypair().0);
+            paths_pool.path_nodes.put(node);
+        }
+
+        for _ in 0 .. 100 {
+            let nodes = PathsPool::best_candidate(&paths_pool.path_nodes, &paths_pool.node_stats).unwrap();
+            let subnets = nodes.iter().map(node_subnet).collect::<HashSet<_>>();
+            assert_eq!(subnets.len(), 3);
+        }
+    }
+
+    #[test]
+    fn failed_path_nodes_are_blacklisted() {
+        crypto_init().unwrap();
+        let mut paths_pool = PathsPool::new();
+        for _ in 0 .. MIN_NODES_POOL_SIZE {
+            let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+            paths_pool.path_nodes.put(node);
+        }
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let mut now = clock_now();
+
+        for _ in 0 .. ONION_NODE_MAX_FAILURES {
+            let clock = Clock::new_with_now(ConstNow(now));
+            with_default(&clock, &mut enter, |_| {
+                let path = paths_pool.random_path(false).unwrap();
+                for _ in 0 .. ONION_PATH_MAX_NO_RESPONSE_USES {
+                    paths_pool.use_path(path.id(), false);
+                }
+            });
+            now += Duration::from_secs(ONION_PATH_FIRST_TIMEOUT);
+        }
+
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            // all nodes from the pool are blacklisted now but they are still
+            // used since there are no other nodes
+            assert!(paths_pool.random_path(false).is_some());
+            assert_eq!(paths_pool.info().blacklisted_nodes_count, MIN_NODES_POOL_SIZE);
+        });
+
+        // blacklist expires after a while
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(ONION_NODE_BLACKLIST_TIME)));
+        with_default(&clock, &mut enter, |_| {
+            assert!(paths_pool.random_path(false).is_some());
+            assert_eq!(paths_pool.info().blacklisted_nodes_count, 0);
+        });
+    }
+
+    #[test]
+    fn blacklisted_nodes_are_used_when_pool_is_small() {
+        crypto_init().unwrap();
+        let mut paths_pool = PathsPool::new();
+        let good_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let better_blacklisted_node = PackedNode::new("127.0.0.2:12345".parse().unwrap(), &gen_keypair().0);
+        let worse_blacklisted_node = PackedNode::new("127.0.0.3:12345".parse().unwrap(), &gen_keypair().0);
+        let blacklisted_until = Some(clock_now() + Duration::from_secs(ONION_NODE_BLACKLIST_TIME));
+        paths_pool.path_nodes.put(good_node);
+        paths_pool.path_nodes.put(better_blacklisted_node);
+        paths_pool.path_nodes.put(worse_blacklisted_node);
+        paths_pool.node_stats.insert(better_blacklisted_node.pk, NodeStats {
+            successes: 10,
+            failures: 0,
+            blacklisted_until,
+        });
+        paths_pool.node_stats.insert(worse_blacklisted_node.pk, NodeStats {
+            successes: 0,
+            failures: 0,
+            blacklisted_until,
+        });
+        let other_node = PackedNode::new("127.0.0.4:12345".parse().unwrap(), &gen_keypair().0);
+        paths_pool.path_nodes.put(other_node);
+
+        // the blacklisted node with the best score is used to top up the pool
+        for _ in 0 .. 10 {
+            let nodes = PathsPool::best_candidate(&paths_pool.path_nodes, &paths_pool.node_stats).unwrap();
+            let pks = nodes.iter().map(|node| node.pk).collect::<HashSet<_>>();
+            assert!(pks.contains(&good_node.pk));
+            assert!(pks.contains(&other_node.pk));
+            assert!(pks.contains(&better_blacklisted_node.pk));
+        }
+    }
+
+    #[test]
+    fn successful_path_resets_failures() {
+        let mut paths_pool = PathsPool::new();
+        for _ in 0 .. MIN_NODES_POOL_SIZE {
+            let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+            paths_pool.path_nodes.put(node);
+            paths_pool.node_stats.insert(node.pk, NodeStats {
+                successes: 0,
+                failures: ONION_NODE_MAX_FAILURES - 1,
+                blacklisted_until: None,
+            });
+        }
+
+        let path = paths_pool.random_path(false).unwrap();
+        paths_pool.set_timeouts(path.id(), false);
+
+        for node in &path.nodes {
+            assert_eq!(paths_pool.node_stats[&node.public_key], NodeStats {
+                successes: 1,
+                failures: 0,
+                blacklisted_until: None,
+            });
+        }
+    }
+
+    #[test]
+    fn random_path_events() {
+        crypto_init().unwrap();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let mut paths_pool = PathsPool::new();
+        paths_pool.set_event_sink(event_tx);
+        for _ in 0 .. MIN_NODES_POOL_SIZE {
+            let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+            paths_pool.path_nodes.put(node);
+        }
+
+        let path = paths_pool.random_path(true).unwrap();
+
+        let mut enter = tokio_executor::enter().unwrap();
+        // time when the path is timed out
+        let clock = Clock::new_with_now(ConstNow(
+            clock_now() + Duration::from_secs(ONION_PATH_MAX_LIFETIME)
+        ));
+
+        let new_path = with_default(&clock, &mut enter, |_| {
+            paths_pool.random_path(true).unwrap()
+        });
+
+        drop(paths_pool);
+
+        assert_eq!(event_rx.collect().wait().unwrap(), vec![
+            Event::OnionPathBuilt { path_id: path.id(), friend: true },
+            Event::OnionPathTimedOut { path_id: path.id(), friend: true },
+            Event::OnionPathBuilt { path_id: new_path.id(), friend: true },
+        ]);
+    }
 }
@@ -5,11 +5,14 @@ use std::io::{ErrorKind, Error};
 use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, Instant, SystemTime};
 
+use nom::be_u64;
+
 use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::time::*;
 use crate::toxcore::onion::packet::*;
 use crate::toxcore::dht::kbucket::Distance;
+use crate::toxcore::dht::packed_node::PackedNode;
 
 /// Number of secret random bytes to make onion ping id unique for each node.
 pub const SECRET_BYTES_SIZE: usize = 32;
@@ -27,6 +30,10 @@ pub const PING_ID_TIMEOUT: u64 = 300;
 /// without re-announcing.
 pub const ONION_ANNOUNCE_TIMEOUT: u64 = 300;
 
+/// Interval in seconds for removing timed out entries from onion announce
+/// list.
+pub const ONION_ANNOUNCE_CLEANUP_INTERVAL: u64 = 10;
+
 /// Create onion ping id filled with zeros.
 pub fn initial_ping_id() -> sha256::Digest {
     // can not fail since slice has enough length
@@ -77,6 +84,107 @@ impl OnionAnnounceEntry {
     pub fn is_timed_out(&self) -> bool {
         clock_elapsed(self.time) >= Duration::from_secs(ONION_ANNOUNCE_TIMEOUT)
     }
+
+    // Deserialize entry stored by `OnionAnnounceState`. Returns `None` if the
+    // entry is already timed out. `Instant` can't be stored between restarts
+    // so unix time of the announce is stored instead and converted back to
+    // `Instant` relative to current time.
+    named!(from_state_bytes<Option<OnionAnnounceEntry>>, do_parse!(
+        node: call!(PackedNode::from_bytes) >>
+        data_pk: call!(PublicKey::from_bytes) >>
+        time: be_u64 >>
+        onion_return: flat_map!(take!(ONION_RETURN_3_SIZE), OnionReturn::from_bytes) >>
+        ({
+            let age = unix_time(clock_system_time()).saturating_sub(time);
+            if age >= ONION_ANNOUNCE_TIMEOUT {
+                None
+            } else {
+                clock_now().checked_sub(Duration::from_secs(age)).map(|time| OnionAnnounceEntry {
+                    pk: node.pk,
+                    ip_addr: node.saddr.ip(),
+                    port: node.saddr.port(),
+                    onion_return,
+                    data_pk,
+                    time,
+                })
+            }
+        })
+    ));
+
+    /// Serialize entry to be stored by `OnionAnnounceState`.
+    fn to_state_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        let time = unix_time(clock_system_time()).saturating_sub(clock_elapsed(self.time).as_secs());
+        do_gen!(buf,
+            gen_call!(|buf, node| PackedNode::to_bytes(node, buf), &PackedNode::new(SocketAddr::new(self.ip_addr, self.port), &self.pk)) >>
+            gen_slice!(self.data_pk.as_ref()) >>
+            gen_be_u64!(time) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return)
+        )
+    }
+}
+
+/// Maximum size of serialized `OnionAnnounceState`.
+pub const ONION_ANNOUNCE_STATE_MAX_SIZE: usize = SECRET_BYTES_SIZE + ONION_ANNOUNCE_MAX_ENTRIES * (
+    /* PackedNode */ 1 + 16 + 2 + PUBLICKEYBYTES +
+    /* data_pk */ PUBLICKEYBYTES +
+    /* time */ 8 +
+    ONION_RETURN_3_SIZE
+);
+
+/** State of `OnionAnnounce` that can be stored between restarts so that
+announced nodes can be found without re-announcing and issued onion ping ids
+remain valid.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`32`     | Secret bytes of onion node
+variable | Announce entries
+
+Serialized form of announce entry:
+
+Length   | Content
+-------- | ------
+variable | `PackedNode` of announced node
+`32`     | `PublicKey` that should be used to encrypt data packets
+`8`      | Unix time in seconds when the node was announced
+`177`    | `OnionReturn` to send data packets to announced node
+
+Timed out entries are skipped while deserializing.
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceState {
+    /// Secret bytes of onion node to make onion ping id unique
+    secret_bytes: [u8; SECRET_BYTES_SIZE],
+    /// List of announced onion nodes
+    entries: Vec<OnionAnnounceEntry>,
+}
+
+impl FromBytes for OnionAnnounceState {
+    named!(from_bytes<OnionAnnounceState>, do_parse!(
+        secret_bytes: take!(SECRET_BYTES_SIZE) >>
+        entries: many0!(complete!(OnionAnnounceEntry::from_state_bytes)) >>
+        eof!() >>
+        ({
+            let mut secret = [0; SECRET_BYTES_SIZE];
+            secret.copy_from_slice(secret_bytes);
+            OnionAnnounceState {
+                secret_bytes: secret,
+                entries: entries.into_iter().flatten().collect(),
+            }
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceState {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(&self.secret_bytes) >>
+            gen_many_ref!(&self.entries, |buf, entry| OnionAnnounceEntry::to_state_bytes(entry, buf))
+        )
+    }
 }
 
 /// Size of serialized `OnionPingData` struct.
@@ -157,7 +265,10 @@ pub struct OnionAnnounce {
     /// List of announced onion nodes
     entries: Vec<OnionAnnounceEntry>,
     /// Short term DHT `PublicKey`
-    dht_pk: PublicKey
+    dht_pk: PublicKey,
+    /// Maximum number of entries announced through the same onion relay,
+    /// `None` if unlimited
+    max_entries_per_ip: Option<usize>,
 }
 
 impl OnionAnnounce {
@@ -168,7 +279,50 @@ impl OnionAnnounce {
         OnionAnnounce {
             secret_bytes,
             entries: Vec::with_capacity(ONION_ANNOUNCE_MAX_ENTRIES),
-            dht_pk
+            dht_pk,
+            max_entries_per_ip: None,
+        }
+    }
+
+    /** Set maximum number of entries announced from the same IP address.
+
+    Announce requests are delivered by the last node of an onion path, so the
+    IP address here is the address of that relay rather than of the announcing
+    client. This limits how much of the announce list can be occupied via a
+    single relay. Many honest clients may share the same relay, so the limit
+    should be large enough to not starve them, e.g. a half of
+    `ONION_ANNOUNCE_MAX_ENTRIES`. Unlimited by default.
+    */
+    pub fn set_max_entries_per_ip(&mut self, max_entries_per_ip: Option<usize>) {
+        self.max_entries_per_ip = max_entries_per_ip;
+    }
+
+    /// Remove timed out entries from announce list. Returns the number of
+    /// removed entries.
+    pub fn remove_timed_out_entries(&mut self) -> usize {
+        let len = self.entries.len();
+        self.entries.retain(|e| !e.is_timed_out());
+        len - self.entries.len()
+    }
+
+    /// Get the state of announce list that can be restored after restart.
+    pub fn state(&self) -> OnionAnnounceState {
+        OnionAnnounceState {
+            secret_bytes: self.secret_bytes,
+            entries: self.entries.iter().filter(|e| !e.is_timed_out()).cloned().collect(),
+        }
+    }
+
+    /** Restore the state of announce list. Secret bytes are replaced so that
+    onion ping ids issued before restart remain valid. Restored entries
+    replace current entries and are subject to the same limits as announced
+    ones.
+    */
+    pub fn restore(&mut self, state: OnionAnnounceState) {
+        self.secret_bytes = state.secret_bytes;
+        self.entries.clear();
+        for entry in state.entries {
+            self.add_to_entries(entry);
         }
     }
 
@@ -190,6 +344,11 @@ impl OnionAnnounce {
         data.ping_id()
     }
 
+    /// Get the number of announced onion nodes ignoring timed out entries.
+    pub fn entries_count(&self) -> usize {
+        self.entries.iter().filter(|e| !e.is_timed_out()).count()
+    }
+
     /// Find entry by its `PublicKey` ignoring timed out entries
     fn find_in_entries(&self, pk: PublicKey) -> Option<&OnionAnnounceEntry> {
         match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &pk)) {
@@ -202,6 +361,8 @@ impl OnionAnnounce {
     /** Try to add announce entry to onion announce list.
 
     Firstly we remove all timed out entries. Then if:
+    - the limit of entries per IP address is reached for a new entry then
+      don't add it
     - announce list already contains entry with such `PublicKey` then update
       entry and return it
     - announce list with new entry does not exceed `ONION_ANNOUNCE_MAX_ENTRIES`
@@ -214,8 +375,15 @@ impl OnionAnnounce {
 
     */
     fn add_to_entries(&mut self, entry: OnionAnnounceEntry) -> Option<&OnionAnnounceEntry> {
-        //TODO: remove timed out entries by timer?
         self.entries.retain(|e| !e.is_timed_out());
+        if let Some(max_entries_per_ip) = self.max_entries_per_ip {
+            let entries_per_ip = self.entries.iter()
+                .filter(|e| e.ip_addr == entry.ip_addr && e.pk != entry.pk)
+                .count();
+            if entries_per_ip >= max_entries_per_ip {
+                return None;
+            }
+        }
         match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &entry.pk)) {
             Ok(idx) => {
                 // node with such pk already announced - just update the entry
@@ -263,7 +431,7 @@ impl OnionAnnounce {
         onion_return: OnionReturn,
         addr: SocketAddr
     ) -> (AnnounceStatus, sha256::Digest) {
-        let time = SystemTime::now();
+        let time = clock_system_time();
         let ping_id_1 = self.ping_id(
             time,
             request_pk,
@@ -338,6 +506,7 @@ impl OnionAnnounce {
 mod tests {
     use super::*;
 
+    use byteorder::{BigEndian, ByteOrder};
     use tokio_executor;
     use tokio_timer::clock::*;
 
@@ -484,6 +653,150 @@ mod tests {
         });
     }
 
+    #[test]
+    fn entries_count() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        assert_eq!(onion_announce.entries_count(), 0);
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_time = entry.time;
+        onion_announce.entries.push(entry);
+        assert_eq!(onion_announce.entries_count(), 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        // time when entry is timed out
+        let clock = Clock::new_with_now(ConstNow(
+            entry_time + Duration::from_secs(ONION_ANNOUNCE_TIMEOUT + 1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert_eq!(onion_announce.entries_count(), 0);
+        });
+    }
+
+    fn create_random_state_entry(saddr: SocketAddr) -> OnionAnnounceEntry {
+        let mut entry = create_random_entry(saddr);
+        entry.onion_return.payload = vec![42; ONION_RETURN_3_PAYLOAD_SIZE];
+        entry
+    }
+
+    #[test]
+    fn remove_timed_out_entries() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_time = entry.time;
+        onion_announce.entries.push(entry);
+        assert_eq!(onion_announce.remove_timed_out_entries(), 0);
+        assert_eq!(onion_announce.entries.len(), 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        // time when entry is timed out
+        let clock = Clock::new_with_now(ConstNow(
+            entry_time + Duration::from_secs(ONION_ANNOUNCE_TIMEOUT + 1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert_eq!(onion_announce.remove_timed_out_entries(), 1);
+        });
+        assert!(onion_announce.entries.is_empty());
+    }
+
+    #[test]
+    fn state_encode_decode() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        onion_announce.add_to_entries(create_random_state_entry("1.2.3.4:12345".parse().unwrap()));
+        onion_announce.add_to_entries(create_random_state_entry("[2001:db8::1]:33445".parse().unwrap()));
+
+        let state = onion_announce.state();
+        let mut buf = [0; ONION_ANNOUNCE_STATE_MAX_SIZE];
+        let (_, size) = state.to_bytes((&mut buf, 0)).unwrap();
+        let (rest, decoded_state) = OnionAnnounceState::from_bytes(&buf[..size]).unwrap();
+
+        assert!(rest.is_empty());
+        assert_eq!(decoded_state.secret_bytes, state.secret_bytes);
+        assert_eq!(decoded_state.entries.len(), 2);
+        for (decoded_entry, entry) in decoded_state.entries.iter().zip(state.entries.iter()) {
+            assert_eq!(decoded_entry.pk, entry.pk);
+            assert_eq!(decoded_entry.ip_addr, entry.ip_addr);
+            assert_eq!(decoded_entry.port, entry.port);
+            assert_eq!(decoded_entry.onion_return, entry.onion_return);
+            assert_eq!(decoded_entry.data_pk, entry.data_pk);
+            assert!(!decoded_entry.is_timed_out());
+        }
+    }
+
+    #[test]
+    fn state_skips_timed_out_entries() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        onion_announce.add_to_entries(create_random_state_entry("1.2.3.4:12345".parse().unwrap()));
+
+        let state = onion_announce.state();
+        let mut buf = [0; ONION_ANNOUNCE_STATE_MAX_SIZE];
+        let (_, size) = state.to_bytes((&mut buf, 0)).unwrap();
+        // rewrite unix time of the entry to make it timed out
+        let time_pos = size - ONION_RETURN_3_SIZE - 8;
+        let time = unix_time(SystemTime::now()) - ONION_ANNOUNCE_TIMEOUT - 1;
+        BigEndian::write_u64(&mut buf[time_pos .. time_pos + 8], time);
+
+        let (_, decoded_state) = OnionAnnounceState::from_bytes(&buf[..size]).unwrap();
+        assert_eq!(decoded_state.secret_bytes, state.secret_bytes);
+        assert!(decoded_state.entries.is_empty());
+    }
+
+    #[test]
+    fn restore_keeps_ping_id_valid() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        let entry = create_random_state_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        onion_announce.add_to_entries(entry);
+
+        let time = SystemTime::now();
+        let pk = gen_keypair().0;
+        let ip_addr = "1.2.3.4".parse().unwrap();
+        let ping_id = onion_announce.ping_id(time, pk, ip_addr, 12345);
+
+        let mut restored = OnionAnnounce::new(dht_pk);
+        restored.restore(onion_announce.state());
+
+        assert_eq!(restored.ping_id(time, pk, ip_addr, 12345), ping_id);
+        assert!(restored.find_in_entries(entry_pk).is_some());
+    }
+
+    #[test]
+    fn add_to_entries_respects_max_entries_per_ip() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        onion_announce.set_max_entries_per_ip(Some(2));
+
+        let entry_1 = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_2 = create_random_entry("1.2.3.4:12346".parse().unwrap());
+        let entry_3 = create_random_entry("1.2.3.4:12347".parse().unwrap());
+        let entry_4 = create_random_entry("1.2.3.5:12345".parse().unwrap());
+        let entry_1_c = entry_1.clone();
+
+        assert!(onion_announce.add_to_entries(entry_1).is_some());
+        assert!(onion_announce.add_to_entries(entry_2).is_some());
+        assert!(onion_announce.add_to_entries(entry_3).is_none());
+        // entries from other IP addresses are not affected
+        assert!(onion_announce.add_to_entries(entry_4).is_some());
+        // already announced entries can be updated
+        assert!(onion_announce.add_to_entries(entry_1_c).is_some());
+
+        assert_eq!(onion_announce.entries.len(), 3);
+    }
+
     ////////////////////////////////////////////////////////////////////////////////////////
     // Tests for OnionAnnounce::add_to_entries
     #[test]
@@ -0,0 +1,129 @@
+/*! OnionCustomData packet
+*/
+
+use super::*;
+use crate::toxcore::friend_connection::packet::*;
+
+use nom::be_u64;
+use std::cmp;
+
+/// Maximum size of application-defined data that can be sent via onion.
+pub const MAX_ONION_CUSTOM_DATA_SIZE: usize = MAX_ONION_CLIENT_DATA_SIZE - 9;
+
+/** Application-defined data that can be enclosed in onion data packet and
+sent through onion path to a friend without established crypto connection.
+
+Length    | Content
+--------- | -------------------------
+`1`       | `0xa0`
+`8`       | `no_reply`
+`1..987`  | Data
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionCustomData {
+    /// Number used as a protection against reply attacks. The packet should be
+    /// accepted only if it's higher than the number in the last received packet.
+    pub no_reply: u64,
+    /// Application-defined data.
+    pub data: Vec<u8>,
+}
+
+impl ToBytes for OnionCustomData {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0xa0) >>
+            gen_be_u64!(self.no_reply) >>
+            gen_cond!(self.data.len() > MAX_ONION_CUSTOM_DATA_SIZE || self.data.is_empty(), |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.data.as_slice())
+        )
+    }
+}
+
+impl FromBytes for OnionCustomData {
+    named!(from_bytes<OnionCustomData>, do_parse!(
+        tag!(&[0xa0][..]) >>
+        no_reply: be_u64 >>
+        data: verify!(rest, |data: &[u8]| data.len() <= MAX_ONION_CUSTOM_DATA_SIZE && !data.is_empty()) >>
+        (OnionCustomData {
+            no_reply,
+            data: data.to_vec(),
+        })
+    ));
+}
+
+impl OnionCustomData {
+    /// Create new `OnionCustomData` with `no_reply` set to current time in
+    /// milliseconds. If it's not higher than `last_no_reply` of the previous
+    /// packet sent to the same friend `last_no_reply + 1` is used instead so
+    /// that packets sent in a quick succession are not dropped.
+    pub fn new(data: Vec<u8>, last_no_reply: u64) -> Self {
+        use std::time::UNIX_EPOCH;
+        use crate::toxcore::time::clock_system_time;
+
+        let since_the_epoch = clock_system_time().duration_since(UNIX_EPOCH)
+            .expect("Current time is earlier than Unix epoch");
+        let now = since_the_epoch.as_secs() * 1000 + u64::from(since_the_epoch.subsec_millis());
+
+        OnionCustomData {
+            no_reply: cmp::max(now, last_no_reply.saturating_add(1)),
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        onion_custom_data_encode_decode,
+        OnionCustomData {
+            no_reply: 42,
+            data: vec![42; 123],
+        }
+    );
+
+    #[test]
+    fn onion_custom_data_new_no_reply_increases() {
+        let custom_data_1 = OnionCustomData::new(vec![42; 123], 0);
+        let custom_data_2 = OnionCustomData::new(vec![42; 123], custom_data_1.no_reply);
+        assert!(custom_data_2.no_reply > custom_data_1.no_reply);
+
+        // no_reply from the future is still increased
+        let custom_data_3 = OnionCustomData::new(vec![42; 123], u64::max_value() - 1);
+        assert_eq!(custom_data_3.no_reply, u64::max_value());
+    }
+
+    #[test]
+    fn onion_custom_data_from_bytes_overflow() {
+        let mut custom_data = vec![0xa0, 0, 0, 0, 0, 0, 0, 0, 42];
+        custom_data.extend(vec![42; MAX_ONION_CUSTOM_DATA_SIZE + 1]);
+        assert!(OnionCustomData::from_bytes(&custom_data).is_err());
+    }
+
+    #[test]
+    fn onion_custom_data_to_bytes_overflow() {
+        let custom_data = OnionCustomData {
+            no_reply: 42,
+            data: vec![42; MAX_ONION_CUSTOM_DATA_SIZE + 1],
+        };
+        let mut buf = [0; MAX_ONION_CLIENT_DATA_SIZE + 1]; // `1` is to provide enough space for success of serializing
+        assert!(custom_data.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn onion_custom_data_from_bytes_underflow() {
+        assert!(OnionCustomData::from_bytes(&[0xa0, 0, 0, 0, 0, 0, 0, 0, 42]).is_err());
+    }
+
+    #[test]
+    fn onion_custom_data_to_bytes_underflow() {
+        let custom_data = OnionCustomData {
+            no_reply: 42,
+            data: Vec::new(),
+        };
+        let mut buf = [0; MAX_ONION_CLIENT_DATA_SIZE];
+        assert!(custom_data.to_bytes((&mut buf, 0)).is_err());
+    }
+}
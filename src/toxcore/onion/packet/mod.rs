@@ -14,6 +14,7 @@ mod onion_response_1;
 mod onion_response_2;
 mod onion_response_3;
 mod friend_request;
+mod custom_data;
 
 pub use self::onion_announce_request::*;
 pub use self::onion_announce_response::*;
@@ -28,6 +29,7 @@ pub use self::onion_response_1::*;
 pub use self::onion_response_2::*;
 pub use self::onion_response_3::*;
 pub use self::friend_request::*;
+pub use self::custom_data::*;
 
 use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
@@ -205,12 +205,15 @@ pub enum OnionDataResponseInnerPayload {
     DhtPkAnnounce(DhtPkAnnouncePayload),
     /// [`FriendRequest`](../../dht/packet/struct.FriendRequest.html) structure.
     FriendRequest(FriendRequest),
+    /// [`OnionCustomData`](./struct.OnionCustomData.html) structure.
+    CustomData(OnionCustomData),
 }
 
 impl FromBytes for OnionDataResponseInnerPayload {
     named!(from_bytes<OnionDataResponseInnerPayload>, alt!(
         map!(DhtPkAnnouncePayload::from_bytes, OnionDataResponseInnerPayload::DhtPkAnnounce) |
-        map!(FriendRequest::from_bytes, OnionDataResponseInnerPayload::FriendRequest)
+        map!(FriendRequest::from_bytes, OnionDataResponseInnerPayload::FriendRequest) |
+        map!(OnionCustomData::from_bytes, OnionDataResponseInnerPayload::CustomData)
     ));
 }
 
@@ -219,6 +222,7 @@ impl ToBytes for OnionDataResponseInnerPayload {
         match *self {
             OnionDataResponseInnerPayload::DhtPkAnnounce(ref p) => p.to_bytes(buf),
             OnionDataResponseInnerPayload::FriendRequest(ref p) => p.to_bytes(buf),
+            OnionDataResponseInnerPayload::CustomData(ref p) => p.to_bytes(buf),
         }
     }
 }
@@ -398,4 +402,20 @@ mod tests {
         let decoded_payload = packet.get_payload(&nonce, &shared_secret).unwrap();
         assert_eq!(payload, decoded_payload);
     }
+
+    #[test]
+    fn onion_data_response_payload_encrypt_decrypt_custom_data() {
+        crypto_init().unwrap();
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let nonce = gen_nonce();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let custom_data = OnionCustomData { no_reply: 42, data: vec![42; 123] };
+        let payload = OnionDataResponseInnerPayload::CustomData(custom_data);
+        // encode payload
+        let packet = OnionDataResponsePayload::new(&shared_secret, alice_pk, &nonce, &payload);
+        // decode payload
+        let decoded_payload = packet.get_payload(&nonce, &shared_secret).unwrap();
+        assert_eq!(payload, decoded_payload);
+    }
 }
@@ -0,0 +1,54 @@
+/*! Errors enum for port mapping.
+*/
+
+use std::io::Error as IoError;
+
+use failure::Fail;
+
+error_kind! {
+    #[doc = "Error that can happen when mapping ports on a gateway."]
+    #[derive(Debug)]
+    PortMappingError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    PortMappingErrorKind {
+        #[doc = "General IO error that can happen with sockets."]
+        #[fail(display = "IO error")]
+        Io,
+        #[doc = "Renewal wakeup timer error."]
+        #[fail(display = "Port mapping renewal wakeup timer error")]
+        Wakeup,
+        #[doc = "Gateway didn't respond in time."]
+        #[fail(display = "Gateway didn't respond in time")]
+        Timeout,
+        #[doc = "No gateway that supports port mapping was found."]
+        #[fail(display = "No gateway that supports port mapping was found")]
+        NoGateway,
+        #[doc = "Gateway sent response that can't be parsed."]
+        #[fail(display = "Invalid response from gateway")]
+        InvalidResponse,
+        #[doc = "Gateway doesn't support the protocol version."]
+        #[fail(display = "Gateway doesn't support the protocol version")]
+        UnsupportedVersion,
+        #[doc = "Gateway refused the request."]
+        #[fail(display = "Gateway refused the request with code {}", code)]
+        Refused {
+            #[doc = "Result code returned by gateway."]
+            code: u16,
+        },
+    }
+}
+
+impl PortMappingError {
+    pub(crate) fn refused(code: u16) -> PortMappingError {
+        PortMappingError::from(PortMappingErrorKind::Refused { code })
+    }
+}
+
+impl From<IoError> for PortMappingError {
+    fn from(error: IoError) -> PortMappingError {
+        PortMappingError {
+            ctx: error.context(PortMappingErrorKind::Io)
+        }
+    }
+}
@@ -0,0 +1,681 @@
+/*! Port mapping on NAT gateways.
+
+Hole punching often fails when we are behind a symmetric NAT. Most home
+routers however allow to create port mappings explicitly using UPnP IGD,
+NAT-PMP or PCP protocols. `PortMapper` finds such gateway, maps our UDP DHT
+port and TCP relay port, renews the mappings before they expire and reports
+our external address back to the DHT.
+*/
+
+mod errors;
+pub mod natpmp;
+pub mod pcp;
+pub mod upnp;
+
+pub use self::errors::*;
+
+use std::fs;
+use std::io::Error as IoError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, NativeEndian};
+use failure::Fail;
+use futures::{future, Future, Stream};
+use futures::future::{Either, Loop, join_all};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::net::UdpSocket;
+use tokio::timer::Interval;
+use tokio::timer::timeout::Error as TimeoutError;
+use tokio::util::FutureExt;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::port_mapping::pcp::PCP_NONCE_SIZE;
+use crate::toxcore::port_mapping::upnp::IgdGateway;
+use crate::toxcore::time::*;
+
+/// UDP port of gateway for NAT-PMP and PCP requests.
+pub const NATPMP_PORT: u16 = 5351;
+
+/// Multicast IP address for SSDP requests.
+pub fn ssdp_multicast_ip() -> Ipv4Addr {
+    Ipv4Addr::new(239, 255, 255, 250)
+}
+
+/// UDP port for SSDP requests.
+pub const SSDP_PORT: u16 = 1900;
+
+/// Requested lifetime of port mappings in seconds.
+pub const PORT_MAPPING_LIFETIME: u32 = 7200;
+
+/// Initial timeout in milliseconds for UDP requests to gateway. It's doubled
+/// on every retransmission.
+pub const PORT_MAPPING_TIMEOUT: u64 = 250;
+
+/// How many times UDP requests to gateway are sent before giving up.
+pub const PORT_MAPPING_RETRIES: u32 = 4;
+
+/// Interval in seconds for checking if port mappings should be renewed.
+pub const PORT_MAPPING_CHECK_INTERVAL: u64 = 30;
+
+/// Maximum size of UDP response from gateway.
+const MAX_RESPONSE_SIZE: usize = 1100;
+
+/// A convenience typedef around a `Future` whose error component is
+/// `PortMappingError`.
+pub type PortMappingFuture<T> = Box<Future<Item = T, Error = PortMappingError> + Send>;
+
+/// Transport protocol of a port mapping.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Protocol {
+    /// UDP port.
+    Udp,
+    /// TCP port.
+    Tcp,
+}
+
+/// Port mapping created on gateway.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mapping {
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Local port.
+    pub internal_port: u16,
+    /// Address that is visible from the internet.
+    pub external_addr: SocketAddr,
+    /// Lifetime of the mapping in seconds assigned by gateway. 0 means that
+    /// the mapping is permanent.
+    pub lifetime: u32,
+    /// Nonce that identifies the mapping on PCP gateway.
+    nonce: [u8; PCP_NONCE_SIZE],
+}
+
+/// Gateway that supports port mapping.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Gateway {
+    /// Gateway that supports PCP.
+    Pcp {
+        /// Address of gateway.
+        addr: SocketAddr,
+        /// Our local IP address as seen by gateway.
+        client_ip: IpAddr,
+    },
+    /// Gateway that supports NAT-PMP.
+    NatPmp {
+        /// Address of gateway.
+        addr: SocketAddr,
+    },
+    /// UPnP Internet Gateway Device.
+    Igd(IgdGateway),
+}
+
+impl Gateway {
+    /// Create a port mapping on gateway. When `previous` mapping is specified
+    /// it will be renewed.
+    pub fn map(&self, protocol: Protocol, internal_port: u16, previous: Option<&Mapping>, lifetime: u32, timeout: Duration)
+        -> PortMappingFuture<Mapping>
+    {
+        let external_port = previous.map_or(internal_port, |mapping| mapping.external_addr.port());
+        match *self {
+            Gateway::Pcp { addr, client_ip } => {
+                let nonce = previous.map_or_else(pcp::gen_nonce, |mapping| mapping.nonce);
+                let external_ip = previous.map_or_else(
+                    || if client_ip.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() },
+                    |mapping| mapping.external_addr.ip()
+                );
+                let request = pcp::MapRequest {
+                    lifetime,
+                    client_ip,
+                    nonce,
+                    protocol,
+                    internal_port,
+                    external_port,
+                    external_ip,
+                };
+                Box::new(pcp::map(addr, request, timeout).map(move |response| Mapping {
+                    protocol,
+                    internal_port,
+                    external_addr: SocketAddr::new(response.external_ip, response.external_port),
+                    lifetime: response.header.lifetime,
+                    nonce,
+                }))
+            },
+            Gateway::NatPmp { addr } => {
+                let request = natpmp::MapRequest {
+                    protocol,
+                    internal_port,
+                    external_port,
+                    lifetime,
+                };
+                let future = natpmp::external_address(addr, timeout)
+                    .and_then(move |ip| natpmp::map(addr, request, timeout).map(move |response| Mapping {
+                        protocol,
+                        internal_port,
+                        external_addr: SocketAddr::new(ip.into(), response.external_port),
+                        lifetime: response.lifetime,
+                        nonce: [0; PCP_NONCE_SIZE],
+                    }));
+                Box::new(future)
+            },
+            Gateway::Igd(ref igd) => {
+                let igd = igd.clone();
+                let future = igd.external_ip()
+                    .and_then(move |ip| igd.add_port_mapping(protocol, internal_port, external_port, lifetime).map(move |lifetime| Mapping {
+                        protocol,
+                        internal_port,
+                        external_addr: SocketAddr::new(ip, external_port),
+                        lifetime,
+                        nonce: [0; PCP_NONCE_SIZE],
+                    }));
+                Box::new(future)
+            },
+        }
+    }
+
+    /// Remove a port mapping from gateway.
+    pub fn unmap(&self, mapping: &Mapping, timeout: Duration) -> PortMappingFuture<()> {
+        match *self {
+            Gateway::Pcp { addr, client_ip } => {
+                let request = pcp::MapRequest {
+                    lifetime: 0,
+                    client_ip,
+                    nonce: mapping.nonce,
+                    protocol: mapping.protocol,
+                    internal_port: mapping.internal_port,
+                    external_port: mapping.external_addr.port(),
+                    external_ip: mapping.external_addr.ip(),
+                };
+                Box::new(pcp::map(addr, request, timeout).map(|_| ()))
+            },
+            Gateway::NatPmp { addr } => {
+                let request = natpmp::MapRequest {
+                    protocol: mapping.protocol,
+                    internal_port: mapping.internal_port,
+                    external_port: 0,
+                    lifetime: 0,
+                };
+                Box::new(natpmp::map(addr, request, timeout).map(|_| ()))
+            },
+            Gateway::Igd(ref igd) =>
+                Box::new(igd.remove_port_mapping(mapping.protocol, mapping.external_addr.port())),
+        }
+    }
+}
+
+/// Configuration of `PortMapper`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortMapperConfig {
+    /// IP address of gateway for NAT-PMP and PCP requests. If it's `None`
+    /// the default gateway of the host will be used.
+    pub gateway: Option<IpAddr>,
+    /// UDP port of gateway for NAT-PMP and PCP requests.
+    pub natpmp_port: u16,
+    /// Address where SSDP requests are sent to find UPnP IGD.
+    pub ssdp_addr: SocketAddr,
+    /// Requested lifetime of port mappings in seconds.
+    pub lifetime: u32,
+    /// Initial timeout for UDP requests to gateway.
+    pub timeout: Duration,
+    /// Whether PCP should be used.
+    pub enable_pcp: bool,
+    /// Whether NAT-PMP should be used.
+    pub enable_natpmp: bool,
+    /// Whether UPnP IGD should be used.
+    pub enable_upnp: bool,
+}
+
+impl Default for PortMapperConfig {
+    fn default() -> Self {
+        PortMapperConfig {
+            gateway: None,
+            natpmp_port: NATPMP_PORT,
+            ssdp_addr: SocketAddr::new(ssdp_multicast_ip().into(), SSDP_PORT),
+            lifetime: PORT_MAPPING_LIFETIME,
+            timeout: Duration::from_millis(PORT_MAPPING_TIMEOUT),
+            enable_pcp: true,
+            enable_natpmp: true,
+            enable_upnp: true,
+        }
+    }
+}
+
+/// Port mapper that keeps port mappings on gateway alive.
+#[derive(Clone)]
+pub struct PortMapper {
+    /// Configuration of the port mapper.
+    config: PortMapperConfig,
+    /// Local ports that should be mapped.
+    ports: Arc<RwLock<Vec<(Protocol, u16)>>>,
+    /// Gateway that is used to map ports. `None` if it's not found yet or if
+    /// it stopped responding.
+    gateway: Arc<RwLock<Option<Gateway>>>,
+    /// Current port mappings.
+    mappings: Arc<RwLock<Vec<Mapping>>>,
+    /// Time when port mappings should be renewed. `None` if they should be
+    /// renewed immediately.
+    refresh_time: Arc<RwLock<Option<Instant>>>,
+    /// Sink to send our external address of mapped UDP ports to. It's sent
+    /// every time the external address changes.
+    external_addr_sink: Option<mpsc::UnboundedSender<SocketAddr>>,
+}
+
+impl PortMapper {
+    /// Create new `PortMapper`.
+    pub fn new(config: PortMapperConfig) -> PortMapper {
+        PortMapper {
+            config,
+            ports: Arc::new(RwLock::new(Vec::new())),
+            gateway: Arc::new(RwLock::new(None)),
+            mappings: Arc::new(RwLock::new(Vec::new())),
+            refresh_time: Arc::new(RwLock::new(None)),
+            external_addr_sink: None,
+        }
+    }
+
+    /// Set sink to send our external address of mapped UDP ports to.
+    pub fn set_external_addr_sink(&mut self, tx: mpsc::UnboundedSender<SocketAddr>) {
+        self.external_addr_sink = Some(tx);
+    }
+
+    /// Add a local port that should be mapped. It will be mapped on the next
+    /// refresh.
+    pub fn add_port(&self, protocol: Protocol, port: u16) {
+        let mut ports = self.ports.write();
+        if !ports.contains(&(protocol, port)) {
+            ports.push((protocol, port));
+            *self.refresh_time.write() = None;
+        }
+    }
+
+    /// Get gateway that is used to map ports.
+    pub fn gateway(&self) -> Option<Gateway> {
+        self.gateway.read().clone()
+    }
+
+    /// Get current port mappings.
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.mappings.read().clone()
+    }
+
+    /// Find gateway that supports port mapping. PCP is tried first, then
+    /// NAT-PMP and then UPnP IGD.
+    pub fn discover(&self) -> PortMappingFuture<Gateway> {
+        let config = self.config;
+        let gateway_ip = config.gateway.or_else(|| default_gateway().map(IpAddr::V4));
+        let gateway_addr = gateway_ip.map(|ip| SocketAddr::new(ip, config.natpmp_port));
+
+        let pcp_future = match gateway_addr {
+            Some(addr) if config.enable_pcp => match local_ip_for(addr) {
+                Ok(client_ip) => Either::A(pcp::announce(addr, client_ip, config.timeout)
+                    .map(move |()| Gateway::Pcp { addr, client_ip })),
+                Err(e) => Either::B(future::err(e.into())),
+            },
+            _ => Either::B(future::err(PortMappingErrorKind::NoGateway.into())),
+        };
+
+        let future = pcp_future
+            .or_else(move |e| {
+                debug!("PCP gateway is not found: {}", e);
+                match gateway_addr {
+                    Some(addr) if config.enable_natpmp => Either::A(natpmp::external_address(addr, config.timeout)
+                        .map(move |_| Gateway::NatPmp { addr })),
+                    _ => Either::B(future::err(PortMappingErrorKind::NoGateway.into())),
+                }
+            })
+            .or_else(move |e| {
+                debug!("NAT-PMP gateway is not found: {}", e);
+                if config.enable_upnp {
+                    Either::A(upnp::discover(config.ssdp_addr, config.timeout).map(Gateway::Igd))
+                } else {
+                    Either::B(future::err(PortMappingErrorKind::NoGateway.into()))
+                }
+            })
+            .map_err(|e| {
+                debug!("UPnP IGD is not found: {}", e);
+                e.context(PortMappingErrorKind::NoGateway).into()
+            });
+
+        Box::new(future)
+    }
+
+    /// Create or renew port mappings for all ports. Gateway will be
+    /// discovered if it's not found yet.
+    pub fn refresh(&self) -> PortMappingFuture<()> {
+        let gateway_future = match self.gateway() {
+            Some(gateway) => Either::A(future::ok(gateway)),
+            None => Either::B(self.discover()),
+        };
+
+        let mapper = self.clone();
+        let future = gateway_future.and_then(move |gateway| {
+            let ports = mapper.ports.read().clone();
+            let mappings = mapper.mappings();
+            let futures = ports.into_iter().map(|(protocol, port)| {
+                let previous = mappings.iter().find(|mapping|
+                    mapping.protocol == protocol && mapping.internal_port == port
+                );
+                gateway.map(protocol, port, previous, mapper.config.lifetime, mapper.config.timeout)
+            }).collect::<Vec<_>>();
+
+            join_all(futures).then(move |result| match result {
+                Ok(mappings) => {
+                    *mapper.gateway.write() = Some(gateway);
+                    mapper.update_mappings(mappings);
+                    Ok(())
+                },
+                Err(e) => {
+                    // gateway might be replaced so we should look for it again
+                    *mapper.gateway.write() = None;
+                    *mapper.refresh_time.write() = None;
+                    Err(e)
+                },
+            })
+        });
+
+        Box::new(future)
+    }
+
+    /// Store new port mappings, schedule their renewal and report changed
+    /// external addresses of UDP ports.
+    fn update_mappings(&self, mappings: Vec<Mapping>) {
+        let mut old_mappings = self.mappings.write();
+
+        for mapping in mappings.iter().filter(|mapping| mapping.protocol == Protocol::Udp) {
+            let changed = !old_mappings.iter().any(|old_mapping|
+                old_mapping.protocol == mapping.protocol &&
+                    old_mapping.internal_port == mapping.internal_port &&
+                    old_mapping.external_addr == mapping.external_addr
+            );
+            if changed {
+                info!("Port {} is mapped to {}", mapping.internal_port, mapping.external_addr);
+                if let Some(ref tx) = self.external_addr_sink {
+                    if tx.unbounded_send(mapping.external_addr).is_err() {
+                        warn!("Failed to send external address: receiver is dropped");
+                    }
+                }
+            }
+        }
+
+        // renew mappings at half of the shortest lifetime
+        let lifetime = mappings.iter()
+            .map(|mapping| mapping.lifetime)
+            .filter(|&lifetime| lifetime > 0)
+            .min()
+            .unwrap_or(self.config.lifetime);
+        *self.refresh_time.write() = Some(clock_now() + Duration::from_secs(u64::from(lifetime / 2)));
+
+        *old_mappings = mappings;
+    }
+
+    /// Run periodical renewal of port mappings. Errors of gateway are logged
+    /// and the gateway is looked for again on the next check.
+    pub fn run(self) -> PortMappingFuture<()> {
+        let interval = Duration::from_secs(PORT_MAPPING_CHECK_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        let future = wakeups
+            .map_err(|e| e.context(PortMappingErrorKind::Wakeup).into())
+            .for_each(move |_instant| {
+                let refresh_needed = match *self.refresh_time.read() {
+                    Some(refresh_time) => clock_now() >= refresh_time,
+                    None => true,
+                };
+                if !refresh_needed {
+                    return Either::A(future::ok(()));
+                }
+
+                Either::B(self.refresh().then(|result| {
+                    if let Err(e) = result {
+                        warn!("Failed to map ports: {}", e);
+                    }
+                    Ok(())
+                }))
+            });
+
+        Box::new(future)
+    }
+
+    /// Remove all port mappings from gateway. Should be called after the
+    /// future returned by `run` is dropped.
+    pub fn shutdown(&self) -> PortMappingFuture<()> {
+        let mappings = self.mappings.write().drain(..).collect::<Vec<_>>();
+        *self.refresh_time.write() = None;
+
+        let gateway = match self.gateway() {
+            Some(gateway) => gateway,
+            None => return Box::new(future::ok(())),
+        };
+
+        let timeout = self.config.timeout;
+        let futures = mappings.iter()
+            .map(|mapping| gateway.unmap(mapping, timeout))
+            .collect::<Vec<_>>();
+
+        Box::new(join_all(futures).map(|_| ()))
+    }
+}
+
+/// Get the default IPv4 gateway of the host from `/proc/net/route`. Returns
+/// `None` on platforms without this file.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    fs::read_to_string("/proc/net/route").ok()
+        .and_then(|routes| parse_routes(&routes))
+}
+
+/// Parse the default gateway from the content of `/proc/net/route`.
+/// Addresses in this file are hex numbers in host byte order.
+fn parse_routes(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() < 3 || fields[1] != "00000000" {
+                return None;
+            }
+            u32::from_str_radix(fields[2], 16).ok()
+        })
+        .find(|&gateway| gateway != 0)
+        .map(|gateway| {
+            let mut octets = [0; 4];
+            NativeEndian::write_u32(&mut octets, gateway);
+            Ipv4Addr::from(octets)
+        })
+}
+
+/// Get our local IP address that is used to send packets to the given
+/// address. No packets are sent.
+fn local_ip_for(addr: SocketAddr) -> Result<IpAddr, IoError> {
+    let bind_addr = if addr.is_ipv4() {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+    let socket = StdUdpSocket::bind(bind_addr)?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Convert error of a future with timeout to `PortMappingError`.
+pub(crate) fn timeout_error<E: Into<PortMappingError>>(error: TimeoutError<E>) -> PortMappingError {
+    if error.is_elapsed() {
+        PortMappingErrorKind::Timeout.into()
+    } else if let Some(error) = error.into_inner() {
+        error.into()
+    } else {
+        PortMappingErrorKind::Wakeup.into()
+    }
+}
+
+/// Send UDP datagram to the address and wait for a response. The datagram is
+/// retransmitted with doubled timeout if there is no response. If
+/// `any_source` is false responses from other addresses are ignored.
+pub(crate) fn udp_exchange(dest: SocketAddr, data: Vec<u8>, timeout: Duration, any_source: bool)
+    -> impl Future<Item = Vec<u8>, Error = PortMappingError> + Send
+{
+    let bind_addr = if dest.is_ipv4() {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+
+    future::loop_fn(0, move |attempt| {
+        let data = data.clone();
+        future::result(UdpSocket::bind(&bind_addr))
+            .and_then(move |socket| socket.send_dgram(data, &dest))
+            .and_then(|(socket, _)| socket.recv_dgram(vec![0; MAX_RESPONSE_SIZE]))
+            .timeout(timeout * 2u32.pow(attempt))
+            .then(move |result| match result {
+                Ok((_socket, mut buf, size, addr)) => if any_source || addr.ip() == dest.ip() {
+                    buf.truncate(size);
+                    Ok(Loop::Break(buf))
+                } else if attempt + 1 < PORT_MAPPING_RETRIES {
+                    Ok(Loop::Continue(attempt + 1))
+                } else {
+                    Err(PortMappingErrorKind::InvalidResponse.into())
+                },
+                Err(ref e) if e.is_elapsed() && attempt + 1 < PORT_MAPPING_RETRIES =>
+                    Ok(Loop::Continue(attempt + 1)),
+                Err(e) => Err(timeout_error(e)),
+            })
+    })
+}
+
+/// Send NAT-PMP or PCP request to gateway and wait for a response.
+pub(crate) fn udp_request<T: ToBytes>(gateway: SocketAddr, request: &T, timeout: Duration)
+    -> impl Future<Item = Vec<u8>, Error = PortMappingError> + Send
+{
+    let mut buf = [0; MAX_RESPONSE_SIZE];
+    let (_, size) = request.to_bytes((&mut buf, 0))
+        .expect("Failed to serialize port mapping request");
+    udp_exchange(gateway, buf[..size].to_vec(), timeout, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+
+    use tokio::runtime::Runtime;
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn parse_routes_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+            eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert_eq!(parse_routes(routes), Some(Ipv4Addr::new(192, 168, 1, 1)));
+    }
+
+    #[test]
+    fn parse_routes_no_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+        assert_eq!(parse_routes(routes), None);
+    }
+
+    #[test]
+    fn discover_disabled() {
+        let config = PortMapperConfig {
+            gateway: Some("127.0.0.1".parse().unwrap()),
+            enable_pcp: false,
+            enable_natpmp: false,
+            enable_upnp: false,
+            .. PortMapperConfig::default()
+        };
+        let mapper = PortMapper::new(config);
+        let mut runtime = Runtime::new().unwrap();
+        let error = runtime.block_on(mapper.discover()).unwrap_err();
+        assert_eq!(*error.kind(), PortMappingErrorKind::NoGateway);
+    }
+
+    /// Spawn NAT-PMP gateway responder that doesn't support PCP. Received map
+    /// requests are sent to the returned channel.
+    fn spawn_natpmp_gateway() -> (SocketAddr, std_mpsc::Receiver<natpmp::MapRequest>) {
+        let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, rx) = std_mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; MAX_RESPONSE_SIZE];
+            loop {
+                let (size, peer) = socket.recv_from(&mut buf).unwrap();
+                let mut out = [0; 16];
+                let out_size = if buf[0] == pcp::PCP_VERSION {
+                    out[..4].copy_from_slice(&[0, 0x80 | buf[1], 0, 1]);
+                    4
+                } else if let IResult::Done(_, request) = natpmp::MapRequest::from_bytes(&buf[..size]) {
+                    let external_port = if request.lifetime == 0 { 0 } else { request.internal_port + 1000 };
+                    let (_, out_size) = natpmp::MapResponse {
+                        protocol: request.protocol,
+                        result: 0,
+                        epoch: 1,
+                        internal_port: request.internal_port,
+                        external_port,
+                        lifetime: request.lifetime.min(3600),
+                    }.to_bytes((&mut out, 0)).unwrap();
+                    if tx.send(request).is_err() {
+                        return;
+                    }
+                    out_size
+                } else {
+                    let (_, out_size) = natpmp::ExternalAddressResponse {
+                        result: 0,
+                        epoch: 1,
+                        addr: Ipv4Addr::new(1, 2, 3, 4),
+                    }.to_bytes((&mut out, 0)).unwrap();
+                    out_size
+                };
+                socket.send_to(&out[..out_size], peer).unwrap();
+            }
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn refresh_and_shutdown() {
+        let (gateway_addr, requests_rx) = spawn_natpmp_gateway();
+        let config = PortMapperConfig {
+            gateway: Some(gateway_addr.ip()),
+            natpmp_port: gateway_addr.port(),
+            enable_upnp: false,
+            .. PortMapperConfig::default()
+        };
+        let mut mapper = PortMapper::new(config);
+        let (tx, rx) = mpsc::unbounded();
+        mapper.set_external_addr_sink(tx);
+        mapper.add_port(Protocol::Udp, 33445);
+        mapper.add_port(Protocol::Tcp, 33446);
+        // duplicates are ignored
+        mapper.add_port(Protocol::Udp, 33445);
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(mapper.refresh()).unwrap();
+
+        assert_eq!(mapper.gateway(), Some(Gateway::NatPmp { addr: gateway_addr }));
+        let mappings = mapper.mappings();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].protocol, Protocol::Udp);
+        assert_eq!(mappings[0].external_addr, "1.2.3.4:34445".parse().unwrap());
+        assert_eq!(mappings[0].lifetime, 3600);
+        assert_eq!(mappings[1].protocol, Protocol::Tcp);
+        assert_eq!(mappings[1].external_addr, "1.2.3.4:34446".parse().unwrap());
+
+        // renewal requests the previously assigned external port
+        runtime.block_on(mapper.refresh()).unwrap();
+
+        runtime.block_on(mapper.shutdown()).unwrap();
+        assert!(mapper.mappings().is_empty());
+
+        let requests = requests_rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 6);
+        assert!(requests[2..4].iter().all(|request| request.external_port == request.internal_port + 1000));
+        assert!(requests[4..].iter().all(|request| request.lifetime == 0));
+
+        // external address is reported only once
+        drop(mapper);
+        let addrs = runtime.block_on(rx.collect()).unwrap();
+        assert_eq!(addrs, vec!["1.2.3.4:34445".parse().unwrap()]);
+    }
+}
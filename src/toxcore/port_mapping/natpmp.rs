@@ -0,0 +1,393 @@
+/*! NAT-PMP protocol implementation.
+
+NAT-PMP is described in [RFC 6886](https://tools.ietf.org/html/rfc6886). A
+client sends requests to UDP port 5351 of its default gateway. All numbers
+are in network byte order.
+*/
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::Future;
+use nom::{be_u8, be_u16, be_u32};
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::port_mapping::*;
+
+/// NAT-PMP protocol version.
+pub const NATPMP_VERSION: u8 = 0;
+
+/// Result code returned by gateway when it doesn't support the protocol
+/// version of request.
+pub const NATPMP_UNSUPPORTED_VERSION: u16 = 1;
+
+/// Opcode of a NAT-PMP request for the given protocol.
+fn opcode(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+    }
+}
+
+/** Request for the external IPv4 address of gateway.
+
+Serialized form:
+
+Length      | Contents
+----------- | --------
+`1`         | Version (0)
+`1`         | Opcode (0)
+
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExternalAddressRequest;
+
+impl ToBytes for ExternalAddressRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(NATPMP_VERSION) >>
+            gen_be_u8!(0)
+        )
+    }
+}
+
+impl FromBytes for ExternalAddressRequest {
+    named!(from_bytes<ExternalAddressRequest>, do_parse!(
+        tag!(&[NATPMP_VERSION, 0][..]) >>
+        (ExternalAddressRequest)
+    ));
+}
+
+/** Response to `ExternalAddressRequest`.
+
+Serialized form:
+
+Length      | Contents
+----------- | --------
+`1`         | Version (0)
+`1`         | Opcode (128)
+`2`         | Result code
+`4`         | Seconds since start of epoch
+`4`         | External IPv4 address
+
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExternalAddressResponse {
+    /// Result code, 0 means success.
+    pub result: u16,
+    /// Seconds since the gateway's port mapping table was initialized.
+    pub epoch: u32,
+    /// External IPv4 address of gateway.
+    pub addr: Ipv4Addr,
+}
+
+impl ToBytes for ExternalAddressResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(NATPMP_VERSION) >>
+            gen_be_u8!(128) >>
+            gen_be_u16!(self.result) >>
+            gen_be_u32!(self.epoch) >>
+            gen_slice!(&self.addr.octets())
+        )
+    }
+}
+
+impl FromBytes for ExternalAddressResponse {
+    named!(from_bytes<ExternalAddressResponse>, do_parse!(
+        tag!(&[NATPMP_VERSION, 128][..]) >>
+        result: be_u16 >>
+        epoch: be_u32 >>
+        addr: take!(4) >>
+        (ExternalAddressResponse {
+            result,
+            epoch,
+            addr: Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]),
+        })
+    ));
+}
+
+/** Request to create, renew or remove a port mapping. The mapping is removed
+when lifetime is 0.
+
+Serialized form:
+
+Length      | Contents
+----------- | --------
+`1`         | Version (0)
+`1`         | Opcode (1 for UDP, 2 for TCP)
+`2`         | Reserved (0)
+`2`         | Internal port
+`2`         | Suggested external port
+`4`         | Requested lifetime in seconds
+
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MapRequest {
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Local port.
+    pub internal_port: u16,
+    /// Suggested external port.
+    pub external_port: u16,
+    /// Requested lifetime of the mapping in seconds.
+    pub lifetime: u32,
+}
+
+impl ToBytes for MapRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(NATPMP_VERSION) >>
+            gen_be_u8!(opcode(self.protocol)) >>
+            gen_be_u16!(0) >>
+            gen_be_u16!(self.internal_port) >>
+            gen_be_u16!(self.external_port) >>
+            gen_be_u32!(self.lifetime)
+        )
+    }
+}
+
+impl FromBytes for MapRequest {
+    named!(from_bytes<MapRequest>, do_parse!(
+        tag!(&[NATPMP_VERSION][..]) >>
+        protocol: switch!(be_u8,
+            1 => value!(Protocol::Udp) |
+            2 => value!(Protocol::Tcp)
+        ) >>
+        tag!(&[0, 0][..]) >>
+        internal_port: be_u16 >>
+        external_port: be_u16 >>
+        lifetime: be_u32 >>
+        (MapRequest { protocol, internal_port, external_port, lifetime })
+    ));
+}
+
+/** Response to `MapRequest`.
+
+Serialized form:
+
+Length      | Contents
+----------- | --------
+`1`         | Version (0)
+`1`         | Opcode (129 for UDP, 130 for TCP)
+`2`         | Result code
+`4`         | Seconds since start of epoch
+`2`         | Internal port
+`2`         | Mapped external port
+`4`         | Lifetime of the mapping in seconds
+
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MapResponse {
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Result code, 0 means success.
+    pub result: u16,
+    /// Seconds since the gateway's port mapping table was initialized.
+    pub epoch: u32,
+    /// Local port.
+    pub internal_port: u16,
+    /// External port assigned by gateway.
+    pub external_port: u16,
+    /// Lifetime of the mapping in seconds assigned by gateway.
+    pub lifetime: u32,
+}
+
+impl ToBytes for MapResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(NATPMP_VERSION) >>
+            gen_be_u8!(128 + opcode(self.protocol)) >>
+            gen_be_u16!(self.result) >>
+            gen_be_u32!(self.epoch) >>
+            gen_be_u16!(self.internal_port) >>
+            gen_be_u16!(self.external_port) >>
+            gen_be_u32!(self.lifetime)
+        )
+    }
+}
+
+impl FromBytes for MapResponse {
+    named!(from_bytes<MapResponse>, do_parse!(
+        tag!(&[NATPMP_VERSION][..]) >>
+        protocol: switch!(be_u8,
+            129 => value!(Protocol::Udp) |
+            130 => value!(Protocol::Tcp)
+        ) >>
+        result: be_u16 >>
+        epoch: be_u32 >>
+        internal_port: be_u16 >>
+        external_port: be_u16 >>
+        lifetime: be_u32 >>
+        (MapResponse { protocol, result, epoch, internal_port, external_port, lifetime })
+    ));
+}
+
+/// Check the common header of a NAT-PMP response and return an error if the
+/// gateway refused the request.
+fn check_result(data: &[u8]) -> Result<(), PortMappingError> {
+    if data.len() < 4 {
+        return Err(PortMappingErrorKind::InvalidResponse.into());
+    }
+    let result = u16::from(data[2]) << 8 | u16::from(data[3]);
+    if data[0] != NATPMP_VERSION || result == NATPMP_UNSUPPORTED_VERSION {
+        Err(PortMappingErrorKind::UnsupportedVersion.into())
+    } else if result != 0 {
+        Err(PortMappingError::refused(result))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse a NAT-PMP response checking its result code.
+fn parse_response<T: FromBytes>(data: &[u8]) -> Result<T, PortMappingError> {
+    check_result(data)?;
+    match T::from_bytes(data) {
+        IResult::Done(_, response) => Ok(response),
+        _ => Err(PortMappingErrorKind::InvalidResponse.into()),
+    }
+}
+
+/// Request the external IPv4 address of the gateway.
+pub fn external_address(gateway: SocketAddr, timeout: Duration)
+    -> impl Future<Item = Ipv4Addr, Error = PortMappingError> + Send
+{
+    udp_request(gateway, &ExternalAddressRequest, timeout)
+        .and_then(|data| parse_response::<ExternalAddressResponse>(&data))
+        .map(|response| response.addr)
+}
+
+/// Create or renew a port mapping on the gateway. Returns the mapped external
+/// port and the lifetime assigned by gateway.
+pub fn map(gateway: SocketAddr, request: MapRequest, timeout: Duration)
+    -> impl Future<Item = MapResponse, Error = PortMappingError> + Send
+{
+    udp_request(gateway, &request, timeout)
+        .and_then(move |data| {
+            let response = parse_response::<MapResponse>(&data)?;
+            if response.protocol != request.protocol || response.internal_port != request.internal_port {
+                return Err(PortMappingErrorKind::InvalidResponse.into());
+            }
+            Ok(response)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use tokio::runtime::Runtime;
+
+    encode_decode_test!(
+        external_address_request_encode_decode,
+        ExternalAddressRequest
+    );
+
+    encode_decode_test!(
+        external_address_response_encode_decode,
+        ExternalAddressResponse {
+            result: 0,
+            epoch: 42,
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+        }
+    );
+
+    encode_decode_test!(
+        map_request_encode_decode,
+        MapRequest {
+            protocol: Protocol::Tcp,
+            internal_port: 33445,
+            external_port: 33445,
+            lifetime: 7200,
+        }
+    );
+
+    encode_decode_test!(
+        map_response_encode_decode,
+        MapResponse {
+            protocol: Protocol::Udp,
+            result: 0,
+            epoch: 42,
+            internal_port: 33445,
+            external_port: 12345,
+            lifetime: 3600,
+        }
+    );
+
+    #[test]
+    fn map_request_bytes() {
+        let request = MapRequest {
+            protocol: Protocol::Udp,
+            internal_port: 0x1234,
+            external_port: 0x5678,
+            lifetime: 7200,
+        };
+        let mut buf = [0; 12];
+        let (_, size) = request.to_bytes((&mut buf, 0)).unwrap();
+        assert_eq!(&buf[..size], &[0, 1, 0, 0, 0x12, 0x34, 0x56, 0x78, 0, 0, 0x1c, 0x20]);
+    }
+
+    #[test]
+    fn check_result_codes() {
+        assert!(check_result(&[0, 128, 0, 0]).is_ok());
+        assert_eq!(*check_result(&[0, 128]).unwrap_err().kind(), PortMappingErrorKind::InvalidResponse);
+        assert_eq!(*check_result(&[0, 128, 0, 1]).unwrap_err().kind(), PortMappingErrorKind::UnsupportedVersion);
+        assert_eq!(*check_result(&[2, 129, 0, 0]).unwrap_err().kind(), PortMappingErrorKind::UnsupportedVersion);
+        assert_eq!(*check_result(&[0, 129, 0, 3]).unwrap_err().kind(), PortMappingErrorKind::Refused { code: 3 });
+    }
+
+    /// Spawn NAT-PMP gateway responder that answers a single request.
+    fn spawn_gateway(external_port: u16) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1100];
+            let (size, peer) = socket.recv_from(&mut buf).unwrap();
+            let mut out = [0; 16];
+            let (_, out_size) = if let IResult::Done(_, request) = MapRequest::from_bytes(&buf[..size]) {
+                MapResponse {
+                    protocol: request.protocol,
+                    result: 0,
+                    epoch: 1,
+                    internal_port: request.internal_port,
+                    external_port,
+                    lifetime: request.lifetime,
+                }.to_bytes((&mut out, 0)).unwrap()
+            } else {
+                ExternalAddressResponse {
+                    result: 0,
+                    epoch: 1,
+                    addr: Ipv4Addr::new(1, 2, 3, 4),
+                }.to_bytes((&mut out, 0)).unwrap()
+            };
+            socket.send_to(&out[..out_size], peer).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn external_address_from_gateway() {
+        let gateway = spawn_gateway(0);
+        let mut runtime = Runtime::new().unwrap();
+        let addr = runtime.block_on(external_address(gateway, Duration::from_millis(250))).unwrap();
+        assert_eq!(addr, Ipv4Addr::new(1, 2, 3, 4));
+    }
+
+    #[test]
+    fn map_on_gateway() {
+        let gateway = spawn_gateway(12345);
+        let request = MapRequest {
+            protocol: Protocol::Udp,
+            internal_port: 33445,
+            external_port: 33445,
+            lifetime: 7200,
+        };
+        let mut runtime = Runtime::new().unwrap();
+        let response = runtime.block_on(map(gateway, request, Duration::from_millis(250))).unwrap();
+        assert_eq!(response.external_port, 12345);
+        assert_eq!(response.lifetime, 7200);
+    }
+}
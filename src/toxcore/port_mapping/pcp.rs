@@ -0,0 +1,522 @@
+/*! PCP protocol implementation.
+
+PCP is described in [RFC 6887](https://tools.ietf.org/html/rfc6887). It's the
+successor of NAT-PMP and uses the same UDP port 5351. A NAT-PMP gateway
+responds to PCP requests with "unsupported version" result code so a client
+can fall back to NAT-PMP. IPv4 addresses are sent as IPv4-mapped IPv6
+addresses. All numbers are in network byte order.
+*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::Future;
+use nom::{be_u8, be_u16, be_u32};
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::port_mapping::*;
+
+/// PCP protocol version.
+pub const PCP_VERSION: u8 = 2;
+
+/// Result code returned by gateway when it doesn't support the protocol
+/// version of request.
+pub const PCP_UNSUPPORTED_VERSION: u8 = 1;
+
+/// Opcode of ANNOUNCE request.
+const OPCODE_ANNOUNCE: u8 = 0;
+
+/// Opcode of MAP request.
+const OPCODE_MAP: u8 = 1;
+
+/// Bit that is set in opcode of responses.
+const RESPONSE_BIT: u8 = 0x80;
+
+/// Length of mapping nonce.
+pub const PCP_NONCE_SIZE: usize = 12;
+
+/// IANA protocol number of the given protocol.
+fn protocol_number(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Udp => 17,
+        Protocol::Tcp => 6,
+    }
+}
+
+/// Convert IP address to the form it's sent in PCP packets.
+fn to_pcp_ip(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Convert IP address from PCP packets mapping IPv4-mapped addresses back to
+/// IPv4.
+fn from_pcp_ip(ip: Ipv6Addr) -> IpAddr {
+    let segments = ip.segments();
+    if segments[..5].iter().all(|&s| s == 0) && segments[5] == 0xffff {
+        let octets = ip.octets();
+        IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+    } else {
+        IpAddr::V6(ip)
+    }
+}
+
+named!(parse_pcp_ip<IpAddr>, map!(take!(16), |bytes: &[u8]| {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(bytes);
+    from_pcp_ip(Ipv6Addr::from(octets))
+}));
+
+named!(parse_protocol<Protocol>, switch!(be_u8,
+    17 => value!(Protocol::Udp) |
+    6 => value!(Protocol::Tcp)
+));
+
+/** Request that is used to check if gateway supports PCP.
+
+Serialized form:
+
+Length      | Contents
+----------- | --------
+`1`         | Version (2)
+`1`         | Opcode (0)
+`2`         | Reserved (0)
+`4`         | Lifetime (0)
+`16`        | Client IP address
+
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AnnounceRequest {
+    /// Our local IP address.
+    pub client_ip: IpAddr,
+}
+
+impl ToBytes for AnnounceRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PCP_VERSION) >>
+            gen_be_u8!(OPCODE_ANNOUNCE) >>
+            gen_be_u16!(0) >>
+            gen_be_u32!(0) >>
+            gen_slice!(&to_pcp_ip(self.client_ip).octets())
+        )
+    }
+}
+
+impl FromBytes for AnnounceRequest {
+    named!(from_bytes<AnnounceRequest>, do_parse!(
+        tag!(&[PCP_VERSION, OPCODE_ANNOUNCE, 0, 0, 0, 0, 0, 0][..]) >>
+        client_ip: parse_pcp_ip >>
+        (AnnounceRequest { client_ip })
+    ));
+}
+
+/** Request to create, renew or remove a port mapping. The mapping is removed
+when lifetime is 0. Renewal requests must use the same nonce as the request
+that created the mapping.
+
+Serialized form:
+
+Length      | Contents
+----------- | --------
+`1`         | Version (2)
+`1`         | Opcode (1)
+`2`         | Reserved (0)
+`4`         | Requested lifetime in seconds
+`16`        | Client IP address
+`12`        | Mapping nonce
+`1`         | Protocol (17 for UDP, 6 for TCP)
+`3`         | Reserved (0)
+`2`         | Internal port
+`2`         | Suggested external port
+`16`        | Suggested external IP address
+
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MapRequest {
+    /// Requested lifetime of the mapping in seconds.
+    pub lifetime: u32,
+    /// Our local IP address.
+    pub client_ip: IpAddr,
+    /// Random nonce that identifies the mapping.
+    pub nonce: [u8; PCP_NONCE_SIZE],
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Local port.
+    pub internal_port: u16,
+    /// Suggested external port.
+    pub external_port: u16,
+    /// Suggested external IP address.
+    pub external_ip: IpAddr,
+}
+
+impl ToBytes for MapRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PCP_VERSION) >>
+            gen_be_u8!(OPCODE_MAP) >>
+            gen_be_u16!(0) >>
+            gen_be_u32!(self.lifetime) >>
+            gen_slice!(&to_pcp_ip(self.client_ip).octets()) >>
+            gen_slice!(&self.nonce) >>
+            gen_be_u8!(protocol_number(self.protocol)) >>
+            gen_slice!(&[0; 3]) >>
+            gen_be_u16!(self.internal_port) >>
+            gen_be_u16!(self.external_port) >>
+            gen_slice!(&to_pcp_ip(self.external_ip).octets())
+        )
+    }
+}
+
+impl FromBytes for MapRequest {
+    named!(from_bytes<MapRequest>, do_parse!(
+        tag!(&[PCP_VERSION, OPCODE_MAP, 0, 0][..]) >>
+        lifetime: be_u32 >>
+        client_ip: parse_pcp_ip >>
+        nonce: take!(PCP_NONCE_SIZE) >>
+        protocol: parse_protocol >>
+        tag!(&[0; 3][..]) >>
+        internal_port: be_u16 >>
+        external_port: be_u16 >>
+        external_ip: parse_pcp_ip >>
+        (MapRequest {
+            lifetime,
+            client_ip,
+            nonce: {
+                let mut array = [0; PCP_NONCE_SIZE];
+                array.copy_from_slice(nonce);
+                array
+            },
+            protocol,
+            internal_port,
+            external_port,
+            external_ip,
+        })
+    ));
+}
+
+/** Common header of PCP responses.
+
+Serialized form:
+
+Length      | Contents
+----------- | --------
+`1`         | Version (2)
+`1`         | Opcode with the highest bit set
+`1`         | Reserved (0)
+`1`         | Result code
+`4`         | Lifetime in seconds
+`4`         | Seconds since start of epoch
+`12`        | Reserved (0)
+
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ResponseHeader {
+    /// Opcode of the request without the response bit.
+    pub opcode: u8,
+    /// Result code, 0 means success.
+    pub result: u8,
+    /// Lifetime of the mapping in seconds assigned by gateway.
+    pub lifetime: u32,
+    /// Seconds since the gateway's port mapping table was initialized.
+    pub epoch: u32,
+}
+
+impl ToBytes for ResponseHeader {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PCP_VERSION) >>
+            gen_be_u8!(self.opcode | RESPONSE_BIT) >>
+            gen_be_u8!(0) >>
+            gen_be_u8!(self.result) >>
+            gen_be_u32!(self.lifetime) >>
+            gen_be_u32!(self.epoch) >>
+            gen_slice!(&[0; 12])
+        )
+    }
+}
+
+impl FromBytes for ResponseHeader {
+    named!(from_bytes<ResponseHeader>, do_parse!(
+        tag!(&[PCP_VERSION][..]) >>
+        opcode: verify!(be_u8, |opcode| opcode & RESPONSE_BIT != 0) >>
+        take!(1) >>
+        result: be_u8 >>
+        lifetime: be_u32 >>
+        epoch: be_u32 >>
+        take!(12) >>
+        (ResponseHeader { opcode: opcode & !RESPONSE_BIT, result, lifetime, epoch })
+    ));
+}
+
+/** Response to `MapRequest`.
+
+Serialized form:
+
+Length      | Contents
+----------- | --------
+`24`        | `ResponseHeader`
+`12`        | Mapping nonce
+`1`         | Protocol (17 for UDP, 6 for TCP)
+`3`         | Reserved (0)
+`2`         | Internal port
+`2`         | Assigned external port
+`16`        | Assigned external IP address
+
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MapResponse {
+    /// Common response header.
+    pub header: ResponseHeader,
+    /// Nonce of the request.
+    pub nonce: [u8; PCP_NONCE_SIZE],
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Local port.
+    pub internal_port: u16,
+    /// External port assigned by gateway.
+    pub external_port: u16,
+    /// External IP address assigned by gateway.
+    pub external_ip: IpAddr,
+}
+
+impl ToBytes for MapResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, header| ResponseHeader::to_bytes(header, buf), &self.header) >>
+            gen_slice!(&self.nonce) >>
+            gen_be_u8!(protocol_number(self.protocol)) >>
+            gen_slice!(&[0; 3]) >>
+            gen_be_u16!(self.internal_port) >>
+            gen_be_u16!(self.external_port) >>
+            gen_slice!(&to_pcp_ip(self.external_ip).octets())
+        )
+    }
+}
+
+impl FromBytes for MapResponse {
+    named!(from_bytes<MapResponse>, do_parse!(
+        header: verify!(call!(ResponseHeader::from_bytes), |header: ResponseHeader| header.opcode == OPCODE_MAP) >>
+        nonce: take!(PCP_NONCE_SIZE) >>
+        protocol: parse_protocol >>
+        take!(3) >>
+        internal_port: be_u16 >>
+        external_port: be_u16 >>
+        external_ip: parse_pcp_ip >>
+        (MapResponse {
+            header,
+            nonce: {
+                let mut array = [0; PCP_NONCE_SIZE];
+                array.copy_from_slice(nonce);
+                array
+            },
+            protocol,
+            internal_port,
+            external_port,
+            external_ip,
+        })
+    ));
+}
+
+/// Generate random nonce for a new mapping.
+pub fn gen_nonce() -> [u8; PCP_NONCE_SIZE] {
+    let mut nonce = [0; PCP_NONCE_SIZE];
+    randombytes_into(&mut nonce);
+    nonce
+}
+
+/// Check the common header of a PCP response and return an error if the
+/// gateway refused the request. NAT-PMP gateways respond with version 0.
+fn check_result(data: &[u8]) -> Result<(), PortMappingError> {
+    if data.len() < 4 {
+        return Err(PortMappingErrorKind::InvalidResponse.into());
+    }
+    if data[0] != PCP_VERSION || data[3] == PCP_UNSUPPORTED_VERSION {
+        Err(PortMappingErrorKind::UnsupportedVersion.into())
+    } else if data[3] != 0 {
+        Err(PortMappingError::refused(u16::from(data[3])))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse a PCP response checking its result code.
+fn parse_response<T: FromBytes>(data: &[u8]) -> Result<T, PortMappingError> {
+    check_result(data)?;
+    match T::from_bytes(data) {
+        IResult::Done(_, response) => Ok(response),
+        _ => Err(PortMappingErrorKind::InvalidResponse.into()),
+    }
+}
+
+/// Check if the gateway supports PCP.
+pub fn announce(gateway: SocketAddr, client_ip: IpAddr, timeout: Duration)
+    -> impl Future<Item = (), Error = PortMappingError> + Send
+{
+    udp_request(gateway, &AnnounceRequest { client_ip }, timeout)
+        .and_then(|data| parse_response::<ResponseHeader>(&data))
+        .and_then(|header| if header.opcode == OPCODE_ANNOUNCE {
+            Ok(())
+        } else {
+            Err(PortMappingErrorKind::InvalidResponse.into())
+        })
+}
+
+/// Create, renew or remove a port mapping on the gateway.
+pub fn map(gateway: SocketAddr, request: MapRequest, timeout: Duration)
+    -> impl Future<Item = MapResponse, Error = PortMappingError> + Send
+{
+    udp_request(gateway, &request, timeout)
+        .and_then(move |data| {
+            let response = parse_response::<MapResponse>(&data)?;
+            if response.nonce != request.nonce ||
+                response.protocol != request.protocol ||
+                response.internal_port != request.internal_port {
+                return Err(PortMappingErrorKind::InvalidResponse.into());
+            }
+            Ok(response)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use tokio::runtime::Runtime;
+
+    encode_decode_test!(
+        announce_request_encode_decode,
+        AnnounceRequest {
+            client_ip: "192.168.1.2".parse().unwrap(),
+        }
+    );
+
+    encode_decode_test!(
+        map_request_encode_decode,
+        MapRequest {
+            lifetime: 7200,
+            client_ip: "192.168.1.2".parse().unwrap(),
+            nonce: [42; PCP_NONCE_SIZE],
+            protocol: Protocol::Udp,
+            internal_port: 33445,
+            external_port: 33445,
+            external_ip: "2001:db8::1".parse().unwrap(),
+        }
+    );
+
+    encode_decode_test!(
+        response_header_encode_decode,
+        ResponseHeader {
+            opcode: OPCODE_ANNOUNCE,
+            result: 0,
+            lifetime: 0,
+            epoch: 42,
+        }
+    );
+
+    encode_decode_test!(
+        map_response_encode_decode,
+        MapResponse {
+            header: ResponseHeader {
+                opcode: OPCODE_MAP,
+                result: 0,
+                lifetime: 3600,
+                epoch: 42,
+            },
+            nonce: [42; PCP_NONCE_SIZE],
+            protocol: Protocol::Tcp,
+            internal_port: 33445,
+            external_port: 12345,
+            external_ip: "1.2.3.4".parse().unwrap(),
+        }
+    );
+
+    #[test]
+    fn pcp_ip_v4_mapped() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let pcp_ip = to_pcp_ip(ip);
+        assert_eq!(pcp_ip, "::ffff:1.2.3.4".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(from_pcp_ip(pcp_ip), ip);
+
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(from_pcp_ip(to_pcp_ip(ip)), ip);
+    }
+
+    #[test]
+    fn check_result_codes() {
+        assert!(check_result(&[2, 0x81, 0, 0]).is_ok());
+        assert_eq!(*check_result(&[2]).unwrap_err().kind(), PortMappingErrorKind::InvalidResponse);
+        // NAT-PMP gateway response
+        assert_eq!(*check_result(&[0, 0x82, 0, 1]).unwrap_err().kind(), PortMappingErrorKind::UnsupportedVersion);
+        assert_eq!(*check_result(&[2, 0x81, 0, 1]).unwrap_err().kind(), PortMappingErrorKind::UnsupportedVersion);
+        assert_eq!(*check_result(&[2, 0x81, 0, 8]).unwrap_err().kind(), PortMappingErrorKind::Refused { code: 8 });
+    }
+
+    /// Spawn PCP gateway responder that answers a single request.
+    fn spawn_gateway() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1100];
+            let (size, peer) = socket.recv_from(&mut buf).unwrap();
+            let mut out = [0; 1100];
+            let (_, out_size) = if let IResult::Done(_, request) = MapRequest::from_bytes(&buf[..size]) {
+                MapResponse {
+                    header: ResponseHeader {
+                        opcode: OPCODE_MAP,
+                        result: 0,
+                        lifetime: request.lifetime.min(3600),
+                        epoch: 1,
+                    },
+                    nonce: request.nonce,
+                    protocol: request.protocol,
+                    internal_port: request.internal_port,
+                    external_port: 12345,
+                    external_ip: "1.2.3.4".parse().unwrap(),
+                }.to_bytes((&mut out, 0)).unwrap()
+            } else {
+                ResponseHeader {
+                    opcode: OPCODE_ANNOUNCE,
+                    result: 0,
+                    lifetime: 0,
+                    epoch: 1,
+                }.to_bytes((&mut out, 0)).unwrap()
+            };
+            socket.send_to(&out[..out_size], peer).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn announce_to_gateway() {
+        let gateway = spawn_gateway();
+        let mut runtime = Runtime::new().unwrap();
+        let client_ip = "127.0.0.1".parse().unwrap();
+        assert!(runtime.block_on(announce(gateway, client_ip, Duration::from_millis(250))).is_ok());
+    }
+
+    #[test]
+    fn map_on_gateway() {
+        let gateway = spawn_gateway();
+        let request = MapRequest {
+            lifetime: 7200,
+            client_ip: "127.0.0.1".parse().unwrap(),
+            nonce: gen_nonce(),
+            protocol: Protocol::Tcp,
+            internal_port: 33445,
+            external_port: 33445,
+            external_ip: Ipv4Addr::UNSPECIFIED.into(),
+        };
+        let mut runtime = Runtime::new().unwrap();
+        let response = runtime.block_on(map(gateway, request, Duration::from_millis(250))).unwrap();
+        assert_eq!(response.external_port, 12345);
+        assert_eq!(response.external_ip, "1.2.3.4".parse::<IpAddr>().unwrap());
+        assert_eq!(response.header.lifetime, 3600);
+    }
+}
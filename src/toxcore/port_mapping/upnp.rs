@@ -0,0 +1,528 @@
+/*! UPnP Internet Gateway Device protocol implementation.
+
+IGD is discovered by sending SSDP `M-SEARCH` request to the multicast address
+239.255.255.250:1900. Gateway responds with the location of its XML
+description that contains the control URL of `WANIPConnection` or
+`WANPPPConnection` service. Port mappings are managed by SOAP requests sent
+to this URL.
+*/
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use futures::{future, Future};
+use futures::future::Either;
+use tokio::io::{read_to_end, write_all};
+use tokio::net::TcpStream;
+use tokio::util::FutureExt;
+
+use crate::toxcore::port_mapping::*;
+
+/// Timeout in seconds for HTTP requests to gateway.
+pub const UPNP_HTTP_TIMEOUT: u64 = 5;
+
+/// Search target of SSDP request.
+const SSDP_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// Prefixes of services that can be used to map ports.
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+
+/// Error code returned by gateways that support only permanent leases.
+const ONLY_PERMANENT_LEASES_SUPPORTED: u16 = 725;
+
+/// Description of port mappings created by us.
+const MAPPING_DESCRIPTION: &str = "Tox";
+
+/// Parsed `http://` URL. Only IP addresses are supported as hosts since
+/// gateways always use them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct HttpUrl {
+    /// Address of HTTP server.
+    addr: SocketAddr,
+    /// Absolute path of the resource.
+    path: String,
+}
+
+impl HttpUrl {
+    /// Parse `http://` URL.
+    fn parse(url: &str) -> Option<HttpUrl> {
+        let url = url.trim();
+        // the URL comes from the network so it can have multibyte
+        // characters at any position
+        match url.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("http://") => {},
+            _ => return None,
+        }
+        let rest = &url[7..];
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let addr = host.parse::<SocketAddr>().ok().or_else(|| {
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, 80))
+        })?;
+        Some(HttpUrl {
+            addr,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Resolve URL that can be relative to this one.
+    fn join(&self, url: &str) -> Option<HttpUrl> {
+        let url = url.trim();
+        if url.contains("://") {
+            HttpUrl::parse(url)
+        } else {
+            let path = if url.starts_with('/') {
+                url.to_owned()
+            } else {
+                format!("/{}", url)
+            };
+            Some(HttpUrl {
+                addr: self.addr,
+                path,
+            })
+        }
+    }
+}
+
+/// Get text of all elements with the given name. Elements with attributes
+/// are not supported.
+fn xml_elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len() ..];
+        match rest.find(&close) {
+            Some(end) => {
+                elements.push(rest[..end].trim());
+                rest = &rest[end + close.len() ..];
+            },
+            None => break,
+        }
+    }
+    elements
+}
+
+/// Get text of the first element with the given name.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    xml_elements(xml, name).into_iter().next()
+}
+
+/// Find service that can be used to map ports in the device description.
+/// Returns its type and control URL.
+fn find_wan_service(description: &str) -> Option<(&str, &str)> {
+    xml_elements(description, "service").into_iter().filter_map(|service| {
+        let service_type = xml_element(service, "serviceType")?;
+        let control_url = xml_element(service, "controlURL")?;
+        if WAN_SERVICES.iter().any(|prefix| service_type.starts_with(prefix)) {
+            Some((service_type, control_url))
+        } else {
+            None
+        }
+    }).next()
+}
+
+/// Get the location of the device description from SSDP response.
+fn parse_ssdp_location(data: &[u8]) -> Option<HttpUrl> {
+    let response = String::from_utf8_lossy(data);
+    response.lines()
+        .filter_map(|line| {
+            let index = line.find(':')?;
+            if line[..index].trim().eq_ignore_ascii_case("location") {
+                HttpUrl::parse(&line[index + 1 ..])
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+/// Parse HTTP response returning its status code and body.
+fn parse_http_response(data: &[u8]) -> Result<(u16, String), PortMappingError> {
+    let response = String::from_utf8_lossy(data);
+    let header_end = response.find("\r\n\r\n")
+        .ok_or(PortMappingErrorKind::InvalidResponse)?;
+    let status = response.lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or(PortMappingErrorKind::InvalidResponse)?;
+    Ok((status, response[header_end + 4 ..].to_owned()))
+}
+
+/// Send HTTP request to gateway and return status code and body of the
+/// response. The request is built by `request` function that gets our local
+/// IP address as seen by gateway.
+fn http_request<F>(addr: SocketAddr, request: F) -> impl Future<Item = (u16, String), Error = PortMappingError> + Send
+    where F: FnOnce(IpAddr) -> Vec<u8> + Send + 'static
+{
+    TcpStream::connect(&addr)
+        .and_then(move |stream| {
+            let data = stream.local_addr().map(|local_addr| request(local_addr.ip()));
+            future::result(data).and_then(move |data| write_all(stream, data))
+        })
+        .and_then(|(stream, _)| read_to_end(stream, Vec::new()))
+        .timeout(Duration::from_secs(UPNP_HTTP_TIMEOUT))
+        .map_err(timeout_error)
+        .and_then(|(_stream, data)| parse_http_response(&data))
+}
+
+/// Find UPnP IGD by sending SSDP request to the given address.
+pub fn discover(ssdp_addr: SocketAddr, timeout: Duration) -> impl Future<Item = IgdGateway, Error = PortMappingError> + Send {
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+        HOST: {}\r\n\
+        MAN: \"ssdp:discover\"\r\n\
+        MX: 2\r\n\
+        ST: {}\r\n\r\n",
+        ssdp_addr,
+        SSDP_SEARCH_TARGET
+    );
+
+    udp_exchange(ssdp_addr, request.into_bytes(), timeout, true)
+        .and_then(|data| parse_ssdp_location(&data).ok_or_else(|| PortMappingErrorKind::InvalidResponse.into()))
+        .and_then(|location| {
+            let request = format!(
+                "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                location.path,
+                location.addr
+            );
+            http_request(location.addr, move |_| request.into_bytes())
+                .map(move |response| (location, response))
+        })
+        .and_then(|(location, (status, description))| {
+            if status != 200 {
+                return Err(PortMappingErrorKind::InvalidResponse.into());
+            }
+            let base = xml_element(&description, "URLBase")
+                .and_then(HttpUrl::parse)
+                .unwrap_or(location);
+            let (service_type, control_url) = find_wan_service(&description)
+                .ok_or(PortMappingErrorKind::NoGateway)?;
+            let control_url = base.join(control_url)
+                .ok_or(PortMappingErrorKind::InvalidResponse)?;
+            Ok(IgdGateway {
+                control_url,
+                service_type: service_type.to_owned(),
+            })
+        })
+}
+
+/// Name of the protocol used in SOAP requests.
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Udp => "UDP",
+        Protocol::Tcp => "TCP",
+    }
+}
+
+/// UPnP Internet Gateway Device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IgdGateway {
+    /// URL where SOAP requests should be sent.
+    control_url: HttpUrl,
+    /// Type of the service that is used to map ports.
+    service_type: String,
+}
+
+impl IgdGateway {
+    /// Type of the service that is used to map ports.
+    pub fn service_type(&self) -> &str {
+        &self.service_type
+    }
+
+    /// Address where SOAP requests are sent.
+    pub fn control_addr(&self) -> SocketAddr {
+        self.control_url.addr
+    }
+
+    /// Send SOAP request to gateway and return body of the response. Request
+    /// arguments are built by `args` function that gets our local IP address
+    /// as seen by gateway.
+    fn soap_request<F>(&self, action: &'static str, args: F) -> impl Future<Item = String, Error = PortMappingError> + Send
+        where F: FnOnce(IpAddr) -> Vec<(&'static str, String)> + Send + 'static
+    {
+        let control_url = self.control_url.clone();
+        let service_type = self.service_type.clone();
+        http_request(control_url.addr, move |local_ip| {
+            let args = args(local_ip).into_iter()
+                .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+                .collect::<String>();
+            let body = format!(
+                "<?xml version=\"1.0\"?>\r\n\
+                <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+                <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>\r\n",
+                action,
+                service_type,
+                args
+            );
+            format!(
+                "POST {} HTTP/1.0\r\n\
+                Host: {}\r\n\
+                Content-Type: text/xml; charset=\"utf-8\"\r\n\
+                Content-Length: {}\r\n\
+                SOAPAction: \"{}#{}\"\r\n\r\n{}",
+                control_url.path,
+                control_url.addr,
+                body.len(),
+                service_type,
+                action,
+                body
+            ).into_bytes()
+        }).and_then(|(status, body)| {
+            if status == 200 {
+                Ok(body)
+            } else {
+                let code = xml_element(&body, "errorCode")
+                    .and_then(|code| code.parse().ok())
+                    .unwrap_or(status);
+                Err(PortMappingError::refused(code))
+            }
+        })
+    }
+
+    /// Request the external IP address of gateway.
+    pub fn external_ip(&self) -> impl Future<Item = IpAddr, Error = PortMappingError> + Send {
+        self.soap_request("GetExternalIPAddress", |_| Vec::new())
+            .and_then(|body| xml_element(&body, "NewExternalIPAddress")
+                .and_then(|ip| ip.parse().ok())
+                .ok_or_else(|| PortMappingErrorKind::InvalidResponse.into())
+            )
+    }
+
+    /// Create a port mapping on gateway with `AddPortMapping` request.
+    fn add_port_mapping_inner(&self, protocol: Protocol, internal_port: u16, external_port: u16, lifetime: u32)
+        -> impl Future<Item = (), Error = PortMappingError> + Send
+    {
+        self.soap_request("AddPortMapping", move |local_ip| vec![
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", protocol_name(protocol).to_owned()),
+            ("NewInternalPort", internal_port.to_string()),
+            ("NewInternalClient", local_ip.to_string()),
+            ("NewEnabled", "1".to_owned()),
+            ("NewPortMappingDescription", MAPPING_DESCRIPTION.to_owned()),
+            ("NewLeaseDuration", lifetime.to_string()),
+        ]).map(|_| ())
+    }
+
+    /// Create or renew a port mapping on gateway. Some gateways support only
+    /// permanent mappings so the request is repeated with zero lifetime if
+    /// gateway refuses it. Returns the lifetime of the mapping.
+    pub fn add_port_mapping(&self, protocol: Protocol, internal_port: u16, external_port: u16, lifetime: u32)
+        -> impl Future<Item = u32, Error = PortMappingError> + Send
+    {
+        let gateway = self.clone();
+        self.add_port_mapping_inner(protocol, internal_port, external_port, lifetime)
+            .map(move |()| lifetime)
+            .or_else(move |e| {
+                if lifetime != 0 && *e.kind() == (PortMappingErrorKind::Refused { code: ONLY_PERMANENT_LEASES_SUPPORTED }) {
+                    Either::A(gateway.add_port_mapping_inner(protocol, internal_port, external_port, 0).map(|()| 0))
+                } else {
+                    Either::B(future::err(e))
+                }
+            })
+    }
+
+    /// Remove a port mapping from gateway.
+    pub fn remove_port_mapping(&self, protocol: Protocol, external_port: u16) -> impl Future<Item = (), Error = PortMappingError> + Send {
+        self.soap_request("DeletePortMapping", move |_| vec![
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", protocol_name(protocol).to_owned()),
+        ]).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream as StdTcpStream, UdpSocket as StdUdpSocket};
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+
+    use tokio::runtime::Runtime;
+
+    const DESCRIPTION: &str = "<?xml version=\"1.0\"?>\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device>\
+        <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>\
+        <serviceList><service>\
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/ctl/L3F</controlURL>\
+        </service></serviceList>\
+        <deviceList><device><deviceList><device><serviceList><service>\
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL>\
+        </service></serviceList></device></deviceList></device></deviceList>\
+        </device></root>";
+
+    #[test]
+    fn http_url_parse() {
+        let url = HttpUrl::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(url.addr, "192.168.1.1:5000".parse().unwrap());
+        assert_eq!(url.path, "/rootDesc.xml");
+
+        let url = HttpUrl::parse("HTTP://192.168.1.1").unwrap();
+        assert_eq!(url.addr, "192.168.1.1:80".parse().unwrap());
+        assert_eq!(url.path, "/");
+
+        let url = HttpUrl::parse("http://[fe80::1]/desc").unwrap();
+        assert_eq!(url.addr, "[fe80::1]:80".parse().unwrap());
+
+        assert!(HttpUrl::parse("https://192.168.1.1/").is_none());
+        assert!(HttpUrl::parse("http://router.local/").is_none());
+        assert!(HttpUrl::parse("httpпп").is_none());
+    }
+
+    #[test]
+    fn http_url_join() {
+        let base = HttpUrl::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(base.join("/ctl/IPConn").unwrap().path, "/ctl/IPConn");
+        assert_eq!(base.join("ctl/IPConn").unwrap().path, "/ctl/IPConn");
+        assert_eq!(base.join("/ctl/IPConn").unwrap().addr, base.addr);
+        assert_eq!(
+            base.join("http://192.168.1.2:80/ctl").unwrap(),
+            HttpUrl::parse("http://192.168.1.2/ctl").unwrap()
+        );
+    }
+
+    #[test]
+    fn find_wan_service_in_description() {
+        assert_eq!(
+            find_wan_service(DESCRIPTION),
+            Some(("urn:schemas-upnp-org:service:WANIPConnection:1", "/ctl/IPConn"))
+        );
+        assert_eq!(find_wan_service("<root></root>"), None);
+    }
+
+    #[test]
+    fn ssdp_location() {
+        let response = b"HTTP/1.1 200 OK\r\n\
+            CACHE-CONTROL: max-age=120\r\n\
+            ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            parse_ssdp_location(response),
+            HttpUrl::parse("http://192.168.1.1:5000/rootDesc.xml")
+        );
+        assert_eq!(parse_ssdp_location(b"HTTP/1.1 200 OK\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn http_response() {
+        let (status, body) = parse_http_response(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody").unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, "body");
+        assert!(parse_http_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_http_response(b"garbage\r\n\r\n").is_err());
+    }
+
+    /// Read HTTP request with body from the stream.
+    fn read_http_request(stream: &mut StdTcpStream) -> String {
+        let mut data = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let size = stream.read(&mut buf).unwrap();
+            if size == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..size]);
+            let request = String::from_utf8_lossy(&data).into_owned();
+            if let Some(header_end) = request.find("\r\n\r\n") {
+                let content_length = request.lines()
+                    .find(|line| line.to_lowercase().starts_with("content-length:"))
+                    .map_or(0, |line| line[15..].trim().parse::<usize>().unwrap());
+                if data.len() >= header_end + 4 + content_length {
+                    break;
+                }
+            }
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    /// Spawn UPnP IGD responder that supports only permanent leases. SOAP
+    /// actions are sent to the returned channel. Returns the SSDP address.
+    fn spawn_gateway() -> (SocketAddr, std_mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_addr = listener.local_addr().unwrap();
+        let ssdp_socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let ssdp_addr = ssdp_socket.local_addr().unwrap();
+        let (tx, rx) = std_mpsc::channel();
+
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (size, peer) = ssdp_socket.recv_from(&mut buf).unwrap();
+            assert!(String::from_utf8_lossy(&buf[..size]).starts_with("M-SEARCH"));
+            let response = format!("HTTP/1.1 200 OK\r\nLOCATION: http://{}/rootDesc.xml\r\n\r\n", http_addr);
+            ssdp_socket.send_to(response.as_bytes(), peer).unwrap();
+        });
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = read_http_request(&mut stream);
+                let (status, body) = if request.starts_with("GET /rootDesc.xml ") {
+                    (200, DESCRIPTION.to_owned())
+                } else if !request.starts_with("POST /ctl/IPConn ") {
+                    (404, String::new())
+                } else if request.contains("#GetExternalIPAddress\"") {
+                    (200, "<NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>".to_owned())
+                } else if request.contains("#AddPortMapping\"") && !request.contains("<NewLeaseDuration>0<") {
+                    (500, "<UPnPError><errorCode>725</errorCode></UPnPError>".to_owned())
+                } else {
+                    (200, String::new())
+                };
+                if request.starts_with("POST") {
+                    let action = request.split("SOAPAction: \"").nth(1).unwrap().split('"').next().unwrap();
+                    let action = action.split('#').nth(1).unwrap().to_owned();
+                    if tx.send(action).is_err() {
+                        return;
+                    }
+                }
+                let response = format!("HTTP/1.1 {} OK\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (ssdp_addr, rx)
+    }
+
+    #[test]
+    fn igd_port_mapping() {
+        let (ssdp_addr, actions_rx) = spawn_gateway();
+        let mut runtime = Runtime::new().unwrap();
+
+        let gateway = runtime.block_on(discover(ssdp_addr, Duration::from_millis(250))).unwrap();
+        assert_eq!(gateway.service_type(), "urn:schemas-upnp-org:service:WANIPConnection:1");
+
+        let ip = runtime.block_on(gateway.external_ip()).unwrap();
+        assert_eq!(ip, "1.2.3.4".parse::<IpAddr>().unwrap());
+
+        // gateway supports only permanent leases
+        let lifetime = runtime.block_on(gateway.add_port_mapping(Protocol::Udp, 33445, 33445, 7200)).unwrap();
+        assert_eq!(lifetime, 0);
+
+        runtime.block_on(gateway.remove_port_mapping(Protocol::Udp, 33445)).unwrap();
+
+        let actions = actions_rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(actions, vec![
+            "GetExternalIPAddress",
+            "AddPortMapping",
+            "AddPortMapping",
+            "DeletePortMapping",
+        ]);
+    }
+}
@@ -0,0 +1,1208 @@
+/*! Deterministic in-process network simulator for integration tests.
+
+`Network` connects many DHT nodes through virtual UDP links and TCP relays
+through virtual TCP links. Every node consists of a DHT `Server` and,
+optionally, `NetCrypto` with `OnionClient` to establish friend connections and
+a TCP relay `Server`. Periodic tasks of all modules are driven by the
+simulator on the mocked clock so that minutes of network activity take
+milliseconds to simulate.
+
+Links have configurable latency, jitter, loss and reordering. Nodes can be
+placed behind NATs of different types. All decisions of the network itself
+(latency, losses, NAT ports) and keys of nodes are derived from the seed.
+While modules are driven by the simulator random bytes they request (ephemeral
+keys, nonces, ping ids, onion paths) are taken from a generator derived from
+the seed as well and system time is derived from the mocked clock, so runs
+with the same seed produce the same packets and events.
+
+Since the clock can be mocked only in tests the simulator is available only
+under `cfg(test)`.
+*/
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use futures::{future, Async, Future, Stream};
+use futures::sync::mpsc;
+use tokio_executor;
+use tokio_timer::clock::*;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packed_node::PackedNode;
+use crate::toxcore::dht::packet::Packet as DhtPacket;
+use crate::toxcore::dht::server::{Server as DhtServer, TIME_TO_PING};
+use crate::toxcore::events::Event;
+use crate::toxcore::net_crypto::{NetCrypto, NetCryptoNewArgs, PACKET_COUNTER_AVERAGE_INTERVAL, PACKET_COUNTER_AVERAGE_INTERVAL_MS};
+use crate::toxcore::onion::client::OnionClient;
+use crate::toxcore::onion::packet::InnerOnionResponse;
+use crate::toxcore::tcp::client::{Connections as TcpConnections, IncomingPacket};
+use crate::toxcore::tcp::packet::{OnionRequest, Packet as TcpPacket, PongResponse};
+use crate::toxcore::tcp::server::{Client as TcpClient, Server as TcpServer};
+use crate::toxcore::time::{set_system_time_base, MutNow};
+
+/// Buffer size of channels that connect simulated modules with the network.
+const SIM_CHANNEL_SIZE: usize = 4096;
+
+/// Port that nodes listen on.
+const SIM_NODE_PORT: u16 = 33445;
+
+/// The first port that NAT assigns to outgoing mappings.
+const SIM_NAT_FIRST_PORT: u16 = 40000;
+
+/// Unix time in seconds of the start of the simulation.
+const SIM_START_UNIX_TIME: u64 = 1_500_000_000;
+
+/// Small deterministic pseudo random numbers generator (xorshift64*).
+#[derive(Clone, Debug)]
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    /// Create new `SimRng` from the seed.
+    pub fn new(seed: u64) -> SimRng {
+        // scramble the seed with splitmix64 since xorshift needs non-zero
+        // state with enough set bits
+        let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        state ^= state >> 31;
+        SimRng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    /// Generate next random `u64`.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Return `true` with the given probability.
+    pub fn gen_bool(&mut self, probability: f64) -> bool {
+        let value = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        value < probability
+    }
+
+    /// Generate random duration in the range `[0, max]`.
+    pub fn gen_duration(&mut self, max: Duration) -> Duration {
+        let max_nanos = max.as_secs()
+            .saturating_mul(1_000_000_000)
+            .saturating_add(u64::from(max.subsec_nanos()));
+        if max_nanos == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_nanos(self.next_u64() % max_nanos.saturating_add(1))
+    }
+
+    /// Fill the buffer with random bytes.
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        let mut bytes = [0; 8];
+        for chunk in buf.chunks_mut(8) {
+            LittleEndian::write_u64(&mut bytes, self.next_u64());
+            chunk.copy_from_slice(&bytes[.. chunk.len()]);
+        }
+    }
+
+    /// Generate keypair derived from the state of the generator.
+    pub fn gen_keypair(&mut self) -> (PublicKey, SecretKey) {
+        let mut seed = [0; SEEDBYTES];
+        self.fill_bytes(&mut seed);
+        keypair_from_seed(&Seed(seed))
+    }
+}
+
+/// Properties of a virtual link between two nodes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConfig {
+    /// One-way delay of a packet.
+    pub latency: Duration,
+    /// Maximum random delay added to the latency.
+    pub jitter: Duration,
+    /// Probability that a UDP packet is lost.
+    pub loss: f64,
+    /// Probability that a UDP packet is delayed for additional latency so
+    /// that it arrives after packets sent later.
+    pub reorder: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_secs(0),
+            loss: 0.0,
+            reorder: 0.0,
+        }
+    }
+}
+
+/// Behaviour of NAT that a node is placed behind.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NatType {
+    /// The same external port is used for all destinations. Any host can
+    /// send packets to the mapped port.
+    FullCone,
+    /// The same external port is used for all destinations. Only hosts that
+    /// we sent packets to can send packets to the mapped port.
+    AddressRestricted,
+    /// The same external port is used for all destinations. Only address and
+    /// port pairs that we sent packets to can send packets to the mapped port.
+    PortRestricted,
+    /// Every destination gets new sequentially assigned external port. Only
+    /// the destination can send packets to the mapped port.
+    Symmetric,
+}
+
+/// NAT state of a node.
+struct Nat {
+    /// Behaviour of the NAT.
+    kind: NatType,
+    /// External IP address of the NAT.
+    public_ip: IpAddr,
+    /// Port that will be assigned to the next mapping.
+    next_port: u16,
+    /// External ports by destination. Destination is `None` for all NAT
+    /// types except symmetric one.
+    ports: HashMap<Option<SocketAddr>, u16>,
+    /// Destinations we sent packets to by external port.
+    permitted: HashMap<u16, HashSet<SocketAddr>>,
+}
+
+impl Nat {
+    /// Create new `Nat` without mappings.
+    fn new(kind: NatType, public_ip: IpAddr) -> Nat {
+        Nat {
+            kind,
+            public_ip,
+            next_port: SIM_NAT_FIRST_PORT,
+            ports: HashMap::new(),
+            permitted: HashMap::new(),
+        }
+    }
+
+    /// Get external address for a packet sent to `to` creating a mapping if
+    /// necessary.
+    fn map(&mut self, to: SocketAddr) -> SocketAddr {
+        let key = if self.kind == NatType::Symmetric { Some(to) } else { None };
+        let next_port = &mut self.next_port;
+        let port = *self.ports.entry(key).or_insert_with(|| {
+            let port = *next_port;
+            *next_port = next_port.wrapping_add(1);
+            port
+        });
+        self.permitted.entry(port).or_default().insert(to);
+        SocketAddr::new(self.public_ip, port)
+    }
+
+    /// Check if a packet from `from` to `to` passes through the NAT.
+    fn accepts(&self, from: SocketAddr, to: SocketAddr) -> bool {
+        if to.ip() != self.public_ip {
+            return false;
+        }
+        let permitted = match self.permitted.get(&to.port()) {
+            Some(permitted) => permitted,
+            None => return false,
+        };
+        match self.kind {
+            NatType::FullCone => true,
+            NatType::AddressRestricted => permitted.iter().any(|addr| addr.ip() == from.ip()),
+            NatType::PortRestricted | NatType::Symmetric => permitted.contains(&from),
+        }
+    }
+}
+
+/// Options of a simulated node.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NodeOptions {
+    /// Place the node behind NAT of this type.
+    pub nat: Option<NatType>,
+    /// Create `NetCrypto` and `OnionClient` to establish friend connections.
+    pub friend_connections: bool,
+    /// Run TCP relay on the node.
+    pub tcp_relay: bool,
+}
+
+/// Identifier of a node in the simulated network.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct NodeId(pub usize);
+
+/// Identifier of a virtual TCP connection to a relay.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TcpLinkId(pub usize);
+
+/// Counters of UDP packets that were sent through the network.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SimStats {
+    /// Number of sent packets.
+    pub sent: u64,
+    /// Number of packets lost on links.
+    pub lost: u64,
+    /// Number of packets dropped by NAT or sent to unknown address.
+    pub filtered: u64,
+    /// Number of packets delivered to nodes.
+    pub delivered: u64,
+}
+
+/// Modules that are responsible for friend connections.
+struct FriendsStack {
+    /// Our long term `PublicKey`.
+    real_pk: PublicKey,
+    net_crypto: NetCrypto,
+    onion_client: OnionClient,
+    /// DHT `PublicKey`s of friends announced by `OnionClient` or `NetCrypto`.
+    dht_pk_rx: mpsc::UnboundedReceiver<(PublicKey, PublicKey)>,
+    lossless_rx: mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>,
+    lossy_rx: mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>,
+    /// Packets from TCP relays. There are no TCP connections in the
+    /// simulator so it stays empty.
+    _tcp_incoming_rx: mpsc::UnboundedReceiver<(PublicKey, IncomingPacket)>,
+    /// Known DHT `PublicKey`s of friends by their long term `PublicKey`s.
+    dht_pks: HashMap<PublicKey, PublicKey>,
+    /// Received lossless packets.
+    received: Vec<(PublicKey, Vec<u8>)>,
+}
+
+/// TCP relay running on a node.
+struct Relay {
+    server: TcpServer,
+    /// Onion requests from TCP clients to send via DHT.
+    udp_onion_rx: mpsc::Receiver<(OnionRequest, SocketAddr)>,
+    /// Onion responses from DHT to send to TCP clients.
+    tcp_onion_rx: mpsc::Receiver<(InnerOnionResponse, SocketAddr)>,
+}
+
+/// Simulated node.
+struct SimNode {
+    /// Address the node is bound to.
+    addr: SocketAddr,
+    /// NAT the node is placed behind.
+    nat: Option<Nat>,
+    dht: DhtServer,
+    udp_rx: mpsc::Receiver<(DhtPacket, SocketAddr)>,
+    friend_saddr_rx: mpsc::UnboundedReceiver<PackedNode>,
+    event_rx: mpsc::UnboundedReceiver<Event>,
+    friends: Option<FriendsStack>,
+    relay: Option<Relay>,
+    /// Addresses of DHT friends found by DHT server.
+    friend_addrs: Vec<PackedNode>,
+    /// Events emitted by modules of the node.
+    events: Vec<Event>,
+}
+
+impl SimNode {
+    /// Pass received UDP packet to the module that handles it.
+    fn handle_udp(&self, packet: DhtPacket, from: SocketAddr) {
+        match (packet, &self.friends) {
+            (DhtPacket::OnionAnnounceResponse(packet), &Some(ref friends)) =>
+                log_error("Handling OnionAnnounceResponse", friends.onion_client.handle_announce_response(&packet, from).wait()),
+            (DhtPacket::OnionDataResponse(packet), &Some(ref friends)) =>
+                log_error("Handling OnionDataResponse", friends.onion_client.handle_data_response(&packet).wait()),
+            (packet, _) =>
+                log_error("Handling UDP packet", self.dht.handle_packet(packet, from).wait()),
+        }
+    }
+
+    /// Process messages that modules sent to each other. Return outgoing UDP
+    /// packets.
+    fn flush(&mut self) -> Vec<(DhtPacket, SocketAddr)> {
+        self.events.extend(drain(&mut self.event_rx));
+
+        let friend_addrs = drain(&mut self.friend_saddr_rx);
+        self.friend_addrs.extend(friend_addrs.iter().cloned());
+
+        if let Some(ref mut friends) = self.friends {
+            for (real_pk, dht_pk) in drain(&mut friends.dht_pk_rx) {
+                match friends.dht_pks.insert(real_pk, dht_pk) {
+                    Some(old_dht_pk) if old_dht_pk == dht_pk => continue,
+                    Some(old_dht_pk) => self.dht.remove_friend(old_dht_pk),
+                    None => {},
+                }
+                self.dht.add_friend(dht_pk);
+                friends.net_crypto.add_connection(real_pk, dht_pk);
+            }
+            for node in friend_addrs {
+                let real_pk = friends.dht_pks.iter()
+                    .find(|&(_, &dht_pk)| dht_pk == node.pk)
+                    .map(|(&real_pk, _)| real_pk);
+                if let Some(real_pk) = real_pk {
+                    friends.net_crypto.set_friend_udp_addr(real_pk, node.saddr);
+                }
+            }
+            friends.received.extend(drain(&mut friends.lossless_rx));
+            drain(&mut friends.lossy_rx);
+        }
+
+        if let Some(ref mut relay) = self.relay {
+            for (packet, addr) in drain(&mut relay.udp_onion_rx) {
+                log_error("Handling TCP onion request", self.dht.handle_tcp_onion_request(packet, addr).wait());
+            }
+            for (payload, addr) in drain(&mut relay.tcp_onion_rx) {
+                log_error("Handling UDP onion response", relay.server.handle_udp_onion_response(addr.ip(), addr.port(), payload).wait());
+            }
+        }
+
+        drain(&mut self.udp_rx)
+    }
+}
+
+/// Virtual TCP connection from a client to a relay.
+struct TcpLink {
+    /// Node the relay is running on.
+    relay: usize,
+    /// `PublicKey` of the client.
+    pk: PublicKey,
+    /// Address of the client.
+    addr: SocketAddr,
+    /// Packets from the relay to the client.
+    rx: mpsc::Receiver<TcpPacket>,
+    /// Packets delivered to the client.
+    received: Vec<TcpPacket>,
+    /// Arrival time of the last packet sent to the relay.
+    last_to_relay: Duration,
+    /// Arrival time of the last packet sent to the client.
+    last_to_client: Duration,
+}
+
+/// Packet travelling through the network.
+enum Delivery {
+    /// UDP packet between nodes.
+    Udp {
+        from: SocketAddr,
+        to: SocketAddr,
+        packet: DhtPacket,
+    },
+    /// TCP packet from a client to a relay.
+    TcpToRelay {
+        link: usize,
+        packet: TcpPacket,
+    },
+    /// TCP packet from a relay to a client.
+    TcpToClient {
+        link: usize,
+        packet: TcpPacket,
+    },
+}
+
+/// Simulated network of toxcore nodes running on the mocked clock.
+pub struct Network {
+    /// Generator for all random decisions of the network.
+    rng: SimRng,
+    /// Generator for random bytes requested by modules of nodes.
+    modules_rng: Rc<RefCell<SimRng>>,
+    /// Mocked clock that all modules use.
+    clock: MutNow,
+    /// Instant corresponding to the start of the simulation.
+    start: Instant,
+    /// Simulated time since the start.
+    elapsed: Duration,
+    /// Time of the next run of periodic tasks.
+    next_tick: Duration,
+    /// Number of runs of periodic tasks.
+    ticks: u64,
+    /// Packets in flight by arrival time and sequence number.
+    queue: BTreeMap<(Duration, u64), Delivery>,
+    /// Sequence number of the next packet in flight.
+    seq: u64,
+    nodes: Vec<SimNode>,
+    links: Vec<TcpLink>,
+    /// Link properties used when there is no override for a pair of nodes.
+    default_link: LinkConfig,
+    /// Link properties for pairs of nodes.
+    node_links: HashMap<(usize, usize), LinkConfig>,
+    stats: SimStats,
+}
+
+impl Network {
+    /// Create new empty `Network` with random decisions derived from the
+    /// seed.
+    pub fn new(seed: u64) -> Network {
+        let start = Instant::now();
+        let mut rng = SimRng::new(seed);
+        let modules_rng = SimRng::new(rng.next_u64());
+        Network {
+            rng,
+            modules_rng: Rc::new(RefCell::new(modules_rng)),
+            clock: MutNow::new(start),
+            start,
+            elapsed: Duration::from_secs(0),
+            next_tick: Duration::from_secs(0),
+            ticks: 0,
+            queue: BTreeMap::new(),
+            seq: 0,
+            nodes: Vec::new(),
+            links: Vec::new(),
+            default_link: LinkConfig::default(),
+            node_links: HashMap::new(),
+            stats: SimStats::default(),
+        }
+    }
+
+    /// Set properties of links between nodes without override and of TCP
+    /// links.
+    pub fn set_default_link(&mut self, link: LinkConfig) {
+        self.default_link = link;
+    }
+
+    /// Set properties of the link between two nodes.
+    pub fn set_link(&mut self, a: NodeId, b: NodeId, link: LinkConfig) {
+        self.node_links.insert(link_key(a.0, b.0), link);
+    }
+
+    /// Simulated time since the start.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Counters of UDP packets.
+    pub fn stats(&self) -> SimStats {
+        self.stats
+    }
+
+    /// Add new node to the network.
+    pub fn add_node(&mut self, options: NodeOptions) -> NodeId {
+        let index = self.nodes.len();
+        let (dht_pk, dht_sk) = self.rng.gen_keypair();
+        let (real_pk, real_sk) = self.rng.gen_keypair();
+
+        let node = self.with_clock(|_| {
+            let (udp_tx, udp_rx) = mpsc::channel(SIM_CHANNEL_SIZE);
+            let (friend_saddr_tx, friend_saddr_rx) = mpsc::unbounded();
+            let (event_tx, event_rx) = mpsc::unbounded();
+
+            let mut dht = DhtServer::new(udp_tx.clone(), dht_pk, dht_sk.clone());
+            dht.enable_lan_discovery(false);
+            dht.set_friend_saddr_sink(friend_saddr_tx);
+            dht.set_event_sink(event_tx.clone());
+
+            let relay = if options.tcp_relay {
+                let (udp_onion_tx, udp_onion_rx) = mpsc::channel(SIM_CHANNEL_SIZE);
+                let (tcp_onion_tx, tcp_onion_rx) = mpsc::channel(SIM_CHANNEL_SIZE);
+                let mut server = TcpServer::new();
+                server.set_udp_onion_sink(udp_onion_tx);
+                server.set_event_sink(event_tx.clone());
+                dht.set_tcp_onion_sink(tcp_onion_tx);
+                Some(Relay {
+                    server,
+                    udp_onion_rx,
+                    tcp_onion_rx,
+                })
+            } else {
+                None
+            };
+
+            let friends = if options.friend_connections {
+                let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+                let (lossless_tx, lossless_rx) = mpsc::unbounded();
+                let (lossy_tx, lossy_rx) = mpsc::unbounded();
+                let (tcp_incoming_tx, tcp_incoming_rx) = mpsc::unbounded();
+
+                let mut net_crypto = NetCrypto::new(NetCryptoNewArgs {
+                    udp_tx,
+                    dht_pk_tx: dht_pk_tx.clone(),
+                    lossless_tx,
+                    lossy_tx,
+                    dht_pk,
+                    dht_sk: dht_sk.clone(),
+                    real_pk,
+                    real_sk: real_sk.clone(),
+                    precomputed_keys: dht.get_precomputed_keys(),
+                });
+                net_crypto.set_event_sink(event_tx.clone());
+                dht.set_net_crypto(net_crypto.clone());
+
+                let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+                let mut onion_client = OnionClient::new(dht.clone(), tcp_connections, dht_pk_tx, real_sk, real_pk);
+                onion_client.set_event_sink(event_tx);
+
+                Some(FriendsStack {
+                    real_pk,
+                    net_crypto,
+                    onion_client,
+                    dht_pk_rx,
+                    lossless_rx,
+                    lossy_rx,
+                    _tcp_incoming_rx: tcp_incoming_rx,
+                    dht_pks: HashMap::new(),
+                    received: Vec::new(),
+                })
+            } else {
+                None
+            };
+
+            let (addr, nat) = match options.nat {
+                Some(kind) => (
+                    SocketAddr::new(Ipv4Addr::new(192, 168, 0, 2).into(), SIM_NODE_PORT),
+                    Some(Nat::new(kind, index_ip(2, index))),
+                ),
+                None => (SocketAddr::new(index_ip(1, index), SIM_NODE_PORT), None),
+            };
+
+            SimNode {
+                addr,
+                nat,
+                dht,
+                udp_rx,
+                friend_saddr_rx,
+                event_rx,
+                friends,
+                relay,
+                friend_addrs: Vec::new(),
+                events: Vec::new(),
+            }
+        });
+
+        self.nodes.push(node);
+        NodeId(index)
+    }
+
+    /// DHT server of the node.
+    pub fn dht(&self, node: NodeId) -> &DhtServer {
+        &self.nodes[node.0].dht
+    }
+
+    /// DHT `PublicKey` of the node.
+    pub fn dht_pk(&self, node: NodeId) -> PublicKey {
+        self.nodes[node.0].dht.pk
+    }
+
+    /// Long term `PublicKey` of the node if it has friend connections enabled.
+    pub fn real_pk(&self, node: NodeId) -> Option<PublicKey> {
+        self.nodes[node.0].friends.as_ref().map(|friends| friends.real_pk)
+    }
+
+    /// `NetCrypto` of the node if it has friend connections enabled.
+    pub fn net_crypto(&self, node: NodeId) -> Option<&NetCrypto> {
+        self.nodes[node.0].friends.as_ref().map(|friends| &friends.net_crypto)
+    }
+
+    /// Address of the node that is reachable by other nodes. `None` if the
+    /// node is behind NAT.
+    pub fn public_addr(&self, node: NodeId) -> Option<SocketAddr> {
+        let node = &self.nodes[node.0];
+        if node.nat.is_some() {
+            None
+        } else {
+            Some(node.addr)
+        }
+    }
+
+    /// Events emitted by modules of the node so far.
+    pub fn events(&self, node: NodeId) -> &[Event] {
+        &self.nodes[node.0].events
+    }
+
+    /// Addresses of DHT friends found by the node so far.
+    pub fn friend_addrs(&self, node: NodeId) -> &[PackedNode] {
+        &self.nodes[node.0].friend_addrs
+    }
+
+    /// Lossless packets received by the node from its friends so far.
+    pub fn received_lossless(&self, node: NodeId) -> &[(PublicKey, Vec<u8>)] {
+        self.nodes[node.0].friends.as_ref().map_or(&[], |friends| &friends.received)
+    }
+
+    /// Use `from` node as initial bootstrap node of `node`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `from` node is behind NAT.
+    pub fn bootstrap(&mut self, node: NodeId, from: NodeId) {
+        let addr = self.public_addr(from).expect("Bootstrap node should not be behind NAT");
+        let pn = PackedNode::new(addr, &self.dht_pk(from));
+        self.nodes[node.0].dht.add_initial_bootstrap(pn);
+    }
+
+    /// Make `node` look for DHT address of `friend`.
+    pub fn add_dht_friend(&mut self, node: NodeId, friend: NodeId) {
+        let friend_pk = self.dht_pk(friend);
+        self.with_clock(|network| network.nodes[node.0].dht.add_friend(friend_pk));
+    }
+
+    /// Make `node` look for `friend` via onion and establish crypto
+    /// connection to it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if friend connections are not enabled for both nodes.
+    pub fn add_friend(&mut self, node: NodeId, friend: NodeId) {
+        let friend_pk = self.real_pk(friend).expect("Friend connections should be enabled for the friend");
+        self.with_clock(|network| {
+            let friends = network.nodes[node.0].friends.as_ref().expect("Friend connections should be enabled for the node");
+            friends.net_crypto.add_friend(friend_pk);
+            friends.onion_client.add_friend(friend_pk);
+        });
+    }
+
+    /// Send lossless packet from `node` to `friend` via crypto connection.
+    pub fn send_lossless(&mut self, node: NodeId, friend: NodeId, data: Vec<u8>) -> bool {
+        let friend_pk = match self.real_pk(friend) {
+            Some(friend_pk) => friend_pk,
+            None => return false,
+        };
+        let sent = self.with_clock(|network| match network.nodes[node.0].friends {
+            Some(ref friends) => friends.net_crypto.send_lossless(friend_pk, data).wait().is_ok(),
+            None => false,
+        });
+        self.with_clock(Network::flush);
+        sent
+    }
+
+    /// Open virtual TCP connection from a client with `pk` to the relay
+    /// running on `relay` node. Handshake is omitted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `relay` node doesn't run TCP relay.
+    pub fn connect_tcp(&mut self, relay: NodeId, pk: PublicKey) -> TcpLinkId {
+        let index = self.links.len();
+        let addr = SocketAddr::new(index_ip(3, index), SIM_NODE_PORT);
+        let (tx, rx) = mpsc::channel(SIM_CHANNEL_SIZE);
+        self.with_clock(|network| {
+            let server = &network.nodes[relay.0].relay.as_ref().expect("Node should run TCP relay").server;
+            let client = TcpClient::new(tx, &pk, addr.ip(), addr.port());
+            log_error("Inserting TCP client", server.insert(client).wait());
+        });
+        self.links.push(TcpLink {
+            relay: relay.0,
+            pk,
+            addr,
+            rx,
+            received: Vec::new(),
+            last_to_relay: self.elapsed,
+            last_to_client: self.elapsed,
+        });
+        self.with_clock(Network::flush);
+        TcpLinkId(index)
+    }
+
+    /// Close virtual TCP connection.
+    pub fn disconnect_tcp(&mut self, link: TcpLinkId) {
+        self.with_clock(|network| {
+            let link = &network.links[link.0];
+            let server = &network.nodes[link.relay].relay.as_ref().expect("Node should run TCP relay").server;
+            log_error("Shutting down TCP client", server.shutdown_client(&link.pk, link.addr.ip(), link.addr.port()).wait());
+            network.flush();
+        });
+    }
+
+    /// Send packet from a client to the relay via virtual TCP connection.
+    pub fn send_tcp(&mut self, link: TcpLinkId, packet: TcpPacket) {
+        self.schedule_tcp(Delivery::TcpToRelay { link: link.0, packet });
+    }
+
+    /// Packets received by a client from the relay so far. `PingRequest`
+    /// packets are answered automatically.
+    pub fn tcp_received(&self, link: TcpLinkId) -> &[TcpPacket] {
+        &self.links[link.0].received
+    }
+
+    /// Run the simulation for the given amount of simulated time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.elapsed + duration;
+        self.with_clock(|network| {
+            loop {
+                let next_delivery = network.queue.keys().next().map(|&(time, _)| time);
+                let next = match next_delivery {
+                    Some(time) if time < network.next_tick => time,
+                    _ => network.next_tick,
+                };
+                if next > end {
+                    break;
+                }
+                network.set_elapsed(next);
+                if next_delivery == Some(next) {
+                    let key = *network.queue.keys().next().unwrap();
+                    let delivery = network.queue.remove(&key).unwrap();
+                    network.deliver(delivery);
+                } else {
+                    network.tick();
+                }
+                network.flush();
+            }
+            network.set_elapsed(end);
+        });
+    }
+
+    /// Run the simulation until the condition is met checking it after every
+    /// simulated second. Return `false` if it isn't met within `timeout`.
+    pub fn run_until<F>(&mut self, timeout: Duration, mut condition: F) -> bool
+        where F: FnMut(&Network) -> bool
+    {
+        let end = self.elapsed + timeout;
+        while self.elapsed < end {
+            if condition(self) {
+                return true;
+            }
+            self.run_for(Duration::from_secs(1));
+        }
+        condition(self)
+    }
+
+    /// Run closure with mocked clock set to the simulated time. Random bytes
+    /// requested by modules are taken from the generator of the network
+    /// while the closure runs.
+    fn with_clock<F, R>(&mut self, f: F) -> R
+        where F: FnOnce(&mut Network) -> R
+    {
+        let clock = Clock::new_with_now(self.clock.clone());
+        let modules_rng = self.modules_rng.clone();
+        let source = set_random_bytes_source(Some(Box::new(move |buf| modules_rng.borrow_mut().fill_bytes(buf))));
+        let system_time_base = set_system_time_base(Some((UNIX_EPOCH + Duration::from_secs(SIM_START_UNIX_TIME), self.start)));
+        let result = {
+            let mut enter = tokio_executor::enter().unwrap();
+            with_default(&clock, &mut enter, |_| f(self))
+        };
+        set_random_bytes_source(source);
+        set_system_time_base(system_time_base);
+        result
+    }
+
+    /// Move simulated time.
+    fn set_elapsed(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+        self.clock.set(self.start + elapsed);
+    }
+
+    /// Run periodic tasks of all modules.
+    fn tick(&mut self) {
+        let ticks_per_second = 1000 / PACKET_COUNTER_AVERAGE_INTERVAL_MS;
+        let every_second = self.ticks % ticks_per_second == 0;
+        let ping = self.ticks % (ticks_per_second * TIME_TO_PING) == 0;
+
+        for node in &self.nodes {
+            if let Some(ref friends) = node.friends {
+                log_error("NetCrypto main loop", friends.net_crypto.main_loop().wait());
+            }
+            if every_second {
+                log_error("DHT main loop", node.dht.dht_main_loop().wait());
+                log_error("DHT bootstrap", node.dht.send_bootstrap_requests().wait());
+                if let Some(ref friends) = node.friends {
+                    log_error("Onion client main loop", friends.onion_client.main_loop().wait());
+                }
+                if let Some(ref relay) = node.relay {
+                    log_error("TCP relay pings", relay.server.send_pings().wait());
+                }
+            }
+            if ping {
+                log_error("DHT pings", node.dht.send_pings().wait());
+            }
+        }
+
+        self.ticks += 1;
+        self.next_tick += PACKET_COUNTER_AVERAGE_INTERVAL;
+    }
+
+    /// Move messages between modules and send outgoing packets until there
+    /// is nothing to move.
+    fn flush(&mut self) {
+        loop {
+            let mut progress = false;
+            for index in 0 .. self.nodes.len() {
+                let packets = self.nodes[index].flush();
+                progress |= !packets.is_empty();
+                for (packet, to) in packets {
+                    self.send_udp(index, packet, to);
+                }
+            }
+            for index in 0 .. self.links.len() {
+                let packets = drain(&mut self.links[index].rx);
+                progress |= !packets.is_empty();
+                for packet in packets {
+                    self.schedule_tcp(Delivery::TcpToClient { link: index, packet });
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+    }
+
+    /// Send UDP packet from the node applying its NAT and link properties.
+    fn send_udp(&mut self, index: usize, packet: DhtPacket, to: SocketAddr) {
+        self.stats.sent += 1;
+
+        let from = match self.nodes[index].nat {
+            Some(ref mut nat) => nat.map(to),
+            None => self.nodes[index].addr,
+        };
+        let link = self.nodes.iter()
+            .position(|node| match node.nat {
+                Some(ref nat) => nat.public_ip == to.ip(),
+                None => node.addr == to,
+            })
+            .and_then(|dest| self.node_links.get(&link_key(index, dest)))
+            .cloned()
+            .unwrap_or(self.default_link);
+
+        if self.rng.gen_bool(link.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let mut delay = link.latency + self.rng.gen_duration(link.jitter);
+        if self.rng.gen_bool(link.reorder) {
+            delay += link.latency;
+        }
+        self.schedule(self.elapsed + delay, Delivery::Udp { from, to, packet });
+    }
+
+    /// Send TCP packet keeping the order of packets on the link.
+    fn schedule_tcp(&mut self, delivery: Delivery) {
+        let delay = self.default_link.latency + self.rng.gen_duration(self.default_link.jitter);
+        let time = self.elapsed + delay;
+        let time = match delivery {
+            Delivery::TcpToRelay { link, .. } => {
+                let link = &mut self.links[link];
+                link.last_to_relay = link.last_to_relay.max(time);
+                link.last_to_relay
+            },
+            Delivery::TcpToClient { link, .. } => {
+                let link = &mut self.links[link];
+                link.last_to_client = link.last_to_client.max(time);
+                link.last_to_client
+            },
+            Delivery::Udp { .. } => time,
+        };
+        self.schedule(time, delivery);
+    }
+
+    /// Put packet to the queue of packets in flight.
+    fn schedule(&mut self, time: Duration, delivery: Delivery) {
+        self.queue.insert((time, self.seq), delivery);
+        self.seq += 1;
+    }
+
+    /// Deliver packet that arrived to its destination.
+    fn deliver(&mut self, delivery: Delivery) {
+        match delivery {
+            Delivery::Udp { from, to, packet } => {
+                let dest = self.nodes.iter().position(|node| match node.nat {
+                    Some(ref nat) => nat.accepts(from, to),
+                    None => node.addr == to,
+                });
+                match dest {
+                    Some(dest) => {
+                        self.stats.delivered += 1;
+                        self.nodes[dest].handle_udp(packet, from);
+                    },
+                    None => self.stats.filtered += 1,
+                }
+            },
+            Delivery::TcpToRelay { link, packet } => {
+                let link = &self.links[link];
+                let server = &self.nodes[link.relay].relay.as_ref().unwrap().server;
+                log_error("Handling TCP packet", server.handle_packet(&link.pk, packet).wait());
+            },
+            Delivery::TcpToClient { link, packet } => {
+                if let TcpPacket::PingRequest(ref ping_request) = packet {
+                    let pong_response = PongResponse { ping_id: ping_request.ping_id };
+                    self.schedule_tcp(Delivery::TcpToRelay { link, packet: TcpPacket::PongResponse(pong_response) });
+                }
+                self.links[link].received.push(packet);
+            },
+        }
+    }
+}
+
+/// Key of a link between two nodes that doesn't depend on direction.
+fn link_key(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+/// Generate IP address for the node with the given index.
+fn index_ip(network: u8, index: usize) -> IpAddr {
+    let index = index + 1;
+    Ipv4Addr::new(network, (index >> 16) as u8, (index >> 8) as u8, index as u8).into()
+}
+
+/// Take all items that are ready in the stream without blocking.
+fn drain<S: Stream<Error = ()>>(stream: &mut S) -> Vec<S::Item> {
+    future::lazy(|| {
+        let mut items = Vec::new();
+        while let Ok(Async::Ready(Some(item))) = stream.poll() {
+            items.push(item);
+        }
+        future::ok::<_, ()>(items)
+    }).wait().unwrap()
+}
+
+/// Log error of an operation. Errors are expected in simulation since packets
+/// get lost and nodes might be not ready to handle them.
+fn log_error<E: Display>(operation: &str, result: Result<(), E>) {
+    if let Err(e) = result {
+        trace!("{} failed: {}", operation, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::tcp::packet::{Data, RouteRequest, RouteResponse};
+
+    /// Create bootstrap node and `count` nodes bootstrapping from it.
+    fn create_network(network: &mut Network, count: usize, options: NodeOptions) -> (NodeId, Vec<NodeId>) {
+        let bootstrap = network.add_node(NodeOptions::default());
+        let nodes = (0 .. count).map(|_| {
+            let node = network.add_node(options);
+            network.bootstrap(node, bootstrap);
+            node
+        }).collect();
+        (bootstrap, nodes)
+    }
+
+    #[test]
+    fn rng_is_deterministic() {
+        let mut rng_1 = SimRng::new(42);
+        let mut rng_2 = SimRng::new(42);
+        let mut rng_3 = SimRng::new(43);
+
+        let values_1 = (0 .. 10).map(|_| rng_1.next_u64()).collect::<Vec<_>>();
+        let values_2 = (0 .. 10).map(|_| rng_2.next_u64()).collect::<Vec<_>>();
+        let values_3 = (0 .. 10).map(|_| rng_3.next_u64()).collect::<Vec<_>>();
+
+        assert_eq!(values_1, values_2);
+        assert_ne!(values_1, values_3);
+        assert_eq!(rng_1.gen_keypair(), rng_2.gen_keypair());
+        assert!(!rng_1.gen_bool(0.0));
+        assert!(rng_1.gen_bool(1.0));
+        assert!(rng_1.gen_duration(Duration::from_millis(10)) <= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn same_seed_same_keys() {
+        crypto_init().unwrap();
+        let mut network_1 = Network::new(7);
+        let mut network_2 = Network::new(7);
+
+        let node_1 = network_1.add_node(NodeOptions { friend_connections: true, .. NodeOptions::default() });
+        let node_2 = network_2.add_node(NodeOptions { friend_connections: true, .. NodeOptions::default() });
+
+        assert_eq!(network_1.dht_pk(node_1), network_2.dht_pk(node_2));
+        assert_eq!(network_1.real_pk(node_1), network_2.real_pk(node_2));
+        assert_eq!(network_1.public_addr(node_1), network_2.public_addr(node_2));
+    }
+
+    /// Run a network with friend connections on lossy links and return
+    /// events of all nodes and counters of packets.
+    fn run_lossy_network(seed: u64) -> (Vec<Vec<Event>>, SimStats) {
+        let mut network = Network::new(seed);
+        network.set_default_link(LinkConfig {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(30),
+            loss: 0.05,
+            reorder: 0.05,
+        });
+        let (bootstrap, _nodes) = create_network(&mut network, 8, NodeOptions::default());
+        let friend_options = NodeOptions { friend_connections: true, .. NodeOptions::default() };
+        let alice = network.add_node(friend_options);
+        let bob = network.add_node(friend_options);
+        network.bootstrap(alice, bootstrap);
+        network.bootstrap(bob, bootstrap);
+        network.add_friend(alice, bob);
+        network.add_friend(bob, alice);
+
+        network.run_for(Duration::from_secs(120));
+
+        let events = (0 .. network.nodes.len())
+            .map(|index| network.events(NodeId(index)).to_vec())
+            .collect();
+        (events, network.stats())
+    }
+
+    #[test]
+    fn same_seed_same_events() {
+        crypto_init().unwrap();
+        let (events_1, stats_1) = run_lossy_network(6);
+        let (events_2, stats_2) = run_lossy_network(6);
+
+        assert!(events_1.iter().all(|events| !events.is_empty()));
+        assert_eq!(events_1, events_2);
+        assert_eq!(stats_1, stats_2);
+
+        let (events_3, _stats_3) = run_lossy_network(7);
+        assert_ne!(events_1, events_3);
+    }
+
+    #[test]
+    fn nat_mapping() {
+        let public_ip: IpAddr = "2.0.0.1".parse().unwrap();
+        let peer_1: SocketAddr = "1.0.0.1:33445".parse().unwrap();
+        let peer_1_other_port: SocketAddr = "1.0.0.1:33446".parse().unwrap();
+        let peer_2: SocketAddr = "1.0.0.2:33445".parse().unwrap();
+
+        let mut nat = Nat::new(NatType::FullCone, public_ip);
+        let mapped = nat.map(peer_1);
+        assert_eq!(nat.map(peer_2), mapped);
+        assert!(nat.accepts(peer_1_other_port, mapped));
+
+        let mut nat = Nat::new(NatType::AddressRestricted, public_ip);
+        let mapped = nat.map(peer_1);
+        assert!(nat.accepts(peer_1_other_port, mapped));
+        assert!(!nat.accepts(peer_2, mapped));
+
+        let mut nat = Nat::new(NatType::PortRestricted, public_ip);
+        let mapped = nat.map(peer_1);
+        assert!(nat.accepts(peer_1, mapped));
+        assert!(!nat.accepts(peer_1_other_port, mapped));
+
+        let mut nat = Nat::new(NatType::Symmetric, public_ip);
+        let mapped_1 = nat.map(peer_1);
+        let mapped_2 = nat.map(peer_2);
+        assert_eq!(mapped_2.port(), mapped_1.port() + 1);
+        assert!(nat.accepts(peer_1, mapped_1));
+        assert!(!nat.accepts(peer_1, mapped_2));
+        assert!(!nat.accepts(peer_2, mapped_1));
+    }
+
+    #[test]
+    fn bootstrap_with_loss_and_latency() {
+        crypto_init().unwrap();
+        let mut network = Network::new(1);
+        network.set_default_link(LinkConfig {
+            latency: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+            loss: 0.1,
+            reorder: 0.05,
+        });
+        let (_bootstrap, nodes) = create_network(&mut network, 16, NodeOptions::default());
+
+        let bootstrapped = network.run_until(Duration::from_secs(120), |network|
+            nodes.iter().all(|&node| network.dht(node).close_nodes.read().len() >= 8)
+        );
+
+        assert!(bootstrapped);
+        assert!(network.elapsed() < Duration::from_secs(120));
+        let stats = network.stats();
+        assert!(stats.lost > 0);
+        assert_eq!(stats.sent, stats.lost + stats.filtered + stats.delivered + network.queue.len() as u64);
+        assert!(network.events(nodes[0]).iter().any(|event| match *event {
+            Event::CloseNodeAdded { .. } => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn nat_drops_unsolicited_packets() {
+        crypto_init().unwrap();
+        let mut network = Network::new(2);
+        let public = network.add_node(NodeOptions::default());
+        let natted = network.add_node(NodeOptions { nat: Some(NatType::PortRestricted), .. NodeOptions::default() });
+        network.bootstrap(natted, public);
+
+        network.run_for(Duration::from_secs(10));
+
+        // NATed node is reachable only via the address observed by public node
+        let close_nodes = network.dht(public).close_nodes.read();
+        let node = close_nodes.get_node(&network.dht_pk(natted)).and_then(|node| node.to_packed_node()).unwrap();
+        assert_eq!(node.saddr.ip(), index_ip(2, natted.0));
+        assert_eq!(node.saddr.port(), SIM_NAT_FIRST_PORT);
+    }
+
+    #[test]
+    fn hole_punching() {
+        crypto_init().unwrap();
+        let mut network = Network::new(3);
+        let (_bootstrap, public_nodes) = create_network(&mut network, 8, NodeOptions::default());
+        let alice = network.add_node(NodeOptions { nat: Some(NatType::Symmetric), .. NodeOptions::default() });
+        let bob = network.add_node(NodeOptions { nat: Some(NatType::PortRestricted), .. NodeOptions::default() });
+        network.bootstrap(alice, public_nodes[0]);
+        network.bootstrap(bob, public_nodes[1]);
+        network.add_dht_friend(alice, bob);
+        network.add_dht_friend(bob, alice);
+
+        let bob_pk = network.dht_pk(bob);
+        let alice_pk = network.dht_pk(alice);
+        let found = network.run_until(Duration::from_secs(300), |network|
+            network.friend_addrs(alice).iter().any(|node| node.pk == bob_pk) &&
+                network.friend_addrs(bob).iter().any(|node| node.pk == alice_pk)
+        );
+
+        assert!(found);
+        assert!(network.stats().filtered > 0);
+    }
+
+    #[test]
+    fn friend_connection() {
+        crypto_init().unwrap();
+        let mut network = Network::new(4);
+        network.set_default_link(LinkConfig {
+            latency: Duration::from_millis(30),
+            jitter: Duration::from_millis(20),
+            loss: 0.02,
+            reorder: 0.0,
+        });
+        let (bootstrap, _nodes) = create_network(&mut network, 12, NodeOptions::default());
+        let friend_options = NodeOptions { friend_connections: true, .. NodeOptions::default() };
+        let alice = network.add_node(friend_options);
+        let bob = network.add_node(friend_options);
+        network.bootstrap(alice, bootstrap);
+        network.bootstrap(bob, bootstrap);
+        network.add_friend(alice, bob);
+        network.add_friend(bob, alice);
+
+        let bob_real_pk = network.real_pk(bob).unwrap();
+        let alice_real_pk = network.real_pk(alice).unwrap();
+        let established = network.run_until(Duration::from_secs(600), |network|
+            network.events(alice).contains(&Event::CryptoConnectionEstablished { real_pk: bob_real_pk }) &&
+                network.events(bob).contains(&Event::CryptoConnectionEstablished { real_pk: alice_real_pk })
+        );
+        assert!(established);
+        assert!(network.events(alice).iter().any(|event| match *event {
+            Event::FriendDhtPkLearned { real_pk, .. } => real_pk == bob_real_pk,
+            _ => false,
+        }));
+
+        assert!(network.send_lossless(alice, bob, vec![64, 42]));
+        let received = network.run_until(Duration::from_secs(10), |network|
+            network.received_lossless(bob).contains(&(alice_real_pk, vec![64, 42]))
+        );
+        assert!(received);
+    }
+
+    #[test]
+    fn tcp_relay() {
+        crypto_init().unwrap();
+        let mut network = Network::new(5);
+        let relay = network.add_node(NodeOptions { tcp_relay: true, .. NodeOptions::default() });
+        let (alice_pk, _alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+
+        let alice = network.connect_tcp(relay, alice_pk);
+        let bob = network.connect_tcp(relay, bob_pk);
+        network.send_tcp(alice, TcpPacket::RouteRequest(RouteRequest { pk: bob_pk }));
+        network.send_tcp(bob, TcpPacket::RouteRequest(RouteRequest { pk: alice_pk }));
+        network.run_for(Duration::from_secs(1));
+
+        let connection_id = network.tcp_received(alice).iter().filter_map(|packet| match *packet {
+            TcpPacket::RouteResponse(RouteResponse { connection_id, pk }) if pk == bob_pk => Some(connection_id),
+            _ => None,
+        }).next().unwrap();
+        network.send_tcp(alice, TcpPacket::Data(Data { connection_id, data: vec![42; 16] }));
+
+        // clients have to answer pings to stay connected
+        network.run_for(Duration::from_secs(60));
+
+        assert!(network.tcp_received(bob).iter().any(|packet| match *packet {
+            TcpPacket::Data(Data { ref data, .. }) => data == &vec![42; 16],
+            _ => false,
+        }));
+        assert!(network.tcp_received(bob).iter().any(|packet| match *packet {
+            TcpPacket::PingRequest(_) => true,
+            _ => false,
+        }));
+        assert!(network.events(relay).contains(&Event::TcpClientConnected { pk: alice_pk, saddr: SocketAddr::new(index_ip(3, alice.0), SIM_NODE_PORT) }));
+
+        network.disconnect_tcp(alice);
+        assert!(network.events(relay).contains(&Event::TcpClientDisconnected { pk: alice_pk }));
+    }
+}
@@ -13,3 +13,5 @@ https://zetok.github.io/tox-spec/#state-format
 
 // FIXME: use new dht code instead of old
 pub mod old;
+pub mod profile;
+pub mod v2;
@@ -8,6 +8,7 @@ use nom::{le_u16, be_u16, le_u8, le_u32, le_u64, rest};
 use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::packed_node::*;
+use crate::toxcore::identity::IdentitySeed;
 use crate::toxcore::toxid::{NoSpam, NOSPAMBYTES};
 use crate::toxcore::packed_node::*;
 
@@ -44,6 +45,19 @@ impl NospamKeys {
             sk
         }
     }
+
+    /** Derives `NospamKeys` from [`IdentitySeed`](../../identity/struct.IdentitySeed.html).
+    If `nospam` is `None` it's derived from the seed as well, otherwise only
+    keys are derived.
+    */
+    pub fn from_seed(seed: &IdentitySeed, nospam: Option<NoSpam>) -> Self {
+        let (pk, sk) = seed.keypair();
+        NospamKeys {
+            nospam: nospam.unwrap_or_else(|| seed.nospam()),
+            pk,
+            sk
+        }
+    }
 }
 
 /** Provided that there's at least [`NOSPAMKEYSBYTES`]
@@ -231,7 +245,7 @@ pub const USER_STATUS_LEN: usize = 1;
 
 /// User status section
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
-pub struct UserStatus(UserWorkingStatus);
+pub struct UserStatus(pub UserWorkingStatus);
 
 impl FromBytes for UserStatus {
     named!(from_bytes<UserStatus>, do_parse!(
@@ -343,17 +357,22 @@ https://zetok.github.io/tox-spec/#friends-0x03
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FriendState {
-    friend_status: FriendStatus,
-    pk: PublicKey,
+    /// Status of the friendship.
+    pub friend_status: FriendStatus,
+    /// Friend's long term `PublicKey`.
+    pub pk: PublicKey,
     /// Friend request message that is being sent to friend.
-    fr_msg: Vec<u8>,
+    pub fr_msg: Vec<u8>,
     /// Friend's name.
-    name: Name,
-    status_msg: StatusMsg,
-    user_status: UserWorkingStatus,
-    nospam: NoSpam,
+    pub name: Name,
+    /// Friend's status message.
+    pub status_msg: StatusMsg,
+    /// Friend's user status.
+    pub user_status: UserWorkingStatus,
+    /// Friend's `NoSpam`, only used for sending friend request.
+    pub nospam: NoSpam,
     /// Time when friend was last seen online.
-    last_seen: u64,
+    pub last_seen: u64,
 }
 
 /// Number of bytes of serialized [`FriendState`](./struct.FriendState.html).
@@ -461,6 +480,204 @@ impl ToBytes for Friends {
     }
 }
 
+/// Length in bytes of conference id.
+pub const CONFERENCE_ID_LEN: usize = 32;
+
+/// Max length in bytes of conference title and peer nick, limited by one byte
+/// used to store the length.
+pub const CONFERENCE_NAME_LEN: usize = 255;
+
+/** Type of conference. Used by [`Conference`](./struct.Conference.html).
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConferenceType {
+    /// Text only conference.
+    Text = 0,
+    /// Conference with audio.
+    Av   = 1,
+}
+
+impl FromBytes for ConferenceType {
+    named!(from_bytes<ConferenceType>, switch!(le_u8,
+        0 => value!(ConferenceType::Text) |
+        1 => value!(ConferenceType::Av)
+    ));
+}
+
+/** Peer of a saved conference.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`32`        | Real `PublicKey` of the peer
+`32`        | Temporary `PublicKey` of the peer
+`2`         | Peer number (LE)
+`8`         | Time when peer was last active (LE)
+`1`         | Length of nick
+`[0, 255]`  | Nick
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConferencePeer {
+    /// Long term `PublicKey` of the peer.
+    pub real_pk: PublicKey,
+    /// Temporary `PublicKey` the peer uses in this conference.
+    pub temp_pk: PublicKey,
+    /// Number of the peer in the conference.
+    pub peer_number: u16,
+    /// Unix time when the peer was last active.
+    pub last_active: u64,
+    /// Nick of the peer. Might be invalid UTF-8.
+    pub nick: Vec<u8>,
+}
+
+impl FromBytes for ConferencePeer {
+    named!(from_bytes<ConferencePeer>, do_parse!(
+        real_pk: call!(PublicKey::from_bytes) >>
+        temp_pk: call!(PublicKey::from_bytes) >>
+        peer_number: le_u16 >>
+        last_active: le_u64 >>
+        nick_len: le_u8 >>
+        nick: take!(nick_len) >>
+        (ConferencePeer {
+            real_pk,
+            temp_pk,
+            peer_number,
+            last_active,
+            nick: nick.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for ConferencePeer {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.nick.len() > CONFERENCE_NAME_LEN, |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.real_pk.as_ref()) >>
+            gen_slice!(self.temp_pk.as_ref()) >>
+            gen_le_u16!(self.peer_number) >>
+            gen_le_u64!(self.last_active) >>
+            gen_le_u8!(self.nick.len() as u8) >>
+            gen_slice!(self.nick.as_slice())
+        )
+    }
+}
+
+/** Conference saved by c-toxcore.
+
+Own peer is not saved. All saved peers are restored as frozen peers, i.e.
+peers that are not known to be online until they are seen in the conference
+again. Since c-toxcore doesn't distinguish active and frozen peers when
+saving, they are stored in a single list.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | Conference type
+`32`        | Conference id
+`4`         | Message number (LE)
+`2`         | Lossy message number (LE)
+`2`         | Own peer number (LE)
+`4`         | Number of saved peers (LE)
+`1`         | Length of title
+`[0, 255]`  | Title
+variable    | Saved peers
+
+https://github.com/TokTok/c-toxcore/blob/master/toxcore/group.c
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Conference {
+    /// Type of the conference.
+    pub conference_type: ConferenceType,
+    /// Unique id of the conference.
+    pub id: [u8; CONFERENCE_ID_LEN],
+    /// Number of the last own message.
+    pub message_number: u32,
+    /// Number of the last own lossy message.
+    pub lossy_message_number: u16,
+    /// Own peer number in the conference.
+    pub peer_number: u16,
+    /// Title of the conference. Might be invalid UTF-8.
+    pub title: Vec<u8>,
+    /// Saved peers of the conference.
+    pub peers: Vec<ConferencePeer>,
+}
+
+impl FromBytes for Conference {
+    named!(from_bytes<Conference>, do_parse!(
+        conference_type: call!(ConferenceType::from_bytes) >>
+        id: take!(CONFERENCE_ID_LEN) >>
+        message_number: le_u32 >>
+        lossy_message_number: le_u16 >>
+        peer_number: le_u16 >>
+        peers_count: le_u32 >>
+        title_len: le_u8 >>
+        title: take!(title_len) >>
+        peers: count!(ConferencePeer::from_bytes, peers_count as usize) >>
+        (Conference {
+            conference_type,
+            id: {
+                let mut conference_id = [0; CONFERENCE_ID_LEN];
+                conference_id.copy_from_slice(id);
+                conference_id
+            },
+            message_number,
+            lossy_message_number,
+            peer_number,
+            title: title.to_vec(),
+            peers,
+        })
+    ));
+}
+
+impl ToBytes for Conference {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.title.len() > CONFERENCE_NAME_LEN, |buf| gen_error(buf, 0)) >>
+            gen_le_u8!(self.conference_type as u8) >>
+            gen_slice!(&self.id) >>
+            gen_le_u32!(self.message_number) >>
+            gen_le_u16!(self.lossy_message_number) >>
+            gen_le_u16!(self.peer_number) >>
+            gen_le_u32!(self.peers.len() as u32) >>
+            gen_le_u8!(self.title.len() as u8) >>
+            gen_slice!(self.title.as_slice()) >>
+            gen_many_ref!(&self.peers, |buf, peer| ConferencePeer::to_bytes(peer, buf))
+        )
+    }
+}
+
+/** Conferences section. Contains list of [`Conference`](./struct.Conference.html).
+
+https://zetok.github.io/tox-spec/#conferences-0x14
+*/
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Conferences(pub Vec<Conference>);
+
+impl FromBytes for Conferences {
+    named!(from_bytes<Conferences>, do_parse!(
+        tag!([0x14, 0x00]) >>
+        tag!(SECTION_MAGIC) >>
+        conferences: many0!(complete!(Conference::from_bytes)) >>
+        // fail on trailing data so that the section is kept as unknown
+        // instead of being truncated
+        eof!() >>
+        (Conferences(conferences))
+    ));
+}
+
+impl ToBytes for Conferences {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_le_u16!(0x0014) >>
+            gen_slice!(SECTION_MAGIC) >>
+            gen_many_ref!(&self.0, |buf, conference| Conference::to_bytes(conference, buf))
+        )
+    }
+}
+
 /// End of the state format data.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct Eof;
@@ -531,21 +748,47 @@ pub enum Section {
     https://zetok.github.io/tox-spec/#path-nodes-0x0b
     */
     PathNodes(PathNodes),
+    /** Section for a list of [`Conferences`](./struct.Conferences.html).
+
+    https://zetok.github.io/tox-spec/#conferences-0x14
+    */
+    Conferences(Conferences),
     /// End of file. https://zetok.github.io/tox-spec/#eof-0xff
     Eof(Eof),
+    /** Section that is not known or can't be parsed, e.g. written by a newer
+    version of toxcore. It's kept as is so that saving the state doesn't lose
+    any data.
+    */
+    Unknown {
+        /// Type of the section.
+        kind: u16,
+        /// Data of the section following the section type.
+        data: Vec<u8>,
+    },
 }
 
 impl FromBytes for Section {
+    // truncated known sections are kept as unknown
     named!(from_bytes<Section>, alt!(
-        map!(NospamKeys::from_bytes, Section::NospamKeys) |
-        map!(DhtState::from_bytes, Section::DhtState) |
-        map!(Friends::from_bytes, Section::Friends) |
-        map!(Name::from_bytes, Section::Name) |
-        map!(StatusMsg::from_bytes, Section::StatusMsg) |
-        map!(UserStatus::from_bytes, Section::UserStatus) |
-        map!(TcpRelays::from_bytes, Section::TcpRelays) |
-        map!(PathNodes::from_bytes, Section::PathNodes) |
-        map!(Eof::from_bytes, Section::Eof)
+        complete!(map!(NospamKeys::from_bytes, Section::NospamKeys)) |
+        complete!(map!(DhtState::from_bytes, Section::DhtState)) |
+        complete!(map!(Friends::from_bytes, Section::Friends)) |
+        complete!(map!(Name::from_bytes, Section::Name)) |
+        complete!(map!(StatusMsg::from_bytes, Section::StatusMsg)) |
+        complete!(map!(UserStatus::from_bytes, Section::UserStatus)) |
+        complete!(map!(TcpRelays::from_bytes, Section::TcpRelays)) |
+        complete!(map!(PathNodes::from_bytes, Section::PathNodes)) |
+        complete!(map!(Conferences::from_bytes, Section::Conferences)) |
+        complete!(map!(Eof::from_bytes, Section::Eof)) |
+        do_parse!(
+            kind: le_u16 >>
+            tag!(SECTION_MAGIC) >>
+            data: rest >>
+            (Section::Unknown {
+                kind,
+                data: data.to_vec(),
+            })
+        )
     ));
 }
 
@@ -567,7 +810,13 @@ impl ToBytes for Section {
             Section::UserStatus(ref p) => p.to_bytes(buf),
             Section::TcpRelays(ref p) => p.to_bytes(buf),
             Section::PathNodes(ref p) => p.to_bytes(buf),
+            Section::Conferences(ref p) => p.to_bytes(buf),
             Section::Eof(ref p) => p.to_bytes(buf),
+            Section::Unknown { kind, ref data } => do_gen!(buf,
+                gen_le_u16!(kind) >>
+                gen_slice!(SECTION_MAGIC) >>
+                gen_slice!(data.as_slice())
+            ),
         }?;
 
         let len = (idx - start_idx - 8) as u32;
@@ -589,6 +838,23 @@ pub struct State {
     sections: Vec<Section>,
 }
 
+impl State {
+    /// Create `State` from sections.
+    pub fn new(sections: Vec<Section>) -> State {
+        State { sections }
+    }
+
+    /// Sections of the state.
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Take sections of the state.
+    pub fn into_sections(self) -> Vec<Section> {
+        self.sections
+    }
+}
+
 impl FromBytes for State {
     named!(from_bytes<State>, do_parse!(
         tag!(&[0; 4][..]) >>
@@ -621,6 +887,20 @@ mod tests {
         NospamKeys::random()
     );
 
+    #[test]
+    fn no_spam_keys_from_seed() {
+        crypto_init().unwrap();
+        let seed = IdentitySeed::random();
+        let keys = NospamKeys::from_seed(&seed, None);
+        assert_eq!((keys.pk, keys.sk.clone()), seed.keypair());
+        assert_eq!(keys.nospam, seed.nospam());
+
+        let nospam = NoSpam([1, 2, 3, 4]);
+        let keys = NospamKeys::from_seed(&seed, Some(nospam));
+        assert_eq!(keys.pk, seed.keypair().0);
+        assert_eq!(keys.nospam, nospam);
+    }
+
     encode_decode_test!(
         dht_state_encode_decode,
         DhtState(vec![
@@ -725,6 +1005,129 @@ mod tests {
         ])
     );
 
+    encode_decode_test!(
+        conferences_encode_decode,
+        Conferences(vec![
+            Conference {
+                conference_type: ConferenceType::Text,
+                id: [42; CONFERENCE_ID_LEN],
+                message_number: 123,
+                lossy_message_number: 12,
+                peer_number: 1,
+                title: b"test title".to_vec(),
+                peers: vec![
+                    ConferencePeer {
+                        real_pk: gen_keypair().0,
+                        temp_pk: gen_keypair().0,
+                        peer_number: 2,
+                        last_active: 1234,
+                        nick: b"test nick".to_vec(),
+                    },
+                    ConferencePeer {
+                        real_pk: gen_keypair().0,
+                        temp_pk: gen_keypair().0,
+                        peer_number: 3,
+                        last_active: 1235,
+                        nick: Vec::new(),
+                    },
+                ],
+            },
+            Conference {
+                conference_type: ConferenceType::Av,
+                id: [43; CONFERENCE_ID_LEN],
+                message_number: 0,
+                lossy_message_number: 0,
+                peer_number: 0,
+                title: Vec::new(),
+                peers: Vec::new(),
+            },
+        ])
+    );
+
+    #[test]
+    fn conference_from_bytes() {
+        let real_pk = PublicKey([1; PUBLICKEYBYTES]);
+        let temp_pk = PublicKey([2; PUBLICKEYBYTES]);
+        let mut bytes = vec![0x14, 0x00, 0xce, 0x01, 1];
+        bytes.extend_from_slice(&[3; CONFERENCE_ID_LEN]);
+        bytes.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x01, 0x00, 0x00, 0x00, 3]);
+        bytes.extend_from_slice(b"abc");
+        bytes.extend_from_slice(&real_pk.0);
+        bytes.extend_from_slice(&temp_pk.0);
+        bytes.extend_from_slice(&[0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, 0x11, 0x12, 2]);
+        bytes.extend_from_slice(b"de");
+
+        let (rest, conferences) = Conferences::from_bytes(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(conferences, Conferences(vec![
+            Conference {
+                conference_type: ConferenceType::Av,
+                id: [3; CONFERENCE_ID_LEN],
+                message_number: 0x0403_0201,
+                lossy_message_number: 0x0605,
+                peer_number: 0x0807,
+                title: b"abc".to_vec(),
+                peers: vec![
+                    ConferencePeer {
+                        real_pk,
+                        temp_pk,
+                        peer_number: 0x0a09,
+                        last_active: 0x1211_100f_0e0d_0c0b,
+                        nick: b"de".to_vec(),
+                    },
+                ],
+            },
+        ]));
+    }
+
+    #[test]
+    fn conference_to_bytes_too_long_title() {
+        let conference = Conference {
+            conference_type: ConferenceType::Text,
+            id: [42; CONFERENCE_ID_LEN],
+            message_number: 0,
+            lossy_message_number: 0,
+            peer_number: 0,
+            title: vec![0; CONFERENCE_NAME_LEN + 1],
+            peers: Vec::new(),
+        };
+        let mut buf = [0; 1024];
+        assert!(conference.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn state_keeps_unknown_sections() {
+        crypto_init().unwrap();
+        let mut buf = [0; 1024];
+        let (_, size) = NospamKeys::random().to_bytes((&mut buf, 0)).unwrap();
+        let nospam_keys = buf[.. size].to_vec();
+
+        let mut bytes = vec![0, 0, 0, 0, 0x1f, 0x1b, 0xed, 0x15];
+        let mut push_section = |data: &[u8]| {
+            let mut len = [0; 4];
+            LittleEndian::write_u32(&mut len, data.len() as u32 - 4);
+            bytes.extend_from_slice(&len);
+            bytes.extend_from_slice(data);
+        };
+        push_section(&nospam_keys);
+        // unknown section type
+        push_section(&[0x42, 0x00, 0xce, 0x01, 1, 2, 3]);
+        // conferences section with trailing garbage
+        push_section(&[0x14, 0x00, 0xce, 0x01, 1]);
+        push_section(&[0xff, 0x00, 0xce, 0x01]);
+
+        let (rest, state) = State::from_bytes(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(state.sections.len(), 4);
+        assert_eq!(state.sections[1], Section::Unknown { kind: 0x42, data: vec![1, 2, 3] });
+        assert_eq!(state.sections[2], Section::Unknown { kind: 0x14, data: vec![1] });
+        assert_eq!(state.sections[3], Section::Eof(Eof));
+
+        let mut buf = [0; 1024];
+        let (_, size) = state.to_bytes((&mut buf, 0)).unwrap();
+        assert_eq!(&buf[.. size], bytes.as_slice());
+    }
+
     encode_decode_test!(
         state_encode_decode,
         State {
@@ -801,6 +1204,29 @@ mod tests {
                         },
                     },
                 ])),
+                Section::Conferences(Conferences(vec![
+                    Conference {
+                        conference_type: ConferenceType::Text,
+                        id: [42; CONFERENCE_ID_LEN],
+                        message_number: 123,
+                        lossy_message_number: 12,
+                        peer_number: 1,
+                        title: b"test title".to_vec(),
+                        peers: vec![
+                            ConferencePeer {
+                                real_pk: gen_keypair().0,
+                                temp_pk: gen_keypair().0,
+                                peer_number: 2,
+                                last_active: 1234,
+                                nick: b"test nick".to_vec(),
+                            },
+                        ],
+                    },
+                ])),
+                Section::Unknown {
+                    kind: 0x42,
+                    data: vec![1, 2, 3],
+                },
                 Section::Eof(Eof),
             ],
         }
@@ -0,0 +1,474 @@
+/*! Loading and saving `.tox` profiles.
+
+[`Profile`](./struct.Profile.html) detects whether the profile is encrypted
+with **TES** and asks for a passphrase only when it's needed. Profiles are
+written atomically: new data is written to a temporary file which is synced to
+disk and then renamed over the old profile. Previous versions of the profile
+are kept as rolling backups `<profile>.1`, `<profile>.2`, etc.
+
+E.g.
+
+```
+# use std::fs;
+use self::tox::toxcore::binary_io::*;
+use self::tox::toxcore::crypto_core::crypto_init;
+use self::tox::toxcore::state_format::old::*;
+use self::tox::toxcore::state_format::profile::Profile;
+use self::tox::toxencryptsave::PassKey;
+
+crypto_init().unwrap();
+# let dir = std::env::temp_dir().join(format!("tox-profile-doc-{}", std::process::id()));
+# fs::create_dir_all(&dir).unwrap();
+# let mut buf = vec![0; 1024];
+# let (_, size) = Section::NospamKeys(NospamKeys::random()).to_bytes((&mut buf, 0)).unwrap();
+# let mut bytes = vec![0, 0, 0, 0, 0x1f, 0x1b, 0xed, 0x15];
+# bytes.extend_from_slice(&buf[.. size]);
+# let state = State::from_bytes(&bytes).unwrap().1;
+let profile = Profile::new(dir.join("profile.tox"));
+let passkey = PassKey::from_passphrase(b"123456").unwrap();
+profile.save(&state, Some(&passkey)).unwrap();
+
+let loaded = profile.load(|| Some(b"123456".to_vec())).unwrap();
+assert_eq!(loaded.state, state);
+assert!(loaded.passkey.is_some());
+# fs::remove_dir_all(&dir).unwrap();
+```
+*/
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use failure::Fail;
+use sodiumoxide::utils::memzero;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::state_format::old::State;
+use crate::toxencryptsave::*;
+
+/// Number of backups that are kept by default.
+pub const DEFAULT_BACKUPS: usize = 1;
+
+/// Initial size of the buffer used to serialize `State`.
+const INITIAL_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Max size of serialized `State`.
+const MAX_STATE_SIZE: usize = 64 * 1024 * 1024;
+
+error_kind! {
+    #[doc = "Error that can happen when loading or saving profile."]
+    #[derive(Debug)]
+    ProfileError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Debug, PartialEq, Fail)]
+    ProfileErrorKind {
+        #[doc = "Reading or writing profile file failed."]
+        #[fail(display = "Profile IO error")]
+        Io,
+        #[doc = "Profile is encrypted but passphrase callback didn't provide a passphrase."]
+        #[fail(display = "Profile is encrypted but no passphrase was provided")]
+        PassphraseRequired,
+        #[doc = "Decrypting profile failed. Usually it means that passphrase is wrong."]
+        #[fail(display = "Failed to decrypt profile: {}", error)]
+        Decrypt {
+            #[doc = "Decryption error."]
+            error: DecryptionError,
+        },
+        #[doc = "Encrypting profile failed."]
+        #[fail(display = "Failed to encrypt profile: {}", error)]
+        Encrypt {
+            #[doc = "Encryption error."]
+            error: EncryptionError,
+        },
+        #[doc = "Profile data is not a valid state."]
+        #[fail(display = "Failed to deserialize profile")]
+        Deserialize,
+        #[doc = "Serializing state failed."]
+        #[fail(display = "Failed to serialize profile: {:?}", error)]
+        Serialize {
+            #[doc = "Serialization error."]
+            error: GenError,
+        },
+    }
+}
+
+impl ProfileError {
+    fn io(error: io::Error) -> ProfileError {
+        ProfileError::from(error.context(ProfileErrorKind::Io))
+    }
+}
+
+/// Profile that was successfully loaded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoadedProfile {
+    /// Loaded state.
+    pub state: State,
+    /// Key that was used to decrypt the profile. `None` if profile is not
+    /// encrypted. Can be used to save the profile without asking for a
+    /// passphrase again.
+    pub passkey: Option<PassKey>,
+    /// Index of the backup that was loaded because the profile itself
+    /// couldn't be loaded. `None` if the profile was loaded.
+    pub backup: Option<usize>,
+}
+
+/// `.tox` profile stored in a file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Profile {
+    /// Path to the profile file.
+    path: PathBuf,
+    /// Number of rolling backups to keep.
+    backups: usize,
+}
+
+impl Profile {
+    /// Create new `Profile` stored at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Profile {
+        Profile {
+            path: path.into(),
+            backups: DEFAULT_BACKUPS,
+        }
+    }
+
+    /// Path to the profile file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Set number of rolling backups to keep. `0` disables backups.
+    pub fn set_backups(&mut self, backups: usize) {
+        self.backups = backups;
+    }
+
+    /// Path to the backup with `index` starting from 1. The higher index the
+    /// older backup.
+    pub fn backup_path(&self, index: usize) -> PathBuf {
+        self.sibling_path(&format!(".{}", index))
+    }
+
+    /// Path of the file with `suffix` appended to the profile file name.
+    fn sibling_path(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+
+    /** Load the profile.
+
+    `passphrase` is called only if the profile is encrypted and at most once.
+    If the profile can't be read or parsed backups are tried from the newest to
+    the oldest. When nothing can be loaded the error of the profile itself is
+    returned.
+    */
+    pub fn load<F>(&self, passphrase: F) -> Result<LoadedProfile, ProfileError>
+        where F: FnOnce() -> Option<Vec<u8>>
+    {
+        let mut callback = Some(passphrase);
+        let mut passphrase = None;
+        let mut first_error = None;
+
+        for index in 0 ..= self.backups {
+            let path = if index == 0 { self.path.clone() } else { self.backup_path(index) };
+            let result = match fs::read(&path) {
+                Ok(ref data) if is_encrypted(data) => {
+                    if passphrase.is_none() {
+                        passphrase = callback.take().and_then(|callback| callback());
+                    }
+                    match passphrase {
+                        Some(ref passphrase) => decode(data, Some(passphrase)),
+                        None => Err(ProfileErrorKind::PassphraseRequired.into()),
+                    }
+                },
+                Ok(ref data) => decode(data, None),
+                Err(ref e) if index > 0 && e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => Err(ProfileError::io(e)),
+            };
+
+            match result {
+                Ok((state, passkey)) => {
+                    if let Some(ref mut passphrase) = passphrase {
+                        memzero(passphrase);
+                    }
+                    return Ok(LoadedProfile {
+                        state,
+                        passkey,
+                        backup: if index == 0 { None } else { Some(index) },
+                    });
+                },
+                Err(e) => {
+                    if *e.kind() == ProfileErrorKind::PassphraseRequired {
+                        return Err(e);
+                    }
+                    if first_error.is_none() {
+                        first_error = Some(e);
+                    }
+                },
+            }
+        }
+
+        if let Some(ref mut passphrase) = passphrase {
+            memzero(passphrase);
+        }
+        Err(first_error.expect("The profile itself is always tried"))
+    }
+
+    /** Save the profile encrypting it with `passkey` if it's provided.
+
+    New data is written to a temporary file and synced to disk before it
+    replaces the profile, so the profile is never left partially written. The
+    previous version of the profile becomes the newest backup.
+    */
+    pub fn save(&self, state: &State, passkey: Option<&PassKey>) -> Result<(), ProfileError> {
+        let mut data = serialize(state)?;
+        if let Some(passkey) = passkey {
+            let encrypted = passkey.encrypt(&data);
+            memzero(&mut data);
+            data = encrypted.map_err(|error| ProfileError::from(ProfileErrorKind::Encrypt { error }))?;
+        }
+
+        let tmp_path = self.sibling_path(".tmp");
+        let result = write_synced(&tmp_path, &data);
+        memzero(&mut data);
+        result.map_err(ProfileError::io)?;
+
+        self.rotate_backups().map_err(ProfileError::io)?;
+        fs::rename(&tmp_path, &self.path).map_err(ProfileError::io)?;
+        sync_parent(&self.path).map_err(ProfileError::io)
+    }
+
+    /// Shift backups by one and copy the current profile to the newest
+    /// backup. The oldest backup is overwritten.
+    fn rotate_backups(&self) -> io::Result<()> {
+        if self.backups == 0 {
+            return Ok(());
+        }
+        for index in (1 .. self.backups).rev() {
+            ignore_not_found(fs::rename(self.backup_path(index), self.backup_path(index + 1)))?;
+        }
+        ignore_not_found(fs::copy(&self.path, self.backup_path(1)).map(drop))
+    }
+}
+
+/// Decrypt if `passphrase` is provided and parse the state.
+fn decode(data: &[u8], passphrase: Option<&[u8]>) -> Result<(State, Option<PassKey>), ProfileError> {
+    let passkey = match passphrase {
+        Some(passphrase) => {
+            let salt = get_salt(data)
+                .ok_or_else(|| ProfileError::from(ProfileErrorKind::Decrypt { error: DecryptionError::InvalidLength }))?;
+            let passkey = PassKey::with_salt(passphrase, salt)
+                .map_err(|e| ProfileError::from(ProfileErrorKind::Decrypt { error: e.into() }))?;
+            Some(passkey)
+        },
+        None => None,
+    };
+
+    let mut decrypted = match passkey {
+        Some(ref passkey) => passkey.decrypt(data)
+            .map_err(|error| ProfileError::from(ProfileErrorKind::Decrypt { error }))?,
+        None => data.to_vec(),
+    };
+    let state = match State::from_bytes(&decrypted) {
+        IResult::Done(_, state) => Ok(state),
+        _ => Err(ProfileError::from(ProfileErrorKind::Deserialize)),
+    };
+    memzero(&mut decrypted);
+    state.map(|state| (state, passkey))
+}
+
+/// Serialize the state growing the buffer when it's too small.
+fn serialize(state: &State) -> Result<Vec<u8>, ProfileError> {
+    let mut buf = vec![0; INITIAL_BUFFER_SIZE];
+    loop {
+        let result = state.to_bytes((&mut buf, 0)).map(|(_, size)| size);
+        match result {
+            Ok(size) => {
+                buf.truncate(size);
+                return Ok(buf);
+            },
+            Err(GenError::BufferTooSmall(_)) if buf.len() < MAX_STATE_SIZE => {
+                memzero(&mut buf);
+                buf = vec![0; buf.len() * 2];
+            },
+            Err(error) => {
+                memzero(&mut buf);
+                return Err(ProfileErrorKind::Serialize { error }.into());
+            },
+        }
+    }
+}
+
+/// Write data to a new file and sync it to disk. The file is readable only by
+/// the owner since profile contains the secret key.
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Sync the directory containing `path` so that rename is persisted.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be synced on this platform.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::crypto_core::*;
+    use crate::toxcore::state_format::old::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("tox-profile-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn random_state() -> State {
+        let mut buf = [0; 1024];
+        let (_, size) = Section::NospamKeys(NospamKeys::random()).to_bytes((&mut buf, 0)).unwrap();
+        let mut bytes = vec![0, 0, 0, 0, 0x1f, 0x1b, 0xed, 0x15];
+        bytes.extend_from_slice(&buf[.. size]);
+        State::from_bytes(&bytes).unwrap().1
+    }
+
+    fn no_passphrase() -> Option<Vec<u8>> {
+        panic!("Passphrase should not be asked")
+    }
+
+    #[test]
+    fn save_load() {
+        crypto_init().unwrap();
+        let dir = TempDir::new("save_load");
+        let profile = Profile::new(dir.0.join("profile.tox"));
+        let state = random_state();
+
+        profile.save(&state, None).unwrap();
+        let data = fs::read(profile.path()).unwrap();
+        assert!(!is_encrypted(&data));
+        assert!(!profile.sibling_path(".tmp").exists());
+
+        let loaded = profile.load(no_passphrase).unwrap();
+        assert_eq!(loaded, LoadedProfile { state, passkey: None, backup: None });
+    }
+
+    #[test]
+    fn save_load_encrypted() {
+        crypto_init().unwrap();
+        let dir = TempDir::new("save_load_encrypted");
+        let profile = Profile::new(dir.0.join("profile.tox"));
+        let state = random_state();
+        let passkey = PassKey::from_passphrase(b"123456").unwrap();
+
+        profile.save(&state, Some(&passkey)).unwrap();
+        let data = fs::read(profile.path()).unwrap();
+        assert!(is_encrypted(&data));
+
+        let loaded = profile.load(|| Some(b"123456".to_vec())).unwrap();
+        assert_eq!(loaded.state, state);
+        assert_eq!(loaded.passkey, Some(passkey));
+
+        let error = profile.load(|| None).err().unwrap();
+        assert_eq!(*error.kind(), ProfileErrorKind::PassphraseRequired);
+
+        let error = profile.load(|| Some(b"654321".to_vec())).err().unwrap();
+        assert_eq!(*error.kind(), ProfileErrorKind::Decrypt { error: DecryptionError::Failed });
+    }
+
+    #[test]
+    fn load_missing() {
+        let dir = TempDir::new("load_missing");
+        let profile = Profile::new(dir.0.join("profile.tox"));
+
+        let error = profile.load(no_passphrase).err().unwrap();
+        assert_eq!(*error.kind(), ProfileErrorKind::Io);
+    }
+
+    #[test]
+    fn rolling_backups() {
+        crypto_init().unwrap();
+        let dir = TempDir::new("rolling_backups");
+        let mut profile = Profile::new(dir.0.join("profile.tox"));
+        profile.set_backups(2);
+        let states = (0 .. 4).map(|_| random_state()).collect::<Vec<_>>();
+
+        for state in &states {
+            profile.save(state, None).unwrap();
+        }
+
+        let load = |path: PathBuf| Profile::new(path).load(no_passphrase).unwrap().state;
+        assert_eq!(load(profile.path().to_owned()), states[3]);
+        assert_eq!(load(profile.backup_path(1)), states[2]);
+        assert_eq!(load(profile.backup_path(2)), states[1]);
+        assert!(!profile.backup_path(3).exists());
+    }
+
+    #[test]
+    fn load_from_backup() {
+        crypto_init().unwrap();
+        let dir = TempDir::new("load_from_backup");
+        let profile = Profile::new(dir.0.join("profile.tox"));
+        let state = random_state();
+        let passkey = PassKey::from_passphrase(b"123456").unwrap();
+
+        profile.save(&state, Some(&passkey)).unwrap();
+        profile.save(&random_state(), Some(&passkey)).unwrap();
+        // corrupt the profile
+        let mut data = fs::read(profile.path()).unwrap();
+        let len = data.len();
+        data[len - 1] ^= 1;
+        fs::write(profile.path(), &data).unwrap();
+
+        let loaded = profile.load(|| Some(b"123456".to_vec())).unwrap();
+        assert_eq!(loaded.state, state);
+        assert_eq!(loaded.backup, Some(1));
+
+        // the error of the profile itself is returned when backups fail too
+        let error = profile.load(|| Some(b"654321".to_vec())).err().unwrap();
+        assert_eq!(*error.kind(), ProfileErrorKind::Decrypt { error: DecryptionError::Failed });
+    }
+
+    #[test]
+    fn load_corrupted_without_backups() {
+        let dir = TempDir::new("load_corrupted");
+        let profile = Profile::new(dir.0.join("profile.tox"));
+        fs::write(profile.path(), b"not a profile").unwrap();
+
+        let error = profile.load(no_passphrase).err().unwrap();
+        assert_eq!(*error.kind(), ProfileErrorKind::Deserialize);
+    }
+}
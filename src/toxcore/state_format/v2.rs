@@ -0,0 +1,718 @@
+/*! Versioned **Tox State Format**.
+
+Unlike the [old](../old/index.html) format every value is stored in a record
+with a typed tag and a length, so new kinds of data can be added without
+breaking older readers: records with unknown tags are kept as is and written
+back when the state is saved.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`8`      | [`STATE_MAGIC`](./constant.STATE_MAGIC.html)
+`2`      | Version (LE), [`STATE_VERSION`](./constant.STATE_VERSION.html)
+variable | Records
+`6`      | End record
+
+Record:
+
+Length   | Content
+-------- | ------
+`2`      | Tag (LE)
+`4`      | Length of the value (LE)
+variable | Value
+
+Friends are stored as records which values are records as well, so per-friend
+data is extensible the same way.
+
+`State` can be converted from and to [`old::State`](../old/struct.State.html)
+without losing data that the old format can hold.
+*/
+
+use byteorder::{ByteOrder, LittleEndian};
+use nom::{le_u16, le_u32, le_u64, rest};
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packed_node::*;
+use crate::toxcore::packed_node::*;
+use crate::toxcore::state_format::old;
+use crate::toxcore::state_format::old::{
+    Conference,
+    FriendStatus,
+    NospamKeys,
+    UserWorkingStatus,
+};
+use crate::toxcore::toxid::{NoSpam, NOSPAMBYTES};
+
+/// Magic bytes at the beginning of the state.
+pub const STATE_MAGIC: &[u8; 8] = b"toxstate";
+
+/// Version of the state format.
+pub const STATE_VERSION: u16 = 2;
+
+/// Length in bytes of friend's avatar hash.
+pub const AVATAR_HASH_LEN: usize = 32;
+
+/// Own `NoSpam` and keys.
+const TAG_NOSPAM_KEYS: u16 = 0x01;
+/// DHT nodes.
+const TAG_DHT_NODES: u16 = 0x02;
+/// A single friend.
+const TAG_FRIEND: u16 = 0x03;
+/// Own name.
+const TAG_NAME: u16 = 0x04;
+/// Own status message.
+const TAG_STATUS_MSG: u16 = 0x05;
+/// Own user status.
+const TAG_USER_STATUS: u16 = 0x06;
+/// TCP relays.
+const TAG_TCP_RELAYS: u16 = 0x0a;
+/// Onion path nodes.
+const TAG_PATH_NODES: u16 = 0x0b;
+/// A single conference.
+const TAG_CONFERENCE: u16 = 0x14;
+/// Section of the old format that isn't known.
+const TAG_OLD_SECTION: u16 = 0xfe;
+/// End of the state.
+const TAG_END: u16 = 0xff;
+
+/// Friend's long term `PublicKey`.
+const TAG_FRIEND_PK: u16 = 0x01;
+/// Status of the friendship.
+const TAG_FRIEND_STATUS: u16 = 0x02;
+/// Friend request message.
+const TAG_FRIEND_REQUEST_MSG: u16 = 0x03;
+/// Friend's name.
+const TAG_FRIEND_NAME: u16 = 0x04;
+/// Friend's status message.
+const TAG_FRIEND_STATUS_MSG: u16 = 0x05;
+/// Friend's user status.
+const TAG_FRIEND_USER_STATUS: u16 = 0x06;
+/// Friend's `NoSpam`.
+const TAG_FRIEND_NOSPAM: u16 = 0x07;
+/// Time when friend was last seen online.
+const TAG_FRIEND_LAST_SEEN: u16 = 0x08;
+/// Local alias of the friend.
+const TAG_FRIEND_ALIAS: u16 = 0x09;
+/// Friend's DHT `PublicKey`.
+const TAG_FRIEND_DHT_PK: u16 = 0x0a;
+/// TCP relays friend was last seen connected to.
+const TAG_FRIEND_RELAYS: u16 = 0x0b;
+/// Hash of friend's avatar.
+const TAG_FRIEND_AVATAR_HASH: u16 = 0x0c;
+
+/// Record with a tag that is not known, kept to be written back as is.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// Tag of the record.
+    pub tag: u16,
+    /// Value of the record.
+    pub data: Vec<u8>,
+}
+
+impl ToBytes for Record {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        gen_record(buf, self.tag, |buf| do_gen!(buf, gen_slice!(self.data.as_slice())))
+    }
+}
+
+// parse a record returning its tag and value
+named!(record<(u16, &'a [u8])>, do_parse!(
+    tag: le_u16 >>
+    data: length_data!(le_u32) >>
+    ((tag, data))
+));
+
+/// Parse the whole value with `parser`. Returns `None` if the value is
+/// invalid or has trailing bytes.
+fn parse_value<T, F>(data: &[u8], parser: F) -> Option<T>
+    where F: Fn(&[u8]) -> IResult<&[u8], T>
+{
+    match parser(data) {
+        IResult::Done(&[], value) => Some(value),
+        _ => None,
+    }
+}
+
+/// Write a record with `tag` which value is generated by `gen_value`.
+fn gen_record<'a, F>(buf: (&'a mut [u8], usize), tag: u16, gen_value: F) -> Result<(&'a mut [u8], usize), GenError>
+    where F: FnOnce((&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError>
+{
+    let start_idx = buf.1;
+    let buf = do_gen!(buf,
+        gen_le_u16!(tag) >>
+        gen_skip!(4)
+    )?;
+    let (buf, idx) = gen_value(buf)?;
+
+    let len = idx - start_idx - 6;
+    if len > u32::max_value() as usize {
+        return Err(GenError::CustomError(0));
+    }
+    LittleEndian::write_u32(&mut buf[start_idx + 2 ..], len as u32);
+
+    Ok((buf, idx))
+}
+
+named!(parse_nospam_keys<NospamKeys>, do_parse!(
+    nospam: call!(NoSpam::from_bytes) >>
+    pk: call!(PublicKey::from_bytes) >>
+    sk: call!(SecretKey::from_bytes) >>
+    (NospamKeys {
+        nospam,
+        pk,
+        sk,
+    })
+));
+
+named!(parse_dht_nodes<Vec<PackedNode>>, many0!(complete!(PackedNode::from_bytes)));
+
+named!(parse_tcp_udp_nodes<Vec<TcpUdpPackedNode>>, many0!(complete!(TcpUdpPackedNode::from_bytes)));
+
+named!(parse_old_section<Record>, do_parse!(
+    kind: le_u16 >>
+    data: rest >>
+    (Record {
+        tag: kind,
+        data: data.to_vec(),
+    })
+));
+
+named!(end_record, tag!([0xff, 0x00, 0x00, 0x00, 0x00, 0x00]));
+
+named!(parse_avatar_hash<[u8; AVATAR_HASH_LEN]>, map!(take!(AVATAR_HASH_LEN), |bytes| {
+    let mut hash = [0; AVATAR_HASH_LEN];
+    hash.copy_from_slice(bytes);
+    hash
+}));
+
+/** Friend with metadata that the old format can't hold.
+
+Data that is supposed to be strings might be invalid UTF-8.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Friend {
+    /// Friend's long term `PublicKey`.
+    pub pk: PublicKey,
+    /// Status of the friendship.
+    pub status: FriendStatus,
+    /// Friend request message that is being sent to friend.
+    pub fr_msg: Vec<u8>,
+    /// Friend's name.
+    pub name: Vec<u8>,
+    /// Friend's status message.
+    pub status_msg: Vec<u8>,
+    /// Friend's user status.
+    pub user_status: UserWorkingStatus,
+    /// Friend's `NoSpam`, only used for sending friend request.
+    pub nospam: NoSpam,
+    /// Unix time when friend was last seen online.
+    pub last_seen: u64,
+    /// Local alias of the friend that is shown instead of the name.
+    pub alias: Option<Vec<u8>>,
+    /// Friend's last known DHT `PublicKey`.
+    pub dht_pk: Option<PublicKey>,
+    /// TCP relays friend was last seen connected to.
+    pub relays: Vec<TcpUdpPackedNode>,
+    /// Hash of friend's avatar.
+    pub avatar_hash: Option<[u8; AVATAR_HASH_LEN]>,
+    /// Records with unknown tags.
+    pub unknown: Vec<Record>,
+}
+
+impl Friend {
+    /// Create `Friend` from its records. Returns `None` if a record is
+    /// invalid or friend's `PublicKey` or status is missing.
+    fn from_records(records: Vec<(u16, &[u8])>) -> Option<Friend> {
+        let mut pk = None;
+        let mut status = None;
+        let mut friend = Friend {
+            pk: PublicKey([0; PUBLICKEYBYTES]),
+            status: FriendStatus::NotFriend,
+            fr_msg: Vec::new(),
+            name: Vec::new(),
+            status_msg: Vec::new(),
+            user_status: UserWorkingStatus::default(),
+            nospam: NoSpam([0; NOSPAMBYTES]),
+            last_seen: 0,
+            alias: None,
+            dht_pk: None,
+            relays: Vec::new(),
+            avatar_hash: None,
+            unknown: Vec::new(),
+        };
+
+        for (tag, data) in records {
+            match tag {
+                TAG_FRIEND_PK => pk = Some(parse_value(data, PublicKey::from_bytes)?),
+                TAG_FRIEND_STATUS => status = Some(parse_value(data, FriendStatus::from_bytes)?),
+                TAG_FRIEND_REQUEST_MSG => friend.fr_msg = data.to_vec(),
+                TAG_FRIEND_NAME => friend.name = data.to_vec(),
+                TAG_FRIEND_STATUS_MSG => friend.status_msg = data.to_vec(),
+                TAG_FRIEND_USER_STATUS => friend.user_status = parse_value(data, UserWorkingStatus::from_bytes)?,
+                TAG_FRIEND_NOSPAM => friend.nospam = parse_value(data, NoSpam::from_bytes)?,
+                TAG_FRIEND_LAST_SEEN => friend.last_seen = parse_value(data, le_u64)?,
+                TAG_FRIEND_ALIAS => friend.alias = Some(data.to_vec()),
+                TAG_FRIEND_DHT_PK => friend.dht_pk = Some(parse_value(data, PublicKey::from_bytes)?),
+                TAG_FRIEND_RELAYS => friend.relays = parse_value(data, parse_tcp_udp_nodes)?,
+                TAG_FRIEND_AVATAR_HASH => friend.avatar_hash = Some(parse_value(data, parse_avatar_hash)?),
+                tag => friend.unknown.push(Record { tag, data: data.to_vec() }),
+            }
+        }
+
+        friend.pk = pk?;
+        friend.status = status?;
+        Some(friend)
+    }
+}
+
+impl FromBytes for Friend {
+    named!(from_bytes<Friend>, do_parse!(
+        records: many0!(complete!(record)) >>
+        eof!() >>
+        friend: expr_opt!(Friend::from_records(records)) >>
+        (friend)
+    ));
+}
+
+impl ToBytes for Friend {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        let buf = gen_record(buf, TAG_FRIEND_PK, |buf| do_gen!(buf, gen_slice!(self.pk.as_ref())))?;
+        let buf = gen_record(buf, TAG_FRIEND_STATUS, |buf| do_gen!(buf, gen_le_u8!(self.status as u8)))?;
+        let buf = gen_record(buf, TAG_FRIEND_REQUEST_MSG, |buf| do_gen!(buf, gen_slice!(self.fr_msg.as_slice())))?;
+        let buf = gen_record(buf, TAG_FRIEND_NAME, |buf| do_gen!(buf, gen_slice!(self.name.as_slice())))?;
+        let buf = gen_record(buf, TAG_FRIEND_STATUS_MSG, |buf| do_gen!(buf, gen_slice!(self.status_msg.as_slice())))?;
+        let buf = gen_record(buf, TAG_FRIEND_USER_STATUS, |buf| do_gen!(buf, gen_le_u8!(self.user_status as u8)))?;
+        let buf = gen_record(buf, TAG_FRIEND_NOSPAM, |buf| do_gen!(buf, gen_slice!(self.nospam.0)))?;
+        let buf = gen_record(buf, TAG_FRIEND_LAST_SEEN, |buf| do_gen!(buf, gen_le_u64!(self.last_seen)))?;
+        let buf = match self.alias {
+            Some(ref alias) => gen_record(buf, TAG_FRIEND_ALIAS, |buf| do_gen!(buf, gen_slice!(alias.as_slice())))?,
+            None => buf,
+        };
+        let buf = match self.dht_pk {
+            Some(ref dht_pk) => gen_record(buf, TAG_FRIEND_DHT_PK, |buf| do_gen!(buf, gen_slice!(dht_pk.as_ref())))?,
+            None => buf,
+        };
+        let buf = gen_record(buf, TAG_FRIEND_RELAYS, |buf| do_gen!(buf,
+            gen_many_ref!(&self.relays, |buf, node| TcpUdpPackedNode::to_bytes(node, buf))
+        ))?;
+        let buf = match self.avatar_hash {
+            Some(ref hash) => gen_record(buf, TAG_FRIEND_AVATAR_HASH, |buf| do_gen!(buf, gen_slice!(&hash[..])))?,
+            None => buf,
+        };
+        do_gen!(buf,
+            gen_many_ref!(&self.unknown, |buf, record| Record::to_bytes(record, buf))
+        )
+    }
+}
+
+impl From<old::FriendState> for Friend {
+    fn from(friend: old::FriendState) -> Friend {
+        Friend {
+            pk: friend.pk,
+            status: friend.friend_status,
+            fr_msg: friend.fr_msg,
+            name: friend.name.0,
+            status_msg: friend.status_msg.0,
+            user_status: friend.user_status,
+            nospam: friend.nospam,
+            last_seen: friend.last_seen,
+            alias: None,
+            dht_pk: None,
+            relays: Vec::new(),
+            avatar_hash: None,
+            unknown: Vec::new(),
+        }
+    }
+}
+
+/// Metadata that the old format can't hold is dropped.
+impl From<Friend> for old::FriendState {
+    fn from(friend: Friend) -> old::FriendState {
+        old::FriendState {
+            friend_status: friend.status,
+            pk: friend.pk,
+            fr_msg: friend.fr_msg,
+            name: old::Name(friend.name),
+            status_msg: old::StatusMsg(friend.status_msg),
+            user_status: friend.user_status,
+            nospam: friend.nospam,
+            last_seen: friend.last_seen,
+        }
+    }
+}
+
+/** Tox state. Use to manage `.tox` save files in the versioned format.
+
+E.g.
+
+```
+use self::tox::toxcore::binary_io::*;
+use self::tox::toxcore::crypto_core::crypto_init;
+use self::tox::toxcore::state_format::{old, v2};
+
+crypto_init().unwrap();
+let mut state = v2::State::default();
+state.nospam_keys = Some(old::NospamKeys::random());
+state.name = b"Alice".to_vec();
+
+let mut buf = [0; 1024];
+let (_, size) = state.to_bytes((&mut buf, 0)).unwrap();
+let (_, decoded) = v2::State::from_bytes(&buf[.. size]).unwrap();
+assert_eq!(decoded, state);
+
+// convert to the old format
+let old_state = old::State::from(state.clone());
+assert_eq!(v2::State::from(old_state), state);
+```
+*/
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct State {
+    /// Own `NoSpam` and keys.
+    pub nospam_keys: Option<NospamKeys>,
+    /// DHT nodes that are used to bootstrap.
+    pub dht_nodes: Vec<PackedNode>,
+    /// List of friends.
+    pub friends: Vec<Friend>,
+    /// Own name.
+    pub name: Vec<u8>,
+    /// Own status message.
+    pub status_msg: Vec<u8>,
+    /// Own user status.
+    pub user_status: UserWorkingStatus,
+    /// TCP relays.
+    pub tcp_relays: Vec<TcpUdpPackedNode>,
+    /// Nodes for onion paths.
+    pub path_nodes: Vec<TcpUdpPackedNode>,
+    /// Conferences.
+    pub conferences: Vec<Conference>,
+    /// Sections of the old format that are not known, tag is the section
+    /// type.
+    pub old_sections: Vec<Record>,
+    /// Records with unknown tags.
+    pub unknown: Vec<Record>,
+}
+
+impl State {
+    /// Create `State` from its records. Returns `None` if a record is invalid.
+    fn from_records(records: Vec<(u16, &[u8])>) -> Option<State> {
+        let mut state = State::default();
+
+        for (tag, data) in records {
+            match tag {
+                TAG_NOSPAM_KEYS => state.nospam_keys = Some(parse_value(data, parse_nospam_keys)?),
+                TAG_DHT_NODES => state.dht_nodes.extend(parse_value(data, parse_dht_nodes)?),
+                TAG_FRIEND => state.friends.push(parse_value(data, Friend::from_bytes)?),
+                TAG_NAME => state.name = data.to_vec(),
+                TAG_STATUS_MSG => state.status_msg = data.to_vec(),
+                TAG_USER_STATUS => state.user_status = parse_value(data, UserWorkingStatus::from_bytes)?,
+                TAG_TCP_RELAYS => state.tcp_relays.extend(parse_value(data, parse_tcp_udp_nodes)?),
+                TAG_PATH_NODES => state.path_nodes.extend(parse_value(data, parse_tcp_udp_nodes)?),
+                TAG_CONFERENCE => state.conferences.push(parse_value(data, Conference::from_bytes)?),
+                TAG_OLD_SECTION => state.old_sections.push(parse_value(data, parse_old_section)?),
+                tag => state.unknown.push(Record { tag, data: data.to_vec() }),
+            }
+        }
+
+        Some(state)
+    }
+}
+
+impl FromBytes for State {
+    named!(from_bytes<State>, do_parse!(
+        tag!(STATE_MAGIC) >>
+        verify!(le_u16, |version| version == STATE_VERSION) >>
+        records: map!(many_till!(call!(record), call!(end_record)), |(records, _)| records) >>
+        eof!() >>
+        state: expr_opt!(State::from_records(records)) >>
+        (state)
+    ));
+}
+
+impl ToBytes for State {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        let buf = do_gen!(buf,
+            gen_slice!(STATE_MAGIC) >>
+            gen_le_u16!(STATE_VERSION)
+        )?;
+        let buf = match self.nospam_keys {
+            Some(ref keys) => gen_record(buf, TAG_NOSPAM_KEYS, |buf| do_gen!(buf,
+                gen_slice!(keys.nospam.0) >>
+                gen_slice!(keys.pk.as_ref()) >>
+                gen_slice!(keys.sk.0)
+            ))?,
+            None => buf,
+        };
+        let buf = gen_record(buf, TAG_DHT_NODES, |buf| do_gen!(buf,
+            gen_many_ref!(&self.dht_nodes, |buf, node| PackedNode::to_bytes(node, buf))
+        ))?;
+        let mut buf = buf;
+        for friend in &self.friends {
+            buf = gen_record(buf, TAG_FRIEND, |buf| friend.to_bytes(buf))?;
+        }
+        let buf = gen_record(buf, TAG_NAME, |buf| do_gen!(buf, gen_slice!(self.name.as_slice())))?;
+        let buf = gen_record(buf, TAG_STATUS_MSG, |buf| do_gen!(buf, gen_slice!(self.status_msg.as_slice())))?;
+        let buf = gen_record(buf, TAG_USER_STATUS, |buf| do_gen!(buf, gen_le_u8!(self.user_status as u8)))?;
+        let buf = gen_record(buf, TAG_TCP_RELAYS, |buf| do_gen!(buf,
+            gen_many_ref!(&self.tcp_relays, |buf, node| TcpUdpPackedNode::to_bytes(node, buf))
+        ))?;
+        let buf = gen_record(buf, TAG_PATH_NODES, |buf| do_gen!(buf,
+            gen_many_ref!(&self.path_nodes, |buf, node| TcpUdpPackedNode::to_bytes(node, buf))
+        ))?;
+        let mut buf = buf;
+        for conference in &self.conferences {
+            buf = gen_record(buf, TAG_CONFERENCE, |buf| conference.to_bytes(buf))?;
+        }
+        for section in &self.old_sections {
+            buf = gen_record(buf, TAG_OLD_SECTION, |buf| do_gen!(buf,
+                gen_le_u16!(section.tag) >>
+                gen_slice!(section.data.as_slice())
+            ))?;
+        }
+        let buf = do_gen!(buf,
+            gen_many_ref!(&self.unknown, |buf, record| Record::to_bytes(record, buf))
+        )?;
+        gen_record(buf, TAG_END, Ok)
+    }
+}
+
+impl From<old::State> for State {
+    fn from(old_state: old::State) -> State {
+        let mut state = State::default();
+
+        for section in old_state.into_sections() {
+            match section {
+                old::Section::NospamKeys(keys) => state.nospam_keys = Some(keys),
+                old::Section::DhtState(old::DhtState(nodes)) => state.dht_nodes.extend(nodes),
+                old::Section::Friends(old::Friends(friends)) =>
+                    state.friends.extend(friends.into_iter().map(Friend::from)),
+                old::Section::Name(old::Name(name)) => state.name = name,
+                old::Section::StatusMsg(old::StatusMsg(status_msg)) => state.status_msg = status_msg,
+                old::Section::UserStatus(old::UserStatus(user_status)) => state.user_status = user_status,
+                old::Section::TcpRelays(old::TcpRelays(nodes)) => state.tcp_relays.extend(nodes),
+                old::Section::PathNodes(old::PathNodes(nodes)) => state.path_nodes.extend(nodes),
+                old::Section::Conferences(old::Conferences(conferences)) => state.conferences.extend(conferences),
+                old::Section::Eof(_) => {},
+                old::Section::Unknown { kind, data } => state.old_sections.push(Record { tag: kind, data }),
+            }
+        }
+
+        state
+    }
+}
+
+/** Sections are written in the same order as they are in profiles written by
+c-toxcore.
+Conferences section is written only if there are conferences. Metadata that
+the old format can't hold and records with unknown tags are dropped.
+*/
+impl From<State> for old::State {
+    fn from(state: State) -> old::State {
+        let mut sections = Vec::new();
+
+        if let Some(keys) = state.nospam_keys {
+            sections.push(old::Section::NospamKeys(keys));
+        }
+        sections.push(old::Section::Friends(old::Friends(
+            state.friends.into_iter().map(old::FriendState::from).collect()
+        )));
+        sections.push(old::Section::Name(old::Name(state.name)));
+        sections.push(old::Section::StatusMsg(old::StatusMsg(state.status_msg)));
+        sections.push(old::Section::UserStatus(old::UserStatus(state.user_status)));
+        sections.push(old::Section::DhtState(old::DhtState(state.dht_nodes)));
+        sections.push(old::Section::TcpRelays(old::TcpRelays(state.tcp_relays)));
+        sections.push(old::Section::PathNodes(old::PathNodes(state.path_nodes)));
+        if !state.conferences.is_empty() {
+            sections.push(old::Section::Conferences(old::Conferences(state.conferences)));
+        }
+        sections.extend(state.old_sections.into_iter().map(|section| old::Section::Unknown {
+            kind: section.tag,
+            data: section.data,
+        }));
+        sections.push(old::Section::Eof(old::Eof));
+
+        old::State::new(sections)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::ip_port::*;
+    use crate::toxcore::state_format::old::{ConferencePeer, ConferenceType, CONFERENCE_ID_LEN};
+
+    fn friend() -> Friend {
+        Friend {
+            pk: gen_keypair().0,
+            status: FriendStatus::Added,
+            fr_msg: b"test msg".to_vec(),
+            name: b"test name".to_vec(),
+            status_msg: b"test status msg".to_vec(),
+            user_status: UserWorkingStatus::Away,
+            nospam: NoSpam([7; 4]),
+            last_seen: 1234,
+            alias: Some(b"test alias".to_vec()),
+            dht_pk: Some(gen_keypair().0),
+            relays: vec![
+                TcpUdpPackedNode {
+                    pk: gen_keypair().0,
+                    ip_port: IpPort {
+                        protocol: ProtocolType::TCP,
+                        ip_addr: "1.2.3.4".parse().unwrap(),
+                        port: 1234,
+                    },
+                },
+            ],
+            avatar_hash: Some([42; AVATAR_HASH_LEN]),
+            unknown: vec![Record { tag: 0x1234, data: vec![1, 2, 3] }],
+        }
+    }
+
+    fn state() -> State {
+        State {
+            nospam_keys: Some(NospamKeys::random()),
+            dht_nodes: vec![
+                PackedNode {
+                    pk: gen_keypair().0,
+                    saddr: "1.2.3.4:1234".parse().unwrap(),
+                },
+            ],
+            friends: vec![friend(), Friend { alias: None, dht_pk: None, avatar_hash: None, ..friend() }],
+            name: b"test name".to_vec(),
+            status_msg: b"test status msg".to_vec(),
+            user_status: UserWorkingStatus::Busy,
+            tcp_relays: vec![
+                TcpUdpPackedNode {
+                    pk: gen_keypair().0,
+                    ip_port: IpPort {
+                        protocol: ProtocolType::TCP,
+                        ip_addr: "1.2.3.5".parse().unwrap(),
+                        port: 12345,
+                    },
+                },
+            ],
+            path_nodes: vec![
+                TcpUdpPackedNode {
+                    pk: gen_keypair().0,
+                    ip_port: IpPort {
+                        protocol: ProtocolType::UDP,
+                        ip_addr: "1.2.3.6".parse().unwrap(),
+                        port: 33445,
+                    },
+                },
+            ],
+            conferences: vec![
+                Conference {
+                    conference_type: ConferenceType::Text,
+                    id: [42; CONFERENCE_ID_LEN],
+                    message_number: 123,
+                    lossy_message_number: 12,
+                    peer_number: 1,
+                    title: b"test title".to_vec(),
+                    peers: vec![
+                        ConferencePeer {
+                            real_pk: gen_keypair().0,
+                            temp_pk: gen_keypair().0,
+                            peer_number: 2,
+                            last_active: 1234,
+                            nick: b"test nick".to_vec(),
+                        },
+                    ],
+                },
+            ],
+            old_sections: vec![Record { tag: 0x42, data: vec![4, 5, 6] }],
+            unknown: vec![Record { tag: 0x4321, data: vec![7, 8, 9] }],
+        }
+    }
+
+    encode_decode_test!(
+        friend_encode_decode,
+        friend()
+    );
+
+    encode_decode_test!(
+        state_encode_decode,
+        state()
+    );
+
+    encode_decode_test!(
+        empty_state_encode_decode,
+        State::default()
+    );
+
+    #[test]
+    fn state_from_bytes_truncated() {
+        crypto_init().unwrap();
+        let mut buf = [0; 1024 * 16];
+        let (_, size) = state().to_bytes((&mut buf, 0)).unwrap();
+
+        // without end record
+        assert!(!State::from_bytes(&buf[.. size - 6]).is_done());
+        assert!(!State::from_bytes(&buf[.. size - 1]).is_done());
+    }
+
+    #[test]
+    fn state_from_bytes_invalid() {
+        crypto_init().unwrap();
+        let mut buf = [0; 1024 * 16];
+        let (_, size) = State::default().to_bytes((&mut buf, 0)).unwrap();
+
+        // wrong version
+        let mut bytes = buf[.. size].to_vec();
+        bytes[STATE_MAGIC.len()] = 3;
+        assert!(!State::from_bytes(&bytes).is_done());
+
+        // trailing data
+        let mut bytes = buf[.. size].to_vec();
+        bytes.push(0);
+        assert!(!State::from_bytes(&bytes).is_done());
+
+        // invalid value of a known record
+        let mut bytes = buf[.. STATE_MAGIC.len() + 2].to_vec();
+        bytes.extend_from_slice(&[0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03]);
+        bytes.extend_from_slice(&buf[STATE_MAGIC.len() + 2 .. size]);
+        assert!(!State::from_bytes(&bytes).is_done());
+    }
+
+    #[test]
+    fn friend_without_pk() {
+        let bytes = [0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01];
+        assert!(!Friend::from_bytes(&bytes).is_done());
+    }
+
+    #[test]
+    fn old_state_conversion() {
+        crypto_init().unwrap();
+        let state = State {
+            friends: vec![Friend {
+                alias: None,
+                dht_pk: None,
+                relays: Vec::new(),
+                avatar_hash: None,
+                unknown: Vec::new(),
+                ..friend()
+            }],
+            unknown: Vec::new(),
+            ..state()
+        };
+
+        let old_state = old::State::from(state.clone());
+        assert_eq!(State::from(old_state.clone()), state);
+        assert_eq!(old_state.sections().len(), 11);
+        assert_eq!(old_state.sections()[9], old::Section::Unknown { kind: 0x42, data: vec![4, 5, 6] });
+    }
+
+    #[test]
+    fn old_state_conversion_drops_metadata() {
+        crypto_init().unwrap();
+        let state = state();
+        let converted = State::from(old::State::from(state.clone()));
+
+        assert!(converted.unknown.is_empty());
+        assert_eq!(converted.friends[0].pk, state.friends[0].pk);
+        assert_eq!(converted.friends[0].alias, None);
+        assert_eq!(converted.friends[0].avatar_hash, None);
+    }
+}
@@ -3,17 +3,22 @@ Statistics of incoming/outgoing packets
 This is used by both Udp codec and Tcp codec.
 */
 
+use std::collections::HashMap;
 use std::sync::Arc;
 #[cfg(target_pointer_width = "64")]
 use std::sync::atomic::*;
 #[cfg(not(target_pointer_width = "64"))]
 use std::sync::Mutex;
 
+use parking_lot::Mutex as PlMutex;
+
 /// Struct for various counters
 #[derive(Clone, Default)]
 pub struct Stats {
     /// incoming/outgoing counters
-    pub counters: Arc<Counters>
+    pub counters: Arc<Counters>,
+    /// counters broken down by packet kinds
+    pub packets: Arc<PacketCounters>,
 }
 
 impl Stats {
@@ -93,6 +98,130 @@ impl Counters {
     }
 }
 
+/// Transport that was used to receive or send a packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Transport {
+    /// DHT packets sent over UDP
+    Udp,
+    /// Packets sent over TCP relay connections
+    Tcp,
+}
+
+impl Transport {
+    /// Lowercase name of the transport.
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+/// Direction of a packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Direction {
+    /// Packet was received
+    Incoming,
+    /// Packet was sent
+    Outgoing,
+}
+
+impl Direction {
+    /// Lowercase name of the direction.
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::Incoming => "incoming",
+            Direction::Outgoing => "outgoing",
+        }
+    }
+}
+
+/// Number of packets of some kind and their total size.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PacketCount {
+    /// Number of packets
+    pub packets: u64,
+    /// Total size of packets in bytes
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+struct PacketCountersState {
+    packets: HashMap<(Transport, Direction, &'static str), PacketCount>,
+    decode_errors: HashMap<(Transport, &'static str), u64>,
+    ipv6_dropped: u64,
+}
+
+/// Counters of packets broken down by their kinds, decode errors broken down
+/// by error kinds and packets dropped by the IPv6 filter.
+#[derive(Default)]
+pub struct PacketCounters {
+    state: PlMutex<PacketCountersState>,
+}
+
+impl PacketCounters {
+    /// Count a packet of the given kind with its size in bytes.
+    pub fn add_packet(&self, transport: Transport, direction: Direction, kind: &'static str, bytes: usize) {
+        let mut state = self.state.lock();
+        let count = state.packets.entry((transport, direction, kind)).or_default();
+        count.packets += 1;
+        count.bytes += bytes as u64;
+    }
+
+    /// Count a packet that can't be decoded.
+    pub fn add_decode_error(&self, transport: Transport, kind: &'static str) {
+        *self.state.lock().decode_errors.entry((transport, kind)).or_default() += 1;
+    }
+
+    /// Count an outgoing IPv6 packet dropped because the node is running in
+    /// IPv4 mode.
+    pub fn increase_ipv6_dropped(&self) {
+        self.state.lock().ipv6_dropped += 1;
+    }
+
+    /// Get the counter of packets of the given kind.
+    pub fn packet_count(&self, transport: Transport, direction: Direction, kind: &'static str) -> PacketCount {
+        self.state.lock().packets.get(&(transport, direction, kind)).cloned().unwrap_or_default()
+    }
+
+    /// Get the total number of bytes of all packets sent or received over the
+    /// transport.
+    pub fn bytes(&self, transport: Transport, direction: Direction) -> u64 {
+        self.state.lock().packets.iter()
+            .filter(|&(&(t, d, _), _)| t == transport && d == direction)
+            .map(|(_, count)| count.bytes)
+            .sum()
+    }
+
+    /// Get the number of decode errors of the given kind.
+    pub fn decode_errors(&self, transport: Transport, kind: &'static str) -> u64 {
+        self.state.lock().decode_errors.get(&(transport, kind)).cloned().unwrap_or_default()
+    }
+
+    /// Get the number of outgoing packets dropped by the IPv6 filter.
+    pub fn ipv6_dropped(&self) -> u64 {
+        self.state.lock().ipv6_dropped
+    }
+
+    /// Get all packet counters sorted by transport, direction and kind.
+    pub fn packet_counts(&self) -> Vec<(Transport, Direction, &'static str, PacketCount)> {
+        let mut counts = self.state.lock().packets.iter()
+            .map(|(&(transport, direction, kind), &count)| (transport, direction, kind, count))
+            .collect::<Vec<_>>();
+        counts.sort_by_key(|&(transport, direction, kind, _)| (transport, direction, kind));
+        counts
+    }
+
+    /// Get all decode error counters sorted by transport and kind.
+    pub fn decode_error_counts(&self) -> Vec<(Transport, &'static str, u64)> {
+        let mut counts = self.state.lock().decode_errors.iter()
+            .map(|(&(transport, kind), &count)| (transport, kind, count))
+            .collect::<Vec<_>>();
+        counts.sort();
+        counts
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,4 +248,58 @@ mod tests {
         stats.counters.increase_outgoing();
         assert_eq!(2, stats.counters.outgoing());
     }
+
+    #[test]
+    fn packet_counts() {
+        let stats = Stats::new();
+        stats.packets.add_packet(Transport::Udp, Direction::Incoming, "PingRequest", 113);
+        stats.packets.add_packet(Transport::Udp, Direction::Incoming, "PingRequest", 113);
+        stats.packets.add_packet(Transport::Udp, Direction::Incoming, "NodesRequest", 113);
+        stats.packets.add_packet(Transport::Udp, Direction::Outgoing, "PingResponse", 113);
+        stats.packets.add_packet(Transport::Tcp, Direction::Incoming, "PingRequest", 27);
+
+        assert_eq!(
+            stats.packets.packet_count(Transport::Udp, Direction::Incoming, "PingRequest"),
+            PacketCount { packets: 2, bytes: 226 }
+        );
+        assert_eq!(
+            stats.packets.packet_count(Transport::Udp, Direction::Outgoing, "PingRequest"),
+            PacketCount::default()
+        );
+        assert_eq!(stats.packets.bytes(Transport::Udp, Direction::Incoming), 339);
+        assert_eq!(stats.packets.bytes(Transport::Udp, Direction::Outgoing), 113);
+        assert_eq!(stats.packets.bytes(Transport::Tcp, Direction::Incoming), 27);
+        assert_eq!(stats.packets.bytes(Transport::Tcp, Direction::Outgoing), 0);
+
+        let counts = stats.packets.packet_counts();
+        assert_eq!(counts, vec![
+            (Transport::Udp, Direction::Incoming, "NodesRequest", PacketCount { packets: 1, bytes: 113 }),
+            (Transport::Udp, Direction::Incoming, "PingRequest", PacketCount { packets: 2, bytes: 226 }),
+            (Transport::Udp, Direction::Outgoing, "PingResponse", PacketCount { packets: 1, bytes: 113 }),
+            (Transport::Tcp, Direction::Incoming, "PingRequest", PacketCount { packets: 1, bytes: 27 }),
+        ]);
+    }
+
+    #[test]
+    fn decode_errors() {
+        let stats = Stats::new();
+        stats.packets.add_decode_error(Transport::Udp, "Deserialize");
+        stats.packets.add_decode_error(Transport::Udp, "Deserialize");
+        stats.packets.add_decode_error(Transport::Tcp, "DecryptError");
+
+        assert_eq!(stats.packets.decode_errors(Transport::Udp, "Deserialize"), 2);
+        assert_eq!(stats.packets.decode_errors(Transport::Udp, "TooBigPacket"), 0);
+        assert_eq!(stats.packets.decode_error_counts(), vec![
+            (Transport::Udp, "Deserialize", 2),
+            (Transport::Tcp, "DecryptError", 1),
+        ]);
+    }
+
+    #[test]
+    fn ipv6_dropped() {
+        let stats = Stats::new();
+        assert_eq!(stats.packets.ipv6_dropped(), 0);
+        stats.packets.increase_ipv6_dropped();
+        assert_eq!(stats.packets.ipv6_dropped(), 1);
+    }
 }
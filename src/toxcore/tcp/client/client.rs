@@ -11,9 +11,9 @@ use futures::sync::mpsc;
 use parking_lot::RwLock;
 use tokio::codec::Framed;
 use tokio;
-use tokio::net::TcpStream;
 
 use crate::toxcore::crypto_core::*;
+use crate::toxcore::events::*;
 use crate::toxcore::io_tokio::*;
 use crate::toxcore::onion::packet::InnerOnionResponse;
 use crate::toxcore::stats::Stats;
@@ -22,6 +22,7 @@ use crate::toxcore::tcp::connection_id::ConnectionId;
 use crate::toxcore::tcp::handshake::make_client_handshake;
 use crate::toxcore::tcp::links::*;
 use crate::toxcore::tcp::packet::*;
+use crate::toxcore::tcp::transport::{self, RelayTransport};
 use crate::toxcore::time::*;
 
 /// Buffer size (in packets) for outgoing packets. This number shouldn't be high
@@ -70,9 +71,13 @@ pub struct Client {
     pub pk: PublicKey,
     /// IP address of the TCP relay.
     pub addr: SocketAddr,
+    /// Transport that is used to connect to the TCP relay.
+    transport: RelayTransport,
     /// Sink for packets that should be handled somewhere else. `PublicKey` here
     /// belongs to TCP relay.
     incoming_tx: mpsc::UnboundedSender<(PublicKey, IncomingPacket)>,
+    /// Sink to send events about connection to the relay.
+    event_tx: Option<EventTx>,
     /// Status of the relay.
     status: Arc<RwLock<ClientStatus>>,
     /// Time when a connection to the relay was established.
@@ -92,7 +97,9 @@ impl Client {
         Client {
             pk,
             addr,
+            transport: RelayTransport::Tcp,
             incoming_tx,
+            event_tx: None,
             status: Arc::new(RwLock::new(ClientStatus::Disconnected)),
             connected_time: Arc::new(RwLock::new(None)),
             connection_attempts: Arc::new(RwLock::new(0)),
@@ -101,6 +108,17 @@ impl Client {
         }
     }
 
+    /// Set sink to send events about connection to the relay.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Set transport that is used to connect to the relay. Raw TCP is used
+    /// by default.
+    pub fn set_transport(&mut self, transport: RelayTransport) {
+        self.transport = transport;
+    }
+
     /// Handle packet received from TCP relay.
     pub fn handle_packet(&self, packet: Packet) -> impl Future<Item = (), Error = Error> + Send {
         // TODO: use anonymous sum types when rust has them
@@ -294,7 +312,7 @@ impl Client {
                 _ => return future::ok(()),
             }
 
-            let future = TcpStream::connect(&self.addr)
+            let future = transport::connect(&self.addr, &self.transport)
                 .and_then(move |socket| make_client_handshake(socket, &dht_pk, &dht_sk, &relay_pk)) // TODO: timeout
                 .and_then(move |(socket, channel)| {
                     let stats = Stats::new();
@@ -311,6 +329,8 @@ impl Client {
 
                     *self.connected_time.write() = Some(clock_now());
 
+                    emit(&self.event_tx, Event::TcpRelayConnected { relay_pk: self.pk });
+
                     let route_requests = self.send_route_requests();
 
                     let writer = to_server_rx
@@ -338,7 +358,9 @@ impl Client {
                         let mut connection_attempts = self_c.connection_attempts.write();
                         *connection_attempts = connection_attempts.saturating_add(1);
                     }
-                    *self_c.connected_time.write() = None;
+                    if self_c.connected_time.write().take().is_some() {
+                        emit(&self_c.event_tx, Event::TcpRelayDisconnected { relay_pk: self_c.pk });
+                    }
                     self_c.links.write().clear();
                     future::result(res)
                 })
@@ -1157,7 +1179,9 @@ pub mod tests {
         // run first client
         let (client_pk_1, client_sk_1) = gen_keypair();
         let (incoming_tx_1, incoming_rx_1) = mpsc::unbounded();
-        let client_1 = Client::new(server_pk, addr, incoming_tx_1);
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let mut client_1 = Client::new(server_pk, addr, incoming_tx_1);
+        client_1.set_event_sink(event_tx);
         // connection attempts should be set to 0 after successful connection
         set_connection_attempts(&client_1, 3);
         let client_future_1 = client_1.clone().spawn(client_sk_1, client_pk_1);
@@ -1165,7 +1189,9 @@ pub mod tests {
         // run second client
         let (client_pk_2, client_sk_2) = gen_keypair();
         let (incoming_tx_2, incoming_rx_2) = mpsc::unbounded();
-        let client_2 = Client::new(server_pk, addr, incoming_tx_2);
+        let mut client_2 = Client::new(server_pk, addr, incoming_tx_2);
+        // the server should accept both transports on the same port
+        client_2.set_transport(RelayTransport::WebSocket { path: "/".to_owned() });
         // connection attempts should be set to 0 after successful connection
         set_connection_attempts(&client_2, 3);
         let client_future_2 = client_2.clone().spawn(client_sk_2, client_pk_2);
@@ -1231,6 +1257,11 @@ pub mod tests {
             .map_err(|_| ());
 
         tokio::run(future);
+
+        assert_eq!(event_rx.collect().wait().unwrap(), vec![
+            Event::TcpRelayConnected { relay_pk: server_pk },
+            Event::TcpRelayDisconnected { relay_pk: server_pk },
+        ]);
     }
 
     #[test]
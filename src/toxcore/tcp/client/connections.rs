@@ -26,6 +26,7 @@ use tokio::timer::Interval;
 
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::packed_node::PackedNode;
+use crate::toxcore::events::*;
 use crate::toxcore::tcp::client::client::*;
 use crate::toxcore::tcp::packet::*;
 use crate::toxcore::time::*;
@@ -94,6 +95,8 @@ pub struct Connections {
     /// Sink for packets that should be handled somewhere else. `PublicKey` here
     /// belongs to TCP relay we received packet from.
     incoming_tx: mpsc::UnboundedSender<(PublicKey, IncomingPacket)>,
+    /// Sink to send events about connections to relays.
+    event_tx: Option<EventTx>,
     /// List of TCP relays we are connected to. Key is a `PublicKey` of TCP
     /// relay.
     clients: Arc<RwLock<HashMap<PublicKey, Client>>>,
@@ -109,18 +112,34 @@ impl Connections {
             dht_pk,
             dht_sk,
             incoming_tx,
+            event_tx: None,
             clients: Arc::new(RwLock::new(HashMap::new())),
             connections: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// Set sink to send events about connections to relays. Only relays added
+    /// after this call will send events.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Create new relay client.
+    fn new_client(&self, relay_addr: SocketAddr, relay_pk: PublicKey) -> Client {
+        let mut client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+        if let Some(ref event_tx) = self.event_tx {
+            client.set_event_sink(event_tx.clone());
+        }
+        client
+    }
+
     /// Add relay we are supposed to be connected to. These relays are necessary
     /// for initial connection so that we are able to find friends and to send
     /// them our relays. Later when more relays are received from our friends
     /// they should be added via `add_relay_connection` method.
     pub fn add_relay_global(&self, relay_addr: SocketAddr, relay_pk: PublicKey) -> impl Future<Item = (), Error = Error> + Send {
         if let hash_map::Entry::Vacant(vacant) = self.clients.write().entry(relay_pk) {
-            let client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+            let client = self.new_client(relay_addr, relay_pk);
             vacant.insert(client.clone());
             Either::A(client.spawn(self.dht_sk.clone(), self.dht_pk))
         } else {
@@ -147,7 +166,7 @@ impl Connections {
             ).count();
 
             if online_connections_count < RECOMMENDED_FRIEND_TCP_CONNECTIONS && connections_count < MAX_FRIEND_TCP_CONNECTIONS {
-                let client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+                let client = self.new_client(relay_addr, relay_pk);
                 clients.insert(relay_pk, client.clone());
                 connection.connections.insert(relay_pk);
                 let future = client.add_connection(node_pk)
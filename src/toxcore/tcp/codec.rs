@@ -2,8 +2,10 @@
 */
 
 use std::io::{Error as IoError};
+use std::net::SocketAddr;
 
 use crate::toxcore::binary_io::*;
+use crate::toxcore::dissect::{tap, TapTx};
 use crate::toxcore::tcp::packet::*;
 use crate::toxcore::tcp::secure::*;
 use crate::toxcore::stats::*;
@@ -52,6 +54,19 @@ pub enum DecodeError {
     },
 }
 
+impl DecodeError {
+    /// Name of the error kind. Used to collect statistics.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DecodeError::DeserializeEncryptedError { .. } => "DeserializeEncryptedError",
+            DecodeError::DecryptError => "DecryptError",
+            DecodeError::IncompleteDecryptedPacket { .. } => "IncompleteDecryptedPacket",
+            DecodeError::DeserializeDecryptedError { .. } => "DeserializeDecryptedError",
+            DecodeError::IoError { .. } => "IoError",
+        }
+    }
+}
+
 impl From<IoError> for DecodeError {
     fn from(error: IoError) -> DecodeError {
         DecodeError::IoError {
@@ -89,7 +104,11 @@ impl From<IoError> for EncodeError {
 /// implements tokio-io's Decoder and Encoder to deal with Packet
 pub struct Codec {
     channel: Channel,
-    stats: Stats
+    stats: Stats,
+    /// Statistics of this connection only
+    connection_stats: Option<Stats>,
+    /// Sink to send decrypted packets to with our and peer's addresses
+    tap: Option<(TapTx, SocketAddr, SocketAddr)>,
 }
 
 impl Codec {
@@ -97,16 +116,36 @@ impl Codec {
     pub fn new(channel: Channel, stats: Stats) -> Codec {
         Codec {
             channel,
-            stats
+            stats,
+            connection_stats: None,
+            tap: None,
         }
     }
-}
 
-impl Decoder for Codec {
-    type Item = Packet;
-    type Error = DecodeError;
+    /// Set statistics that are updated only by this codec in addition to
+    /// the shared statistics.
+    pub fn set_connection_stats(&mut self, connection_stats: Stats) {
+        self.connection_stats = Some(connection_stats);
+    }
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    /// Set sink to send received packets after decryption and sent packets
+    /// before encryption to. `local` and `remote` are addresses of our side
+    /// and of the peer that are attached to tapped packets.
+    pub fn set_tap_sink(&mut self, tap: TapTx, local: SocketAddr, remote: SocketAddr) {
+        self.tap = Some((tap, local, remote));
+    }
+
+    /// Send packet to the tap if it's set.
+    fn tap(&self, direction: Direction, data: &[u8]) {
+        if let Some((ref tap_tx, local, remote)) = self.tap {
+            tap(Some(tap_tx), Transport::Tcp, direction, local, remote, data);
+        }
+    }
+}
+
+impl Codec {
+    /// Decode `Packet` returning it with the size of encrypted packet.
+    fn decode_packet(&mut self, buf: &mut BytesMut) -> Result<Option<(Packet, usize)>, DecodeError> {
         // deserialize EncryptedPacket
         let (consumed, encrypted_packet) = match EncryptedPacket::from_bytes(buf) {
             IResult::Incomplete(_) => {
@@ -123,6 +162,7 @@ impl Decoder for Codec {
         // decrypt payload
         let decrypted_data = self.channel.decrypt(&encrypted_packet.payload)
             .map_err(|()| DecodeError::DecryptError)?;
+        self.tap(Direction::Incoming, &decrypted_data);
 
         // deserialize Packet
         match Packet::from_bytes(&decrypted_data) {
@@ -133,16 +173,38 @@ impl Decoder for Codec {
                 Err(DecodeError::DeserializeDecryptedError { error, packet: decrypted_data })
             },
             IResult::Done(_, packet) => {
-                // Add 1 to incoming counter
-                self.stats.counters.increase_incoming();
-
                 buf.split_to(consumed);
-                Ok(Some(packet))
+                Ok(Some((packet, consumed)))
             }
         }
     }
 }
 
+impl Decoder for Codec {
+    type Item = Packet;
+    type Error = DecodeError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.decode_packet(buf);
+
+        match result {
+            Ok(Some((ref packet, size))) => {
+                // Add 1 to incoming counter
+                self.stats.counters.increase_incoming();
+                self.stats.packets.add_packet(Transport::Tcp, Direction::Incoming, packet.name(), size);
+                if let Some(ref connection_stats) = self.connection_stats {
+                    connection_stats.counters.increase_incoming();
+                    connection_stats.packets.add_packet(Transport::Tcp, Direction::Incoming, packet.name(), size);
+                }
+            },
+            Ok(None) => {},
+            Err(ref error) => self.stats.packets.add_decode_error(Transport::Tcp, error.name()),
+        }
+
+        result.map(|packet| packet.map(|(packet, _)| packet))
+    }
+}
+
 impl Encoder for Codec {
     type Item = Packet;
     type Error = EncodeError;
@@ -152,10 +214,13 @@ impl Encoder for Codec {
         self.stats.counters.increase_outgoing();
 
         // serialize Packet
+        let name = packet.name();
         let mut packet_buf = [0; MAX_TCP_PACKET_SIZE];
         let (_, packet_size) = packet.to_bytes((&mut packet_buf, 0))
             .map_err(|error| EncodeError::SerializeError { error })?;
 
+        self.tap(Direction::Outgoing, &packet_buf[..packet_size]);
+
         // encrypt it
         let encrypted = self.channel.encrypt(&packet_buf[..packet_size]);
 
@@ -169,6 +234,11 @@ impl Encoder for Codec {
                                                                 // serialized Packet is not longer than 2032 bytes
                                                                 // and we provided 2050 bytes for EncryptedPacket
         buf.extend_from_slice(&encrypted_packet_buf[..encrypted_packet_size]);
+        self.stats.packets.add_packet(Transport::Tcp, Direction::Outgoing, name, encrypted_packet_size);
+        if let Some(ref connection_stats) = self.connection_stats {
+            connection_stats.counters.increase_outgoing();
+            connection_stats.packets.add_packet(Transport::Tcp, Direction::Outgoing, name, encrypted_packet_size);
+        }
         Ok(())
     }
 }
@@ -356,6 +426,7 @@ mod tests {
         alice_codec.encode(packet, &mut buf).expect("Alice should encode");
         // Mallory cannot decode the payload of EncryptedPacket
         assert!(mallory_codec.decode(&mut buf).err().is_some());
+        assert_eq!(stats.packets.decode_errors(Transport::Tcp, "DecryptError"), 1);
     }
     #[test]
     fn decode_packet_imcomplete() {
@@ -401,6 +472,89 @@ mod tests {
         assert!(alice_codec.decode(&mut buf).is_err());
     }
 
+    #[test]
+    fn encode_decode_stats() {
+        crypto_init().unwrap();
+        let (alice_channel, bob_channel) = create_channels();
+        let alice_stats = Stats::new();
+        let bob_stats = Stats::new();
+        let mut alice_codec = Codec::new(alice_channel, alice_stats.clone());
+        let mut bob_codec = Codec::new(bob_channel, bob_stats.clone());
+
+        let mut buf = BytesMut::new();
+        let packet = Packet::PingRequest( PingRequest { ping_id: 4242 } );
+        alice_codec.encode(packet, &mut buf).expect("Alice should encode");
+        let size = buf.len() as u64;
+        bob_codec.decode(&mut buf).unwrap().expect("Bob should decode");
+
+        let count = PacketCount { packets: 1, bytes: size };
+        assert_eq!(alice_stats.packets.packet_count(Transport::Tcp, Direction::Outgoing, "PingRequest"), count);
+        assert_eq!(bob_stats.packets.packet_count(Transport::Tcp, Direction::Incoming, "PingRequest"), count);
+        assert_eq!(bob_stats.packets.bytes(Transport::Tcp, Direction::Incoming), size);
+    }
+
+    #[test]
+    fn encode_decode_connection_stats() {
+        crypto_init().unwrap();
+        let (alice_channel, bob_channel) = create_channels();
+        let alice_stats = Stats::new();
+        let alice_connection_stats = Stats::new();
+        let mut alice_codec = Codec::new(alice_channel, alice_stats.clone());
+        alice_codec.set_connection_stats(alice_connection_stats.clone());
+        let mut bob_codec = Codec::new(bob_channel, Stats::new());
+
+        let mut buf = BytesMut::new();
+        let packet = Packet::PingRequest( PingRequest { ping_id: 4242 } );
+        alice_codec.encode(packet, &mut buf).expect("Alice should encode");
+        let size = buf.len() as u64;
+        let packet = Packet::PongResponse( PongResponse { ping_id: 4242 } );
+        bob_codec.encode(packet, &mut buf).expect("Bob should encode");
+        alice_codec.decode(&mut buf.split_off(size as usize)).unwrap().expect("Alice should decode");
+
+        assert_eq!(alice_connection_stats.counters.outgoing(), 1);
+        assert_eq!(alice_connection_stats.counters.incoming(), 1);
+        assert_eq!(alice_connection_stats.packets.bytes(Transport::Tcp, Direction::Outgoing), size);
+        assert_eq!(
+            alice_connection_stats.packets.bytes(Transport::Tcp, Direction::Incoming),
+            alice_stats.packets.bytes(Transport::Tcp, Direction::Incoming)
+        );
+    }
+
+    #[test]
+    fn encode_decode_tap() {
+        use futures::{Future, Stream};
+        use futures::sync::mpsc;
+
+        crypto_init().unwrap();
+        let (alice_channel, bob_channel) = create_channels();
+        let mut alice_codec = Codec::new(alice_channel, Stats::new());
+        let mut bob_codec = Codec::new(bob_channel, Stats::new());
+        let (alice_tap_tx, alice_tap_rx) = mpsc::unbounded();
+        let (bob_tap_tx, bob_tap_rx) = mpsc::unbounded();
+        let alice_addr = "1.2.3.4:12345".parse().unwrap();
+        let bob_addr = "5.6.7.8:33445".parse().unwrap();
+        alice_codec.set_tap_sink(alice_tap_tx, alice_addr, bob_addr);
+        bob_codec.set_tap_sink(bob_tap_tx, bob_addr, alice_addr);
+
+        let mut buf = BytesMut::new();
+        let packet = Packet::PingRequest( PingRequest { ping_id: 4242 } );
+        alice_codec.encode(packet.clone(), &mut buf).expect("Alice should encode");
+        bob_codec.decode(&mut buf).unwrap().expect("Bob should decode");
+
+        let mut packet_buf = [0; 32];
+        let (_, size) = packet.to_bytes((&mut packet_buf, 0)).unwrap();
+        let (sent, _) = alice_tap_rx.into_future().wait().unwrap();
+        let sent = sent.unwrap();
+        assert_eq!((sent.transport, sent.direction), (Transport::Tcp, Direction::Outgoing));
+        assert_eq!((sent.local, sent.remote), (alice_addr, bob_addr));
+        assert_eq!(sent.data, packet_buf[..size].to_vec());
+        let (received, _) = bob_tap_rx.into_future().wait().unwrap();
+        let received = received.unwrap();
+        assert_eq!((received.transport, received.direction), (Transport::Tcp, Direction::Incoming));
+        assert_eq!((received.local, received.remote), (bob_addr, alice_addr));
+        assert_eq!(received.data, packet_buf[..size].to_vec());
+    }
+
     #[test]
     fn encode_packet_too_big() {
         crypto_init().unwrap();
@@ -16,7 +16,7 @@ use crate::toxcore::tcp::secure;
 use futures::{self, Stream, Sink, Future};
 use std::io::{Error, ErrorKind};
 use tokio::codec::Framed;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 /// Create a handshake from client to server
 pub fn create_client_handshake(client_pk: &PublicKey,
@@ -95,11 +95,13 @@ pub fn handle_server_handshake(common_key: &PrecomputedKey,
 
 /// Sends handshake to the server, receives handshake from the server
 /// and processes it
-pub fn make_client_handshake(socket: TcpStream,
+pub fn make_client_handshake<S>(socket: S,
                             client_pk: &PublicKey,
                             client_sk: &SecretKey,
                             server_pk: &PublicKey)
-    -> impl Future<Item = (TcpStream, secure::Channel), Error = Error> + Send {
+    -> impl Future<Item = (S, secure::Channel), Error = Error> + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
     futures::done(create_client_handshake(client_pk, client_sk, server_pk))
         .and_then(|(session, common_key, handshake)| {
             // send handshake
@@ -144,9 +146,11 @@ pub fn make_client_handshake(socket: TcpStream,
 
 /// Receives handshake from the client, processes it and
 /// sends handshake to the client
-pub fn make_server_handshake(socket: TcpStream,
+pub fn make_server_handshake<S>(socket: S,
                             server_sk: SecretKey)
-    -> impl Future<Item = (TcpStream, secure::Channel, PublicKey), Error = Error> + Send {
+    -> impl Future<Item = (S, secure::Channel, PublicKey), Error = Error> + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
     Framed::new(socket, ClientHandshakeCodec)
         .into_future() // receive handshake from client
         .map_err(|(e, _socket)| {
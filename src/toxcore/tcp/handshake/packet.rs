@@ -118,6 +118,7 @@ Length  | Contents
 
 */
 
+#[derive(Debug)]
 pub struct HandshakePayload {
     /// Temporary Session PK
     pub session_pk: PublicKey,
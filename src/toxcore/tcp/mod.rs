@@ -9,4 +9,5 @@ pub mod codec;
 pub mod server;
 pub mod client;
 pub mod connection_id;
+pub mod transport;
 mod links;
@@ -63,6 +63,25 @@ pub enum Packet {
 /// A serialized Packet should be not longer than 2032 bytes
 pub const MAX_TCP_PACKET_SIZE: usize = 2032;
 
+impl Packet {
+    /// Name of the packet kind. Used to collect statistics.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Packet::RouteRequest(_) => "RouteRequest",
+            Packet::RouteResponse(_) => "RouteResponse",
+            Packet::ConnectNotification(_) => "ConnectNotification",
+            Packet::DisconnectNotification(_) => "DisconnectNotification",
+            Packet::PingRequest(_) => "PingRequest",
+            Packet::PongResponse(_) => "PongResponse",
+            Packet::OobSend(_) => "OobSend",
+            Packet::OobReceive(_) => "OobReceive",
+            Packet::OnionRequest(_) => "OnionRequest",
+            Packet::OnionResponse(_) => "OnionResponse",
+            Packet::Data(_) => "Data",
+        }
+    }
+}
+
 impl FromBytes for Packet {
     named!(from_bytes<Packet>, alt!(
         map!(RouteRequest::from_bytes, Packet::RouteRequest) |
@@ -0,0 +1,887 @@
+/*! Admin control interface of the TCP relay server.
+
+`Server` has methods to list connected clients with their traffic, to dump
+the graph of links, to kick or ban clients and to gracefully shutdown the
+relay. `AdminServer` exposes these methods as a line based JSON protocol on a
+local Unix socket so that operators can manage a running relay. The socket
+listener is available only on Unix platforms, other platforms can pass
+requests to `AdminServer::handle_request` directly.
+
+Every request is a single line with a JSON object that has `command` field:
+
+- `{"command": "clients"}` – list connected clients
+- `{"command": "links"}` – dump links of all connected clients
+- `{"command": "kick", "pk": "<hex>"}` – disconnect the client
+- `{"command": "ban", "pk": "<hex>"}` – disconnect the client and don't allow
+  it to connect again
+- `{"command": "unban", "pk": "<hex>"}` – allow the banned client to connect
+- `{"command": "banned"}` – list banned clients
+- `{"command": "shutdown"}` – gracefully shutdown the relay
+
+Every response is a single line with a JSON object that has `ok` field. If
+`ok` is `false` the object also has `error` field with the description of
+the error.
+*/
+
+use std::fmt;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Instant;
+
+use futures::{future, Future};
+#[cfg(unix)]
+use futures::{stream, try_ready, Async, Poll, Sink, Stream};
+#[cfg(unix)]
+use futures::future::{Either, Loop};
+#[cfg(unix)]
+use mio::{Evented, PollOpt, Ready, Token};
+#[cfg(unix)]
+use mio::unix::EventedFd;
+#[cfg(unix)]
+use tokio;
+#[cfg(unix)]
+use tokio::codec::{Framed, LinesCodec};
+#[cfg(unix)]
+use tokio::reactor::PollEvented2;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::tcp::connection_id::ConnectionId;
+use crate::toxcore::tcp::links::LinkStatus;
+use crate::toxcore::tcp::server::Server;
+use crate::toxcore::time::*;
+use crate::toxcore::toxid::parse_public_key;
+
+/// Maximum size of a single admin request in bytes.
+#[cfg(unix)]
+const ADMIN_MAX_REQUEST_SIZE: usize = 4096;
+
+/// Information about a client connected to the relay.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientInfo {
+    /// `PublicKey` of the client.
+    pub pk: PublicKey,
+    /// IP address of the client.
+    pub ip_addr: IpAddr,
+    /// Port of the client.
+    pub port: u16,
+    /// Time when the client was connected.
+    pub connected_since: Instant,
+    /// Number of links of the client.
+    pub links_count: usize,
+    /// Number of packets received from the client.
+    pub packets_received: u64,
+    /// Number of packets sent to the client.
+    pub packets_sent: u64,
+    /// Number of bytes received from the client.
+    pub bytes_received: u64,
+    /// Number of bytes sent to the client.
+    pub bytes_sent: u64,
+}
+
+/// Link from a connected client to another client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkInfo {
+    /// `PublicKey` of the client that owns the link.
+    pub pk: PublicKey,
+    /// `PublicKey` of the client the link points to.
+    pub linked_pk: PublicKey,
+    /// Connection id of the link used by the owner.
+    pub connection_id: ConnectionId,
+    /// Status of the link.
+    pub status: LinkStatus,
+}
+
+/// Minimal JSON value. Only non-negative integer numbers are supported
+/// since the admin protocol doesn't need anything else.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parse JSON value. Returns `None` if the string is not a valid JSON
+    /// or contains unsupported values.
+    fn parse(s: &str) -> Option<Json> {
+        let mut parser = JsonParser { input: s.as_bytes(), pos: 0 };
+        let value = parser.parse_value()?;
+        parser.skip_whitespaces();
+        if parser.pos == parser.input.len() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Get a field of JSON object.
+    fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Get a string value.
+    fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Create JSON object from the list of fields.
+    fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+}
+
+/// Write string as a quoted and escaped JSON string.
+fn write_json_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_json_string(f, s),
+            Json::Array(ref values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+/// Recursive descent JSON parser.
+struct JsonParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn skip_whitespaces(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, s: &[u8]) -> Option<()> {
+        if self.input[self.pos ..].starts_with(s) {
+            self.pos += s.len();
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn parse_value(&mut self) -> Option<Json> {
+        self.skip_whitespaces();
+        match self.peek()? {
+            b'n' => self.expect(b"null").map(|()| Json::Null),
+            b't' => self.expect(b"true").map(|()| Json::Bool(true)),
+            b'f' => self.expect(b"false").map(|()| Json::Bool(false)),
+            b'"' => self.parse_string().map(Json::String),
+            b'[' => self.parse_array(),
+            b'{' => self.parse_object(),
+            b'0' ..= b'9' => self.parse_number(),
+            _ => None,
+        }
+    }
+
+    fn parse_number(&mut self) -> Option<Json> {
+        let start = self.pos;
+        while let Some(b'0' ..= b'9') = self.peek() {
+            self.pos += 1;
+        }
+        let s = std::str::from_utf8(&self.input[start .. self.pos]).ok()?;
+        s.parse().ok().map(Json::Number)
+    }
+
+    fn parse_hex4(&mut self) -> Option<u32> {
+        let s = self.input.get(self.pos .. self.pos + 4)?;
+        let s = std::str::from_utf8(s).ok()?;
+        let code = u32::from_str_radix(s, 16).ok()?;
+        self.pos += 4;
+        Some(code)
+    }
+
+    fn parse_string(&mut self) -> Option<String> {
+        self.expect(b"\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => break,
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let high = self.parse_hex4()?;
+                            let code = if (0xd800 .. 0xdc00).contains(&high) {
+                                self.expect(b"\\u")?;
+                                let low = self.parse_hex4()?;
+                                if !(0xdc00 .. 0xe000).contains(&low) {
+                                    return None;
+                                }
+                                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                            } else {
+                                high
+                            };
+                            std::char::from_u32(code)?
+                        },
+                        _ => return None,
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                },
+                c if c < 0x20 => return None,
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).ok()
+    }
+
+    fn parse_array(&mut self) -> Option<Json> {
+        self.expect(b"[")?;
+        let mut values = Vec::new();
+        self.skip_whitespaces();
+        if self.peek()? == b']' {
+            self.pos += 1;
+            return Some(Json::Array(values));
+        }
+        loop {
+            values.push(self.parse_value()?);
+            self.skip_whitespaces();
+            match self.next()? {
+                b',' => {},
+                b']' => return Some(Json::Array(values)),
+                _ => return None,
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Option<Json> {
+        self.expect(b"{")?;
+        let mut fields = Vec::new();
+        self.skip_whitespaces();
+        if self.peek()? == b'}' {
+            self.pos += 1;
+            return Some(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespaces();
+            let key = self.parse_string()?;
+            self.skip_whitespaces();
+            self.expect(b":")?;
+            let value = self.parse_value()?;
+            fields.push((key, value));
+            self.skip_whitespaces();
+            match self.next()? {
+                b',' => {},
+                b'}' => return Some(Json::Object(fields)),
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Format `PublicKey` as upper case hex string.
+fn pk_to_hex(pk: &PublicKey) -> String {
+    pk.as_ref().iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Name of `LinkStatus` used in JSON responses.
+fn link_status_name(status: LinkStatus) -> &'static str {
+    match status {
+        LinkStatus::Registered => "registered",
+        LinkStatus::Online => "online",
+    }
+}
+
+/// Successful response with additional fields.
+fn ok_response(mut fields: Vec<(&str, Json)>) -> Json {
+    fields.insert(0, ("ok", Json::Bool(true)));
+    Json::object(fields)
+}
+
+/// Error response with the error description.
+fn error_response<E: fmt::Display>(error: E) -> Json {
+    Json::object(vec![
+        ("ok", Json::Bool(false)),
+        ("error", Json::String(error.to_string())),
+    ])
+}
+
+/// Request to the admin interface.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum AdminRequest {
+    Clients,
+    Links,
+    Kick(PublicKey),
+    Ban(PublicKey),
+    Unban(PublicKey),
+    Banned,
+    Shutdown,
+}
+
+impl AdminRequest {
+    /// Parse request from a JSON line.
+    fn parse(request: &str) -> Result<AdminRequest, String> {
+        let json = Json::parse(request).ok_or_else(|| "Invalid JSON".to_owned())?;
+        let command = json.get("command").and_then(Json::as_str)
+            .ok_or_else(|| "Missing command".to_owned())?;
+        let pk = || json.get("pk").and_then(Json::as_str)
+            .ok_or_else(|| "Missing pk".to_owned())
+            .and_then(|pk| parse_public_key(pk).map_err(|e| format!("Invalid pk: {}", e)));
+        match command {
+            "clients" => Ok(AdminRequest::Clients),
+            "links" => Ok(AdminRequest::Links),
+            "kick" => pk().map(AdminRequest::Kick),
+            "ban" => pk().map(AdminRequest::Ban),
+            "unban" => pk().map(AdminRequest::Unban),
+            "banned" => Ok(AdminRequest::Banned),
+            "shutdown" => Ok(AdminRequest::Shutdown),
+            command => Err(format!("Unknown command: {}", command)),
+        }
+    }
+}
+
+/** Admin interface of the TCP relay `Server` that serves line based JSON
+requests. See the module documentation for the list of commands.
+*/
+#[derive(Clone)]
+pub struct AdminServer {
+    server: Server,
+}
+
+impl AdminServer {
+    /// Create new `AdminServer` that manages the given `Server`.
+    pub fn new(server: Server) -> AdminServer {
+        AdminServer { server }
+    }
+
+    /// Render list of connected clients.
+    fn clients_response(&self) -> Json {
+        let clients = self.server.clients().into_iter()
+            .map(|client| Json::object(vec![
+                ("pk", Json::String(pk_to_hex(&client.pk))),
+                ("ip", Json::String(client.ip_addr.to_string())),
+                ("port", Json::Number(u64::from(client.port))),
+                ("connected_secs", Json::Number(clock_elapsed(client.connected_since).as_secs())),
+                ("links", Json::Number(client.links_count as u64)),
+                ("packets_received", Json::Number(client.packets_received)),
+                ("packets_sent", Json::Number(client.packets_sent)),
+                ("bytes_received", Json::Number(client.bytes_received)),
+                ("bytes_sent", Json::Number(client.bytes_sent)),
+            ]))
+            .collect();
+        ok_response(vec![("clients", Json::Array(clients))])
+    }
+
+    /// Render the graph of links.
+    fn links_response(&self) -> Json {
+        let links = self.server.links().into_iter()
+            .map(|link| Json::object(vec![
+                ("pk", Json::String(pk_to_hex(&link.pk))),
+                ("linked_pk", Json::String(pk_to_hex(&link.linked_pk))),
+                ("connection_id", link.connection_id.index().map_or(Json::Null, |index| Json::Number(u64::from(index) + 16))),
+                ("status", Json::String(link_status_name(link.status).to_owned())),
+            ]))
+            .collect();
+        ok_response(vec![("links", Json::Array(links))])
+    }
+
+    /// Handle a single JSON request and return JSON response.
+    pub fn handle_request(&self, request: &str) -> impl Future<Item = String, Error = ()> + Send {
+        let request = match AdminRequest::parse(request) {
+            Ok(request) => request,
+            Err(error) => return Box::new(future::ok(error_response(error).to_string())) as Box<dyn Future<Item = _, Error = _> + Send>,
+        };
+        debug!("Admin request {:?}", request);
+
+        let response = match request {
+            AdminRequest::Clients => Box::new(future::ok(self.clients_response())) as Box<dyn Future<Item = _, Error = _> + Send>,
+            AdminRequest::Links => Box::new(future::ok(self.links_response())),
+            AdminRequest::Kick(pk) => Box::new(self.server.kick(&pk).then(|res| Ok(match res {
+                Ok(()) => ok_response(Vec::new()),
+                Err(e) => error_response(e),
+            }))),
+            AdminRequest::Ban(pk) => Box::new(self.server.ban(&pk).then(|res| Ok(match res {
+                Ok(()) => ok_response(Vec::new()),
+                Err(e) => error_response(e),
+            }))),
+            AdminRequest::Unban(pk) => {
+                let unbanned = self.server.unban(&pk);
+                Box::new(future::ok(ok_response(vec![("unbanned", Json::Bool(unbanned))])))
+            },
+            AdminRequest::Banned => {
+                let banned = self.server.banned().iter()
+                    .map(|pk| Json::String(pk_to_hex(pk)))
+                    .collect();
+                Box::new(future::ok(ok_response(vec![("banned", Json::Array(banned))])))
+            },
+            AdminRequest::Shutdown => Box::new(self.server.shutdown().then(|res| Ok(match res {
+                Ok(()) => ok_response(Vec::new()),
+                Err(e) => error_response(e),
+            }))),
+        };
+
+        Box::new(response.map(|response| response.to_string()))
+    }
+
+    /** Listen for admin requests on a Unix socket at `path`. The socket is
+    accessible only by the owner. If a stale socket exists at `path` it will
+    be removed.
+
+    The socket is created inside a temporary directory next to `path` that is
+    accessible only by the owner and then moved to `path`, so nobody can
+    connect to it before its permissions are restricted. Note that the
+    temporary path is 14 bytes longer than `path` and should still fit the
+    system limit on Unix socket path length.
+
+    Every accepted connection is served by its own task. This function uses
+    `tokio::spawn` inside so it should be executed via tokio to be able to get
+    tokio default executor.
+    */
+    #[cfg(unix)]
+    pub fn run_unix(self, path: &Path) -> Result<impl Future<Item = (), Error = IoError> + Send, IoError> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(IoError::new(IoErrorKind::AlreadyExists, "Path exists and is not a socket"));
+            }
+            fs::remove_file(path)?;
+        }
+        let file_name = path.file_name()
+            .ok_or_else(|| IoError::new(IoErrorKind::InvalidInput, "Path has no file name"))?;
+        let tmp_dir = path.with_file_name(format!(".{}.{:08x}", file_name.to_string_lossy(), random_u32()));
+        fs::DirBuilder::new().mode(0o700).create(&tmp_dir)?;
+        let tmp_path = tmp_dir.join("sock");
+        let listener = UnixListener::bind(&tmp_path).and_then(|listener| {
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&tmp_path, path)?;
+            Ok(listener)
+        });
+        // the socket is left in the temporary directory only on failure
+        let _ = fs::remove_file(&tmp_path);
+        let _ = fs::remove_dir(&tmp_dir);
+        let listener = listener?;
+        listener.set_nonblocking(true)?;
+        let listener = PollEvented2::new(EventedUnix(listener));
+
+        let incoming = stream::poll_fn(move || -> Poll<Option<UnixStream>, IoError> {
+            try_ready!(listener.poll_read_ready(Ready::readable()));
+            match listener.get_ref().0.accept() {
+                Ok((stream, _addr)) => Ok(Async::Ready(Some(stream))),
+                Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+                    listener.clear_read_ready(Ready::readable())?;
+                    Ok(Async::NotReady)
+                },
+                Err(e) => Err(e),
+            }
+        });
+
+        let future = incoming.for_each(move |stream| {
+            stream.set_nonblocking(true)?;
+            let stream = PollEvented2::new(EventedUnix(stream));
+            tokio::spawn(self.clone().serve_connection(stream).map_err(|e|
+                debug!("Failed to serve admin connection: {}", e)
+            ));
+            Ok(())
+        });
+
+        Ok(future)
+    }
+
+    /// Read requests from the connection line by line and write responses
+    /// back.
+    #[cfg(unix)]
+    fn serve_connection(self, stream: PollEvented2<EventedUnix<UnixStream>>) -> impl Future<Item = (), Error = IoError> + Send {
+        let (sink, requests) = Framed::new(stream, LinesCodec::new_with_max_length(ADMIN_MAX_REQUEST_SIZE)).split();
+        future::loop_fn((sink, requests), move |(sink, requests)| {
+            let admin = self.clone();
+            requests.into_future().then(move |res| match res {
+                Ok((Some(request), requests)) => if request.trim().is_empty() {
+                    Either::A(future::ok(Loop::Continue((sink, requests))))
+                } else {
+                    Either::B(Either::A(admin.handle_request(&request)
+                        .map_err(|()| unreachable!("admin requests can't fail"))
+                        .and_then(|response| sink.send(response))
+                        .map(|sink| Loop::Continue((sink, requests)))))
+                },
+                Ok((None, _requests)) => Either::A(future::ok(Loop::Break(()))),
+                // `LinesCodec` fails with `Other` kind only when the line is
+                // too long
+                Err((ref e, _)) if e.kind() == IoErrorKind::Other =>
+                    Either::B(Either::B(sink.send(error_response("Request is too long").to_string())
+                        .map(|_sink| Loop::Break(())))),
+                Err((e, _)) => Either::A(future::err(e)),
+            })
+        })
+    }
+}
+
+/// Unix socket that can be registered in tokio reactor.
+#[cfg(unix)]
+struct EventedUnix<S>(S);
+
+#[cfg(unix)]
+impl<S: AsRawFd> Evented for EventedUnix<S> {
+    fn register(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<(), IoError> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<(), IoError> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> Result<(), IoError> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+#[cfg(unix)]
+impl Read for EventedUnix<UnixStream> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.0.read(buf)
+    }
+}
+
+#[cfg(unix)]
+impl Write for EventedUnix<UnixStream> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::sync::mpsc;
+    #[cfg(unix)]
+    use std::io::{BufRead, BufReader};
+    #[cfg(unix)]
+    use tokio::runtime::Runtime;
+
+    use crate::toxcore::tcp::packet::*;
+    use crate::toxcore::tcp::server::Client;
+
+    fn create_random_client(saddr: &str) -> (Client, mpsc::Receiver<Packet>) {
+        crypto_init().unwrap();
+        let saddr: std::net::SocketAddr = saddr.parse().unwrap();
+        let (client_pk, _) = gen_keypair();
+        let (tx, rx) = mpsc::channel(32);
+        let client = Client::new(tx, &client_pk, saddr.ip(), saddr.port());
+        (client, rx)
+    }
+
+    fn request(admin: &AdminServer, request: &str) -> Json {
+        Json::parse(&admin.handle_request(request).wait().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn json_parse() {
+        let json = Json::parse(r#" {"a": [1, true, false, null], "b": {"c": "d\"\\\n\u0041\ud83d\ude00"}, "e": []} "#).unwrap();
+        assert_eq!(json, Json::object(vec![
+            ("a", Json::Array(vec![Json::Number(1), Json::Bool(true), Json::Bool(false), Json::Null])),
+            ("b", Json::object(vec![("c", Json::String("d\"\\\nA\u{1F600}".to_owned()))])),
+            ("e", Json::Array(Vec::new())),
+        ]));
+        assert_eq!(json.get("b").and_then(|b| b.get("c")).and_then(Json::as_str), Some("d\"\\\nA\u{1F600}"));
+        assert_eq!(Json::parse("{}"), Some(Json::Object(Vec::new())));
+    }
+
+    #[test]
+    fn json_parse_invalid() {
+        assert_eq!(Json::parse(""), None);
+        assert_eq!(Json::parse("{"), None);
+        assert_eq!(Json::parse("{\"a\" 1}"), None);
+        assert_eq!(Json::parse("{\"a\": 1,}"), None);
+        assert_eq!(Json::parse("[1 2]"), None);
+        assert_eq!(Json::parse("\"\\x\""), None);
+        assert_eq!(Json::parse("\"\\ud83d\""), None);
+        assert_eq!(Json::parse("-1"), None);
+        assert_eq!(Json::parse("{} {}"), None);
+    }
+
+    #[test]
+    fn json_display() {
+        let json = Json::object(vec![
+            ("a", Json::Array(vec![Json::Number(1), Json::Bool(true), Json::Null])),
+            ("b", Json::String("\"\\\n\u{1}".to_owned())),
+        ]);
+        assert_eq!(json.to_string(), r#"{"a":[1,true,null],"b":"\"\\\n\u0001"}"#);
+        assert_eq!(Json::parse(&json.to_string()), Some(json));
+    }
+
+    #[test]
+    fn request_parse() {
+        let pk = PublicKey([0xab; PUBLICKEYBYTES]);
+        assert_eq!(AdminRequest::parse(r#"{"command": "clients"}"#), Ok(AdminRequest::Clients));
+        assert_eq!(AdminRequest::parse(&format!(r#"{{"command": "kick", "pk": "{}"}}"#, pk_to_hex(&pk))), Ok(AdminRequest::Kick(pk)));
+        assert!(AdminRequest::parse(r#"{"command": "kick"}"#).is_err());
+        assert!(AdminRequest::parse(r#"{"command": "ban", "pk": "ABCD"}"#).is_err());
+        assert!(AdminRequest::parse(r#"{"command": "unknown"}"#).is_err());
+        assert!(AdminRequest::parse(r#"{"pk": "ABCD"}"#).is_err());
+        assert!(AdminRequest::parse("command").is_err());
+    }
+
+    #[test]
+    fn handle_clients_and_links() {
+        let server = Server::new();
+        let admin = AdminServer::new(server.clone());
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345");
+        let pk_1 = client_1.pk();
+        server.insert(client_1).wait().unwrap();
+        let (client_2, _rx_2) = create_random_client("1.2.3.5:12346");
+        let pk_2 = client_2.pk();
+        server.insert(client_2).wait().unwrap();
+
+        server.handle_packet(&pk_1, Packet::RouteRequest(RouteRequest { pk: pk_2 })).wait().unwrap();
+        server.handle_packet(&pk_2, Packet::RouteRequest(RouteRequest { pk: pk_1 })).wait().unwrap();
+
+        let response = request(&admin, r#"{"command": "clients"}"#);
+        assert_eq!(response.get("ok"), Some(&Json::Bool(true)));
+        let clients = match response.get("clients") {
+            Some(Json::Array(clients)) => clients.clone(),
+            _ => panic!("Unexpected response: {}", response),
+        };
+        assert_eq!(clients.len(), 2);
+        let client_1 = clients.iter()
+            .find(|client| client.get("pk").and_then(Json::as_str) == Some(&pk_to_hex(&pk_1)))
+            .unwrap();
+        assert_eq!(client_1.get("ip").and_then(Json::as_str), Some("1.2.3.4"));
+        assert_eq!(client_1.get("port"), Some(&Json::Number(12345)));
+        assert_eq!(client_1.get("links"), Some(&Json::Number(1)));
+
+        let response = request(&admin, r#"{"command": "links"}"#);
+        let links = match response.get("links") {
+            Some(Json::Array(links)) => links.clone(),
+            _ => panic!("Unexpected response: {}", response),
+        };
+        assert_eq!(links.len(), 2);
+        assert!(links.contains(&Json::object(vec![
+            ("pk", Json::String(pk_to_hex(&pk_1))),
+            ("linked_pk", Json::String(pk_to_hex(&pk_2))),
+            ("connection_id", Json::Number(16)),
+            ("status", Json::String("online".to_owned())),
+        ])));
+    }
+
+    #[test]
+    fn handle_kick() {
+        let server = Server::new();
+        let admin = AdminServer::new(server.clone());
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345");
+        let pk_1 = client_1.pk();
+        server.insert(client_1).wait().unwrap();
+
+        let kick = format!(r#"{{"command": "kick", "pk": "{}"}}"#, pk_to_hex(&pk_1));
+        assert_eq!(request(&admin, &kick).get("ok"), Some(&Json::Bool(true)));
+        assert_eq!(server.connected_clients_count(), 0);
+
+        // the client isn't connected anymore
+        let response = request(&admin, &kick);
+        assert_eq!(response.get("ok"), Some(&Json::Bool(false)));
+        assert!(response.get("error").is_some());
+    }
+
+    #[test]
+    fn handle_ban() {
+        let server = Server::new();
+        let admin = AdminServer::new(server.clone());
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345");
+        let pk_1 = client_1.pk();
+        server.insert(client_1).wait().unwrap();
+
+        let ban = format!(r#"{{"command": "ban", "pk": "{}"}}"#, pk_to_hex(&pk_1));
+        assert_eq!(request(&admin, &ban).get("ok"), Some(&Json::Bool(true)));
+        assert_eq!(server.connected_clients_count(), 0);
+
+        let response = request(&admin, r#"{"command": "banned"}"#);
+        assert_eq!(response.get("banned"), Some(&Json::Array(vec![Json::String(pk_to_hex(&pk_1))])));
+
+        // banned client can't connect
+        let (tx, _rx) = mpsc::channel(32);
+        let client_1 = Client::new(tx, &pk_1, "1.2.3.4".parse().unwrap(), 12346);
+        assert!(server.insert(client_1).wait().is_err());
+
+        let unban = format!(r#"{{"command": "unban", "pk": "{}"}}"#, pk_to_hex(&pk_1));
+        assert_eq!(request(&admin, &unban).get("unbanned"), Some(&Json::Bool(true)));
+        assert_eq!(request(&admin, &unban).get("unbanned"), Some(&Json::Bool(false)));
+
+        let (tx, _rx) = mpsc::channel(32);
+        let client_1 = Client::new(tx, &pk_1, "1.2.3.4".parse().unwrap(), 12346);
+        server.insert(client_1).wait().unwrap();
+    }
+
+    #[test]
+    fn handle_shutdown() {
+        let server = Server::new();
+        let admin = AdminServer::new(server.clone());
+
+        let (client_1, rx_1) = create_random_client("1.2.3.4:12345");
+        let pk_1 = client_1.pk();
+        server.insert(client_1).wait().unwrap();
+        let (client_2, rx_2) = create_random_client("1.2.3.5:12346");
+        let pk_2 = client_2.pk();
+        server.insert(client_2).wait().unwrap();
+
+        server.handle_packet(&pk_1, Packet::RouteRequest(RouteRequest { pk: pk_2 })).wait().unwrap();
+        server.handle_packet(&pk_2, Packet::RouteRequest(RouteRequest { pk: pk_1 })).wait().unwrap();
+
+        assert_eq!(request(&admin, r#"{"command": "shutdown"}"#).get("ok"), Some(&Json::Bool(true)));
+        assert!(server.is_shutting_down());
+        assert_eq!(server.connected_clients_count(), 0);
+
+        // new clients are not accepted
+        let (client_3, _rx_3) = create_random_client("1.2.3.6:12347");
+        assert!(server.insert(client_3).wait().is_err());
+
+        // writers are finished after DisconnectNotification
+        drop(admin);
+        drop(server);
+        let packets_1 = rx_1.collect().wait().unwrap();
+        assert_eq!(packets_1.last(), Some(&Packet::DisconnectNotification(DisconnectNotification {
+            connection_id: ConnectionId::from_index(0)
+        })));
+        let packets_2 = rx_2.collect().wait().unwrap();
+        assert_eq!(packets_2.last(), Some(&Packet::DisconnectNotification(DisconnectNotification {
+            connection_id: ConnectionId::from_index(0)
+        })));
+    }
+
+    #[test]
+    fn handle_invalid_request() {
+        let admin = AdminServer::new(Server::new());
+        let response = request(&admin, "invalid");
+        assert_eq!(response, error_response("Invalid JSON"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_unix() {
+        let path = std::env::temp_dir().join(format!("tox-tcp-admin-{}.sock", std::process::id()));
+        let server = Server::new();
+        let admin = AdminServer::new(server.clone());
+
+        let mut runtime = Runtime::new().unwrap();
+        let future = admin.clone().run_unix(&path).unwrap();
+        runtime.spawn(future.map_err(|e| panic!("Admin server failed: {}", e)));
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // temporary directory is removed
+        let tmp_prefix = format!(".tox-tcp-admin-{}.sock.", std::process::id());
+        assert!(fs::read_dir(std::env::temp_dir()).unwrap()
+            .all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(&tmp_prefix)));
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+
+        writeln!(writer, r#"{{"command": "clients"}}"#).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert_eq!(response, "{\"ok\":true,\"clients\":[]}\n");
+
+        writeln!(writer, "\n{{\"command\": \"banned\"}}").unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert_eq!(response, "{\"ok\":true,\"banned\":[]}\n");
+
+        // several connections are served at the same time
+        let stream_2 = UnixStream::connect(&path).unwrap();
+        let mut reader_2 = BufReader::new(stream_2.try_clone().unwrap());
+        let mut writer_2 = stream_2;
+        writeln!(writer_2, "{}", "x".repeat(ADMIN_MAX_REQUEST_SIZE + 1)).unwrap();
+        let mut response = String::new();
+        reader_2.read_line(&mut response).unwrap();
+        assert_eq!(response, "{\"ok\":false,\"error\":\"Request is too long\"}\n");
+        // the connection is closed after too long request
+        let mut response = String::new();
+        assert_eq!(reader_2.read_line(&mut response).unwrap(), 0);
+
+        writeln!(writer, r#"{{"command": "links"}}"#).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert_eq!(response, "{\"ok\":true,\"links\":[]}\n");
+
+        runtime.shutdown_now().wait().unwrap();
+
+        // stale socket is replaced
+        let future = admin.run_unix(&path).unwrap();
+        drop(future);
+        fs::remove_file(&path).unwrap();
+    }
+}
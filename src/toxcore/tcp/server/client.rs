@@ -5,8 +5,10 @@ use crate::toxcore::crypto_core::*;
 use crate::toxcore::tcp::packet::*;
 use crate::toxcore::tcp::connection_id::ConnectionId;
 use crate::toxcore::tcp::links::Links;
+use crate::toxcore::tcp::server::policy::{ClientQuota, QuotaState};
 use crate::toxcore::io_tokio::*;
 use crate::toxcore::onion::packet::InnerOnionResponse;
+use crate::toxcore::stats::Stats;
 use crate::toxcore::time::*;
 use crate::toxcore::utils::*;
 
@@ -53,7 +55,13 @@ pub struct Client {
     /// Last time sent PingRequest packet
     last_pinged: Instant,
     /// Last time received PongResponse
-    last_pong_resp: Instant
+    last_pong_resp: Instant,
+    /// Traffic budget of the client if it's limited
+    quota: Option<QuotaState>,
+    /// Time when the client was connected
+    connected_since: Instant,
+    /// Statistics of packets sent and received by this client
+    stats: Stats,
 }
 
 impl Client {
@@ -68,7 +76,10 @@ impl Client {
             links: Links::new(),
             ping_id: 0,
             last_pinged: clock_now(),
-            last_pong_resp: clock_now()
+            last_pong_resp: clock_now(),
+            quota: None,
+            connected_since: clock_now(),
+            stats: Stats::new(),
         }
     }
 
@@ -90,6 +101,19 @@ impl Client {
         self.port
     }
 
+    /** Time when the `Client` was connected
+    */
+    pub fn connected_since(&self) -> Instant {
+        self.connected_since
+    }
+
+    /** Statistics of packets sent and received by the `Client`. They are
+    updated by the codec of the client's connection.
+    */
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
     /** Last ping_id sent to client.
     */
     pub fn ping_id(&self) -> u64 {
@@ -102,6 +126,24 @@ impl Client {
         self.last_pong_resp = time;
     }
 
+    /** Limit traffic of the client with `ClientQuota`. The budget is full
+    after this call.
+    */
+    pub fn set_quota(&mut self, quota: ClientQuota) {
+        self.quota = Some(QuotaState::new(quota));
+    }
+
+    /** Try to take a packet with `size` bytes of payload from the traffic
+    budget. Returns `false` if the budget is exhausted. Always returns `true`
+    if traffic of the client is not limited.
+    */
+    pub fn try_take_quota(&mut self, size: usize) -> bool {
+        match self.quota {
+            Some(ref mut quota) => quota.try_take(size),
+            None => true,
+        }
+    }
+
     /** Check if PongResponse timed out
     */
     pub fn is_pong_timedout(&self) -> bool {
@@ -194,7 +236,7 @@ impl Client {
     pub fn send_ping_request(&mut self) -> impl Future<Item = (), Error = Error> + Send {
         let ping_id = gen_ping_id();
 
-        self.last_pinged = Instant::now();
+        self.last_pinged = clock_now();
         self.ping_id = ping_id;
 
         self.send(
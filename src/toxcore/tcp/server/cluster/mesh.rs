@@ -0,0 +1,945 @@
+/*! Cluster backend that connects relay processes with TCP connections.
+
+Every relay of the mesh listens for connections from other relays and
+connects to every other relay from the list of peers. Connections are one
+directional: a relay sends packets only via connections it opened and
+receives packets only via connections it accepted. The first packet of a
+connection is `Hello` with the mesh address of the sender. After it the
+sender announces all its clients and then forwards messages. When a connection
+from a peer is lost its clients are considered disconnected and local clients
+linked to them get `DisconnectNotification`.
+
+Before any frames are sent the accepting side sends a random challenge and
+the connecting side answers with its own random challenge. Frames are
+encrypted with keys derived from the key shared by all relays of the mesh,
+both challenges and the mesh address of the accepting side so frames of one
+connection can't be replayed within another connection. Every direction of a
+connection has its own key, so frames sent by a relay can't be reflected back
+to it. Nonces start from zero and are incremented by one with every frame so
+frames can't be reordered or replayed within a connection.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, BytesMut};
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use nom::be_u16;
+use parking_lot::RwLock;
+use tokio;
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Interval;
+use tokio::util::FutureExt;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::ip_port::*;
+use crate::toxcore::tcp::server::cluster::{ClusterBackend, ClusterMessage};
+
+/// Interval in seconds between attempts to connect to disconnected peers.
+const MESH_RECONNECT_INTERVAL: u64 = 1;
+
+/// Timeout in seconds for connecting to a peer.
+const MESH_CONNECT_TIMEOUT: u64 = 5;
+
+/// Timeout in seconds for exchanging challenges with a peer.
+const MESH_HANDSHAKE_TIMEOUT: u64 = 5;
+
+/// Size of the random challenge sent by both sides of a mesh connection.
+const MESH_CHALLENGE_SIZE: usize = 32;
+
+/// Label of the key for frames sent by the connecting side.
+const MESH_CONNECT_LABEL: u8 = 0x00;
+
+/// Label of the key for frames sent by the accepting side.
+const MESH_ACCEPT_LABEL: u8 = 0x01;
+
+/// Maximum size of serialized `MeshPacket`.
+const MAX_MESH_PACKET_SIZE: usize = 4096;
+
+/// Maximum size of encrypted frame without the length prefix.
+const MAX_MESH_FRAME_SIZE: usize = secretbox::NONCEBYTES + MAX_MESH_PACKET_SIZE + secretbox::MACBYTES;
+
+/** Packet sent between relays of the mesh.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | Packet id: `0x00` for `Hello`, `0x01` for `Register`, `0x02` for `Unregister`
+variable | `IpPort` of the sender for `Hello`, `PublicKey` of a client otherwise
+
+`Message` packets are serialized as `ClusterMessage`.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum MeshPacket {
+    /// The first packet of a connection with the mesh address of the sender.
+    Hello(SocketAddr),
+    /// The client is connected to the sender.
+    Register(PublicKey),
+    /// The client is disconnected from the sender.
+    Unregister(PublicKey),
+    /// Message for a client connected to the receiver.
+    Message(ClusterMessage),
+}
+
+impl FromBytes for MeshPacket {
+    named!(from_bytes<MeshPacket>, alt!(
+        do_parse!(
+            tag!("\x00") >>
+            ip_port: call!(IpPort::from_tcp_bytes, IpPortPadding::NoPadding) >>
+            eof!() >>
+            (MeshPacket::Hello(ip_port.to_saddr()))
+        ) |
+        do_parse!(
+            tag!("\x01") >>
+            pk: call!(PublicKey::from_bytes) >>
+            eof!() >>
+            (MeshPacket::Register(pk))
+        ) |
+        do_parse!(
+            tag!("\x02") >>
+            pk: call!(PublicKey::from_bytes) >>
+            eof!() >>
+            (MeshPacket::Unregister(pk))
+        ) |
+        map!(ClusterMessage::from_bytes, MeshPacket::Message)
+    ));
+}
+
+impl ToBytes for MeshPacket {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            MeshPacket::Hello(addr) => do_gen!(buf,
+                gen_be_u8!(0x00) >>
+                gen_call!(|buf, ip_port| IpPort::to_tcp_bytes(ip_port, buf, IpPortPadding::NoPadding), &IpPort::from_tcp_saddr(addr))
+            ),
+            MeshPacket::Register(ref pk) => do_gen!(buf,
+                gen_be_u8!(0x01) >>
+                gen_slice!(pk.as_ref())
+            ),
+            MeshPacket::Unregister(ref pk) => do_gen!(buf,
+                gen_be_u8!(0x02) >>
+                gen_slice!(pk.as_ref())
+            ),
+            MeshPacket::Message(ref message) => message.to_bytes(buf),
+        }
+    }
+}
+
+/// Parameters of a mesh connection both sides agree on after exchanging
+/// challenges.
+struct MeshHandshake {
+    /// Mesh address of the accepting side
+    acceptor: SocketAddr,
+    /// Challenge sent by the accepting side
+    accept_challenge: [u8; MESH_CHALLENGE_SIZE],
+    /// Challenge sent by the connecting side
+    connect_challenge: [u8; MESH_CHALLENGE_SIZE],
+}
+
+impl MeshHandshake {
+    /// Derive the key for one direction of the connection from the key
+    /// shared by all relays of the mesh.
+    fn session_key(&self, key: &secretbox::Key, label: u8) -> secretbox::Key {
+        let mut data = Vec::with_capacity(secretbox::KEYBYTES + 2 * MESH_CHALLENGE_SIZE + 1);
+        data.extend_from_slice(&key.0);
+        data.extend_from_slice(&self.accept_challenge);
+        data.extend_from_slice(&self.connect_challenge);
+        data.push(label);
+        data.extend_from_slice(self.acceptor.to_string().as_bytes());
+        let sha256::Digest(digest) = sha256::hash(&data);
+        secretbox::Key(digest)
+    }
+
+    /// Create the codec for the connecting side of the connection.
+    fn connect_codec(&self, key: &secretbox::Key) -> MeshCodec {
+        MeshCodec::new(self.session_key(key, MESH_CONNECT_LABEL), self.session_key(key, MESH_ACCEPT_LABEL))
+    }
+
+    /// Create the codec for the accepting side of the connection.
+    fn accept_codec(&self, key: &secretbox::Key) -> MeshCodec {
+        MeshCodec::new(self.session_key(key, MESH_ACCEPT_LABEL), self.session_key(key, MESH_CONNECT_LABEL))
+    }
+}
+
+/// Generate random challenge for a mesh connection.
+fn gen_challenge() -> [u8; MESH_CHALLENGE_SIZE] {
+    let mut challenge = [0; MESH_CHALLENGE_SIZE];
+    randombytes_into(&mut challenge);
+    challenge
+}
+
+/// Codec for encrypted frames of mesh connections.
+pub(crate) struct MeshCodec {
+    /// Key of sent frames
+    send_key: secretbox::Key,
+    /// Key of received frames
+    recv_key: secretbox::Key,
+    /// Nonce for the next sent frame
+    send_nonce: secretbox::Nonce,
+    /// Expected nonce of the next received frame
+    recv_nonce: secretbox::Nonce,
+}
+
+impl MeshCodec {
+    /// Create new `MeshCodec` with zero initial nonces. Keys should be
+    /// different and unique for every connection.
+    pub(crate) fn new(send_key: secretbox::Key, recv_key: secretbox::Key) -> MeshCodec {
+        MeshCodec {
+            send_key,
+            recv_key,
+            send_nonce: secretbox::Nonce([0; secretbox::NONCEBYTES]),
+            recv_nonce: secretbox::Nonce([0; secretbox::NONCEBYTES]),
+        }
+    }
+}
+
+/// Create `InvalidData` IO error.
+fn invalid_data(error: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, error)
+}
+
+impl Decoder for MeshCodec {
+    type Item = MeshPacket;
+    type Error = IoError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let length = match be_u16(buf) {
+            IResult::Done(_, length) => length as usize,
+            _ => return Ok(None),
+        };
+        if length < secretbox::NONCEBYTES + secretbox::MACBYTES {
+            return Err(invalid_data("Mesh frame is too short"));
+        }
+        if length > MAX_MESH_FRAME_SIZE {
+            return Err(invalid_data("Mesh frame is too long"));
+        }
+        if buf.len() < 2 + length {
+            return Ok(None);
+        }
+        let frame = buf.split_to(2 + length);
+        let nonce = secretbox::Nonce::from_slice(&frame[2 .. 2 + secretbox::NONCEBYTES])
+            .expect("Nonce has correct length");
+        if nonce != self.recv_nonce {
+            return Err(invalid_data("Unexpected mesh frame nonce"));
+        }
+        let payload = secretbox::open(&frame[2 + secretbox::NONCEBYTES ..], &nonce, &self.recv_key)
+            .map_err(|()| invalid_data("Failed to decrypt mesh frame"))?;
+        self.recv_nonce.increment_le_inplace();
+        match MeshPacket::from_bytes(&payload) {
+            IResult::Done(_, packet) => Ok(Some(packet)),
+            _ => Err(invalid_data("Failed to deserialize mesh packet")),
+        }
+    }
+}
+
+impl Encoder for MeshCodec {
+    type Item = MeshPacket;
+    type Error = IoError;
+
+    fn encode(&mut self, packet: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut packet_buf = [0; MAX_MESH_PACKET_SIZE];
+        let (_, size) = packet.to_bytes((&mut packet_buf, 0))
+            .map_err(|error| IoError::new(IoErrorKind::InvalidInput, format!("Failed to serialize mesh packet: {:?}", error)))?;
+        let encrypted = secretbox::seal(&packet_buf[.. size], &self.send_nonce, &self.send_key);
+        buf.reserve(2 + secretbox::NONCEBYTES + encrypted.len());
+        buf.put_u16_be((secretbox::NONCEBYTES + encrypted.len()) as u16);
+        buf.put_slice(self.send_nonce.as_ref());
+        buf.put_slice(&encrypted);
+        self.send_nonce.increment_le_inplace();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MeshState {
+    /// Clients connected to this relay
+    local: HashSet<PublicKey>,
+    /// Mesh address of the relay every remote client is connected to
+    directory: HashMap<PublicKey, SocketAddr>,
+    /// Local clients that requested connection to every remote client or
+    /// were requested by it
+    links: HashMap<PublicKey, HashSet<PublicKey>>,
+    /// Senders of packets to peers we are connected to
+    peers: HashMap<SocketAddr, mpsc::UnboundedSender<MeshPacket>>,
+    /// Peers we are connecting to right now
+    connecting: HashSet<SocketAddr>,
+    /// Id of the last accepted connection from every peer
+    inbound: HashMap<SocketAddr, u64>,
+    /// Id for the next accepted connection
+    next_inbound_id: u64,
+}
+
+impl MeshState {
+    /** Remember links between local and remote clients from the message
+    sent to another relay or received from it so that local clients can be
+    notified when the relay of a remote client is lost.
+    */
+    fn update_links(&mut self, message: &ClusterMessage, outgoing: bool) {
+        let (from, to, linked) = match *message {
+            ClusterMessage::RouteRequest { from, to } |
+            ClusterMessage::ConnectNotification { from, to } => (from, to, true),
+            ClusterMessage::DisconnectNotification { from, to } => (from, to, false),
+            ClusterMessage::Data { .. } | ClusterMessage::Oob { .. } => return,
+        };
+        let (local_pk, remote_pk) = if outgoing { (from, to) } else { (to, from) };
+        if linked {
+            self.links.entry(remote_pk).or_default().insert(local_pk);
+        } else if let Some(local_pks) = self.links.get_mut(&remote_pk) {
+            local_pks.remove(&local_pk);
+            if local_pks.is_empty() {
+                self.links.remove(&remote_pk);
+            }
+        }
+    }
+}
+
+/** Cluster backend that connects relay processes with TCP connections.
+
+```no_run
+use tokio::net::TcpListener;
+use futures::{Future, Stream};
+use self::tox::toxcore::crypto_core::*;
+use self::tox::toxcore::tcp::server::Server;
+use self::tox::toxcore::tcp::server::cluster::TcpMeshCluster;
+
+let addr = "10.0.0.1:33446".parse().unwrap();
+let peers = vec!["10.0.0.2:33446".parse().unwrap()];
+// the key should be the same for all relays of the mesh
+let key = secretbox::gen_key();
+let (cluster, messages_rx) = TcpMeshCluster::new(addr, key, peers);
+
+let mut server = Server::new();
+server.set_cluster(cluster.clone());
+let server_c = server.clone();
+let messages_future = messages_rx
+    .map_err(|()| unreachable!("rx can't fail"))
+    .for_each(move |message| server_c.handle_cluster_message(message).then(|_| Ok(())));
+
+let listener = TcpListener::bind(&addr).unwrap();
+let mesh_future = cluster.run(listener);
+# drop((messages_future, mesh_future));
+```
+*/
+#[derive(Clone)]
+pub struct TcpMeshCluster {
+    /// Address of this relay in the mesh
+    addr: SocketAddr,
+    /// Key shared by all relays of the mesh
+    key: secretbox::Key,
+    /// Addresses of other relays of the mesh
+    peers: Vec<SocketAddr>,
+    state: Arc<RwLock<MeshState>>,
+    /// Sink for messages received from other relays
+    messages_tx: mpsc::UnboundedSender<ClusterMessage>,
+}
+
+impl TcpMeshCluster {
+    /** Create new `TcpMeshCluster`. `addr` is the address other relays use
+    to connect to this relay, it should be the same as in their lists of
+    peers since keys of connections depend on it. `peers` are addresses of
+    other relays. Returns
+    the backend and the stream of messages received from other relays.
+    */
+    pub fn new(addr: SocketAddr, key: secretbox::Key, peers: Vec<SocketAddr>) -> (TcpMeshCluster, mpsc::UnboundedReceiver<ClusterMessage>) {
+        let (messages_tx, messages_rx) = mpsc::unbounded();
+        let peers = peers.into_iter().filter(|&peer| peer != addr).collect();
+        let cluster = TcpMeshCluster {
+            addr,
+            key,
+            peers,
+            state: Arc::new(RwLock::new(MeshState::default())),
+            messages_tx,
+        };
+        (cluster, messages_rx)
+    }
+
+    /// Get addresses of peers this relay is connected to.
+    pub fn connected_peers(&self) -> Vec<SocketAddr> {
+        self.state.read().peers.keys().cloned().collect()
+    }
+
+    /// Send the packet to all connected peers.
+    fn broadcast(&self, packet: MeshPacket) {
+        let state = self.state.read();
+        for tx in state.peers.values() {
+            // the connection can be closed right now, it will be reopened
+            // with the full list of clients
+            let _ = tx.unbounded_send(packet.clone());
+        }
+    }
+
+    /// Handle a packet received from the peer.
+    fn handle_packet(&self, peer: SocketAddr, packet: MeshPacket) -> Result<(), IoError> {
+        match packet {
+            MeshPacket::Hello(_) => return Err(invalid_data("Unexpected Hello packet")),
+            MeshPacket::Register(pk) => {
+                self.state.write().directory.insert(pk, peer);
+            },
+            MeshPacket::Unregister(pk) => {
+                let mut state = self.state.write();
+                if state.directory.get(&pk) == Some(&peer) {
+                    state.directory.remove(&pk);
+                    state.links.remove(&pk);
+                }
+            },
+            MeshPacket::Message(message) => {
+                self.state.write().update_links(&message, false);
+                self.messages_tx.unbounded_send(message)
+                    .map_err(|_| IoError::new(IoErrorKind::BrokenPipe, "Cluster messages receiver is gone"))?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Remove clients of the lost peer from the directory. Local clients
+    /// linked to them get `DisconnectNotification` as if the remote clients
+    /// were disconnected.
+    fn peer_lost(&self, state: &mut MeshState, peer: SocketAddr) {
+        let lost = state.directory.iter()
+            .filter(|&(_pk, addr)| *addr == peer)
+            .map(|(&pk, _addr)| pk)
+            .collect::<Vec<_>>();
+        for pk in lost {
+            state.directory.remove(&pk);
+            for local_pk in state.links.remove(&pk).unwrap_or_default() {
+                // the receiver can be gone only when the server is stopped
+                let _ = self.messages_tx.unbounded_send(ClusterMessage::DisconnectNotification { from: pk, to: local_pk });
+            }
+        }
+    }
+
+    /// Receive packets from the accepted connection.
+    fn run_inbound(self, stream: TcpStream) -> impl Future<Item = (), Error = IoError> + Send {
+        let challenge = gen_challenge();
+        let key = self.key.clone();
+        let acceptor = self.addr;
+        io::write_all(stream, challenge)
+            .and_then(|(stream, _challenge)| io::read_exact(stream, [0; MESH_CHALLENGE_SIZE]))
+            .timeout(Duration::from_secs(MESH_HANDSHAKE_TIMEOUT))
+            .map_err(|e| e.into_inner().unwrap_or_else(|| IoError::new(IoErrorKind::TimedOut, "Mesh handshake timed out")))
+            .and_then(move |(stream, peer_challenge)| {
+                let handshake = MeshHandshake {
+                    acceptor,
+                    accept_challenge: challenge,
+                    connect_challenge: peer_challenge,
+                };
+                let codec = handshake.accept_codec(&key);
+                let (_sink, stream) = Framed::new(stream, codec).split();
+                stream.into_future().map_err(|(e, _stream)| e)
+            })
+            .and_then(move |(hello, stream)| {
+                let peer = match hello {
+                    Some(MeshPacket::Hello(peer)) => peer,
+                    _ => return future::Either::A(future::err(invalid_data("Expected Hello packet"))),
+                };
+                debug!("Mesh peer {} connected", peer);
+
+                let id = {
+                    let mut state = self.state.write();
+                    let id = state.next_inbound_id;
+                    state.next_inbound_id += 1;
+                    state.inbound.insert(peer, id);
+                    // peer sends the full list of its clients after Hello
+                    state.directory.retain(|_pk, addr| *addr != peer);
+                    id
+                };
+
+                let self_c = self.clone();
+                let future = stream
+                    .for_each(move |packet| self_c.handle_packet(peer, packet))
+                    .then(move |res| {
+                        debug!("Mesh peer {} disconnected: {:?}", peer, res);
+                        let mut state = self.state.write();
+                        // clients are unreachable unless the peer is already
+                        // reconnected
+                        if state.inbound.get(&peer) == Some(&id) {
+                            state.inbound.remove(&peer);
+                            self.peer_lost(&mut state, peer);
+                        }
+                        res
+                    });
+                future::Either::B(future)
+            })
+    }
+
+    /// Connect to the peer and send packets to it until the connection is
+    /// closed.
+    fn connect(self, peer: SocketAddr) -> impl Future<Item = (), Error = IoError> + Send {
+        let self_c = self.clone();
+        TcpStream::connect(&peer)
+            .timeout(Duration::from_secs(MESH_CONNECT_TIMEOUT))
+            .map_err(|e| e.into_inner().unwrap_or_else(|| IoError::new(IoErrorKind::TimedOut, "Mesh connect timed out")))
+            .and_then(|stream| io::read_exact(stream, [0; MESH_CHALLENGE_SIZE])
+                .and_then(|(stream, peer_challenge)|
+                    io::write_all(stream, gen_challenge())
+                        .map(move |(stream, challenge)| (stream, peer_challenge, challenge))
+                )
+                .timeout(Duration::from_secs(MESH_HANDSHAKE_TIMEOUT))
+                .map_err(|e| e.into_inner().unwrap_or_else(|| IoError::new(IoErrorKind::TimedOut, "Mesh handshake timed out")))
+            )
+            .then(move |res| {
+                let mut state = self_c.state.write();
+                state.connecting.remove(&peer);
+                let (stream, peer_challenge, challenge) = res?;
+                debug!("Connected to mesh peer {}", peer);
+
+                let (tx, rx) = mpsc::unbounded();
+                tx.unbounded_send(MeshPacket::Hello(self_c.addr)).expect("rx is alive");
+                for pk in &state.local {
+                    tx.unbounded_send(MeshPacket::Register(*pk)).expect("rx is alive");
+                }
+                state.peers.insert(peer, tx);
+                let handshake = MeshHandshake {
+                    acceptor: peer,
+                    accept_challenge: peer_challenge,
+                    connect_challenge: challenge,
+                };
+                Ok((stream, handshake.connect_codec(&self_c.key), rx))
+            })
+            .and_then(move |(stream, codec, rx)| {
+                let (sink, _stream) = Framed::new(stream, codec).split();
+                rx.map_err(|()| unreachable!("rx can't fail"))
+                    .forward(sink)
+                    .map(|_| ())
+                    .then(move |res| {
+                        debug!("Disconnected from mesh peer {}: {:?}", peer, res);
+                        self.state.write().peers.remove(&peer);
+                        res
+                    })
+            })
+    }
+
+    /// Start connecting to peers we are not connected to.
+    fn connect_peers(&self) {
+        let mut state = self.state.write();
+        for &peer in &self.peers {
+            if state.peers.contains_key(&peer) || state.connecting.contains(&peer) {
+                continue;
+            }
+            state.connecting.insert(peer);
+            tokio::spawn(self.clone().connect(peer).map_err(move |e|
+                debug!("Failed to connect to mesh peer {}: {}", peer, e)
+            ));
+        }
+    }
+
+    /** Accept connections from other relays and maintain connections to
+    them. This function uses `tokio::spawn` inside so it should be executed
+    via tokio to be able to get tokio default executor.
+    */
+    pub fn run(self, listener: TcpListener) -> impl Future<Item = (), Error = IoError> + Send {
+        let self_c = self.clone();
+        let accept_future = listener.incoming()
+            .for_each(move |stream| {
+                let peer_addr = stream.peer_addr();
+                tokio::spawn(self_c.clone().run_inbound(stream).map_err(move |e|
+                    debug!("Mesh connection from {:?} failed: {}", peer_addr, e)
+                ));
+                Ok(())
+            });
+
+        let reconnect_future = Interval::new(Instant::now(), Duration::from_secs(MESH_RECONNECT_INTERVAL))
+            .map_err(|e| IoError::new(IoErrorKind::Other, e))
+            .for_each(move |_instant| {
+                self.connect_peers();
+                Ok(())
+            });
+
+        accept_future
+            .select(reconnect_future)
+            .map(|_| ()).map_err(|(e, _)| e)
+    }
+}
+
+impl ClusterBackend for TcpMeshCluster {
+    fn register(&self, pk: &PublicKey) {
+        self.state.write().local.insert(*pk);
+        self.broadcast(MeshPacket::Register(*pk));
+    }
+
+    fn unregister(&self, pk: &PublicKey) {
+        {
+            let mut state = self.state.write();
+            state.local.remove(pk);
+            for local_pks in state.links.values_mut() {
+                local_pks.remove(pk);
+            }
+        }
+        self.broadcast(MeshPacket::Unregister(*pk));
+    }
+
+    fn is_remote(&self, pk: &PublicKey) -> bool {
+        self.state.read().directory.contains_key(pk)
+    }
+
+    fn send(&self, message: ClusterMessage) -> Box<dyn Future<Item = (), Error = IoError> + Send> {
+        let mut state = self.state.write();
+        state.update_links(&message, true);
+        let tx = state.directory.get(message.destination())
+            .and_then(|addr| state.peers.get(addr));
+        let result = match tx {
+            Some(tx) => tx.unbounded_send(MeshPacket::Message(message))
+                .map_err(|_| IoError::new(IoErrorKind::BrokenPipe, "Mesh connection is closed")),
+            None => Err(IoError::new(IoErrorKind::NotFound, "Client is not connected to the mesh")),
+        };
+        Box::new(future::result(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::tcp::connection_id::ConnectionId;
+    use crate::toxcore::tcp::links::LinkStatus;
+    use crate::toxcore::tcp::packet::*;
+    use crate::toxcore::tcp::server::{Client, Server};
+
+    encode_decode_test!(
+        mesh_packet_hello_encode_decode,
+        MeshPacket::Hello("127.0.0.1:33445".parse().unwrap())
+    );
+
+    encode_decode_test!(
+        mesh_packet_hello_ipv6_encode_decode,
+        MeshPacket::Hello("[::1]:33445".parse().unwrap())
+    );
+
+    encode_decode_test!(
+        mesh_packet_register_encode_decode,
+        MeshPacket::Register(gen_keypair().0)
+    );
+
+    encode_decode_test!(
+        mesh_packet_unregister_encode_decode,
+        MeshPacket::Unregister(gen_keypair().0)
+    );
+
+    encode_decode_test!(
+        mesh_packet_message_encode_decode,
+        MeshPacket::Message(ClusterMessage::Data { from: gen_keypair().0, to: gen_keypair().0, data: vec![42; 123] })
+    );
+
+    #[test]
+    fn codec_encode_decode() {
+        crypto_init().unwrap();
+        let key_1 = secretbox::gen_key();
+        let key_2 = secretbox::gen_key();
+        let mut codec_1 = MeshCodec::new(key_1.clone(), key_2.clone());
+        let mut codec_2 = MeshCodec::new(key_2, key_1);
+
+        let packet_1 = MeshPacket::Register(gen_keypair().0);
+        let packet_2 = MeshPacket::Unregister(gen_keypair().0);
+        let mut buf = BytesMut::new();
+        codec_1.encode(packet_1.clone(), &mut buf).unwrap();
+        codec_1.encode(packet_2.clone(), &mut buf).unwrap();
+
+        // incomplete frame
+        let mut incomplete = BytesMut::from(&buf[.. 10]);
+        assert!(codec_2.decode(&mut incomplete).unwrap().is_none());
+
+        assert_eq!(codec_2.decode(&mut buf).unwrap(), Some(packet_1));
+        assert_eq!(codec_2.decode(&mut buf).unwrap(), Some(packet_2));
+        assert!(codec_2.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn codec_decode_wrong_key() {
+        crypto_init().unwrap();
+        let mut codec_1 = MeshCodec::new(secretbox::gen_key(), secretbox::gen_key());
+        let mut codec_2 = MeshCodec::new(secretbox::gen_key(), secretbox::gen_key());
+
+        let mut buf = BytesMut::new();
+        codec_1.encode(MeshPacket::Register(gen_keypair().0), &mut buf).unwrap();
+        assert!(codec_2.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn codec_decode_replayed_frame() {
+        crypto_init().unwrap();
+        let key_1 = secretbox::gen_key();
+        let key_2 = secretbox::gen_key();
+        let mut codec_1 = MeshCodec::new(key_1.clone(), key_2.clone());
+        let mut codec_2 = MeshCodec::new(key_2, key_1);
+
+        let mut buf = BytesMut::new();
+        codec_1.encode(MeshPacket::Register(gen_keypair().0), &mut buf).unwrap();
+        let mut replayed = buf.clone();
+        assert!(codec_2.decode(&mut buf).unwrap().is_some());
+        assert!(codec_2.decode(&mut replayed).is_err());
+    }
+
+    #[test]
+    fn codec_decode_frame_of_another_connection() {
+        crypto_init().unwrap();
+        let key = secretbox::gen_key();
+        // the same answer to different challenges gives different keys
+        let connect_challenge = gen_challenge();
+        let handshake_1 = MeshHandshake {
+            acceptor: "127.0.0.1:33445".parse().unwrap(),
+            accept_challenge: gen_challenge(),
+            connect_challenge,
+        };
+        let handshake_2 = MeshHandshake {
+            acceptor: "127.0.0.1:33445".parse().unwrap(),
+            accept_challenge: gen_challenge(),
+            connect_challenge,
+        };
+        let mut codec_1 = handshake_1.connect_codec(&key);
+        let mut codec_2 = handshake_2.accept_codec(&key);
+
+        let mut buf = BytesMut::new();
+        codec_1.encode(MeshPacket::Register(gen_keypair().0), &mut buf).unwrap();
+        assert!(codec_2.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn codec_decode_reflected_frame() {
+        crypto_init().unwrap();
+        let key = secretbox::gen_key();
+        let handshake = MeshHandshake {
+            acceptor: "127.0.0.1:33445".parse().unwrap(),
+            accept_challenge: gen_challenge(),
+            connect_challenge: gen_challenge(),
+        };
+        let mut connect_codec = handshake.connect_codec(&key);
+        let mut accept_codec = handshake.accept_codec(&key);
+
+        // frames can't be sent back to the side that sent them
+        let mut buf = BytesMut::new();
+        connect_codec.encode(MeshPacket::Register(gen_keypair().0), &mut buf).unwrap();
+        let mut reflected = buf.clone();
+        assert!(handshake.connect_codec(&key).decode(&mut reflected).is_err());
+        assert!(accept_codec.decode(&mut buf).unwrap().is_some());
+
+        let mut buf = BytesMut::new();
+        accept_codec.encode(MeshPacket::Register(gen_keypair().0), &mut buf).unwrap();
+        let mut reflected = buf.clone();
+        assert!(handshake.accept_codec(&key).decode(&mut reflected).is_err());
+        assert!(connect_codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn codec_decode_frame_for_another_relay() {
+        crypto_init().unwrap();
+        let key = secretbox::gen_key();
+        // a relay connected to a malicious peer can get the peer's challenge
+        // echoed to its own accepted connection
+        let accept_challenge = gen_challenge();
+        let connect_challenge = gen_challenge();
+        let handshake_1 = MeshHandshake {
+            acceptor: "127.0.0.1:33445".parse().unwrap(),
+            accept_challenge,
+            connect_challenge,
+        };
+        let handshake_2 = MeshHandshake {
+            acceptor: "127.0.0.1:33446".parse().unwrap(),
+            accept_challenge,
+            connect_challenge,
+        };
+        let mut codec_1 = handshake_1.connect_codec(&key);
+        let mut codec_2 = handshake_2.accept_codec(&key);
+
+        let mut buf = BytesMut::new();
+        codec_1.encode(MeshPacket::Register(gen_keypair().0), &mut buf).unwrap();
+        assert!(codec_2.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn codec_decode_invalid_length() {
+        crypto_init().unwrap();
+        let mut codec = MeshCodec::new(secretbox::gen_key(), secretbox::gen_key());
+        let mut buf = BytesMut::from(&[0xff, 0xff][..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn mesh_loopback() {
+        crypto_init().unwrap();
+        let key = secretbox::gen_key();
+        let listener_1 = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr_1 = listener_1.local_addr().unwrap();
+        let listener_2 = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr_2 = listener_2.local_addr().unwrap();
+
+        let (mesh_1, _rx_1) = TcpMeshCluster::new(addr_1, key.clone(), vec![addr_1, addr_2]);
+        let (mesh_2, rx_2) = TcpMeshCluster::new(addr_2, key, vec![addr_1, addr_2]);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(mesh_1.clone().run(listener_1).map_err(|e| panic!("Mesh failed: {}", e)));
+        runtime.spawn(mesh_2.clone().run(listener_2).map_err(|e| panic!("Mesh failed: {}", e)));
+
+        let (pk_1, _sk) = gen_keypair();
+        let (pk_2, _sk) = gen_keypair();
+        mesh_1.register(&pk_1);
+        mesh_2.register(&pk_2);
+
+        // wait until clients are announced to each other
+        let mut attempts = 0;
+        while !mesh_1.is_remote(&pk_2) || !mesh_2.is_remote(&pk_1) {
+            attempts += 1;
+            assert!(attempts < 100, "Mesh is not connected");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(mesh_1.connected_peers(), vec![addr_2]);
+        assert!(!mesh_1.is_remote(&pk_1));
+
+        let message = ClusterMessage::Data { from: pk_1, to: pk_2, data: vec![42; 123] };
+        mesh_1.send(message.clone()).wait().unwrap();
+        let (received, _rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(received, Some(message));
+
+        // unregistered client is removed from the directory of other relays
+        mesh_2.unregister(&pk_2);
+        let mut attempts = 0;
+        while mesh_1.is_remote(&pk_2) {
+            attempts += 1;
+            assert!(attempts < 100, "Client is not unregistered");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(mesh_1.send(ClusterMessage::Oob { from: pk_1, to: pk_2, data: vec![42; 123] }).wait().is_err());
+
+        runtime.shutdown_now().wait().unwrap();
+    }
+
+    #[test]
+    fn mesh_replayed_connection() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream as StdTcpStream;
+
+        crypto_init().unwrap();
+        let key = secretbox::gen_key();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mesh, _rx) = TcpMeshCluster::new(addr, key.clone(), Vec::new());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(mesh.clone().run(listener).map_err(|e| panic!("Mesh failed: {}", e)));
+
+        // connect to the mesh as a peer and record everything we send
+        let (pk, _sk) = gen_keypair();
+        let mut stream = StdTcpStream::connect(&addr).unwrap();
+        let mut accept_challenge = [0; MESH_CHALLENGE_SIZE];
+        stream.read_exact(&mut accept_challenge).unwrap();
+        let connect_challenge = gen_challenge();
+        let handshake = MeshHandshake {
+            acceptor: addr,
+            accept_challenge,
+            connect_challenge,
+        };
+        let mut codec = handshake.connect_codec(&key);
+        let mut recorded = BytesMut::from(&connect_challenge[..]);
+        codec.encode(MeshPacket::Hello("127.0.0.1:33445".parse().unwrap()), &mut recorded).unwrap();
+        codec.encode(MeshPacket::Register(pk), &mut recorded).unwrap();
+        stream.write_all(&recorded).unwrap();
+
+        let mut attempts = 0;
+        while !mesh.is_remote(&pk) {
+            attempts += 1;
+            assert!(attempts < 100, "Client is not registered");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        // clients of the lost peer are removed
+        drop(stream);
+        let mut attempts = 0;
+        while mesh.is_remote(&pk) {
+            attempts += 1;
+            assert!(attempts < 100, "Client is not removed");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        // replayed connection gets another challenge and can't register the
+        // client again
+        let mut stream = StdTcpStream::connect(&addr).unwrap();
+        let mut new_accept_challenge = [0; MESH_CHALLENGE_SIZE];
+        stream.read_exact(&mut new_accept_challenge).unwrap();
+        assert_ne!(new_accept_challenge, accept_challenge);
+        stream.write_all(&recorded).unwrap();
+        // the mesh closes the connection after the first invalid frame, it
+        // can be reset since the rest of the data is not read
+        let mut buf = [0; 1];
+        match stream.read(&mut buf) {
+            Ok(size) => assert_eq!(size, 0),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+        }
+        assert!(!mesh.is_remote(&pk));
+
+        runtime.shutdown_now().wait().unwrap();
+    }
+
+    #[test]
+    fn mesh_peer_lost() {
+        crypto_init().unwrap();
+        let key = secretbox::gen_key();
+        let listener_1 = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr_1 = listener_1.local_addr().unwrap();
+        let listener_2 = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr_2 = listener_2.local_addr().unwrap();
+
+        let (mesh_1, messages_rx_1) = TcpMeshCluster::new(addr_1, key.clone(), vec![addr_2]);
+        let (mesh_2, messages_rx_2) = TcpMeshCluster::new(addr_2, key, vec![addr_1]);
+        let mut server_1 = Server::new();
+        server_1.set_cluster(mesh_1.clone());
+        let mut server_2 = Server::new();
+        server_2.set_cluster(mesh_2.clone());
+
+        // relays run in different runtimes so that one of them can be killed
+        let mut runtime_1 = tokio::runtime::Runtime::new().unwrap();
+        let mut runtime_2 = tokio::runtime::Runtime::new().unwrap();
+        for (runtime, mesh, listener, messages_rx, server) in vec![
+            (&mut runtime_1, mesh_1.clone(), listener_1, messages_rx_1, server_1.clone()),
+            (&mut runtime_2, mesh_2.clone(), listener_2, messages_rx_2, server_2.clone()),
+        ] {
+            runtime.spawn(mesh.run(listener).map_err(|e| panic!("Mesh failed: {}", e)));
+            runtime.spawn(messages_rx.for_each(move |message| server.handle_cluster_message(message).then(|_| Ok(()))));
+        }
+
+        let (pk_1, _sk) = gen_keypair();
+        let (pk_2, _sk) = gen_keypair();
+        let (tx_1, rx_1) = mpsc::channel(32);
+        let (tx_2, _rx_2) = mpsc::channel(32);
+        server_1.insert(Client::new(tx_1, &pk_1, "1.2.3.4".parse().unwrap(), 12345)).wait().unwrap();
+        server_2.insert(Client::new(tx_2, &pk_2, "1.2.3.5".parse().unwrap(), 12345)).wait().unwrap();
+
+        // wait until clients are announced to each other
+        let mut attempts = 0;
+        while !mesh_1.is_remote(&pk_2) || !mesh_2.is_remote(&pk_1) {
+            attempts += 1;
+            assert!(attempts < 100, "Mesh is not connected");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        // link clients to each other
+        server_1.handle_packet(&pk_1, Packet::RouteRequest(RouteRequest { pk: pk_2 })).wait().unwrap();
+        server_2.handle_packet(&pk_2, Packet::RouteRequest(RouteRequest { pk: pk_1 })).wait().unwrap();
+        let (packet, rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::RouteResponse(
+            RouteResponse { pk: pk_2, connection_id: ConnectionId::from_index(0) }
+        ));
+        let (packet, rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::ConnectNotification(
+            ConnectNotification { connection_id: ConnectionId::from_index(0) }
+        ));
+
+        // kill the second relay
+        runtime_2.shutdown_now().wait().unwrap();
+
+        let (packet, _rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::DisconnectNotification(
+            DisconnectNotification { connection_id: ConnectionId::from_index(0) }
+        ));
+        assert!(!mesh_1.is_remote(&pk_2));
+        assert_eq!(server_1.links()[0].status, LinkStatus::Registered);
+
+        runtime_1.shutdown_now().wait().unwrap();
+    }
+}
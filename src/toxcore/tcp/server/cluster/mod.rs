@@ -0,0 +1,330 @@
+/*! Clustering of TCP relay servers.
+
+A single `Server` can link only clients connected to it. When several relay
+processes are run behind one public address they should share the directory
+of connected clients and forward packets to each other so that clients
+connected to different processes can be linked.
+
+`ClusterBackend` is the interface the `Server` uses to talk to other relays of
+the cluster. There are two implementations:
+
+- `InMemoryCluster` connects servers running in the same process and is
+  useful for testing
+- `TcpMeshCluster` connects relay processes with TCP connections
+
+Messages received by the backend from other relays should be passed to
+`Server::handle_cluster_message`.
+*/
+
+mod mesh;
+
+pub use self::mesh::*;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use futures::{future, Future};
+use futures::sync::mpsc;
+use nom::{le_u8, rest};
+use parking_lot::RwLock;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+
+/** Message that is sent from one relay of the cluster to the relay the
+destination client is connected to.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | Message id: `0x10` - `0x14`
+`32`     | `PublicKey` of the source client
+`32`     | `PublicKey` of the destination client
+variable | Data for `Data` and `Oob` messages
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClusterMessage {
+    /// Client `from` sent `RouteRequest` for client `to`.
+    RouteRequest {
+        /// `PublicKey` of the source client
+        from: PublicKey,
+        /// `PublicKey` of the destination client
+        to: PublicKey,
+    },
+    /// Client `from` has a link to client `to` and it's online now.
+    ConnectNotification {
+        /// `PublicKey` of the source client
+        from: PublicKey,
+        /// `PublicKey` of the destination client
+        to: PublicKey,
+    },
+    /// Client `from` removed its link to client `to` or disconnected.
+    DisconnectNotification {
+        /// `PublicKey` of the source client
+        from: PublicKey,
+        /// `PublicKey` of the destination client
+        to: PublicKey,
+    },
+    /// Client `from` sent `Data` packet to client `to`.
+    Data {
+        /// `PublicKey` of the source client
+        from: PublicKey,
+        /// `PublicKey` of the destination client
+        to: PublicKey,
+        /// Data payload
+        data: Vec<u8>,
+    },
+    /// Client `from` sent `OobSend` packet to client `to`.
+    Oob {
+        /// `PublicKey` of the source client
+        from: PublicKey,
+        /// `PublicKey` of the destination client
+        to: PublicKey,
+        /// OOB data
+        data: Vec<u8>,
+    },
+}
+
+impl ClusterMessage {
+    /// `PublicKey` of the destination client.
+    pub fn destination(&self) -> &PublicKey {
+        match *self {
+            ClusterMessage::RouteRequest { ref to, .. } |
+            ClusterMessage::ConnectNotification { ref to, .. } |
+            ClusterMessage::DisconnectNotification { ref to, .. } |
+            ClusterMessage::Data { ref to, .. } |
+            ClusterMessage::Oob { ref to, .. } => to,
+        }
+    }
+}
+
+impl FromBytes for ClusterMessage {
+    named!(from_bytes<ClusterMessage>, switch!(le_u8,
+        0x10 => do_parse!(
+            from: call!(PublicKey::from_bytes) >>
+            to: call!(PublicKey::from_bytes) >>
+            eof!() >>
+            (ClusterMessage::RouteRequest { from, to })
+        ) |
+        0x11 => do_parse!(
+            from: call!(PublicKey::from_bytes) >>
+            to: call!(PublicKey::from_bytes) >>
+            eof!() >>
+            (ClusterMessage::ConnectNotification { from, to })
+        ) |
+        0x12 => do_parse!(
+            from: call!(PublicKey::from_bytes) >>
+            to: call!(PublicKey::from_bytes) >>
+            eof!() >>
+            (ClusterMessage::DisconnectNotification { from, to })
+        ) |
+        0x13 => do_parse!(
+            from: call!(PublicKey::from_bytes) >>
+            to: call!(PublicKey::from_bytes) >>
+            data: rest >>
+            (ClusterMessage::Data { from, to, data: data.to_vec() })
+        ) |
+        0x14 => do_parse!(
+            from: call!(PublicKey::from_bytes) >>
+            to: call!(PublicKey::from_bytes) >>
+            data: rest >>
+            (ClusterMessage::Oob { from, to, data: data.to_vec() })
+        )
+    ));
+}
+
+impl ToBytes for ClusterMessage {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        let (id, from, to, data): (u8, _, _, &[u8]) = match *self {
+            ClusterMessage::RouteRequest { ref from, ref to } => (0x10, from, to, &[]),
+            ClusterMessage::ConnectNotification { ref from, ref to } => (0x11, from, to, &[]),
+            ClusterMessage::DisconnectNotification { ref from, ref to } => (0x12, from, to, &[]),
+            ClusterMessage::Data { ref from, ref to, ref data } => (0x13, from, to, data),
+            ClusterMessage::Oob { ref from, ref to, ref data } => (0x14, from, to, data),
+        };
+        do_gen!(buf,
+            gen_be_u8!(id) >>
+            gen_slice!(from.as_ref()) >>
+            gen_slice!(to.as_ref()) >>
+            gen_slice!(data)
+        )
+    }
+}
+
+/** Interface of the TCP relay server to other relays of the cluster.
+*/
+pub trait ClusterBackend: Send + Sync {
+    /// Announce to the cluster that the client is connected to this relay.
+    fn register(&self, pk: &PublicKey);
+    /// Announce to the cluster that the client is disconnected from this
+    /// relay.
+    fn unregister(&self, pk: &PublicKey);
+    /// Check if the client is connected to another relay of the cluster.
+    fn is_remote(&self, pk: &PublicKey) -> bool;
+    /// Send the message to the relay the destination client is connected
+    /// to.
+    fn send(&self, message: ClusterMessage) -> Box<dyn Future<Item = (), Error = Error> + Send>;
+}
+
+#[derive(Default)]
+struct InMemoryClusterState {
+    /// Senders of messages to every node of the cluster
+    nodes: Vec<mpsc::UnboundedSender<ClusterMessage>>,
+    /// Index of the node every client is connected to
+    directory: HashMap<PublicKey, usize>,
+}
+
+/** Cluster of relays running in the same process. Every relay gets its
+`InMemoryClusterNode` via `InMemoryCluster::join`.
+*/
+#[derive(Clone, Default)]
+pub struct InMemoryCluster {
+    state: Arc<RwLock<InMemoryClusterState>>,
+}
+
+impl InMemoryCluster {
+    /// Create new empty `InMemoryCluster`.
+    pub fn new() -> InMemoryCluster {
+        InMemoryCluster::default()
+    }
+
+    /// Add a new node to the cluster. Returns the backend for a relay and
+    /// the stream of messages sent to this relay by other relays.
+    pub fn join(&self) -> (InMemoryClusterNode, mpsc::UnboundedReceiver<ClusterMessage>) {
+        let (tx, rx) = mpsc::unbounded();
+        let mut state = self.state.write();
+        state.nodes.push(tx);
+        let node = InMemoryClusterNode {
+            cluster: self.clone(),
+            index: state.nodes.len() - 1,
+        };
+        (node, rx)
+    }
+}
+
+/** Node of `InMemoryCluster`.
+*/
+#[derive(Clone)]
+pub struct InMemoryClusterNode {
+    cluster: InMemoryCluster,
+    index: usize,
+}
+
+impl ClusterBackend for InMemoryClusterNode {
+    fn register(&self, pk: &PublicKey) {
+        self.cluster.state.write().directory.insert(*pk, self.index);
+    }
+
+    fn unregister(&self, pk: &PublicKey) {
+        let mut state = self.cluster.state.write();
+        // the client can be already reconnected to another node
+        if state.directory.get(pk) == Some(&self.index) {
+            state.directory.remove(pk);
+        }
+    }
+
+    fn is_remote(&self, pk: &PublicKey) -> bool {
+        match self.cluster.state.read().directory.get(pk) {
+            Some(&index) => index != self.index,
+            None => false,
+        }
+    }
+
+    fn send(&self, message: ClusterMessage) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let state = self.cluster.state.read();
+        let node = state.directory.get(message.destination())
+            .filter(|&&index| index != self.index)
+            .map(|&index| &state.nodes[index]);
+        let result = match node {
+            Some(node) => node.unbounded_send(message)
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Cluster node is gone")),
+            None => Err(Error::new(ErrorKind::NotFound, "Client is not connected to the cluster")),
+        };
+        Box::new(future::result(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Stream;
+
+    encode_decode_test!(
+        cluster_message_route_request_encode_decode,
+        ClusterMessage::RouteRequest { from: gen_keypair().0, to: gen_keypair().0 }
+    );
+
+    encode_decode_test!(
+        cluster_message_connect_notification_encode_decode,
+        ClusterMessage::ConnectNotification { from: gen_keypair().0, to: gen_keypair().0 }
+    );
+
+    encode_decode_test!(
+        cluster_message_disconnect_notification_encode_decode,
+        ClusterMessage::DisconnectNotification { from: gen_keypair().0, to: gen_keypair().0 }
+    );
+
+    encode_decode_test!(
+        cluster_message_data_encode_decode,
+        ClusterMessage::Data { from: gen_keypair().0, to: gen_keypair().0, data: vec![42; 123] }
+    );
+
+    encode_decode_test!(
+        cluster_message_oob_encode_decode,
+        ClusterMessage::Oob { from: gen_keypair().0, to: gen_keypair().0, data: vec![42; 123] }
+    );
+
+    #[test]
+    fn in_memory_cluster() {
+        crypto_init().unwrap();
+        let cluster = InMemoryCluster::new();
+        let (node_1, rx_1) = cluster.join();
+        let (node_2, rx_2) = cluster.join();
+
+        let (pk_1, _sk) = gen_keypair();
+        let (pk_2, _sk) = gen_keypair();
+        node_1.register(&pk_1);
+        node_2.register(&pk_2);
+
+        assert!(!node_1.is_remote(&pk_1));
+        assert!(node_1.is_remote(&pk_2));
+        assert!(node_2.is_remote(&pk_1));
+
+        let message = ClusterMessage::Oob { from: pk_1, to: pk_2, data: vec![42; 123] };
+        node_1.send(message.clone()).wait().unwrap();
+        // local clients are handled by the server itself
+        assert!(node_1.send(ClusterMessage::RouteRequest { from: pk_2, to: pk_1 }).wait().is_err());
+
+        drop(cluster);
+        drop(node_1);
+        drop(node_2);
+        assert_eq!(rx_2.collect().wait().unwrap(), vec![message]);
+        assert!(rx_1.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn in_memory_cluster_reconnect() {
+        crypto_init().unwrap();
+        let cluster = InMemoryCluster::new();
+        let (node_1, _rx_1) = cluster.join();
+        let (node_2, _rx_2) = cluster.join();
+        let (node_3, _rx_3) = cluster.join();
+
+        let (pk, _sk) = gen_keypair();
+        node_1.register(&pk);
+        // client reconnected to another node before the old connection was
+        // closed
+        node_2.register(&pk);
+        node_1.unregister(&pk);
+        assert!(node_3.is_remote(&pk));
+        assert!(!node_2.is_remote(&pk));
+
+        node_2.unregister(&pk);
+        assert!(!node_3.is_remote(&pk));
+        assert!(node_3.send(ClusterMessage::RouteRequest { from: pk, to: pk }).wait().is_err());
+    }
+}
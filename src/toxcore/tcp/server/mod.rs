@@ -1,11 +1,16 @@
 /*! The implementation of TCP relay server
 */
 
+mod admin;
+pub mod cluster;
 mod client;
+mod policy;
 #[allow(clippy::module_inception)]
 mod server;
 mod server_ext;
 
+pub use self::admin::{AdminServer, ClientInfo, LinkInfo};
 pub use self::client::Client;
+pub use self::policy::{ClientQuota, IpNet, IpNetParseError, IpNetParseErrorKind, RelayPolicy};
 pub use self::server::Server;
 pub use self::server_ext::ServerExt;
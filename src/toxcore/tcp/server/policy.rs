@@ -0,0 +1,427 @@
+/*! Access policy of the TCP relay server.
+
+By default TCP relay accepts any client that completes the handshake. With
+`RelayPolicy` it's possible to run a private relay that accepts only clients
+with known `PublicKey`s or from known networks, to limit the number of
+connections from a single IP address and to limit the traffic of every
+client.
+*/
+
+use std::cmp;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use failure::Fail;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::ip_port::ip_subnet;
+use crate::toxcore::dht::server::rate_limiter::{RateLimit, TokenBucket};
+use crate::toxcore::tcp::packet::Packet;
+
+error_kind! {
+    #[doc = "Error that can happen when parsing `IpNet` from string."]
+    #[derive(Debug)]
+    IpNetParseError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    IpNetParseErrorKind {
+        #[doc = "IP address part is invalid."]
+        #[fail(display = "Invalid IP address")]
+        InvalidAddress,
+        #[doc = "Prefix length is not a number or exceeds the address length."]
+        #[fail(display = "Invalid prefix length")]
+        InvalidPrefix,
+    }
+}
+
+/// Normalize IP address so that IPv4-mapped IPv6 addresses are treated as
+/// IPv4 addresses.
+fn normalize_ip(ip: IpAddr) -> IpAddr {
+    ip_subnet(ip, 32, 128)
+}
+
+/** IP network defined by an address and a prefix length, e.g.
+`10.0.0.0/8` or `2001:db8::/32`.
+
+```
+use std::net::IpAddr;
+use self::tox::toxcore::tcp::server::IpNet;
+
+let net: IpNet = "10.0.0.0/8".parse().unwrap();
+assert!(net.contains("10.1.2.3".parse().unwrap()));
+assert!(!net.contains("11.1.2.3".parse().unwrap()));
+
+// address without prefix is a network with a single address
+let net: IpNet = "10.1.2.3".parse().unwrap();
+assert_eq!(net.prefix(), 32);
+```
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Create new `IpNet`. Prefix length is truncated to the length of the
+    /// address and host bits of the address are cleared.
+    pub fn new(addr: IpAddr, prefix: u8) -> IpNet {
+        let addr = normalize_ip(addr);
+        let prefix = match addr {
+            IpAddr::V4(_) => prefix.min(32),
+            IpAddr::V6(_) => prefix.min(128),
+        };
+        IpNet {
+            addr: ip_subnet(addr, prefix, prefix),
+            prefix,
+        }
+    }
+
+    /// Network address.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Prefix length.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Check if the network contains IP address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = normalize_ip(ip);
+        self.addr.is_ipv4() == ip.is_ipv4() && ip_subnet(ip, self.prefix, self.prefix) == self.addr
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = IpNetParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = parts.next().unwrap_or_default().parse::<IpAddr>()
+            .map_err(|_| IpNetParseError::from(IpNetParseErrorKind::InvalidAddress))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().ok()
+                .filter(|&prefix| prefix <= max_prefix)
+                .ok_or_else(|| IpNetParseError::from(IpNetParseErrorKind::InvalidPrefix))?,
+            None => max_prefix,
+        };
+        Ok(IpNet::new(addr, prefix))
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Traffic budget of a single client. Applies to `Data`, `OobSend` and
+/// `OnionRequest` packets sent by the client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClientQuota {
+    /// Budget of packets.
+    pub packets: RateLimit,
+    /// Budget of payload bytes. Burst should be not less than the maximum
+    /// payload size, otherwise big packets will never be accepted.
+    pub bytes: RateLimit,
+}
+
+/// Current state of the traffic budget of a single client.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct QuotaState {
+    quota: ClientQuota,
+    packets: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl QuotaState {
+    /// Create new `QuotaState` with full budget.
+    pub(crate) fn new(quota: ClientQuota) -> QuotaState {
+        QuotaState {
+            quota,
+            packets: TokenBucket::new(quota.packets),
+            bytes: TokenBucket::new(quota.bytes),
+        }
+    }
+
+    /// Try to take one packet with `size` bytes of payload from the budget.
+    /// Returns `false` if the budget is exhausted.
+    pub(crate) fn try_take(&mut self, size: usize) -> bool {
+        let size = cmp::min(size, u32::max_value() as usize) as u32;
+        // check bytes first so that rejected packets don't waste packets budget
+        let mut bytes = self.bytes;
+        if !bytes.try_take_n(self.quota.bytes, size) {
+            self.bytes = bytes;
+            return false;
+        }
+        if !self.packets.try_take(self.quota.packets) {
+            return false;
+        }
+        self.bytes = bytes;
+        true
+    }
+}
+
+/// Get the size of payload of a packet that is subject to `ClientQuota`.
+/// Returns `None` if the packet is not limited.
+pub(crate) fn quota_packet_size(packet: &Packet) -> Option<usize> {
+    match *packet {
+        Packet::Data(ref packet) => Some(packet.data.len()),
+        Packet::OobSend(ref packet) => Some(packet.data.len()),
+        Packet::OnionRequest(ref packet) => Some(packet.payload.len()),
+        _ => None,
+    }
+}
+
+/** Access policy of the TCP relay server. Default policy accepts everybody
+and doesn't limit anything.
+
+```
+use self::tox::toxcore::crypto_core::*;
+use self::tox::toxcore::tcp::server::{RelayPolicy, Server};
+
+let (pk, _sk) = gen_keypair();
+let mut policy = RelayPolicy::new();
+policy.allow_pk(pk);
+policy.allow_net("10.0.0.0/8".parse().unwrap());
+policy.set_max_connections_per_ip(4);
+
+let mut server = Server::new();
+server.set_policy(policy);
+```
+*/
+#[derive(Clone, Debug, Default)]
+pub struct RelayPolicy {
+    /// `PublicKey`s of clients that are allowed to connect. `None` means that
+    /// any key is allowed.
+    allowed_pks: Option<HashSet<PublicKey>>,
+    /// Networks clients are allowed to connect from. `None` means that any
+    /// address is allowed.
+    allowed_nets: Option<Vec<IpNet>>,
+    /// Maximum number of simultaneous connections from a single IP address.
+    max_connections_per_ip: Option<usize>,
+    /// Traffic budget of every client.
+    quota: Option<ClientQuota>,
+}
+
+impl RelayPolicy {
+    /// Create new `RelayPolicy` that accepts everybody.
+    pub fn new() -> RelayPolicy {
+        RelayPolicy::default()
+    }
+
+    /// Add `PublicKey` to the allow-list. Once at least one key is added only
+    /// clients with keys from the allow-list are accepted.
+    pub fn allow_pk(&mut self, pk: PublicKey) {
+        self.allowed_pks.get_or_insert_with(HashSet::new).insert(pk);
+    }
+
+    /// Remove `PublicKey` from the allow-list. Returns `true` if the key was
+    /// in the allow-list. The allow-list stays enabled even if it becomes
+    /// empty.
+    pub fn disallow_pk(&mut self, pk: &PublicKey) -> bool {
+        match self.allowed_pks {
+            Some(ref mut pks) => pks.remove(pk),
+            None => false,
+        }
+    }
+
+    /// Add network to the allow-list. Once at least one network is added only
+    /// clients from networks from the allow-list are accepted.
+    pub fn allow_net(&mut self, net: IpNet) {
+        self.allowed_nets.get_or_insert_with(Vec::new).push(net);
+    }
+
+    /// Set maximum number of simultaneous connections from a single IP
+    /// address.
+    pub fn set_max_connections_per_ip(&mut self, max_connections_per_ip: usize) {
+        self.max_connections_per_ip = Some(max_connections_per_ip);
+    }
+
+    /// Get maximum number of simultaneous connections from a single IP
+    /// address.
+    pub fn max_connections_per_ip(&self) -> Option<usize> {
+        self.max_connections_per_ip
+    }
+
+    /// Set traffic budget of every client.
+    pub fn set_quota(&mut self, quota: ClientQuota) {
+        self.quota = Some(quota);
+    }
+
+    /// Get traffic budget of every client.
+    pub fn quota(&self) -> Option<ClientQuota> {
+        self.quota
+    }
+
+    /// Check if `PublicKey` is allowed by the policy.
+    pub fn is_pk_allowed(&self, pk: &PublicKey) -> bool {
+        match self.allowed_pks {
+            Some(ref pks) => pks.contains(pk),
+            None => true,
+        }
+    }
+
+    /// Check if IP address is allowed by the policy.
+    pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        match self.allowed_nets {
+            Some(ref nets) => nets.iter().any(|net| net.contains(ip)),
+            None => true,
+        }
+    }
+
+    /// Check if one more connection from IP address that already has
+    /// `connections` connections is allowed by the policy.
+    pub fn is_connection_allowed(&self, ip: IpAddr, connections: usize) -> bool {
+        let below_limit = match self.max_connections_per_ip {
+            Some(max) => connections < max,
+            None => true,
+        };
+        below_limit && self.is_ip_allowed(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use crate::toxcore::time::*;
+
+    #[test]
+    fn ip_net_parse() {
+        let net: IpNet = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.addr(), "10.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(net.prefix(), 8);
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+
+        let net: IpNet = "2001:db8::1/32".parse().unwrap();
+        assert_eq!(net.to_string(), "2001:db8::/32");
+
+        let net: IpNet = "2001:db8::1".parse().unwrap();
+        assert_eq!(net.prefix(), 128);
+    }
+
+    #[test]
+    fn ip_net_parse_invalid() {
+        let error = "10.0.0/8".parse::<IpNet>().err().unwrap();
+        assert_eq!(*error.kind(), IpNetParseErrorKind::InvalidAddress);
+        let error = "10.0.0.0/33".parse::<IpNet>().err().unwrap();
+        assert_eq!(*error.kind(), IpNetParseErrorKind::InvalidPrefix);
+        let error = "10.0.0.0/".parse::<IpNet>().err().unwrap();
+        assert_eq!(*error.kind(), IpNetParseErrorKind::InvalidPrefix);
+    }
+
+    #[test]
+    fn ip_net_contains() {
+        let net: IpNet = "192.168.0.0/16".parse().unwrap();
+        assert!(net.contains("192.168.1.2".parse().unwrap()));
+        assert!(!net.contains("192.169.1.2".parse().unwrap()));
+        // IPv4-mapped addresses are treated as IPv4
+        assert!(net.contains("::ffff:192.168.1.2".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+        let net: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(net.contains("1.2.3.4".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn default_policy_allows_everything() {
+        let policy = RelayPolicy::new();
+        assert!(policy.is_pk_allowed(&gen_keypair().0));
+        assert!(policy.is_ip_allowed("1.2.3.4".parse().unwrap()));
+        assert!(policy.is_connection_allowed("1.2.3.4".parse().unwrap(), 1000));
+        assert_eq!(policy.quota(), None);
+    }
+
+    #[test]
+    fn allow_pk() {
+        let (pk_1, _sk) = gen_keypair();
+        let (pk_2, _sk) = gen_keypair();
+        let mut policy = RelayPolicy::new();
+        policy.allow_pk(pk_1);
+        assert!(policy.is_pk_allowed(&pk_1));
+        assert!(!policy.is_pk_allowed(&pk_2));
+
+        assert!(policy.disallow_pk(&pk_1));
+        assert!(!policy.disallow_pk(&pk_1));
+        assert!(!policy.is_pk_allowed(&pk_1));
+    }
+
+    #[test]
+    fn allow_net() {
+        let mut policy = RelayPolicy::new();
+        policy.allow_net("10.0.0.0/8".parse().unwrap());
+        policy.allow_net("2001:db8::/32".parse().unwrap());
+        assert!(policy.is_ip_allowed("10.2.3.4".parse().unwrap()));
+        assert!(policy.is_ip_allowed("2001:db8::1".parse().unwrap()));
+        assert!(!policy.is_ip_allowed("1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn max_connections_per_ip() {
+        let mut policy = RelayPolicy::new();
+        policy.set_max_connections_per_ip(2);
+        let ip = "1.2.3.4".parse().unwrap();
+        assert!(policy.is_connection_allowed(ip, 0));
+        assert!(policy.is_connection_allowed(ip, 1));
+        assert!(!policy.is_connection_allowed(ip, 2));
+    }
+
+    #[test]
+    fn quota_state() {
+        let mut state = QuotaState::new(ClientQuota {
+            packets: RateLimit { burst: 3, rate: 1 },
+            bytes: RateLimit { burst: 100, rate: 50 },
+        });
+
+        let now = Instant::now();
+        let time = MutNow::new(now);
+        let clock = Clock::new_with_now(time.clone());
+        let mut enter = tokio_executor::enter().unwrap();
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(state.try_take(60));
+            // not enough bytes
+            assert!(!state.try_take(60));
+            assert!(state.try_take(40));
+            // no bytes left at all
+            assert!(!state.try_take(1));
+
+            time.set(now + Duration::from_secs(1));
+            // bytes are refilled but only two packets are left
+            assert!(state.try_take(10));
+            assert!(state.try_take(10));
+            assert!(!state.try_take(10));
+        });
+    }
+
+    #[test]
+    fn quota_packet_size_of_packets() {
+        use crate::toxcore::tcp::connection_id::ConnectionId;
+        use crate::toxcore::tcp::packet::*;
+
+        let packet = Packet::Data(Data {
+            connection_id: ConnectionId::from_index(0),
+            data: vec![42; 123],
+        });
+        assert_eq!(quota_packet_size(&packet), Some(123));
+
+        let packet = Packet::PingRequest(PingRequest { ping_id: 42 });
+        assert_eq!(quota_packet_size(&packet), None);
+    }
+}
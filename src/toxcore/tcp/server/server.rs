@@ -2,17 +2,23 @@
 */
 
 use crate::toxcore::crypto_core::*;
+use crate::toxcore::dissect::TapTx;
+use crate::toxcore::events::*;
 use crate::toxcore::onion::packet::InnerOnionResponse;
+use crate::toxcore::stats::{Direction, Transport};
+use crate::toxcore::tcp::server::admin::{ClientInfo, LinkInfo};
 use crate::toxcore::tcp::server::client::Client;
+use crate::toxcore::tcp::server::cluster::{ClusterBackend, ClusterMessage};
+use crate::toxcore::tcp::server::policy::*;
 use crate::toxcore::tcp::connection_id::ConnectionId;
 use crate::toxcore::tcp::links::*;
 use crate::toxcore::tcp::packet::*;
+use crate::toxcore::time::*;
+use crate::toxcore::utils::{HashMap, HashSet};
 
 use std::io::{Error, ErrorKind};
-use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Instant;
 
 use futures::{Sink, Stream, Future, future, stream};
 use futures::future::Either;
@@ -35,12 +41,36 @@ pub struct Server {
     state: Arc<RwLock<ServerState>>,
     // None if the server is not responsible to handle OnionRequests
     onion_sink: Option<mpsc::Sender<(OnionRequest, SocketAddr)>>,
+    // Sink to send events about connected and disconnected clients
+    event_tx: Option<EventTx>,
+    // Sink to send decrypted packets of all connections to
+    pub(crate) tap: Option<TapTx>,
+    // Policy to decide which clients are accepted and how much traffic they
+    // can send, None if everybody is accepted
+    policy: Option<Arc<RelayPolicy>>,
+    // Backend to share clients with other relays of the cluster, None if
+    // the server is standalone
+    cluster: Option<Arc<dyn ClusterBackend>>,
 }
 
 #[derive(Default)]
 struct ServerState {
     pub connected_clients: HashMap<PublicKey, Client>,
     pub keys_by_addr: HashMap<(IpAddr, /*port*/ u16), PublicKey>,
+    /// Clients that are not allowed to connect
+    pub banned: HashSet<PublicKey>,
+    /// Whether the server is shut down and doesn't accept new clients
+    pub shutting_down: bool,
+}
+
+impl ServerState {
+    /// Get the number of clients connected from `ip_addr` except the client
+    /// with `except_pk`.
+    fn connections_count(&self, ip_addr: IpAddr, except_pk: Option<&PublicKey>) -> usize {
+        self.keys_by_addr.iter()
+            .filter(|((ip, _port), pk)| *ip == ip_addr && Some(*pk) != except_pk)
+            .count()
+    }
 }
 
 
@@ -55,12 +85,80 @@ impl Server {
     pub fn set_udp_onion_sink(&mut self, onion_sink: mpsc::Sender<(OnionRequest, SocketAddr)>) {
         self.onion_sink = Some(onion_sink)
     }
+    /** Set sink to send events about connected and disconnected clients.
+    */
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx)
+    }
+    /** Set sink to send decrypted packets of all connections to. The sink is
+    attached to codecs of new connections.
+    */
+    pub fn set_tap_sink(&mut self, tap: TapTx) {
+        self.tap = Some(tap)
+    }
+    /** Set policy to decide which clients are accepted and how much traffic
+    they can send.
+    */
+    pub fn set_policy(&mut self, policy: RelayPolicy) {
+        self.policy = Some(Arc::new(policy))
+    }
+    /** Set backend to share connected clients with other relays of the
+    cluster. Messages received by the backend should be passed to
+    `Server::handle_cluster_message`.
+    */
+    pub fn set_cluster<C: ClusterBackend + 'static>(&mut self, cluster: C) {
+        self.cluster = Some(Arc::new(cluster))
+    }
+    /** Check if a new connection from `ip_addr` is allowed by the policy.
+    Can be used to drop connections before the handshake.
+    */
+    pub fn is_connection_allowed(&self, ip_addr: IpAddr) -> bool {
+        if let Some(ref policy) = self.policy {
+            let state = self.state.read();
+            policy.is_connection_allowed(ip_addr, state.connections_count(ip_addr, None))
+        } else {
+            true
+        }
+    }
+    /** Get the number of connected clients.
+    */
+    pub fn connected_clients_count(&self) -> usize {
+        self.state.read().connected_clients.len()
+    }
     /** Insert the client into `connected_clients`. If `connected_clients`
-    contains a client with the same pk it will be terminated.
+    contains a client with the same pk it will be terminated. Returns
+    `PermissionDenied` error if the client is not allowed by the policy.
     */
-    pub fn insert(&self, client: Client) -> impl Future<Item = (), Error = Error> + Send {
+    pub fn insert(&self, mut client: Client) -> impl Future<Item = (), Error = Error> + Send {
         let mut state = self.state.write();
 
+        if state.shutting_down {
+            return Either::A(future::err(
+                Error::new(ErrorKind::PermissionDenied,
+                    "Server is shutting down"
+            )))
+        }
+        if state.banned.contains(&client.pk()) {
+            debug!("TCP client {:?} from {} is banned", client.pk(), client.ip_addr());
+            return Either::A(future::err(
+                Error::new(ErrorKind::PermissionDenied,
+                    "Client is banned"
+            )))
+        }
+        if let Some(ref policy) = self.policy {
+            let connections_count = state.connections_count(client.ip_addr(), Some(&client.pk()));
+            if !policy.is_pk_allowed(&client.pk()) || !policy.is_connection_allowed(client.ip_addr(), connections_count) {
+                debug!("TCP client {:?} from {} is denied by the policy", client.pk(), client.ip_addr());
+                return Either::A(future::err(
+                    Error::new(ErrorKind::PermissionDenied,
+                        "Client is not allowed by the policy"
+                )))
+            }
+            if let Some(quota) = policy.quota() {
+                client.set_quota(quota);
+            }
+        }
+
         let shutdown_future = if state.connected_clients.contains_key(&client.pk()) {
             Either::A(self.shutdown_client_inner(&client.pk(), &mut state))
         } else {
@@ -69,15 +167,29 @@ impl Server {
 
         state.keys_by_addr
             .insert((client.ip_addr(), client.port()), client.pk());
+        if let Some(ref cluster) = self.cluster {
+            cluster.register(&client.pk());
+        }
+        emit(&self.event_tx, Event::TcpClientConnected {
+            pk: client.pk(),
+            saddr: SocketAddr::new(client.ip_addr(), client.port()),
+        });
         state.connected_clients
             .insert(client.pk(), client);
 
-        shutdown_future
+        Either::B(shutdown_future)
     }
     /** The main processing function. Call in on each incoming packet from connected and
     handshaked client.
     */
     pub fn handle_packet(&self, pk: &PublicKey, packet: Packet) -> impl Future<Item = (), Error = Error> + Send {
+        if let Some(size) = quota_packet_size(&packet) {
+            if !self.try_take_quota(pk, size) {
+                trace!("TCP client {:?} exceeded its quota, dropping packet", pk);
+                return Box::new(future::ok(())) as Box<dyn Future<Item = _, Error = _> + Send>
+            }
+        }
+
         // TODO: use anonymous sum types when rust has them
         // https://github.com/rust-lang/rfcs/issues/294
         match packet {
@@ -136,6 +248,128 @@ impl Server {
         Either::B(self.shutdown_client_inner(pk, &mut state))
     }
 
+    /** Get information about all connected clients.
+    */
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let state = self.state.read();
+        state.connected_clients.values()
+            .map(|client| {
+                let stats = client.stats();
+                ClientInfo {
+                    pk: client.pk(),
+                    ip_addr: client.ip_addr(),
+                    port: client.port(),
+                    connected_since: client.connected_since(),
+                    links_count: client.links().iter_links().count(),
+                    packets_received: stats.counters.incoming(),
+                    packets_sent: stats.counters.outgoing(),
+                    bytes_received: stats.packets.bytes(Transport::Tcp, Direction::Incoming),
+                    bytes_sent: stats.packets.bytes(Transport::Tcp, Direction::Outgoing),
+                }
+            })
+            .collect()
+    }
+    /** Get all links of all connected clients, i.e. the graph of clients
+    that requested connection to each other.
+    */
+    pub fn links(&self) -> Vec<LinkInfo> {
+        let state = self.state.read();
+        state.connected_clients.values()
+            .flat_map(|client| {
+                let links = client.links();
+                links.iter_links().filter_map(move |link|
+                    links.id_by_pk(&link.pk).map(|index| LinkInfo {
+                        pk: client.pk(),
+                        linked_pk: link.pk,
+                        connection_id: ConnectionId::from_index(index),
+                        status: link.status,
+                    })
+                )
+            })
+            .collect()
+    }
+    /** Disconnect the client by pk. Clients linked to it will get
+    `DisconnectNotification`. The client can connect again.
+    */
+    pub fn kick(&self, pk: &PublicKey) -> impl Future<Item = (), Error = Error> + Send {
+        let mut state = self.state.write();
+        self.shutdown_client_inner(pk, &mut state)
+    }
+    /** Ban the client by pk and disconnect it if it's connected. Banned
+    clients are not allowed to connect until they are unbanned.
+    */
+    pub fn ban(&self, pk: &PublicKey) -> impl Future<Item = (), Error = Error> + Send {
+        let mut state = self.state.write();
+        state.banned.insert(*pk);
+        if state.connected_clients.contains_key(pk) {
+            Either::A(self.shutdown_client_inner(pk, &mut state))
+        } else {
+            Either::B(future::ok(()))
+        }
+    }
+    /** Allow the banned client to connect again. Returns `false` if the
+    client wasn't banned.
+    */
+    pub fn unban(&self, pk: &PublicKey) -> bool {
+        self.state.write().banned.remove(pk)
+    }
+    /** Get the list of banned clients.
+    */
+    pub fn banned(&self) -> Vec<PublicKey> {
+        self.state.read().banned.iter().cloned().collect()
+    }
+    /** Check whether the server is shut down.
+    */
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.read().shutting_down
+    }
+    /** Gracefully shutdown the server. All clients get
+    `DisconnectNotification` for every online link and are removed from the
+    list of connected clients. New clients are not accepted after this call.
+    Connections are closed after their writers send all queued packets.
+    */
+    pub fn shutdown(&self) -> impl Future<Item = (), Error = Error> + Send {
+        let mut state = self.state.write();
+        state.shutting_down = true;
+        state.keys_by_addr.clear();
+        let clients = state.connected_clients.drain()
+            .map(|(_pk, client)| client)
+            .collect::<Vec<_>>();
+
+        let notifications = clients.iter()
+            .flat_map(|client| {
+                emit(&self.event_tx, Event::TcpClientDisconnected { pk: client.pk() });
+                if let Some(ref cluster) = self.cluster {
+                    cluster.unregister(&client.pk());
+                }
+                let links = client.links();
+                links.iter_links()
+                    .filter(|link| link.status == LinkStatus::Online)
+                    .filter_map(|link| links.id_by_pk(&link.pk).map(|index| (link.pk, index)))
+                    .map(|(linked_pk, index)| {
+                        // linked clients connected to other relays can't
+                        // get notifications from the disconnected client
+                        let cluster_notification = self.send_to_cluster(ClusterMessage::DisconnectNotification {
+                            from: client.pk(),
+                            to: linked_pk,
+                        });
+                        client.send_disconnect_notification(ConnectionId::from_index(index))
+                            .join(cluster_notification)
+                            .map(|_| ())
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        stream::futures_unordered(notifications).for_each(Ok)
+            // drop clients only when all notifications are queued so that
+            // writers are finished after sending them
+            .then(move |res| {
+                drop(clients);
+                res
+            })
+    }
+
     /** Actual shutdown is done here.
     */
     fn shutdown_client_inner(&self, pk: &PublicKey, state: &mut ServerState) -> impl Future<Item = (), Error = Error> + Send {
@@ -150,6 +384,10 @@ impl Server {
         };
 
         state.keys_by_addr.remove(&(client_a.ip_addr(), client_a.port()));
+        emit(&self.event_tx, Event::TcpClientDisconnected { pk: *pk });
+        if let Some(ref cluster) = self.cluster {
+            cluster.unregister(pk);
+        }
         let links = client_a.links();
         let notifications = links.iter_links()
             .map(|link| {
@@ -165,20 +403,62 @@ impl Server {
                                 // they are linked, we should notify client_b
                                 // link from client_b.links should be downgraded
                                 client_b.links_mut().downgrade(a_id_in_client_b);
-                                Either::B(client_b.send_disconnect_notification(ConnectionId::from_index(a_id_in_client_b)))
+                                Either::B(Either::A(client_b.send_disconnect_notification(ConnectionId::from_index(a_id_in_client_b))))
                             } else {
                                 // No a_id_in_client_b
                                 Either::A(future::ok(()))
                             }
                         } else  {
-                            // client_b is not connected to the server
-                            Either::A(future::ok(()))
+                            // client_b is not connected to the server but
+                            // can be connected to another relay of the cluster
+                            Either::B(Either::B(self.send_to_cluster(ClusterMessage::DisconnectNotification {
+                                from: *pk,
+                                to: client_b_pk,
+                            })))
                         }
                     }
                 }
             });
         Either::B(stream::futures_unordered(notifications).for_each(Ok))
     }
+    /** Take a packet with `size` bytes of payload from the traffic budget of
+    the client. Returns `false` if the budget is exhausted.
+    */
+    fn try_take_quota(&self, pk: &PublicKey, size: usize) -> bool {
+        if self.policy.as_ref().and_then(|policy| policy.quota()).is_none() {
+            return true
+        }
+        let mut state = self.state.write();
+        match state.connected_clients.get_mut(pk) {
+            Some(client) => client.try_take_quota(size),
+            // the error will be returned by the packet handler
+            None => true,
+        }
+    }
+    /** Send the message to the relay of the cluster the destination client
+    is connected to. Errors are ignored since the destination client can be
+    disconnected from the cluster at any moment.
+    */
+    fn send_to_cluster(&self, message: ClusterMessage) -> impl Future<Item = (), Error = Error> + Send {
+        if let Some(ref cluster) = self.cluster {
+            Either::A(cluster.send(message).then(|res| {
+                if let Err(e) = res {
+                    trace!("Failed to send message to the cluster: {}", e);
+                }
+                Ok(())
+            }))
+        } else {
+            Either::B(future::ok(()))
+        }
+    }
+    /** Check if the client is connected to another relay of the cluster.
+    */
+    fn is_remote(&self, pk: &PublicKey) -> bool {
+        match self.cluster {
+            Some(ref cluster) => cluster.is_remote(pk),
+            None => false,
+        }
+    }
     // Here start the impl of `handle_***` methods
 
     fn handle_route_request(&self, pk: &PublicKey, packet: &RouteRequest) -> impl Future<Item = (), Error = Error> + Send {
@@ -218,6 +498,13 @@ impl Server {
         // get client_b
         let client_b = if let Some(client) = state.connected_clients.get(&packet.pk) {
             client
+        } else if self.is_remote(&packet.pk) {
+            // client_b is connected to another relay of the cluster, it will
+            // send us ConnectNotification if client_b is linked to client_a
+            return Box::new(client_a_route_response
+                .join(self.send_to_cluster(ClusterMessage::RouteRequest { from: *pk, to: packet.pk }))
+                .map(|_| ())
+            )
         } else {
             // send RouteResponse only to current client
             return Box::new(client_a_route_response)
@@ -306,8 +593,11 @@ impl Server {
                     client
                 } else  {
                     // client_b is not connected to the server
-                    // so ignore DisconnectNotification
-                    return Either::A( future::ok(()) )
+                    // so forward DisconnectNotification to the cluster
+                    return Either::B(Either::B(self.send_to_cluster(ClusterMessage::DisconnectNotification {
+                        from: *pk,
+                        to: client_b_pk,
+                    })))
                 };
                 let a_id_in_client_b = if let Some(id) = client_b.links().id_by_pk(pk) {
                     id
@@ -318,7 +608,7 @@ impl Server {
                 // it is linked, we should notify client_b
                 // link from client_b.links should be downgraded
                 client_b.links_mut().downgrade(a_id_in_client_b);
-                Either::B(client_b.send_disconnect_notification(ConnectionId::from_index(a_id_in_client_b)))
+                Either::B(Either::A(client_b.send_disconnect_notification(ConnectionId::from_index(a_id_in_client_b))))
             }
         }
     }
@@ -349,7 +639,7 @@ impl Server {
         let mut state = self.state.write();
         if let Some(client_a) = state.connected_clients.get_mut(pk) {
             if packet.ping_id == client_a.ping_id() {
-                client_a.set_last_pong_resp(Instant::now());
+                client_a.set_last_pong_resp(clock_now());
 
                 future::ok(())
             } else {
@@ -373,10 +663,15 @@ impl Server {
         }
         let state = self.state.read();
         if let Some(client_b) = state.connected_clients.get(&packet.destination_pk) {
-            Either::B(client_b.send_oob(pk, packet.data))
+            Either::B(Either::A(client_b.send_oob(pk, packet.data)))
         } else {
-            // Do nothing because client_b is not connected to server
-            Either::A( future::ok(()) )
+            // client_b is not connected to the server but can be connected
+            // to another relay of the cluster
+            Either::B(Either::B(self.send_to_cluster(ClusterMessage::Oob {
+                from: *pk,
+                to: packet.destination_pk,
+                data: packet.data,
+            })))
         }
     }
     fn handle_oob_receive(&self, _pk: &PublicKey, _packet: &OobReceive) -> impl Future<Item = (), Error = Error> + Send {
@@ -462,8 +757,13 @@ impl Server {
                 let client_b = if let Some(client) = state.connected_clients.get(&client_b_pk) {
                     client
                 } else  {
-                    // Do nothing because client_b is not connected to server
-                    return Either::A( future::ok(()) )
+                    // client_b is not connected to the server but can be
+                    // connected to another relay of the cluster
+                    return Either::B(Either::B(self.send_to_cluster(ClusterMessage::Data {
+                        from: *pk,
+                        to: client_b_pk,
+                        data: packet.data,
+                    })))
                 };
                 let a_id_in_client_b = if let Some(id) = client_b.links().id_by_pk(pk) {
                     id
@@ -472,10 +772,108 @@ impl Server {
                     return Either::A( future::ok(()) )
                 };
                 // it is linked, we should send data to client_b
-                Either::B(client_b.send_data(ConnectionId::from_index(a_id_in_client_b), packet.data))
+                Either::B(Either::A(client_b.send_data(ConnectionId::from_index(a_id_in_client_b), packet.data)))
             }
         }
     }
+    /** Handle the message received from another relay of the cluster. The
+    destination client of the message should be connected to this server.
+    */
+    pub fn handle_cluster_message(&self, message: ClusterMessage) -> impl Future<Item = (), Error = Error> + Send {
+        match message {
+            ClusterMessage::RouteRequest { from, to } =>
+                Box::new(self.handle_cluster_route_request(&from, &to)) as Box<dyn Future<Item = _, Error = _> + Send>,
+            ClusterMessage::ConnectNotification { from, to } =>
+                Box::new(self.handle_cluster_connect_notification(&from, &to)),
+            ClusterMessage::DisconnectNotification { from, to } =>
+                Box::new(self.handle_cluster_disconnect_notification(&from, &to)),
+            ClusterMessage::Data { from, to, data } =>
+                Box::new(self.handle_cluster_data(&from, &to, data)),
+            ClusterMessage::Oob { from, to, data } =>
+                Box::new(self.handle_cluster_oob(&from, &to, data)),
+        }
+    }
+    fn handle_cluster_route_request(&self, from: &PublicKey, to: &PublicKey) -> impl Future<Item = (), Error = Error> + Send {
+        let mut state = self.state.write();
+
+        let client_b = if let Some(client) = state.connected_clients.get_mut(to) {
+            client
+        } else {
+            // client_b is already disconnected
+            return Either::A(future::ok(()))
+        };
+        let a_id_in_client_b = if let Some(id) = client_b.links().id_by_pk(from) {
+            id
+        } else {
+            // client_b has not sent RouteRequest yet to connect to client_a,
+            // client_a will be notified when it does
+            return Either::A(future::ok(()))
+        };
+
+        // they are both linked, notify client_b and the relay of client_a
+        let client_b_notification = if client_b.links().by_id(a_id_in_client_b).map(|link| link.status) == Some(LinkStatus::Online) {
+            // the link is already online, client_a has repeated RouteRequest
+            Either::B(future::ok(()))
+        } else {
+            client_b.links_mut().upgrade(a_id_in_client_b);
+            Either::A(client_b.send_connect_notification(ConnectionId::from_index(a_id_in_client_b)))
+        };
+        let client_a_notification = self.send_to_cluster(ClusterMessage::ConnectNotification { from: *to, to: *from });
+
+        Either::B(client_b_notification.join(client_a_notification).map(|_| ()))
+    }
+    fn handle_cluster_connect_notification(&self, from: &PublicKey, to: &PublicKey) -> impl Future<Item = (), Error = Error> + Send {
+        let mut state = self.state.write();
+
+        if let Some(client) = state.connected_clients.get_mut(to) {
+            if let Some(id) = client.links().id_by_pk(from) {
+                if client.links().by_id(id).map(|link| link.status) == Some(LinkStatus::Registered) {
+                    client.links_mut().upgrade(id);
+                    return Either::A(client.send_connect_notification(ConnectionId::from_index(id)))
+                }
+            }
+        }
+
+        Either::B(future::ok(()))
+    }
+    fn handle_cluster_disconnect_notification(&self, from: &PublicKey, to: &PublicKey) -> impl Future<Item = (), Error = Error> + Send {
+        let mut state = self.state.write();
+
+        if let Some(client) = state.connected_clients.get_mut(to) {
+            if let Some(id) = client.links().id_by_pk(from) {
+                if client.links().by_id(id).map(|link| link.status) == Some(LinkStatus::Online) {
+                    client.links_mut().downgrade(id);
+                    return Either::A(client.send_disconnect_notification(ConnectionId::from_index(id)))
+                }
+            }
+        }
+
+        Either::B(future::ok(()))
+    }
+    fn handle_cluster_data(&self, from: &PublicKey, to: &PublicKey, data: Vec<u8>) -> impl Future<Item = (), Error = Error> + Send {
+        let state = self.state.read();
+
+        if let Some(client) = state.connected_clients.get(to) {
+            if let Some(id) = client.links().id_by_pk(from) {
+                if client.links().by_id(id).map(|link| link.status) == Some(LinkStatus::Online) {
+                    return Either::A(client.send_data(ConnectionId::from_index(id), data))
+                }
+            }
+        }
+
+        // Do nothing because the clients are not linked
+        Either::B(future::ok(()))
+    }
+    fn handle_cluster_oob(&self, from: &PublicKey, to: &PublicKey, data: Vec<u8>) -> impl Future<Item = (), Error = Error> + Send {
+        let state = self.state.read();
+
+        if let Some(client) = state.connected_clients.get(to) {
+            Either::A(client.send_oob(from, data))
+        } else {
+            // Do nothing because client_b is already disconnected
+            Either::B(future::ok(()))
+        }
+    }
     /** Remove timedout connected clients
     */
     fn remove_timedout_clients(&self, state: &mut ServerState) -> impl Future<Item = (), Error = Error> + Send {
@@ -516,10 +914,12 @@ impl Server {
 mod tests {
     use super::*;
 
+    use crate::toxcore::dht::server::rate_limiter::RateLimit;
     use crate::toxcore::ip_port::*;
     use crate::toxcore::onion::packet::*;
     use crate::toxcore::tcp::server::{Client, Server};
     use crate::toxcore::tcp::server::client::*;
+    use crate::toxcore::tcp::server::cluster::InMemoryCluster;
 
     use futures::sync::mpsc;
     use futures::{Stream, Future};
@@ -529,8 +929,6 @@ mod tests {
     use tokio_executor;
     use tokio_timer::clock::*;
 
-    use crate::toxcore::time::*;
-
     #[test]
     fn server_is_clonable() {
         crypto_init().unwrap();
@@ -551,6 +949,30 @@ mod tests {
         (client, rx)
     }
 
+    #[test]
+    fn insert_and_shutdown_events() {
+        let mut server = Server::new();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        server.set_event_sink(event_tx);
+
+        let saddr = "1.2.3.4:12345".parse().unwrap();
+        let (client, _rx) = create_random_client(saddr);
+        let client_pk = client.pk();
+
+        server.insert(client).wait().unwrap();
+        assert_eq!(server.connected_clients_count(), 1);
+        server.shutdown_client(&client_pk, saddr.ip(), saddr.port()).wait().unwrap();
+        assert_eq!(server.connected_clients_count(), 0);
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(server);
+
+        assert_eq!(event_rx.collect().wait().unwrap(), vec![
+            Event::TcpClientConnected { pk: client_pk, saddr },
+            Event::TcpClientDisconnected { pk: client_pk },
+        ]);
+    }
+
     #[test]
     fn normal_communication_scenario() {
         let server = Server::new();
@@ -1664,4 +2086,218 @@ mod tests {
         assert!(!server.state.read().connected_clients.contains_key(&pk_2));
         assert!(server.state.read().connected_clients.contains_key(&pk_3));
     }
+    #[test]
+    fn insert_denied_by_pk() {
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        let (client_2, _rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+
+        let mut policy = RelayPolicy::new();
+        policy.allow_pk(client_pk_1);
+        let mut server = Server::new();
+        server.set_policy(policy);
+
+        server.insert(client_1).wait().unwrap();
+        let error = server.insert(client_2).wait().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+        assert!(server.state.read().connected_clients.contains_key(&client_pk_1));
+        assert!(!server.state.read().connected_clients.contains_key(&client_pk_2));
+        assert!(server.state.read().keys_by_addr.get(&("1.2.3.5".parse().unwrap(), 12345)).is_none());
+    }
+    #[test]
+    fn insert_denied_by_net() {
+        let mut policy = RelayPolicy::new();
+        policy.allow_net("1.2.3.0/24".parse().unwrap());
+        let mut server = Server::new();
+        server.set_policy(policy);
+
+        assert!(server.is_connection_allowed("1.2.3.4".parse().unwrap()));
+        assert!(!server.is_connection_allowed("1.2.4.4".parse().unwrap()));
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        server.insert(client_1).wait().unwrap();
+        let (client_2, _rx_2) = create_random_client("1.2.4.4:12345".parse().unwrap());
+        let error = server.insert(client_2).wait().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(server.connected_clients_count(), 1);
+    }
+    #[test]
+    fn insert_max_connections_per_ip() {
+        let mut policy = RelayPolicy::new();
+        policy.set_max_connections_per_ip(2);
+        let mut server = Server::new();
+        server.set_policy(policy);
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1).wait().unwrap();
+        let (client_2, _rx_2) = create_random_client("1.2.3.4:12346".parse().unwrap());
+        server.insert(client_2).wait().unwrap();
+
+        assert!(!server.is_connection_allowed("1.2.3.4".parse().unwrap()));
+        assert!(server.is_connection_allowed("1.2.3.5".parse().unwrap()));
+
+        let (client_3, _rx_3) = create_random_client("1.2.3.4:12347".parse().unwrap());
+        let error = server.insert(client_3).wait().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+        // reconnection of the same client replaces the old connection
+        let (tx, _rx) = mpsc::channel(32);
+        let client_1 = Client::new(tx, &client_pk_1, "1.2.3.4".parse().unwrap(), 12348);
+        server.insert(client_1).wait().unwrap();
+        assert_eq!(server.connected_clients_count(), 2);
+    }
+    #[test]
+    fn handle_oob_send_quota_exceeded() {
+        let mut policy = RelayPolicy::new();
+        policy.set_quota(ClientQuota {
+            packets: RateLimit { burst: 2, rate: 1 },
+            bytes: RateLimit { burst: 2048, rate: 1024 },
+        });
+        let mut server = Server::new();
+        server.set_policy(policy);
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        let (client_2, rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+
+        let now = Instant::now();
+        let clock = Clock::new_with_now(ConstNow(now));
+        let mut enter = tokio_executor::enter().unwrap();
+
+        with_default(&clock, &mut enter, |_| {
+            server.insert(client_1).wait().unwrap();
+            server.insert(client_2).wait().unwrap();
+
+            // emulate send OobSend from client_1 3 times
+            for i in 0 .. 3 {
+                server.handle_packet(&client_pk_1, Packet::OobSend(
+                    OobSend { destination_pk: client_pk_2, data: vec![i; 100] }
+                )).wait().unwrap();
+            }
+
+            // ping requests are not limited
+            server.handle_packet(&client_pk_1, Packet::PingRequest(
+                PingRequest { ping_id: 42 }
+            )).wait().unwrap();
+        });
+
+        // only 2 packets should be sent to client_2
+        drop(server);
+        let packets = rx_2.collect().wait().unwrap();
+        assert_eq!(packets, vec![
+            Packet::OobReceive(OobReceive { sender_pk: client_pk_1, data: vec![0; 100] }),
+            Packet::OobReceive(OobReceive { sender_pk: client_pk_1, data: vec![1; 100] }),
+        ]);
+    }
+
+    /// Pass the next message from the cluster node receiver to the server.
+    fn deliver_cluster_message(server: &Server, rx: mpsc::UnboundedReceiver<ClusterMessage>) -> mpsc::UnboundedReceiver<ClusterMessage> {
+        let (message, rx) = rx.into_future().wait().unwrap();
+        server.handle_cluster_message(message.unwrap()).wait().unwrap();
+        rx
+    }
+    #[test]
+    fn cluster_link_data_and_disconnect() {
+        let cluster = InMemoryCluster::new();
+        let (node_1, cluster_rx_1) = cluster.join();
+        let (node_2, cluster_rx_2) = cluster.join();
+        let mut server_1 = Server::new();
+        server_1.set_cluster(node_1);
+        let mut server_2 = Server::new();
+        server_2.set_cluster(node_2);
+
+        let (client_1, rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        let client_addr_1 = client_1.ip_addr();
+        let client_port_1 = client_1.port();
+        server_1.insert(client_1).wait().unwrap();
+        let (client_2, rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server_2.insert(client_2).wait().unwrap();
+
+        // client_1 links client_2 which is connected to another relay
+        server_1.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+        let (packet, rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::RouteResponse(
+            RouteResponse { pk: client_pk_2, connection_id: ConnectionId::from_index(0) }
+        ));
+        // client_2 is not linked yet so nothing happens
+        let cluster_rx_2 = deliver_cluster_message(&server_2, cluster_rx_2);
+
+        // client_2 links client_1
+        server_2.handle_packet(&client_pk_2, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_1 }
+        )).wait().unwrap();
+        let (packet, rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::RouteResponse(
+            RouteResponse { pk: client_pk_1, connection_id: ConnectionId::from_index(0) }
+        ));
+        let cluster_rx_1 = deliver_cluster_message(&server_1, cluster_rx_1);
+        let (packet, rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::ConnectNotification(
+            ConnectNotification { connection_id: ConnectionId::from_index(0) }
+        ));
+        let cluster_rx_2 = deliver_cluster_message(&server_2, cluster_rx_2);
+        let (packet, rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::ConnectNotification(
+            ConnectNotification { connection_id: ConnectionId::from_index(0) }
+        ));
+
+        // data is forwarded to another relay
+        server_1.handle_packet(&client_pk_1, Packet::Data(
+            Data { connection_id: ConnectionId::from_index(0), data: vec![13, 42] }
+        )).wait().unwrap();
+        let cluster_rx_2 = deliver_cluster_message(&server_2, cluster_rx_2);
+        let (packet, rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::Data(
+            Data { connection_id: ConnectionId::from_index(0), data: vec![13, 42] }
+        ));
+
+        // client_2 is notified when client_1 disconnects
+        server_1.shutdown_client(&client_pk_1, client_addr_1, client_port_1).wait().unwrap();
+        let _cluster_rx_2 = deliver_cluster_message(&server_2, cluster_rx_2);
+        let (packet, _rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::DisconnectNotification(
+            DisconnectNotification { connection_id: ConnectionId::from_index(0) }
+        ));
+
+        drop(rx_1);
+        drop(server_1);
+        drop(server_2);
+        drop(cluster);
+        assert!(cluster_rx_1.collect().wait().unwrap().is_empty());
+    }
+    #[test]
+    fn cluster_oob_send() {
+        let cluster = InMemoryCluster::new();
+        let (node_1, _cluster_rx_1) = cluster.join();
+        let (node_2, cluster_rx_2) = cluster.join();
+        let mut server_1 = Server::new();
+        server_1.set_cluster(node_1);
+        let mut server_2 = Server::new();
+        server_2.set_cluster(node_2);
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server_1.insert(client_1).wait().unwrap();
+        let (client_2, rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server_2.insert(client_2).wait().unwrap();
+
+        server_1.handle_packet(&client_pk_1, Packet::OobSend(
+            OobSend { destination_pk: client_pk_2, data: vec![13; 1024] }
+        )).wait().unwrap();
+        let _cluster_rx_2 = deliver_cluster_message(&server_2, cluster_rx_2);
+
+        let (packet, _rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::OobReceive(
+            OobReceive { sender_pk: client_pk_1, data: vec![13; 1024] }
+        ));
+    }
 }
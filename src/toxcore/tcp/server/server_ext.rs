@@ -2,6 +2,7 @@
 */
 
 use std::io::{Error as IoError};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
@@ -20,6 +21,7 @@ use crate::toxcore::crypto_core::*;
 use crate::toxcore::tcp::codec::{DecodeError, EncodeError, Codec};
 use crate::toxcore::tcp::handshake::make_server_handshake;
 use crate::toxcore::tcp::server::{Client, Server};
+use crate::toxcore::tcp::transport;
 use crate::toxcore::stats::*;
 
 /// Interval in seconds for Tcp Ping sender
@@ -65,6 +67,13 @@ pub enum ConnectionError {
         #[fail(cause)]
         error: IoError,
     },
+    /// Error indicates that we couldn't get local address
+    #[fail(display = "Failed to get local address: {}", error)]
+    LocalAddrError {
+        /// Local address error
+        #[fail(cause)]
+        error: IoError,
+    },
     /// Sending packet error
     #[fail(display = "Failed to send TCP packet: {}", error)]
     SendPacketError {
@@ -103,6 +112,12 @@ pub enum ConnectionError {
         #[fail(cause)]
         error: IoError
     },
+    /// Connection is not allowed by the relay policy
+    #[fail(display = "Connection from {} is not allowed by the policy", addr)]
+    ConnectionDeniedError {
+        /// Address of the client
+        addr: SocketAddr,
+    },
 }
 
 /// Extension trait for running TCP server on incoming `TcpStream` and ping sender
@@ -111,7 +126,8 @@ pub trait ServerExt {
     /// `tokio::spawn` inside so it should be executed via tokio to be able to
     /// get tokio default executor.
     fn run(self: Self, listner: TcpListener, dht_sk: SecretKey, stats: Stats, connections_limit: usize) -> Box<Future<Item = (), Error = ServerRunError> + Send>;
-    /// Running TCP server on incoming `TcpStream`
+    /// Running TCP server on incoming `TcpStream`. Raw TCP and WebSocket
+    /// transports are detected automatically.
     fn run_connection(self: Self, stream: TcpStream, dht_sk: SecretKey, stats: Stats) -> Box<Future<Item = (), Error = ConnectionError> + Send>;
 }
 
@@ -124,7 +140,9 @@ impl ServerExt for Server {
         let connections_future = listner.incoming()
             .map_err(|error| ServerRunError::IncomingError { error })
             .for_each(move |stream| {
-                if connections_count.load(Ordering::SeqCst) < connections_limit {
+                if self_c.is_shutting_down() {
+                    trace!("Tcp server is shutting down, dropping new connection");
+                } else if connections_count.load(Ordering::SeqCst) < connections_limit {
                     connections_count.fetch_add(1, Ordering::SeqCst);
                     let connections_count_c = connections_count.clone();
                     tokio::spawn(
@@ -168,10 +186,29 @@ impl ServerExt for Server {
                 error
             })),
         };
+        let local_addr = match stream.local_addr() {
+            Ok(addr) => addr,
+            Err(error) => return Box::new(future::err(ConnectionError::LocalAddrError {
+                error
+            })),
+        };
 
         debug!("A new TCP client connected from {}", addr);
 
-        let register_client = (make_server_handshake(stream, dht_sk.clone()))
+        if !self.is_connection_allowed(addr.ip()) {
+            debug!("TCP connection from {} is denied by the policy", addr);
+            return Box::new(future::err(ConnectionError::ConnectionDeniedError {
+                addr
+            }));
+        }
+
+        let register_client = transport::accept(stream)
+            .and_then(move |stream| {
+                if stream.is_websocket() {
+                    debug!("TCP client from {} uses WebSocket transport", addr);
+                }
+                make_server_handshake(stream, dht_sk)
+            })
             .timeout(Duration::from_secs(TCP_HANDSHAKE_TIMEOUT))
             .map_err(|error| ConnectionError::ServerHandshakeError { error })
             .map(|(stream, channel, client_pk)| {
@@ -181,11 +218,18 @@ impl ServerExt for Server {
 
         let server_c = self.clone();
         let process = register_client.and_then(move |(stream, channel, client_pk)| {
-            let secure_socket = Framed::new(stream, Codec::new(channel, stats));
-            let (to_client, from_client) = secure_socket.split();
             let (to_client_tx, to_client_rx) = mpsc::channel(SERVER_CHANNEL_SIZE);
+            let client = Client::new(to_client_tx, &client_pk, addr.ip(), addr.port());
+
+            let mut codec = Codec::new(channel, stats);
+            codec.set_connection_stats(client.stats());
+            if let Some(ref tap) = server_c.tap {
+                codec.set_tap_sink(tap.clone(), local_addr, addr);
+            }
+            let secure_socket = Framed::new(stream, codec);
+            let (to_client, from_client) = secure_socket.split();
 
-            let insert_future = server_c.insert(Client::new(to_client_tx, &client_pk, addr.ip(), addr.port()));
+            let insert_future = server_c.insert(client);
 
             let server_c_c = server_c.clone();
             // processor = for each Packet from client process it
@@ -241,6 +285,7 @@ mod tests {
     use crate::toxcore::tcp::codec::Codec;
     use crate::toxcore::tcp::handshake::make_client_handshake;
     use crate::toxcore::tcp::packet::{Packet, PingRequest, PongResponse};
+    use crate::toxcore::tcp::server::RelayPolicy;
 
     use tokio_executor;
     use tokio_timer::clock::*;
@@ -266,6 +311,9 @@ mod tests {
         format!("{}", ConnectionError::PeerAddrError {
             error: IoError::new(IoErrorKind::Other, "io error"),
         });
+        format!("{}", ConnectionError::LocalAddrError {
+            error: IoError::new(IoErrorKind::Other, "io error"),
+        });
         format!("{}", ConnectionError::SendPacketError {
             error: EncodeError::IoError {
                 error: IoError::new(IoErrorKind::Other, "io error"),
@@ -283,6 +331,9 @@ mod tests {
         format!("{}", ConnectionError::PacketHandlingError {
             error: IoError::new(IoErrorKind::Other, "io error"),
         });
+        format!("{}", ConnectionError::ConnectionDeniedError {
+            addr: "127.0.0.1:33445".parse().unwrap(),
+        });
     }
 
     #[test]
@@ -344,6 +395,46 @@ mod tests {
         tokio::run(both);
     }
 
+    #[test]
+    fn run_connection_denied_by_policy() {
+        crypto_init().unwrap();
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut policy = RelayPolicy::new();
+        policy.allow_net("10.0.0.0/8".parse().unwrap());
+        let mut server = Server::new();
+        server.set_policy(policy);
+
+        let server = listener.incoming()
+            .into_future() // take the first connection
+            .map_err(|(e, _other_incomings)| Error::from(e))
+            .map(|(connection, _other_incomings)| connection.unwrap())
+            .and_then(move |stream|
+                server.run_connection(stream, server_sk, Stats::new())
+                    .then(|res| match res {
+                        Err(ConnectionError::ConnectionDeniedError { .. }) => Ok(()),
+                        res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+                    })
+            );
+
+        let client = TcpStream::connect(&addr)
+            .map(|_socket| ())
+            .map_err(Error::from);
+
+        let both = server.join(client)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+
+        tokio::run(both);
+    }
+
     #[test]
     fn run() {
         crypto_init().unwrap();
@@ -0,0 +1,202 @@
+/*! Transports for TCP relay connections.
+
+The TCP relay protocol can run over raw TCP connections or be tunneled in
+WebSocket binary frames for networks that allow only HTTP-looking traffic.
+The handshake and the packet codec work on top of `RelayStream` regardless
+of the transport. The server detects the transport by the first bytes of a
+connection so both transports can share one listening port.
+*/
+
+mod websocket;
+
+pub use self::websocket::*;
+
+use std::cmp;
+use std::io::{Error, Read, Write};
+use std::net::SocketAddr;
+
+use bytes::BytesMut;
+use futures::{future, Future, Poll};
+use futures::future::Either;
+use tokio::io::{read_exact, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// Prefix of HTTP upgrade request. Raw connections start with the `PublicKey`
+/// of the client so they can be confused only with negligible probability.
+const WEBSOCKET_PREFIX: &[u8; 4] = b"GET ";
+
+/// Transport that is used to connect to a TCP relay.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RelayTransport {
+    /// Raw TCP connection.
+    Tcp,
+    /// WebSocket connection.
+    WebSocket {
+        /// Path of the HTTP upgrade request, e.g. `/`.
+        path: String,
+    },
+}
+
+enum RelayStreamInner<S> {
+    /// Raw stream with bytes that were read to detect the transport
+    Raw(S, BytesMut),
+    WebSocket(WebSocketStream<S>),
+}
+
+/** Connection to a TCP relay or from a TCP relay client over one of
+transports.
+*/
+pub struct RelayStream<S> {
+    inner: RelayStreamInner<S>,
+}
+
+impl<S> RelayStream<S> {
+    /// Create `RelayStream` over raw stream.
+    pub fn raw(stream: S) -> RelayStream<S> {
+        RelayStream {
+            inner: RelayStreamInner::Raw(stream, BytesMut::new()),
+        }
+    }
+
+    /// Create `RelayStream` over WebSocket stream.
+    pub fn websocket(stream: WebSocketStream<S>) -> RelayStream<S> {
+        RelayStream {
+            inner: RelayStreamInner::WebSocket(stream),
+        }
+    }
+
+    /// Check if the connection is tunneled in WebSocket frames.
+    pub fn is_websocket(&self) -> bool {
+        match self.inner {
+            RelayStreamInner::Raw(..) => false,
+            RelayStreamInner::WebSocket(_) => true,
+        }
+    }
+}
+
+/// Connect to the TCP relay via the transport.
+pub fn connect(addr: &SocketAddr, transport: &RelayTransport) -> impl Future<Item = RelayStream<TcpStream>, Error = Error> + Send {
+    let addr = *addr;
+    let transport = transport.clone();
+    TcpStream::connect(&addr).and_then(move |stream| match transport {
+        RelayTransport::Tcp =>
+            Either::A(future::ok(RelayStream::raw(stream))),
+        RelayTransport::WebSocket { path } =>
+            Either::B(websocket_client_handshake(stream, &addr.to_string(), &path).map(RelayStream::websocket)),
+    })
+}
+
+/// Detect the transport of the accepted connection and make WebSocket
+/// handshake if necessary.
+pub fn accept<S>(stream: S) -> impl Future<Item = RelayStream<S>, Error = Error> + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    read_exact(stream, [0; 4]).and_then(|(stream, prefix)| {
+        let prefix = BytesMut::from(&prefix[..]);
+        if &prefix[..] == WEBSOCKET_PREFIX {
+            Either::A(websocket_server_handshake(stream, prefix).map(RelayStream::websocket))
+        } else {
+            Either::B(future::ok(RelayStream {
+                inner: RelayStreamInner::Raw(stream, prefix),
+            }))
+        }
+    })
+}
+
+impl<S: AsyncRead + AsyncWrite> Read for RelayStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.inner {
+            RelayStreamInner::Raw(ref mut stream, ref mut prefix) => {
+                if prefix.is_empty() {
+                    stream.read(buf)
+                } else {
+                    let n = cmp::min(buf.len(), prefix.len());
+                    buf[.. n].copy_from_slice(&prefix.split_to(n));
+                    Ok(n)
+                }
+            },
+            RelayStreamInner::WebSocket(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for RelayStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> Write for RelayStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.inner {
+            RelayStreamInner::Raw(ref mut stream, _) => stream.write(buf),
+            RelayStreamInner::WebSocket(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.inner {
+            RelayStreamInner::Raw(ref mut stream, _) => stream.flush(),
+            RelayStreamInner::WebSocket(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for RelayStream<S> {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        match self.inner {
+            RelayStreamInner::Raw(ref mut stream, _) => stream.shutdown(),
+            RelayStreamInner::WebSocket(ref mut stream) => stream.shutdown(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Stream;
+    use tokio;
+    use tokio::net::TcpListener;
+
+    use crate::toxcore::crypto_core::*;
+    use crate::toxcore::tcp::handshake::*;
+
+    fn network_handshake(transport: RelayTransport) {
+        crypto_init().unwrap();
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+        let is_websocket = transport != RelayTransport::Tcp;
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _other_incomings)| e)
+            .and_then(|(stream, _other_incomings)| accept(stream.unwrap()))
+            .and_then(move |stream| {
+                assert_eq!(stream.is_websocket(), is_websocket);
+                make_server_handshake(stream, server_sk)
+            })
+            .map(move |(_stream, _channel, pk)| assert_eq!(pk, client_pk));
+
+        let client = connect(&addr, &transport)
+            .and_then(move |stream| make_client_handshake(stream, &client_pk, &client_sk, &server_pk));
+
+        let both = server.join(client)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+        tokio::run(both);
+    }
+
+    #[test]
+    fn network_handshake_tcp() {
+        network_handshake(RelayTransport::Tcp);
+    }
+
+    #[test]
+    fn network_handshake_websocket() {
+        network_handshake(RelayTransport::WebSocket { path: "/".to_owned() });
+    }
+}
@@ -0,0 +1,799 @@
+/*! WebSocket transport for TCP relay connections.
+
+Only the subset of [RFC 6455](https://tools.ietf.org/html/rfc6455) that is
+needed to tunnel a byte stream is implemented: the HTTP upgrade handshake and
+binary frames. Every write is sent as a single binary frame. Payloads of
+received binary and continuation frames are read as a continuous byte stream
+so frame boundaries don't matter. Ping frames are answered with pong frames,
+only the pong to the latest ping is sent if several pings are received while
+the underlying stream is not writable. A close frame ends the stream.
+*/
+
+use std::cmp;
+use std::io::{Error, ErrorKind, Read, Write};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use futures::{future, Async, Future, Poll};
+use futures::future::Either;
+use tokio::io::{write_all, AsyncRead, AsyncWrite};
+
+use crate::toxcore::crypto_core::*;
+
+/// GUID that is appended to `Sec-WebSocket-Key` to calculate
+/// `Sec-WebSocket-Accept`.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Maximum size of HTTP request or response head.
+const MAX_HTTP_HEAD_SIZE: usize = 8192;
+
+/// Maximum payload size of a frame. Bigger writes are split into several
+/// frames.
+const MAX_FRAME_PAYLOAD_SIZE: usize = 65536;
+
+/// Number of bytes reserved in the read buffer before reading from the
+/// underlying stream.
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Maximum payload size of a control frame.
+const MAX_CONTROL_FRAME_PAYLOAD_SIZE: u64 = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Opcodes of control frames have the most significant bit set.
+const OPCODE_CONTROL_BIT: u8 = 0x8;
+
+/// Side of a WebSocket connection. Frames sent by clients are masked while
+/// frames sent by servers are not.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WebSocketRole {
+    /// The side that initiated the connection.
+    Client,
+    /// The side that accepted the connection.
+    Server,
+}
+
+/// Create `InvalidData` IO error.
+fn invalid_data(error: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, error)
+}
+
+/// Calculate SHA-1 digest of the data. It's used only for WebSocket
+/// handshake as required by RFC 6455.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let mut length = [0; 8];
+    BigEndian::write_u64(&mut length, (data.len() as u64).wrapping_mul(8));
+    message.extend_from_slice(&length);
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0 .. 16 {
+            w[i] = BigEndian::read_u32(&chunk[i * 4 .. i * 4 + 4]);
+        }
+        for i in 16 .. 80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, &w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0 ..= 19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20 ..= 39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40 ..= 59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0; 20];
+    for (i, h) in h.iter().enumerate() {
+        BigEndian::write_u32(&mut digest[i * 4 .. i * 4 + 4], *h);
+    }
+    digest
+}
+
+/// Encode the data with standard base64 alphabet and padding.
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity(data.len() / 3 * 4 + 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        result.push(ALPHABET[(n >> 18) as usize & 0x3f] as char);
+        result.push(ALPHABET[(n >> 12) as usize & 0x3f] as char);
+        if chunk.len() > 1 {
+            result.push(ALPHABET[(n >> 6) as usize & 0x3f] as char);
+        } else {
+            result.push('=');
+        }
+        if chunk.len() > 2 {
+            result.push(ALPHABET[n as usize & 0x3f] as char);
+        } else {
+            result.push('=');
+        }
+    }
+    result
+}
+
+/// Calculate `Sec-WebSocket-Accept` header value for `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    let mut data = key.as_bytes().to_vec();
+    data.extend_from_slice(WEBSOCKET_GUID.as_bytes());
+    base64_encode(&sha1(&data))
+}
+
+/// Get the value of the header from HTTP head. Header names are case
+/// insensitive.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.find(':').map(|pos| (&line[.. pos], &line[pos + 1 ..])))
+        .find(|(header_name, _)| header_name.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Check that the header contains the token in its comma separated list of
+/// values ignoring case.
+fn header_contains(head: &str, name: &str, token: &str) -> bool {
+    match header(head, name) {
+        Some(value) => value.split(',').any(|value| value.trim().eq_ignore_ascii_case(token)),
+        None => false,
+    }
+}
+
+/// Check the HTTP upgrade request and return `Sec-WebSocket-Key`.
+fn check_upgrade_request(head: &str) -> Result<&str, Error> {
+    if !head.starts_with("GET ") {
+        return Err(invalid_data("Unexpected HTTP method"));
+    }
+    if !header_contains(head, "Upgrade", "websocket") || !header_contains(head, "Connection", "upgrade") {
+        return Err(invalid_data("Not a WebSocket upgrade request"));
+    }
+    if header(head, "Sec-WebSocket-Version") != Some("13") {
+        return Err(invalid_data("Unsupported WebSocket version"));
+    }
+    header(head, "Sec-WebSocket-Key")
+        .ok_or_else(|| invalid_data("Sec-WebSocket-Key header is missing"))
+}
+
+/// Check the HTTP upgrade response.
+fn check_upgrade_response(head: &str, key: &str) -> Result<(), Error> {
+    let status = head.split("\r\n").next().unwrap_or("");
+    if status.split(' ').nth(1) != Some("101") {
+        return Err(invalid_data("WebSocket upgrade is rejected"));
+    }
+    if header(head, "Sec-WebSocket-Accept") != Some(accept_key(key).as_str()) {
+        return Err(invalid_data("Invalid Sec-WebSocket-Accept header"));
+    }
+    Ok(())
+}
+
+/// Future that reads HTTP head from the stream. It resolves to the stream,
+/// the head and bytes that were received after the head.
+struct ReadHttpHead<S> {
+    stream: Option<S>,
+    buf: BytesMut,
+}
+
+impl<S: AsyncRead> Future for ReadHttpHead<S> {
+    type Item = (S, String, BytesMut);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(pos) = self.buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                let head = self.buf.split_to(pos + 4);
+                let head = String::from_utf8(head.to_vec())
+                    .map_err(|_| invalid_data("HTTP head is not valid UTF-8"))?;
+                let rest = self.buf.take();
+                let stream = self.stream.take().expect("ReadHttpHead is polled after completion");
+                return Ok(Async::Ready((stream, head, rest)));
+            }
+            if self.buf.len() >= MAX_HTTP_HEAD_SIZE {
+                return Err(invalid_data("HTTP head is too long"));
+            }
+            let stream = self.stream.as_mut().expect("ReadHttpHead is polled after completion");
+            self.buf.reserve(READ_CHUNK_SIZE);
+            match AsyncRead::read_buf(stream, &mut self.buf)? {
+                Async::Ready(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed during WebSocket handshake")),
+                Async::Ready(_) => {},
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Make WebSocket handshake as a client. `host` is the value of the `Host`
+/// header, `path` is the path of the request.
+pub fn websocket_client_handshake<S>(stream: S, host: &str, path: &str) -> impl Future<Item = WebSocketStream<S>, Error = Error> + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    let mut key = [0; 16];
+    randombytes_into(&mut key);
+    let key = base64_encode(&key);
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         \r\n",
+        path, host, key
+    );
+
+    write_all(stream, request.into_bytes())
+        .and_then(|(stream, _request)| ReadHttpHead { stream: Some(stream), buf: BytesMut::new() })
+        .and_then(move |(stream, head, rest)| {
+            check_upgrade_response(&head, &key)?;
+            Ok(WebSocketStream::new(stream, WebSocketRole::Client, rest))
+        })
+}
+
+/// Make WebSocket handshake as a server. `prefix` contains bytes of the
+/// request that were already read from the stream.
+pub fn websocket_server_handshake<S>(stream: S, prefix: BytesMut) -> impl Future<Item = WebSocketStream<S>, Error = Error> + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    ReadHttpHead { stream: Some(stream), buf: prefix }
+        .and_then(|(stream, head, rest)| {
+            let key = match check_upgrade_request(&head) {
+                Ok(key) => key,
+                Err(e) => {
+                    let response = b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n";
+                    return Either::A(write_all(stream, response).then(|_| future::err(e)));
+                },
+            };
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\
+                 \r\n",
+                accept_key(key)
+            );
+            Either::B(write_all(stream, response.into_bytes())
+                .map(move |(stream, _response)| WebSocketStream::new(stream, WebSocketRole::Server, rest)))
+        })
+}
+
+/// Received frame.
+struct Frame {
+    opcode: u8,
+    payload: BytesMut,
+}
+
+/** Byte stream tunneled in WebSocket binary frames over the underlying
+stream. It's created after WebSocket handshake, see
+`websocket_client_handshake` and `websocket_server_handshake`.
+*/
+pub struct WebSocketStream<S> {
+    stream: S,
+    role: WebSocketRole,
+    /// Received bytes that are not decoded yet
+    read_buf: BytesMut,
+    /// Decoded payload that is not read yet
+    payload: BytesMut,
+    /// Encoded frames that are not written to the stream yet
+    write_buf: BytesMut,
+    /// Payload of the pong that should be sent when `write_buf` is empty
+    pending_pong: Option<BytesMut>,
+    /// Whether close frame was received
+    closed: bool,
+}
+
+impl<S> WebSocketStream<S> {
+    /// Create new `WebSocketStream` after the handshake. `read_buf` contains
+    /// bytes that were received after the handshake.
+    pub fn new(stream: S, role: WebSocketRole, read_buf: BytesMut) -> WebSocketStream<S> {
+        WebSocketStream {
+            stream,
+            role,
+            read_buf,
+            payload: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            pending_pong: None,
+            closed: false,
+        }
+    }
+
+    /// Get the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Try to decode a frame from received bytes.
+    fn decode_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let buf = &self.read_buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        if buf[0] & 0x70 != 0 {
+            return Err(invalid_data("WebSocket frame has reserved bits set"));
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+        // only clients mask frames
+        if masked != (self.role == WebSocketRole::Server) {
+            return Err(invalid_data("Unexpected WebSocket frame masking"));
+        }
+        let (length, mut offset) = match buf[1] & 0x7f {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                (u64::from(BigEndian::read_u16(&buf[2 .. 4])), 4)
+            },
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+                (BigEndian::read_u64(&buf[2 .. 10]), 10)
+            },
+            length => (u64::from(length), 2),
+        };
+        if length > MAX_FRAME_PAYLOAD_SIZE as u64 {
+            return Err(invalid_data("WebSocket frame is too big"));
+        }
+        // control frames can't be fragmented and have small payload
+        if opcode & OPCODE_CONTROL_BIT != 0 {
+            if !fin {
+                return Err(invalid_data("WebSocket control frame is fragmented"));
+            }
+            if length > MAX_CONTROL_FRAME_PAYLOAD_SIZE {
+                return Err(invalid_data("WebSocket control frame is too big"));
+            }
+        }
+        let length = length as usize;
+        let mask = if masked {
+            if buf.len() < offset + 4 {
+                return Ok(None);
+            }
+            let mut mask = [0; 4];
+            mask.copy_from_slice(&buf[offset .. offset + 4]);
+            offset += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        if buf.len() < offset + length {
+            return Ok(None);
+        }
+
+        let mut payload = self.read_buf.split_to(offset + length).split_off(offset);
+        if let Some(mask) = mask {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        Ok(Some(Frame { opcode, payload }))
+    }
+
+    /// Encode a frame to the write buffer.
+    fn encode_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mask_bit = if self.role == WebSocketRole::Client { 0x80 } else { 0 };
+        self.write_buf.reserve(14 + payload.len());
+        self.write_buf.put_u8(0x80 | opcode);
+        if payload.len() < 126 {
+            self.write_buf.put_u8(mask_bit | payload.len() as u8);
+        } else if payload.len() <= 0xffff {
+            self.write_buf.put_u8(mask_bit | 126);
+            self.write_buf.put_u16_be(payload.len() as u16);
+        } else {
+            self.write_buf.put_u8(mask_bit | 127);
+            self.write_buf.put_u64_be(payload.len() as u64);
+        }
+        if self.role == WebSocketRole::Client {
+            let mut mask = [0; 4];
+            randombytes_into(&mut mask);
+            self.write_buf.put_slice(&mask);
+            for (i, byte) in payload.iter().enumerate() {
+                self.write_buf.put_u8(byte ^ mask[i % 4]);
+            }
+        } else {
+            self.write_buf.put_slice(payload);
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> WebSocketStream<S> {
+    /// Write encoded frames and the pending pong to the underlying stream.
+    fn flush_write_buf(&mut self) -> Result<(), Error> {
+        loop {
+            if self.write_buf.is_empty() {
+                match self.pending_pong.take() {
+                    Some(payload) => self.encode_frame(OPCODE_PONG, &payload),
+                    None => return Ok(()),
+                }
+            }
+            let n = self.stream.write(&self.write_buf)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::WriteZero, "Failed to write WebSocket frame"));
+            }
+            self.write_buf.advance(n);
+        }
+    }
+
+    /// Write encoded frames to the underlying stream if it's ready.
+    fn try_flush_write_buf(&mut self) -> Result<(), Error> {
+        match self.flush_write_buf() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Handle received frame.
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        match frame.opcode {
+            OPCODE_CONTINUATION | OPCODE_BINARY => self.payload.unsplit(frame.payload),
+            OPCODE_TEXT => return Err(invalid_data("Unexpected WebSocket text frame")),
+            OPCODE_CLOSE => {
+                self.closed = true;
+                self.encode_frame(OPCODE_CLOSE, &[]);
+                self.try_flush_write_buf()?;
+            },
+            OPCODE_PING => {
+                // the previous pong is replaced if it's not sent yet
+                self.pending_pong = Some(frame.payload);
+                self.try_flush_write_buf()?;
+            },
+            OPCODE_PONG => {},
+            _ => return Err(invalid_data("Unknown WebSocket frame opcode")),
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Read for WebSocketStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            if !self.payload.is_empty() {
+                let n = cmp::min(buf.len(), self.payload.len());
+                buf[.. n].copy_from_slice(&self.payload.split_to(n));
+                return Ok(n);
+            }
+            if self.closed {
+                return Ok(0);
+            }
+            if let Some(frame) = self.decode_frame()? {
+                self.handle_frame(frame)?;
+                continue;
+            }
+            self.read_buf.reserve(READ_CHUNK_SIZE);
+            match AsyncRead::read_buf(&mut self.stream, &mut self.read_buf)? {
+                Async::Ready(0) if self.read_buf.is_empty() => return Ok(0),
+                Async::Ready(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed in the middle of WebSocket frame")),
+                Async::Ready(_) => {},
+                Async::NotReady => return Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for WebSocketStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> Write for WebSocketStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // don't buffer more than one frame so that backpressure of the
+        // underlying stream is propagated
+        self.flush_write_buf()?;
+        let n = cmp::min(buf.len(), MAX_FRAME_PAYLOAD_SIZE);
+        self.encode_frame(OPCODE_BINARY, &buf[.. n]);
+        self.try_flush_write_buf()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.flush_write_buf()?;
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for WebSocketStream<S> {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        match self.flush_write_buf() {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+        }
+        self.stream.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use futures::Stream;
+    use tokio;
+    use tokio::io::read_exact;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Encode the data as frames written by a client.
+    fn client_frames(data: &[&[u8]]) -> Vec<u8> {
+        let mut client = WebSocketStream::new(Cursor::new(Vec::new()), WebSocketRole::Client, BytesMut::new());
+        for data in data {
+            client.write_all(data).unwrap();
+        }
+        client.into_inner().into_inner()
+    }
+
+    #[test]
+    fn sha1_test_vectors() {
+        assert_eq!(
+            sha1(b"").to_vec(),
+            vec![0xda, 0x39, 0xa3, 0xee, 0x5e, 0x6b, 0x4b, 0x0d, 0x32, 0x55, 0xbf, 0xef, 0x95, 0x60, 0x18, 0x90, 0xaf, 0xd8, 0x07, 0x09]
+        );
+        assert_eq!(
+            sha1(b"abc").to_vec(),
+            vec![0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50, 0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d]
+        );
+        assert_eq!(
+            sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").to_vec(),
+            vec![0x84, 0x98, 0x3e, 0x44, 0x1c, 0x3b, 0xd2, 0x6e, 0xba, 0xae, 0x4a, 0xa1, 0xf9, 0x51, 0x29, 0xe5, 0xe5, 0x46, 0x70, 0xf1]
+        );
+    }
+
+    #[test]
+    fn base64_encode_padding() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn accept_key_rfc_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn check_upgrade_request_headers() {
+        let head = "GET /relay HTTP/1.1\r\n\
+                    Host: example.com\r\n\
+                    upgrade: WebSocket\r\n\
+                    Connection: keep-alive, Upgrade\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                    Sec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(check_upgrade_request(head).unwrap(), "dGhlIHNhbXBsZSBub25jZQ==");
+
+        let head = "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(check_upgrade_request(head).is_err());
+    }
+
+    #[test]
+    fn check_upgrade_response_accept() {
+        let head = "HTTP/1.1 101 Switching Protocols\r\n\
+                    Upgrade: websocket\r\n\
+                    Connection: Upgrade\r\n\
+                    Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        check_upgrade_response(head, "dGhlIHNhbXBsZSBub25jZQ==").unwrap();
+        assert!(check_upgrade_response(head, "AAAAAAAAAAAAAAAAAAAAAA==").is_err());
+
+        let head = "HTTP/1.1 403 Forbidden\r\n\r\n";
+        assert!(check_upgrade_response(head, "dGhlIHNhbXBsZSBub25jZQ==").is_err());
+    }
+
+    #[test]
+    fn read_write_frames() {
+        crypto_init().unwrap();
+        let small = vec![42; 100];
+        let medium = vec![43; 1000];
+        let big = vec![44; MAX_FRAME_PAYLOAD_SIZE + 100];
+        let bytes = client_frames(&[&small, &medium, &big]);
+
+        let mut server = WebSocketStream::new(Cursor::new(bytes), WebSocketRole::Server, BytesMut::new());
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).unwrap();
+
+        let mut expected = small;
+        expected.extend_from_slice(&medium);
+        expected.extend_from_slice(&big);
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn read_frame_with_prefix() {
+        crypto_init().unwrap();
+        let mut bytes = client_frames(&[b"hello"]);
+        // the first bytes were received with the handshake
+        let rest = bytes.split_off(3);
+        let mut server = WebSocketStream::new(Cursor::new(rest), WebSocketRole::Server, BytesMut::from(bytes));
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"hello".to_vec());
+    }
+
+    #[test]
+    fn read_unmasked_frame_by_server() {
+        let mut server = WebSocketStream::new(Cursor::new(vec![0x82, 0x01, 0x42]), WebSocketRole::Server, BytesMut::new());
+        let mut buf = [0; 16];
+        assert_eq!(server.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_close_frame() {
+        let mut client = WebSocketStream::new(Cursor::new(vec![0x82, 0x01, 0x42, 0x88, 0x00, 0x82, 0x01, 0x43]), WebSocketRole::Client, BytesMut::new());
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        // data after the close frame is ignored
+        assert_eq!(received, vec![0x42]);
+    }
+
+    #[test]
+    fn read_incomplete_frame() {
+        let mut client = WebSocketStream::new(Cursor::new(vec![0x82, 0x05, 0x42]), WebSocketRole::Client, BytesMut::new());
+        let mut buf = [0; 16];
+        assert_eq!(client.read(&mut buf).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_fragmented_control_frame() {
+        let mut client = WebSocketStream::new(Cursor::new(vec![0x09, 0x01, 0x42]), WebSocketRole::Client, BytesMut::new());
+        let mut buf = [0; 16];
+        assert_eq!(client.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_too_big_control_frame() {
+        let mut bytes = vec![0x89, 0x7e, 0x00, 0x7e];
+        bytes.extend_from_slice(&[42; 126]);
+        let mut client = WebSocketStream::new(Cursor::new(bytes), WebSocketRole::Client, BytesMut::new());
+        let mut buf = [0; 16];
+        assert_eq!(client.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    /// Stream that reads the given bytes and accepts writes only when it's
+    /// writable.
+    struct BlockingWriteStream {
+        read: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+        writable: bool,
+    }
+
+    impl Read for BlockingWriteStream {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            self.read.read(buf)
+        }
+    }
+
+    impl AsyncRead for BlockingWriteStream {}
+
+    impl Write for BlockingWriteStream {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            if self.writable {
+                self.written.write(buf)
+            } else {
+                Err(ErrorKind::WouldBlock.into())
+            }
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for BlockingWriteStream {
+        fn shutdown(&mut self) -> Poll<(), Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn read_pings_only_latest_pong_is_pending() {
+        crypto_init().unwrap();
+        let stream = BlockingWriteStream {
+            read: Cursor::new(vec![0x89, 0x01, 0x01, 0x89, 0x01, 0x02, 0x89, 0x01, 0x03]),
+            written: Vec::new(),
+            writable: false,
+        };
+        let mut client = WebSocketStream::new(stream, WebSocketRole::Client, BytesMut::new());
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert!(received.is_empty());
+
+        client.stream.writable = true;
+        client.flush().unwrap();
+        // the first pong was already encoded, the second one was replaced
+        // by the third one; pong frames are masked by the client
+        let written = client.into_inner().written;
+        assert_eq!(written.len(), 14);
+        assert_eq!(&written[.. 2], &[0x8a, 0x81]);
+        assert_eq!(written[6] ^ written[2], 0x01);
+        assert_eq!(&written[7 .. 9], &[0x8a, 0x81]);
+        assert_eq!(written[13] ^ written[9], 0x03);
+    }
+
+    #[test]
+    fn network_handshake() {
+        crypto_init().unwrap();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _other_incomings)| e)
+            .and_then(|(stream, _other_incomings)| websocket_server_handshake(stream.unwrap(), BytesMut::new()))
+            .and_then(|stream| read_exact(stream, [0; 5]))
+            .and_then(|(stream, buf)| {
+                assert_eq!(&buf, b"hello");
+                write_all(stream, b"world")
+            });
+
+        let client = TcpStream::connect(&addr)
+            .and_then(move |stream| websocket_client_handshake(stream, &addr.to_string(), "/"))
+            .and_then(|stream| write_all(stream, b"hello"))
+            .and_then(|(stream, _buf)| read_exact(stream, [0; 5]))
+            .map(|(_stream, buf)| assert_eq!(&buf, b"world"));
+
+        let both = server.join(client)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+        tokio::run(both);
+    }
+
+    #[test]
+    fn network_handshake_not_websocket() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _other_incomings)| e)
+            .and_then(|(stream, _other_incomings)| websocket_server_handshake(stream.unwrap(), BytesMut::new()))
+            .then(|res| {
+                assert!(res.is_err());
+                Ok(())
+            });
+
+        let client = TcpStream::connect(&addr)
+            .and_then(|stream| write_all(stream, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"))
+            .and_then(|(stream, _buf)| tokio::io::read_to_end(stream, Vec::new()))
+            .map(|(_stream, response)| assert!(response.starts_with(b"HTTP/1.1 400")));
+
+        let both = server.join(client)
+            .map(|_| ()).map_err(|e: Error| panic!("{}", e));
+        tokio::run(both);
+    }
+}
@@ -1,5 +1,7 @@
 //! Functions to work with time
 
+#[cfg(test)]
+use std::cell::Cell;
 #[cfg(test)]
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, Instant, UNIX_EPOCH};
@@ -32,6 +34,38 @@ pub fn clock_now() -> Instant {
     Instant::now()
 }
 
+#[cfg(test)]
+thread_local! {
+    /// System time corresponding to the `Instant` for the current thread.
+    /// When it's set system time is derived from mocked
+    /// `tokio_timer::clock::now()`.
+    static SYSTEM_TIME_BASE: Cell<Option<(SystemTime, Instant)>> = Cell::new(None);
+}
+
+/// Set system time corresponding to the `Instant` for the current thread.
+/// `None` restores real system time. Returns the previous value.
+#[cfg(test)]
+pub fn set_system_time_base(base: Option<(SystemTime, Instant)>) -> Option<(SystemTime, Instant)> {
+    SYSTEM_TIME_BASE.with(|cell| cell.replace(base))
+}
+
+/// Returns a `SystemTime` corresponding to "now". Should be used instead of
+/// `SystemTime::now()` to work with mocked `tokio_timer::clock::now()`.
+#[cfg(test)]
+pub fn clock_system_time() -> SystemTime {
+    match SYSTEM_TIME_BASE.with(Cell::get) {
+        Some((system_time, instant)) => system_time + (clock_now() - instant),
+        None => SystemTime::now(),
+    }
+}
+
+/// Returns a `SystemTime` corresponding to "now". Should be used instead of
+/// `SystemTime::now()` to work with mocked `tokio_timer::clock::now()`.
+#[cfg(not(test))]
+pub fn clock_system_time() -> SystemTime {
+    SystemTime::now()
+}
+
 /// Returns the amount of time elapsed since this instant was created. Should be
 /// used instead of `Instant::elapsed` in order to work with mocked
 /// `tokio_timer::clock::now()`.
@@ -7,10 +7,105 @@
 
 
 use std::fmt;
+use std::str::FromStr;
+
+use byteorder::{BigEndian, ByteOrder};
+use failure::Fail;
 
 use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
 
+/// Scheme of URIs that contain `ToxId`.
+pub const TOX_URI_SCHEME: &str = "tox:";
+
+error_kind! {
+    #[doc = "Error that can happen when parsing `ToxId`, `PublicKey`, `NoSpam` or `ToxUri` from string."]
+    #[derive(Debug)]
+    ParseError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    ParseErrorKind {
+        #[doc = "String has wrong number of hex characters."]
+        #[fail(display = "Invalid length: expected {} hex characters, got {}", expected, len)]
+        InvalidLength {
+            #[doc = "Expected number of characters."]
+            expected: usize,
+            #[doc = "Actual number of characters."]
+            len: usize,
+        },
+        #[doc = "String contains character that is not a hex digit."]
+        #[fail(display = "Invalid hex character {:?} at position {}", character, position)]
+        InvalidHex {
+            #[doc = "Position of the character counting from 0."]
+            position: usize,
+            #[doc = "The invalid character."]
+            character: char,
+        },
+        #[doc = "Checksum of `ToxId` doesn't match its `PublicKey` and `NoSpam`."]
+        #[fail(display = "Checksum mismatch: expected {:04X}, got {:04X}", expected, found)]
+        ChecksumMismatch {
+            #[doc = "Checksum calculated from `PublicKey` and `NoSpam` as big endian number."]
+            expected: u16,
+            #[doc = "Checksum contained in the string as big endian number."]
+            found: u16,
+        },
+        #[doc = "URI doesn't start with `tox:` scheme."]
+        #[fail(display = "URI should start with \"tox:\"")]
+        InvalidScheme,
+        #[doc = "URI contains invalid percent-encoded sequence."]
+        #[fail(display = "Invalid percent-encoding in URI: {:?}", sequence)]
+        InvalidPercentEncoding {
+            #[doc = "The invalid sequence."]
+            sequence: String,
+        },
+    }
+}
+
+/** Decode hex string that should contain exactly `N` bytes. Both upper and
+lower case digits are accepted.
+*/
+fn decode_hex(s: &str, bytes: &mut [u8]) -> Result<(), ParseError> {
+    let len = s.chars().count();
+    if len != bytes.len() * 2 {
+        return Err(ParseErrorKind::InvalidLength { expected: bytes.len() * 2, len }.into());
+    }
+    let mut digits = s.chars().enumerate().map(|(position, character)|
+        character.to_digit(16)
+            .map(|digit| digit as u8)
+            .ok_or_else(|| ParseError::from(ParseErrorKind::InvalidHex { position, character }))
+    );
+    for byte in bytes.iter_mut() {
+        // length is already checked so there are always 2 more digits
+        let high = digits.next().unwrap()?;
+        let low = digits.next().unwrap()?;
+        *byte = high << 4 | low;
+    }
+    Ok(())
+}
+
+/** Parse `PublicKey` from hex string.
+
+`PublicKey` is defined in `sodiumoxide` so it can't implement `FromStr` here.
+
+E.g.
+
+```
+use self::tox::toxcore::crypto_core::{PublicKey, PUBLICKEYBYTES};
+use self::tox::toxcore::toxid::{parse_public_key, ParseErrorKind};
+
+let pk = parse_public_key(&"ab".repeat(PUBLICKEYBYTES)).unwrap();
+assert_eq!(pk, PublicKey([0xab; PUBLICKEYBYTES]));
+
+let error = parse_public_key("ABCD").err().unwrap();
+assert_eq!(*error.kind(), ParseErrorKind::InvalidLength { expected: 64, len: 4 });
+```
+*/
+pub fn parse_public_key(s: &str) -> Result<PublicKey, ParseError> {
+    let mut bytes = [0; PUBLICKEYBYTES];
+    decode_hex(s.trim(), &mut bytes)?;
+    Ok(PublicKey(bytes))
+}
+
 /** Calculate XOR checksum for 2 [u8; 2].
 
     Used for calculating checksum of ToxId.
@@ -89,6 +184,27 @@ impl fmt::Display for NoSpam {
     }
 }
 
+/** Parse `NoSpam` from hex string.
+
+E.g.
+
+```
+use self::tox::toxcore::toxid::NoSpam;
+
+assert_eq!("0a0B0c0D".parse::<NoSpam>().unwrap(), NoSpam([10, 11, 12, 13]));
+assert!("0A0B0C".parse::<NoSpam>().is_err());
+```
+*/
+impl FromStr for NoSpam {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; NOSPAMBYTES];
+        decode_hex(s.trim(), &mut bytes)?;
+        Ok(NoSpam(bytes))
+    }
+}
+
 impl FromBytes for NoSpam {
     named!(from_bytes<NoSpam>, map!(take!(NOSPAMBYTES), |bytes| {
         NoSpam([bytes[0], bytes[1], bytes[2], bytes[3]])
@@ -222,6 +338,35 @@ impl ToxId {
         }
         self.checksum = Self::checksum(&self.pk, self.nospam);
     }
+
+    /// Get `NoSpam` of `ToxId`.
+    pub fn nospam(&self) -> NoSpam {
+        self.nospam
+    }
+
+    /** Check that checksum of `ToxId` matches its `PublicKey` and `NoSpam`.
+    `ToxId` deserialized from bytes is not validated so it can have invalid
+    checksum.
+
+    E.g.
+
+    ```
+    use self::tox::toxcore::binary_io::FromBytes;
+    use self::tox::toxcore::crypto_core::gen_keypair;
+    use self::tox::toxcore::toxid::ToxId;
+
+    let (pk, _) = gen_keypair();
+    assert!(ToxId::new(pk).is_valid());
+
+    let mut bytes = [0; 38];
+    bytes[0] = 1;
+    let toxid = ToxId::from_bytes(&bytes).unwrap().1;
+    assert!(!toxid.is_valid());
+    ```
+    */
+    pub fn is_valid(&self) -> bool {
+        self.checksum == Self::checksum(&self.pk, self.nospam)
+    }
 }
 
 impl FromBytes for ToxId {
@@ -299,6 +444,179 @@ impl fmt::Display for ToxId {
     }
 }
 
+/** Parse `ToxId` from hex string validating its checksum. Both upper and
+lower case digits are accepted, surrounding whitespace is ignored.
+
+E.g.
+
+```
+use self::tox::toxcore::crypto_core::gen_keypair;
+use self::tox::toxcore::toxid::{ToxId, ParseErrorKind};
+
+let (pk, _) = gen_keypair();
+let toxid = ToxId::new(pk);
+assert_eq!(toxid.to_string().parse::<ToxId>().unwrap(), toxid);
+assert_eq!(toxid.to_string().to_lowercase().parse::<ToxId>().unwrap(), toxid);
+
+let error = "00".parse::<ToxId>().err().unwrap();
+assert_eq!(*error.kind(), ParseErrorKind::InvalidLength { expected: 76, len: 2 });
+```
+*/
+impl FromStr for ToxId {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; TOXIDBYTES];
+        decode_hex(s.trim(), &mut bytes)?;
+        // can't fail since length is correct
+        let (_, toxid) = ToxId::from_bytes(&bytes).unwrap();
+        let expected = Self::checksum(&toxid.pk, toxid.nospam);
+        if toxid.checksum != expected {
+            return Err(ParseErrorKind::ChecksumMismatch {
+                expected: BigEndian::read_u16(&expected),
+                found: BigEndian::read_u16(&toxid.checksum),
+            }.into());
+        }
+        Ok(toxid)
+    }
+}
+
+/** `tox:` URI that contains `ToxId` and optional friend request message and
+name of the owner.
+
+Format is `tox:<ToxId>[?message=<message>][&name=<name>]`. `tox://` prefix is
+also accepted when parsing. Values of parameters are percent-encoded, unknown
+parameters are ignored.
+
+E.g.
+
+```
+use self::tox::toxcore::crypto_core::gen_keypair;
+use self::tox::toxcore::toxid::{ToxId, ToxUri};
+
+let (pk, _) = gen_keypair();
+let uri = ToxUri {
+    toxid: ToxId::new(pk),
+    message: Some("Hi, it's me!".to_owned()),
+    name: None,
+};
+let string = uri.to_string();
+assert!(string.ends_with("?message=Hi%2C%20it%27s%20me%21"));
+assert_eq!(string.parse::<ToxUri>().unwrap(), uri);
+```
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ToxUri {
+    /// `ToxId` of the peer.
+    pub toxid: ToxId,
+    /// Message for friend request.
+    pub message: Option<String>,
+    /// Name of the peer.
+    pub name: Option<String>,
+}
+
+impl ToxUri {
+    /// Create `ToxUri` without parameters.
+    pub fn new(toxid: ToxId) -> Self {
+        ToxUri {
+            toxid,
+            message: None,
+            name: None,
+        }
+    }
+}
+
+/// Percent-encode everything except unreserved characters.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A' ..= b'Z' | b'a' ..= b'z' | b'0' ..= b'9' | b'-' | b'.' | b'_' | b'~' =>
+                encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Decode percent-encoded string. `+` is decoded as space.
+fn percent_decode(s: &str) -> Result<String, ParseError> {
+    let invalid = |sequence: &[u8]| ParseError::from(ParseErrorKind::InvalidPercentEncoding {
+        sequence: String::from_utf8_lossy(sequence).into_owned()
+    });
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let sequence = &bytes[i .. (i + 3).min(bytes.len())];
+                let byte = std::str::from_utf8(&sequence[1 ..]).ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| invalid(sequence))?;
+                decoded.push(byte);
+                i += 3;
+            },
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8(decoded).map_err(|e| invalid(e.as_bytes()))
+}
+
+impl FromStr for ToxUri {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let scheme = s.get(.. TOX_URI_SCHEME.len())
+            .filter(|scheme| scheme.eq_ignore_ascii_case(TOX_URI_SCHEME))
+            .ok_or_else(|| ParseError::from(ParseErrorKind::InvalidScheme))?;
+        let rest = &s[scheme.len() ..];
+        let rest = if rest.starts_with("//") { &rest[2 ..] } else { rest };
+        let (toxid, query) = match rest.find('?') {
+            Some(index) => (&rest[.. index], Some(&rest[index + 1 ..])),
+            None => (rest, None),
+        };
+
+        let mut uri = ToxUri::new(toxid.parse()?);
+        for parameter in query.into_iter().flat_map(|query| query.split('&')) {
+            let (key, value) = match parameter.find('=') {
+                Some(index) => (&parameter[.. index], &parameter[index + 1 ..]),
+                None => (parameter, ""),
+            };
+            match key {
+                "message" => uri.message = Some(percent_decode(value)?),
+                "name" => uri.name = Some(percent_decode(value)?),
+                _ => {},
+            }
+        }
+        Ok(uri)
+    }
+}
+
+impl fmt::Display for ToxUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", TOX_URI_SCHEME, self.toxid)?;
+        let mut separator = '?';
+        if let Some(ref message) = self.message {
+            write!(f, "{}message={}", separator, percent_encode(message))?;
+            separator = '&';
+        }
+        if let Some(ref name) = self.name {
+            write!(f, "{}name={}", separator, percent_encode(name))?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::toxcore::crypto_core::*;
@@ -394,4 +712,99 @@ mod tests {
         toxid_encode_decode,
         ToxId::new(gen_keypair().0)
     );
+
+    // NoSpam::from_str()
+
+    #[test]
+    fn no_spam_from_str() {
+        crypto_init().unwrap();
+        let nospam = NoSpam::random();
+        assert_eq!(nospam.to_string().parse::<NoSpam>().unwrap(), nospam);
+        let error = "0A0B0C0G".parse::<NoSpam>().err().unwrap();
+        assert_eq!(*error.kind(), ParseErrorKind::InvalidHex { position: 7, character: 'G' });
+    }
+
+    // parse_public_key()
+
+    #[test]
+    fn parse_public_key_invalid() {
+        let error = parse_public_key(&"ß".repeat(PUBLICKEYBYTES * 2)).err().unwrap();
+        assert_eq!(*error.kind(), ParseErrorKind::InvalidHex { position: 0, character: 'ß' });
+        let error = parse_public_key("").err().unwrap();
+        assert_eq!(*error.kind(), ParseErrorKind::InvalidLength { expected: 64, len: 0 });
+    }
+
+    // ToxId::from_str()
+
+    #[test]
+    fn tox_id_from_str() {
+        crypto_init().unwrap();
+        let toxid = ToxId::new(gen_keypair().0);
+        assert_eq!(format!("  {}\n", toxid).parse::<ToxId>().unwrap(), toxid);
+    }
+
+    #[test]
+    fn tox_id_from_str_checksum_mismatch() {
+        let mut toxid = ToxId::new(PublicKey([0; PUBLICKEYBYTES]));
+        toxid.new_nospam(Some(NoSpam([0; NOSPAMBYTES])));
+        let mut string = toxid.to_string();
+        string.replace_range(0 .. 2, "01");
+        let error = string.parse::<ToxId>().err().unwrap();
+        assert_eq!(*error.kind(), ParseErrorKind::ChecksumMismatch { expected: 0x0100, found: 0 });
+        assert_eq!(error.to_string(), "Checksum mismatch: expected 0100, got 0000");
+    }
+
+    // ToxId::is_valid()
+
+    #[test]
+    fn tox_id_is_valid() {
+        crypto_init().unwrap();
+        let mut toxid = ToxId::new(gen_keypair().0);
+        assert!(toxid.is_valid());
+        toxid.checksum[0] ^= 1;
+        assert!(!toxid.is_valid());
+    }
+
+    // ToxUri::
+
+    #[test]
+    fn tox_uri_from_str() {
+        crypto_init().unwrap();
+        let toxid = ToxId::new(gen_keypair().0);
+        let uri = format!("TOX://{}?name=Alice+B%C3%B6b&foo=bar&message=hello%20there", toxid)
+            .parse::<ToxUri>().unwrap();
+        assert_eq!(uri, ToxUri {
+            toxid,
+            message: Some("hello there".to_owned()),
+            name: Some("Alice Böb".to_owned()),
+        });
+    }
+
+    #[test]
+    fn tox_uri_display() {
+        crypto_init().unwrap();
+        let toxid = ToxId::new(gen_keypair().0);
+        assert_eq!(ToxUri::new(toxid).to_string(), format!("tox:{}", toxid));
+        let uri = ToxUri {
+            toxid,
+            message: Some("a&b=c".to_owned()),
+            name: Some("Böb".to_owned()),
+        };
+        assert_eq!(uri.to_string(), format!("tox:{}?message=a%26b%3Dc&name=B%C3%B6b", toxid));
+        assert_eq!(uri.to_string().parse::<ToxUri>().unwrap(), uri);
+    }
+
+    #[test]
+    fn tox_uri_from_str_invalid() {
+        crypto_init().unwrap();
+        let toxid = ToxId::new(gen_keypair().0);
+        let error = toxid.to_string().parse::<ToxUri>().err().unwrap();
+        assert_eq!(*error.kind(), ParseErrorKind::InvalidScheme);
+        let error = format!("tox:{}?message=%4", toxid).parse::<ToxUri>().err().unwrap();
+        assert_eq!(*error.kind(), ParseErrorKind::InvalidPercentEncoding { sequence: "%4".to_owned() });
+        let error = format!("tox:{}?message=%FF", toxid).parse::<ToxUri>().err().unwrap();
+        assert_eq!(*error.kind(), ParseErrorKind::InvalidPercentEncoding { sequence: "\u{fffd}".to_owned() });
+        let error = "tox:1234".parse::<ToxUri>().err().unwrap();
+        assert_eq!(*error.kind(), ParseErrorKind::InvalidLength { expected: 76, len: 4 });
+    }
 }
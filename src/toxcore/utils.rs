@@ -1,8 +1,31 @@
 /*! Common utility functions
 */
 
+#[cfg(test)]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(test)]
+use std::hash::BuildHasherDefault;
+
 use crate::toxcore::crypto_core::*;
 
+/// Hasher builder for hash maps of modules. Hash maps are seeded randomly to
+/// resist hash flooding. In tests the seed is fixed so that iteration order
+/// doesn't change between runs of the network simulator.
+#[cfg(not(test))]
+pub type RandomState = std::collections::hash_map::RandomState;
+
+/// Hasher builder for hash maps of modules. Hash maps are seeded randomly to
+/// resist hash flooding. In tests the seed is fixed so that iteration order
+/// doesn't change between runs of the network simulator.
+#[cfg(test)]
+pub type RandomState = BuildHasherDefault<DefaultHasher>;
+
+/// `HashMap` with `RandomState` hasher builder.
+pub type HashMap<K, V> = std::collections::HashMap<K, V, RandomState>;
+
+/// `HashSet` with `RandomState` hasher builder.
+pub type HashSet<T> = std::collections::HashSet<T, RandomState>;
+
 /// Generate non-zero ping_id
 pub fn gen_ping_id() -> u64 {
     let mut ping_id = 0;
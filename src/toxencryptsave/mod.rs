@@ -25,7 +25,8 @@ use failure::Fail;
 
 use sodiumoxide::crypto::pwhash::{
     MEMLIMIT_INTERACTIVE, OPSLIMIT_INTERACTIVE,
-    Salt, OpsLimit,
+    MEMLIMIT_SENSITIVE, OPSLIMIT_SENSITIVE,
+    Salt, OpsLimit, MemLimit,
     gen_salt, derive_key
 };
 
@@ -45,6 +46,9 @@ pub use sodiumoxide::crypto::box_::PRECOMPUTEDKEYBYTES as KEY_LENGTH;
 
 use crate::toxcore::crypto_core;
 
+mod stream;
+
+pub use self::stream::*;
 
 /// Length (in bytes) of [`MAGIC_NUMBER`](./constant.MAGIC_NUMBER.html).
 pub const MAGIC_LENGTH: usize = 8;
@@ -59,6 +63,47 @@ I.e. the amount of bytes that data will "gain" after encryption.
 */
 pub const EXTRA_LENGTH: usize = MAGIC_LENGTH + SALT_LENGTH + NONCEBYTES + MACBYTES;
 
+/** Limits of `pwhash` used to derive key from passphrase.
+
+Data in **TES** format is always encrypted with
+[`INTERACTIVE`](#associatedconstant.INTERACTIVE) limits since the format
+doesn't record them. Stream format stores limits in the header, so data
+encrypted with [`PassEncryptor`](./struct.PassEncryptor.html) can use stronger
+ones.
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PwhashLimits {
+    /// Maximum amount of computations to perform.
+    pub ops: usize,
+    /// Maximum amount of RAM in bytes to use.
+    pub mem: usize,
+}
+
+impl PwhashLimits {
+    /// Limits that are used by **TES** format, compatible with `toxcore`.
+    pub const INTERACTIVE: PwhashLimits = PwhashLimits {
+        ops: OPSLIMIT_INTERACTIVE.0 * 2,
+        mem: MEMLIMIT_INTERACTIVE.0,
+    };
+
+    /** Limits for highly sensitive data.
+
+    Deriving key with these limits takes a few seconds and requires 1 GiB of
+    RAM. They are also the maximum limits accepted by
+    [`PassDecryptor`](./struct.PassDecryptor.html).
+    */
+    pub const SENSITIVE: PwhashLimits = PwhashLimits {
+        ops: OPSLIMIT_SENSITIVE.0,
+        mem: MEMLIMIT_SENSITIVE.0,
+    };
+}
+
+impl Default for PwhashLimits {
+    fn default() -> Self {
+        PwhashLimits::INTERACTIVE
+    }
+}
+
 /** Key and `Salt` that are used to encrypt/decrypt data.
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -68,7 +113,9 @@ pub struct PassKey {
     /// Salt is saved along with encrypted data and used to decrypt it.
     salt: Box<Salt>,
     /// Key used to encrypt/decrypt data. **DO NOT SAVE**.
-    key: Box<PrecomputedKey>
+    key: Box<PrecomputedKey>,
+    /// Limits of `pwhash` that were used to derive key.
+    limits: PwhashLimits,
 }
 
 impl PassKey {
@@ -122,10 +169,24 @@ impl PassKey {
     ```
     */
     pub fn with_salt(passphrase: &[u8], salt: Salt) -> Result<PassKey, KeyDerivationError> {
+        PassKey::with_limits(passphrase, salt, PwhashLimits::INTERACTIVE)
+    }
+
+    /// Create a new `PassKey` with provided `Salt` and `pwhash` limits.
+    ///
+    /// Data encrypted by [`encrypt()`](#method.encrypt) doesn't record limits,
+    /// so it can be decrypted only by a `PassKey` derived with the same limits.
+    /// Use [`PassEncryptor`](./struct.PassEncryptor.html) to store limits along
+    /// with encrypted data.
+    ///
+    /// ## Fails when:
+    ///
+    ///   * passphrase is empty
+    ///   * deriving key failed (can happen due to OOM)
+    pub fn with_limits(passphrase: &[u8], salt: Salt, limits: PwhashLimits) -> Result<PassKey, KeyDerivationError> {
         if passphrase.is_empty() { return Err(KeyDerivationError::Null) };
 
         let sha256::Digest(passhash) = sha256::hash(passphrase);
-        let OpsLimit(ops) = OPSLIMIT_INTERACTIVE;
         let mut key = [0; KEY_LENGTH];
 
         let maybe_key = PrecomputedKey::from_slice(
@@ -133,8 +194,8 @@ impl PassKey {
                 &mut key,
                 &passhash,
                 &salt,
-                OpsLimit(ops * 2),
-                MEMLIMIT_INTERACTIVE
+                OpsLimit(limits.ops),
+                MemLimit(limits.mem)
             ).or(Err(KeyDerivationError::Failed))?
         );
 
@@ -143,7 +204,7 @@ impl PassKey {
         let salt = Box::new(salt);
         let key = Box::new(maybe_key.ok_or(KeyDerivationError::Failed)?);
 
-        Ok(PassKey { salt, key })
+        Ok(PassKey { salt, key, limits })
     }
 
     /**
@@ -353,6 +414,42 @@ pub fn get_salt(data: &[u8]) -> Option<Salt> {
     }
 }
 
+/**
+Re-encrypt given **TES** data with a new passphrase.
+
+Data is decrypted with `old_passphrase` and encrypted again with
+`new_passphrase` and a new random `Salt`. Decrypted data is zeroed before
+returning. Use [`reencrypt_stream()`](./fn.reencrypt_stream.html) for data in
+stream format or to switch to stronger `pwhash` limits.
+
+**Note that passphrases memory is not being zeroed after it has been
+used**. Code that provides passphrases should take care of zeroing that
+memory.
+
+## Fails when:
+
+  * decrypting `data` with `old_passphrase` fails
+  * `new_passphrase` is empty
+
+E.g.
+
+```
+use self::tox::toxencryptsave::*;
+
+let encrypted = pass_encrypt(b"secret", b"old").unwrap();
+let reencrypted = pass_reencrypt(&encrypted, b"old", b"new").unwrap();
+
+assert_eq!(pass_decrypt(&reencrypted, b"new").unwrap(), b"secret");
+assert_eq!(pass_decrypt(&reencrypted, b"old"), Err(DecryptionError::Failed));
+```
+*/
+pub fn pass_reencrypt(data: &[u8], old_passphrase: &[u8], new_passphrase: &[u8]) -> Result<Vec<u8>, ReencryptionError> {
+    let mut plaintext = pass_decrypt(data, old_passphrase)?;
+    let result = pass_encrypt(&plaintext, new_passphrase);
+    memzero(&mut plaintext);
+    result.map_err(ReencryptionError::from)
+}
+
 /// Deriving secret key for [`PassKey`](./struct.PassKey.html).
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Fail)]
 pub enum KeyDerivationError {
@@ -419,6 +516,29 @@ impl From<KeyDerivationError> for DecryptionError {
     }
 }
 
+/// Error when trying to re-encrypt data with a new passphrase.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Fail)]
+pub enum ReencryptionError {
+    /// Decrypting data with old passphrase failed.
+    #[fail(display = "Decrypting data failed: {}", _0)]
+    Decryption(DecryptionError),
+    /// Encrypting data with new passphrase failed.
+    #[fail(display = "Encrypting data failed: {}", _0)]
+    Encryption(EncryptionError),
+}
+
+impl From<DecryptionError> for ReencryptionError {
+    fn from(err: DecryptionError) -> ReencryptionError {
+        ReencryptionError::Decryption(err)
+    }
+}
+
+impl From<EncryptionError> for ReencryptionError {
+    fn from(err: EncryptionError) -> ReencryptionError {
+        ReencryptionError::Encryption(err)
+    }
+}
+
 
 // PassKey::
 
@@ -446,4 +566,18 @@ fn pass_key_with_salt_test() {
     assert_eq!(&*pk.salt, &salt);
     assert_ne!(pk.key.0.as_ref(), &passwd as &[u8]);
     assert_ne!(pk.key.0, [0; KEY_LENGTH]);
+    assert_eq!(pk.limits, PwhashLimits::INTERACTIVE);
+}
+
+// PassKey::with_limits()
+
+#[test]
+fn pass_key_with_limits_test() {
+    let passwd = [42; 123];
+    let salt = gen_salt();
+    let limits = PwhashLimits { ops: PwhashLimits::INTERACTIVE.ops * 2, ..PwhashLimits::INTERACTIVE };
+    let pk = PassKey::with_limits(&passwd, salt, limits).unwrap();
+
+    assert_eq!(pk.limits, limits);
+    assert_ne!(pk, PassKey::with_salt(&passwd, salt).unwrap());
 }
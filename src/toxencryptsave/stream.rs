@@ -0,0 +1,474 @@
+/*! Streaming encryption of data that is too large to be kept in memory.
+
+Data is split into chunks, every chunk is encrypted and authenticated
+separately, so it can be decrypted without reading the whole stream first.
+
+Stream format:
+
+Length | Content
+------ | ------
+`8`    | [`STREAM_MAGIC_NUMBER`](./constant.STREAM_MAGIC_NUMBER.html)
+`1`    | Version of the format
+`8`    | `pwhash` ops limit
+`8`    | `pwhash` mem limit
+`4`    | Max size of plaintext chunk
+`32`   | `Salt`
+`24`   | Base `Nonce`
+`[0,]` | Chunks
+
+Chunk:
+
+Length   | Content
+-------- | ------
+`4`      | Length of plaintext, the highest bit is set for the last chunk
+`[16,]`  | Encrypted plaintext
+
+Nonce of every chunk is the base nonce XORed with the chunk number and with
+the last chunk flag, so that reordered, dropped or truncated chunks fail to
+decrypt.
+*/
+
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+
+use super::*;
+
+/// Bytes used to verify whether given data has been encrypted using
+/// [`PassEncryptor`](./struct.PassEncryptor.html).
+pub const STREAM_MAGIC_NUMBER: &[u8; MAGIC_LENGTH] = b"toxEstrm";
+/// Version of the stream format written by
+/// [`PassEncryptor`](./struct.PassEncryptor.html).
+pub const STREAM_VERSION: u8 = 1;
+/// Length in bytes of the stream header.
+pub const STREAM_HEADER_LENGTH: usize = MAGIC_LENGTH + 1 + 8 + 8 + 4 + SALT_LENGTH + NONCEBYTES;
+/// Max size in bytes of plaintext chunk written by
+/// [`PassEncryptor`](./struct.PassEncryptor.html).
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Max size of plaintext chunk accepted by `PassDecryptor` to not allocate
+/// too much memory for malformed streams.
+const MAX_STREAM_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+/// Flag of the chunk length that marks the last chunk.
+const LAST_CHUNK_FLAG: u32 = 0x8000_0000;
+
+/// Check if given piece of data appears to be encrypted by
+/// [`PassEncryptor`](./struct.PassEncryptor.html).
+#[inline]
+pub fn is_stream_encrypted(data: &[u8]) -> bool {
+    data.starts_with(STREAM_MAGIC_NUMBER)
+}
+
+/// Convert **TES** error to `io::Error` to return it from `Read` and `Write`
+/// implementations.
+fn io_error<E: Fail>(kind: io::ErrorKind, error: E) -> io::Error {
+    io::Error::new(kind, error.compat())
+}
+
+/// Fill the buffer from reader returning `error` when stream ends too early.
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8], error: DecryptionError) -> io::Result<()> {
+    reader.read_exact(buf).map_err(|e|
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            io_error(io::ErrorKind::InvalidData, error)
+        } else {
+            e
+        }
+    )
+}
+
+/// Key and nonce state used to encrypt/decrypt chunks.
+struct ChunkCipher {
+    /// Key derived from passphrase.
+    passkey: PassKey,
+    /// Nonce from the header.
+    nonce: Nonce,
+    /// Number of the next chunk.
+    counter: u64,
+}
+
+impl ChunkCipher {
+    /// Nonce for the next chunk.
+    fn next_nonce(&mut self, last: bool) -> Nonce {
+        let mut nonce = self.nonce;
+        let mut counter = [0; 8];
+        LittleEndian::write_u64(&mut counter, self.counter);
+        for (byte, counter_byte) in nonce.0.iter_mut().zip(&counter) {
+            *byte ^= counter_byte;
+        }
+        if last {
+            nonce.0[NONCEBYTES - 1] ^= 0x80;
+        }
+        self.counter += 1;
+        nonce
+    }
+
+    fn encrypt(&mut self, data: &[u8], last: bool) -> Vec<u8> {
+        let nonce = self.next_nonce(last);
+        crypto_core::encrypt_data_symmetric(&self.passkey.key, &nonce, data)
+    }
+
+    fn decrypt(&mut self, data: &[u8], last: bool) -> Result<Vec<u8>, DecryptionError> {
+        let nonce = self.next_nonce(last);
+        crypto_core::decrypt_data_symmetric(&self.passkey.key, &nonce, data)
+            .or(Err(DecryptionError::Failed))
+    }
+}
+
+/** `Write` adapter that encrypts data written to it in the stream format.
+
+[`finish()`](#method.finish) must be called after all data is written,
+otherwise the last chunk is never written and decrypting the stream fails.
+
+E.g.
+
+```
+use std::io::{Read, Write};
+use self::tox::toxencryptsave::*;
+
+let mut encryptor = PassEncryptor::new(Vec::new(), b"123456").unwrap();
+encryptor.write_all(b"pls no encrypt").unwrap();
+let encrypted = encryptor.finish().unwrap();
+assert!(is_stream_encrypted(&encrypted));
+
+let mut decryptor = PassDecryptor::new(encrypted.as_slice(), b"123456").unwrap();
+let mut decrypted = Vec::new();
+decryptor.read_to_end(&mut decrypted).unwrap();
+assert_eq!(decrypted, b"pls no encrypt");
+```
+*/
+pub struct PassEncryptor<W: Write> {
+    /// Underlying writer, `None` after the stream is finished.
+    inner: Option<W>,
+    /// Cipher used to encrypt chunks.
+    cipher: ChunkCipher,
+    /// Plaintext of the current chunk.
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> PassEncryptor<W> {
+    /// Create a new `PassEncryptor` with a random `Salt` and
+    /// [`PwhashLimits::INTERACTIVE`](./struct.PwhashLimits.html#associatedconstant.INTERACTIVE)
+    /// limits and write the stream header.
+    ///
+    /// **Note that `passphrase` memory is not being zeroed after it has been
+    /// used**. Code that provides `passphrase` should take care of zeroing that
+    /// memory.
+    ///
+    /// ## Fails when:
+    ///
+    ///   * passphrase is empty
+    ///   * deriving key failed (can happen due to OOM)
+    ///   * writing the header failed
+    pub fn new(writer: W, passphrase: &[u8]) -> io::Result<Self> {
+        PassEncryptor::with_limits(writer, passphrase, PwhashLimits::INTERACTIVE)
+    }
+
+    /// Create a new `PassEncryptor` with a random `Salt` and provided
+    /// `pwhash` limits and write the stream header.
+    pub fn with_limits(writer: W, passphrase: &[u8], limits: PwhashLimits) -> io::Result<Self> {
+        let passkey = PassKey::with_limits(passphrase, gen_salt(), limits)
+            .map_err(|e| io_error(io::ErrorKind::InvalidInput, EncryptionError::from(e)))?;
+        PassEncryptor::with_passkey(writer, passkey)
+    }
+
+    /// Create a new `PassEncryptor` with already derived `PassKey` and write
+    /// the stream header.
+    pub fn with_passkey(mut writer: W, passkey: PassKey) -> io::Result<Self> {
+        let nonce = gen_nonce();
+
+        let mut header = Vec::with_capacity(STREAM_HEADER_LENGTH);
+        header.extend_from_slice(STREAM_MAGIC_NUMBER);
+        header.push(STREAM_VERSION);
+        header.write_u64::<BigEndian>(passkey.limits.ops as u64)?;
+        header.write_u64::<BigEndian>(passkey.limits.mem as u64)?;
+        header.write_u32::<BigEndian>(STREAM_CHUNK_SIZE as u32)?;
+        header.extend_from_slice(&passkey.salt.0);
+        header.extend_from_slice(&nonce.0);
+        writer.write_all(&header)?;
+
+        Ok(PassEncryptor {
+            inner: Some(writer),
+            cipher: ChunkCipher {
+                passkey,
+                nonce,
+                counter: 0,
+            },
+            // allocate the whole chunk at once so that plaintext is not left
+            // in reallocated memory
+            buffer: Vec::with_capacity(STREAM_CHUNK_SIZE),
+        })
+    }
+
+    /// Write the last chunk, flush and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(true)?;
+        let mut writer = self.inner.take().expect("PassEncryptor is not finished");
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    /// Encrypt buffered plaintext and write it as a chunk.
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        let encrypted = self.cipher.encrypt(&self.buffer, last);
+        let mut len = self.buffer.len() as u32;
+        if last {
+            len |= LAST_CHUNK_FLAG;
+        }
+        memzero(&mut self.buffer);
+        self.buffer.clear();
+
+        let writer = self.inner.as_mut().expect("PassEncryptor is not finished");
+        writer.write_u32::<BigEndian>(len)?;
+        writer.write_all(&encrypted)
+    }
+}
+
+impl<W: Write> Write for PassEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(STREAM_CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[.. len]);
+        if self.buffer.len() == STREAM_CHUNK_SIZE {
+            self.write_chunk(false)?;
+        }
+        Ok(len)
+    }
+
+    /// Flush the underlying writer. Buffered plaintext of the current chunk
+    /// is not written until the chunk is full or the stream is finished.
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for PassEncryptor<W> {
+    fn drop(&mut self) {
+        memzero(&mut self.buffer);
+    }
+}
+
+/** `Read` adapter that decrypts data encrypted in the stream format or in
+**TES** format.
+
+Data in **TES** format isn't chunked so it's read into memory and decrypted
+when `PassDecryptor` is created.
+
+Decryption errors are returned as `io::Error` with `InvalidData` kind. Data
+read before an error is authenticated but the stream may be incomplete.
+*/
+pub struct PassDecryptor<R: Read> {
+    /// Underlying reader.
+    inner: R,
+    /// Cipher used to decrypt chunks, `None` after the last chunk was read.
+    cipher: Option<ChunkCipher>,
+    /// Limits of `pwhash` used to derive key.
+    limits: PwhashLimits,
+    /// Max size of plaintext chunk from the header.
+    chunk_size: usize,
+    /// Plaintext of the current chunk.
+    buffer: Vec<u8>,
+    /// Position of unread data in `buffer`.
+    position: usize,
+}
+
+impl<R: Read> PassDecryptor<R> {
+    /// Create a new `PassDecryptor` reading the header and deriving key from
+    /// `passphrase`.
+    ///
+    /// **Note that `passphrase` memory is not being zeroed after it has been
+    /// used**. Code that provides `passphrase` should take care of zeroing that
+    /// memory.
+    ///
+    /// ## Fails when:
+    ///
+    ///   * data is neither in stream nor in **TES** format
+    ///   * header has unsupported version or `pwhash` limits stronger than
+    ///     [`PwhashLimits::SENSITIVE`](./struct.PwhashLimits.html#associatedconstant.SENSITIVE)
+    ///   * passphrase is empty
+    ///   * deriving key failed (can happen due to OOM)
+    ///   * decrypting data in **TES** format failed
+    pub fn new(mut reader: R, passphrase: &[u8]) -> io::Result<Self> {
+        let mut header = [0; STREAM_HEADER_LENGTH];
+        read_exact(&mut reader, &mut header[.. MAGIC_LENGTH], DecryptionError::InvalidLength)?;
+
+        if is_encrypted(&header) {
+            let mut data = header[.. MAGIC_LENGTH].to_vec();
+            reader.read_to_end(&mut data)?;
+            let buffer = pass_decrypt(&data, passphrase)
+                .map_err(|e| io_error(io::ErrorKind::InvalidData, e))?;
+            return Ok(PassDecryptor {
+                inner: reader,
+                cipher: None,
+                limits: PwhashLimits::INTERACTIVE,
+                chunk_size: buffer.len(),
+                buffer,
+                position: 0,
+            });
+        }
+
+        if !is_stream_encrypted(&header) {
+            return Err(io_error(io::ErrorKind::InvalidData, DecryptionError::BadFormat));
+        }
+        read_exact(&mut reader, &mut header[MAGIC_LENGTH ..], DecryptionError::InvalidLength)?;
+
+        let bad_format = || io_error(io::ErrorKind::InvalidData, DecryptionError::BadFormat);
+        let mut fields = &header[MAGIC_LENGTH ..];
+        let mut take = |len: usize| {
+            let (field, rest) = fields.split_at(len);
+            fields = rest;
+            field
+        };
+        if take(1)[0] != STREAM_VERSION {
+            return Err(bad_format());
+        }
+        let ops = BigEndian::read_u64(take(8));
+        let mem = BigEndian::read_u64(take(8));
+        let chunk_size = BigEndian::read_u32(take(4)) as usize;
+        let salt = Salt::from_slice(take(SALT_LENGTH)).ok_or_else(bad_format)?;
+        let nonce = Nonce::from_slice(take(NONCEBYTES)).ok_or_else(bad_format)?;
+
+        if ops == 0 || ops > PwhashLimits::SENSITIVE.ops as u64
+            || mem == 0 || mem > PwhashLimits::SENSITIVE.mem as u64
+            || chunk_size == 0 || chunk_size > MAX_STREAM_CHUNK_SIZE
+        {
+            return Err(bad_format());
+        }
+        let limits = PwhashLimits {
+            ops: ops as usize,
+            mem: mem as usize,
+        };
+
+        let passkey = PassKey::with_limits(passphrase, salt, limits)
+            .map_err(|e| io_error(io::ErrorKind::InvalidData, DecryptionError::from(e)))?;
+
+        Ok(PassDecryptor {
+            inner: reader,
+            cipher: Some(ChunkCipher {
+                passkey,
+                nonce,
+                counter: 0,
+            }),
+            limits,
+            chunk_size,
+            buffer: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Limits of `pwhash` that were used to encrypt data.
+    pub fn limits(&self) -> PwhashLimits {
+        self.limits
+    }
+
+    /// Read and decrypt the next chunk into the buffer.
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let cipher = match self.cipher {
+            Some(ref mut cipher) => cipher,
+            None => return Ok(()),
+        };
+
+        let mut len = [0; 4];
+        read_exact(&mut self.inner, &mut len, DecryptionError::Failed)?;
+        let len = BigEndian::read_u32(&len);
+        let last = len & LAST_CHUNK_FLAG != 0;
+        let len = (len & !LAST_CHUNK_FLAG) as usize;
+        if len > self.chunk_size {
+            return Err(io_error(io::ErrorKind::InvalidData, DecryptionError::Failed));
+        }
+
+        let mut encrypted = vec![0; len + MACBYTES];
+        read_exact(&mut self.inner, &mut encrypted, DecryptionError::Failed)?;
+        let buffer = cipher.decrypt(&encrypted, last)
+            .map_err(|e| io_error(io::ErrorKind::InvalidData, e))?;
+
+        memzero(&mut self.buffer);
+        self.buffer = buffer;
+        self.position = 0;
+
+        if last {
+            self.cipher = None;
+            // there should be no data after the last chunk
+            let mut byte = [0];
+            loop {
+                match self.inner.read(&mut byte) {
+                    Ok(0) => break,
+                    Ok(_) => return Err(io_error(io::ErrorKind::InvalidData, DecryptionError::Failed)),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for PassDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.cipher.is_none() {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.position);
+        buf[.. len].copy_from_slice(&self.buffer[self.position .. self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl<R: Read> Drop for PassDecryptor<R> {
+    fn drop(&mut self) {
+        memzero(&mut self.buffer);
+    }
+}
+
+/**
+Re-encrypt data from `reader` with a new passphrase and write it to `writer` in
+the stream format.
+
+`reader` can provide data either in the stream format or in **TES** format,
+so this function can also be used to move data from **TES** format to the
+stream format with stronger `pwhash` limits.
+
+**Note that passphrases memory is not being zeroed after it has been
+used**. Code that provides passphrases should take care of zeroing that
+memory.
+
+E.g.
+
+```
+use std::io::Read;
+use self::tox::toxencryptsave::*;
+
+let encrypted = pass_encrypt(b"secret", b"old").unwrap();
+let reencrypted = reencrypt_stream(
+    encrypted.as_slice(),
+    Vec::new(),
+    b"old",
+    b"new",
+    PwhashLimits::INTERACTIVE
+).unwrap();
+
+let mut decrypted = Vec::new();
+PassDecryptor::new(reencrypted.as_slice(), b"new").unwrap()
+    .read_to_end(&mut decrypted).unwrap();
+assert_eq!(decrypted, b"secret");
+```
+*/
+pub fn reencrypt_stream<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    old_passphrase: &[u8],
+    new_passphrase: &[u8],
+    limits: PwhashLimits
+) -> io::Result<W> {
+    let mut decryptor = PassDecryptor::new(reader, old_passphrase)?;
+    let mut encryptor = PassEncryptor::with_limits(writer, new_passphrase, limits)?;
+    io::copy(&mut decryptor, &mut encryptor)?;
+    encryptor.finish()
+}
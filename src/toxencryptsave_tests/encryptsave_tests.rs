@@ -121,3 +121,35 @@ fn get_salt_wrong_magic_test() {
 
     assert_eq!(get_salt(&bad_ciphertext), None);
 }
+
+
+// pass_reencrypt()
+
+#[test]
+fn pass_reencrypt_test() {
+    crypto_init().unwrap();
+    let ciphertext = include_bytes!("ciphertext");
+    let reencrypted = pass_reencrypt(ciphertext, b"encryptsave", b"new").unwrap();
+
+    assert!(is_encrypted(&reencrypted));
+    assert_ne!(get_salt(&reencrypted), get_salt(ciphertext));
+    assert_eq!(pass_decrypt(&reencrypted, b"new").unwrap(), b"Hello world.\n");
+    assert_eq!(pass_decrypt(&reencrypted, b"encryptsave"), Err(DecryptionError::Failed));
+}
+
+#[test]
+fn pass_reencrypt_error_test() {
+    crypto_init().unwrap();
+    let ciphertext = include_bytes!("ciphertext");
+
+    // wrong old passphrase
+    assert_eq!(
+        pass_reencrypt(ciphertext, b"wrong", b"new"),
+        Err(ReencryptionError::Decryption(DecryptionError::Failed))
+    );
+    // empty new passphrase
+    assert_eq!(
+        pass_reencrypt(ciphertext, b"encryptsave", &[]),
+        Err(ReencryptionError::Encryption(KeyDerivationError::Null.into()))
+    );
+}
@@ -0,0 +1,217 @@
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::toxencryptsave::*;
+use crate::toxcore::crypto_core::*;
+
+
+fn decrypt(data: &[u8], passphrase: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decryptor = PassDecryptor::new(data, passphrase)?;
+    let mut decrypted = Vec::new();
+    decryptor.read_to_end(&mut decrypted)?;
+    Ok(decrypted)
+}
+
+fn encrypt(data: &[u8], passphrase: &[u8]) -> Vec<u8> {
+    let mut encryptor = PassEncryptor::new(Vec::new(), passphrase).unwrap();
+    encryptor.write_all(data).unwrap();
+    encryptor.finish().unwrap()
+}
+
+fn plaintext() -> Vec<u8> {
+    (0 .. STREAM_CHUNK_SIZE * 2 + 123).map(|i| i as u8).collect()
+}
+
+fn assert_invalid_data(result: io::Result<Vec<u8>>) {
+    assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+
+// PassEncryptor / PassDecryptor
+
+#[test]
+fn stream_encrypt_decrypt_test() {
+    crypto_init().unwrap();
+    let plaintext = plaintext();
+    let encrypted = encrypt(&plaintext, b"encryptsave");
+
+    assert!(is_stream_encrypted(&encrypted));
+    assert!(!is_encrypted(&encrypted));
+    // 3 chunks
+    assert_eq!(encrypted.len(), STREAM_HEADER_LENGTH + plaintext.len() + 3 * (4 + MACBYTES));
+    assert_eq!(decrypt(&encrypted, b"encryptsave").unwrap(), plaintext);
+}
+
+#[test]
+fn stream_encrypt_decrypt_empty_test() {
+    crypto_init().unwrap();
+    let encrypted = encrypt(&[], b"encryptsave");
+
+    assert_eq!(encrypted.len(), STREAM_HEADER_LENGTH + 4 + MACBYTES);
+    assert!(decrypt(&encrypted, b"encryptsave").unwrap().is_empty());
+}
+
+#[test]
+fn stream_encrypt_decrypt_full_chunks_test() {
+    crypto_init().unwrap();
+    let plaintext = vec![42; STREAM_CHUNK_SIZE];
+    let encrypted = encrypt(&plaintext, b"encryptsave");
+
+    assert_eq!(decrypt(&encrypted, b"encryptsave").unwrap(), plaintext);
+}
+
+#[test]
+fn stream_encrypt_with_limits_test() {
+    crypto_init().unwrap();
+    let limits = PwhashLimits { ops: PwhashLimits::INTERACTIVE.ops * 2, ..PwhashLimits::INTERACTIVE };
+    let mut encryptor = PassEncryptor::with_limits(Vec::new(), b"encryptsave", limits).unwrap();
+    encryptor.write_all(b"Hello world.\n").unwrap();
+    let encrypted = encryptor.finish().unwrap();
+
+    let mut decryptor = PassDecryptor::new(encrypted.as_slice(), b"encryptsave").unwrap();
+    assert_eq!(decryptor.limits(), limits);
+    let mut decrypted = Vec::new();
+    decryptor.read_to_end(&mut decrypted).unwrap();
+    assert_eq!(decrypted, b"Hello world.\n");
+}
+
+#[test]
+fn stream_encrypt_empty_passphrase_test() {
+    crypto_init().unwrap();
+    let error = PassEncryptor::new(Vec::new(), &[]).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn stream_decrypt_tes_test() {
+    crypto_init().unwrap();
+    let ciphertext = include_bytes!("ciphertext");
+
+    let decryptor = PassDecryptor::new(ciphertext as &[u8], b"encryptsave").unwrap();
+    assert_eq!(decryptor.limits(), PwhashLimits::INTERACTIVE);
+    assert_eq!(decrypt(ciphertext, b"encryptsave").unwrap(), b"Hello world.\n");
+    assert_invalid_data(decrypt(ciphertext, b"wrong"));
+}
+
+#[test]
+fn stream_decrypt_wrong_passphrase_test() {
+    crypto_init().unwrap();
+    let encrypted = encrypt(b"Hello world.\n", b"encryptsave");
+    assert_invalid_data(decrypt(&encrypted, b"wrong"));
+}
+
+#[test]
+fn stream_decrypt_bad_format_test() {
+    crypto_init().unwrap();
+    let encrypted = encrypt(b"Hello world.\n", b"encryptsave");
+
+    // wrong magic
+    assert_invalid_data(decrypt(b"Hello world.\n", b"encryptsave"));
+    // not enough data for magic
+    assert_invalid_data(decrypt(&encrypted[.. 3], b"encryptsave"));
+    // not enough data for header
+    assert_invalid_data(decrypt(&encrypted[.. STREAM_HEADER_LENGTH - 1], b"encryptsave"));
+
+    // unsupported version
+    let mut bad = encrypted.clone();
+    bad[MAGIC_LENGTH] = STREAM_VERSION + 1;
+    assert_invalid_data(decrypt(&bad, b"encryptsave"));
+
+    // too strong limits
+    let mut bad = encrypted.clone();
+    BigEndian::write_u64(&mut bad[MAGIC_LENGTH + 1 .. MAGIC_LENGTH + 9], u64::max_value());
+    assert_invalid_data(decrypt(&bad, b"encryptsave"));
+}
+
+#[test]
+fn stream_decrypt_truncated_test() {
+    crypto_init().unwrap();
+    let plaintext = plaintext();
+    let encrypted = encrypt(&plaintext, b"encryptsave");
+
+    // the last chunk is missing
+    let last_chunk_len = 4 + 123 + MACBYTES;
+    assert_invalid_data(decrypt(&encrypted[.. encrypted.len() - last_chunk_len], b"encryptsave"));
+    // the last chunk is cut
+    assert_invalid_data(decrypt(&encrypted[.. encrypted.len() - 1], b"encryptsave"));
+    // there is data after the last chunk
+    let mut bad = encrypted.clone();
+    bad.push(0);
+    assert_invalid_data(decrypt(&bad, b"encryptsave"));
+}
+
+#[test]
+fn stream_decrypt_not_finished_test() {
+    crypto_init().unwrap();
+    let mut encrypted = Vec::new();
+    {
+        let mut encryptor = PassEncryptor::new(&mut encrypted, b"encryptsave").unwrap();
+        encryptor.write_all(&plaintext()).unwrap();
+        encryptor.flush().unwrap();
+    }
+    assert_invalid_data(decrypt(&encrypted, b"encryptsave"));
+}
+
+#[test]
+fn stream_decrypt_reordered_test() {
+    crypto_init().unwrap();
+    let encrypted = encrypt(&plaintext(), b"encryptsave");
+
+    let chunk_len = 4 + STREAM_CHUNK_SIZE + MACBYTES;
+    let first = STREAM_HEADER_LENGTH .. STREAM_HEADER_LENGTH + chunk_len;
+    let second = STREAM_HEADER_LENGTH + chunk_len .. STREAM_HEADER_LENGTH + 2 * chunk_len;
+    let mut bad = encrypted[.. STREAM_HEADER_LENGTH].to_vec();
+    bad.extend_from_slice(&encrypted[second.clone()]);
+    bad.extend_from_slice(&encrypted[first]);
+    bad.extend_from_slice(&encrypted[second.end ..]);
+    assert_invalid_data(decrypt(&bad, b"encryptsave"));
+
+    // the last chunk flag is set for the first chunk
+    let mut bad = encrypted.clone();
+    bad[STREAM_HEADER_LENGTH] |= 0x80;
+    assert_invalid_data(decrypt(&bad, b"encryptsave"));
+}
+
+
+// reencrypt_stream()
+
+#[test]
+fn reencrypt_stream_test() {
+    crypto_init().unwrap();
+    let plaintext = plaintext();
+    let encrypted = encrypt(&plaintext, b"old");
+
+    let limits = PwhashLimits { ops: PwhashLimits::INTERACTIVE.ops * 2, ..PwhashLimits::INTERACTIVE };
+    let reencrypted = reencrypt_stream(encrypted.as_slice(), Vec::new(), b"old", b"new", limits).unwrap();
+
+    let decryptor = PassDecryptor::new(reencrypted.as_slice(), b"new").unwrap();
+    assert_eq!(decryptor.limits(), limits);
+    assert_eq!(decrypt(&reencrypted, b"new").unwrap(), plaintext);
+    assert_invalid_data(decrypt(&reencrypted, b"old"));
+}
+
+#[test]
+fn reencrypt_stream_tes_test() {
+    crypto_init().unwrap();
+    let ciphertext = include_bytes!("ciphertext");
+    let reencrypted = reencrypt_stream(
+        ciphertext as &[u8],
+        Vec::new(),
+        b"encryptsave",
+        b"new",
+        PwhashLimits::INTERACTIVE
+    ).unwrap();
+
+    assert!(is_stream_encrypted(&reencrypted));
+    assert_eq!(decrypt(&reencrypted, b"new").unwrap(), b"Hello world.\n");
+}
+
+#[test]
+fn reencrypt_stream_wrong_passphrase_test() {
+    crypto_init().unwrap();
+    let encrypted = encrypt(b"Hello world.\n", b"old");
+    let error = reencrypt_stream(encrypted.as_slice(), Vec::new(), b"wrong", b"new", PwhashLimits::INTERACTIVE)
+        .err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
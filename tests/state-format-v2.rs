@@ -0,0 +1,41 @@
+use tox::toxcore::binary_io::*;
+use tox::toxcore::state_format::{old, v2};
+
+/*
+Migrate a real™ profile in the old format to the versioned one and back.
+Converted back profile must be serialized to the same bytes as the original
+one, except for the zeros that trail after the data in original
+implementation.
+*/
+
+fn migrate(bytes: &[u8]) {
+    let (_rest, old_state) = old::State::from_bytes(bytes).unwrap();
+
+    let state = v2::State::from(old_state.clone());
+    let mut buf = vec![0; 1024 * 1024];
+    let (_, size) = state.to_bytes((&mut buf, 0)).unwrap();
+    let (rest, decoded) = v2::State::from_bytes(&buf[..size]).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(decoded, state);
+
+    let migrated_back = old::State::from(decoded);
+    assert_eq!(migrated_back, old_state);
+
+    let (_, size) = migrated_back.to_bytes((&mut buf, 0)).unwrap();
+    assert_eq!(&bytes[..size], &buf[..size]);
+
+    // c-toxcore appends `0`s after EOF because reasons
+    for b in &bytes[size..] {
+        assert_eq!(0, *b);
+    }
+}
+
+#[test]
+fn migrate_old_state_format_with_contacts() {
+    migrate(include_bytes!("data/old-profile-with-contacts.tox"));
+}
+
+#[test]
+fn migrate_old_state_format_no_friends() {
+    migrate(include_bytes!("data/old-profile-no-friends.tox"));
+}